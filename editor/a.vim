@@ -10,11 +10,11 @@ endif
 
 syn keyword aKeyword if else
 syn keyword aKeyword true false
-syn keyword aKeyword or and let in
-syn keyword aKeyword fn type enum
-syn keyword aKeyword return
+syn keyword aKeyword or and let mut in
+syn keyword aKeyword fn type enum struct
+syn keyword aKeyword return while as match
 
-syn keyword aFunction println print
+syn keyword aFunction println print print_int arg_count arg env
 
 hi link aKeyword Keyword
 
@@ -12,11 +12,9 @@ fi
 
 filename=$1;
 exefile=${filename%.*}
-asmfile=$exefile".asm"
 
 function clean() {
   rm $exefile;
-  rm $asmfile;
 }
 
 function run() {
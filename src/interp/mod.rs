@@ -0,0 +1,1104 @@
+//! Evaluates the ast directly, without the ir or an assembler.
+//!
+//! Behaves like the native backend so it can be used as a reference for it:
+//! integers are 64 bit and wrap, `/` is signed, booleans are `1` and `0` when
+//! used as numbers and a call in tail position does not grow the stack.
+//! A `str` has no address here, so it can not be used as a number. Casts keep
+//! the bits as they are, so a value that does not fit the type is an error.
+//! Structs are values too, copied whenever they are bound, passed or returned.
+//! Their `u8` and `u32` fields keep only the low bits of what is stored, as
+//! memory does. So do elements of arrays whose type is known, those of
+//! fields, parameters and return values, but not those of an array literal
+//! bound to a variable. An index out of bounds is an error. An enum holds
+//! the name of its variant and the fields of it, a `match` on a value no arm
+//! matches is an error.
+//! Like the slots of the compiled program, every variable lives in a cell
+//! of its own, one per `let` for each call. A reference is the cell and the
+//! fields and elements leading into it, what is written through one is not
+//! narrowed. Returning a reference to a cell of the returning function is an
+//! error.
+//! `print`, `println`, `print_int`, `arg_count`, `arg` and `env` are builtin
+//! unless the program defines a function of the same name.
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::parse::{
+    Expr, ExprArray, ExprAssign, ExprBinary, ExprBlock, ExprCall, ExprCast, ExprDeref, ExprField,
+    ExprIf, ExprIndex, ExprLet, ExprLit, ExprMatch, ExprPath, ExprRef, ExprRepeat, ExprReturn,
+    ExprStruct, ExprVar, ExprWhile, Ident, Item, ItemEnum, ItemFn, ItemStruct, Lit, Op, Pat,
+    PatVariant, Statement, Type,
+};
+
+/// Calls nested deeper than this are reported instead of overflowing the
+/// stack of the interpreter itself.
+const MAX_DEPTH: usize = 10_000;
+/// Every call of the interpreted program takes a few recursive calls of the
+/// interpreter, so it runs on a thread with a stack big enough for `MAX_DEPTH`.
+const STACK_SIZE: usize = 1 << 30;
+/// What reading through a reference to a cell that is gone reports.
+const DANGLING: &str = "a reference outlived what it refers to";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Char(char),
+    Str(Arc<str>),
+    /// The name of the struct and its fields, in the order they are declared.
+    Struct(String, Vec<(String, Value)>),
+    Array(Vec<Value>),
+    /// The name of the enum, of the variant and its fields.
+    Enum(String, String, Vec<Value>),
+    /// The cell referred to and the way into it.
+    Ref(usize, Vec<Step>),
+}
+
+/// A field or element of what a reference leads into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Field(String),
+    Index(usize),
+}
+
+/// Where the value of an expression is: somewhere in a cell, with its type
+/// when it is known, or nowhere when it is a temporary.
+enum Place<'a> {
+    At(usize, Vec<Step>, Option<&'a Type>),
+    Temp(Value),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Result<i64, String> {
+        match self {
+            Self::Int(i) => Ok(*i),
+            Self::Bool(b) => Ok(*b as i64),
+            Self::Char(c) => Ok(*c as i64),
+            Self::Str(s) => Err(format!("{s:?} is not a number")),
+            Self::Struct(name, _) => Err(format!("a '{name}' is not a number")),
+            Self::Array(_) => Err("an array is not a number".into()),
+            Self::Enum(name, ..) => Err(format!("a '{name}' is not a number")),
+            Self::Ref(..) => Err("a reference is not a number".into()),
+        }
+    }
+
+    fn is_true(&self) -> Result<bool, String> {
+        Ok(self.as_int()? != 0)
+    }
+}
+
+/// Why evaluation of an expression stopped early.
+enum Unwind {
+    Return(Value),
+    /// `return f(args)`, the caller runs `f` in place of the returning
+    /// function.
+    TailCall(String, Vec<Value>),
+    Error(String),
+}
+
+impl From<String> for Unwind {
+    fn from(error: String) -> Self {
+        Self::Error(error)
+    }
+}
+
+type Eval = Result<Value, Unwind>;
+
+/// Runs `main` with the command line `args` and gives back what it returned.
+pub fn run(items: Vec<Item>, args: &[String]) -> Result<i64, Vec<String>> {
+    run_to(items, args, &mut std::io::stdout())
+}
+
+/// Like `run`, with the output of the program going to `out`.
+pub fn run_to(
+    items: Vec<Item>,
+    args: &[String],
+    out: &mut (dyn Write + Send),
+) -> Result<i64, Vec<String>> {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || {
+                let mut interpreter = Interpreter::new(&items, args, out);
+                if !interpreter.funcs.contains_key("main") {
+                    return Err("program has no 'main' function".to_string());
+                }
+                let main_args = interpreter.main_args();
+                interpreter.call("main", main_args)
+            })
+            .map_err(|e| e.to_string())?
+            .join()
+            .unwrap_or_else(|_| Err("interpreter panicked".into()))
+    })
+    .and_then(|value| value.as_int())
+    .map_err(|e| vec![e])
+}
+
+struct Interpreter<'a> {
+    funcs: HashMap<&'a str, &'a ItemFn>,
+    structs: HashMap<&'a str, &'a ItemStruct>,
+    enums: HashMap<&'a str, &'a ItemEnum>,
+    /// The cells of the variables of the running function, one map per
+    /// block.
+    scopes: Vec<HashMap<String, usize>>,
+    /// The cell of each `let`, binding and temporary referred to in the
+    /// running function, by the address of its node.
+    slots: HashMap<*const (), usize>,
+    cells: Vec<Value>,
+    depth: usize,
+    args: &'a [String],
+    out: &'a mut (dyn Write + Send),
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(items: &'a [Item], args: &'a [String], out: &'a mut (dyn Write + Send)) -> Self {
+        let mut funcs = HashMap::new();
+        let mut structs = HashMap::new();
+        let mut enums = HashMap::new();
+        for item in items {
+            match item {
+                Item::Fn(item_fn) => {
+                    funcs.insert(item_fn.name.value.as_str(), &**item_fn);
+                }
+                Item::Struct(item_struct) => {
+                    structs
+                        .entry(item_struct.name.value.as_str())
+                        .or_insert(item_struct);
+                }
+                Item::Enum(item_enum) => {
+                    enums
+                        .entry(item_enum.name.value.as_str())
+                        .or_insert(item_enum);
+                }
+            }
+        }
+        Self {
+            funcs,
+            structs,
+            enums,
+            scopes: vec![],
+            slots: HashMap::new(),
+            cells: vec![],
+            depth: 0,
+            args,
+            out,
+        }
+    }
+
+    /// `argc` and `argv` when `main` takes them. There is no argv here, `arg`
+    /// reads the arguments instead.
+    fn main_args(&self) -> Vec<Value> {
+        match self.funcs.get("main") {
+            Some(main) if main.params.len() == 2 => {
+                vec![Value::Int(self.args.len() as i64), Value::Int(0)]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("stack overflow calling '{name}'"));
+        }
+        self.depth += 1;
+        let scopes = std::mem::take(&mut self.scopes);
+        let slots = std::mem::take(&mut self.slots);
+        let base = self.cells.len();
+        let result = self.run_frame(name.to_string(), args, base);
+        self.cells.truncate(base);
+        self.scopes = scopes;
+        self.slots = slots;
+        self.depth -= 1;
+        result
+    }
+
+    /// Runs `name` with its cells from `base` on.
+    fn run_frame(
+        &mut self,
+        mut name: String,
+        mut args: Vec<Value>,
+        base: usize,
+    ) -> Result<Value, String> {
+        // The function the cells from `base` on belong to, which a tail call
+        // keeping them does not change.
+        let mut owner = name.clone();
+        loop {
+            let Some(func) = self.funcs.get(name.as_str()).copied() else {
+                return self.builtin(&name, args);
+            };
+            if func.params.len() != args.len() {
+                return Err(format!(
+                    "'{name}' takes {} arguments but is given {}",
+                    func.params.len(),
+                    args.len()
+                ));
+            }
+            let params = func
+                .params
+                .iter()
+                .zip(args)
+                .map(|(p, arg)| {
+                    self.cells.push(narrow_array(arg, &p.kind));
+                    (p.name.value.clone(), self.cells.len() - 1)
+                })
+                .collect();
+            self.scopes = vec![params];
+            let result = match self.block(&func.block) {
+                Ok(value) | Err(Unwind::Return(value)) if refers_to(&value, base) => {
+                    return Err(format!(
+                        "'{owner}' returned a reference to one of its locals"
+                    ))
+                }
+                result => result,
+            };
+            match result {
+                // Like the compiled program, a `main` returning nothing exits
+                // with 0 when it runs off the end.
+                Ok(_) if name == "main" && func.ret_type.is_none() => return Ok(Value::Int(0)),
+                Ok(value) | Err(Unwind::Return(value)) => {
+                    return Ok(match &func.ret_type {
+                        Some(ty) => narrow_array(value, ty),
+                        None => value,
+                    })
+                }
+                Err(Unwind::TailCall(callee, callee_args)) => {
+                    // The cells stay while the arguments refer to them.
+                    if !callee_args.iter().any(|arg| refers_to(arg, base)) {
+                        self.cells.truncate(base);
+                        owner = callee.clone();
+                    }
+                    self.slots.clear();
+                    name = callee;
+                    args = callee_args;
+                }
+                Err(Unwind::Error(e)) => return Err(e),
+            }
+        }
+    }
+
+    fn builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let arity = match name {
+            "print" | "println" | "print_int" | "arg" | "env" => 1,
+            "arg_count" => 0,
+            _ => return Err(format!("call to unknown function '{name}'")),
+        };
+        if args.len() != arity {
+            return Err(format!(
+                "'{name}' takes {arity} arguments but is given {}",
+                args.len()
+            ));
+        }
+        let written = match (name, args.first()) {
+            ("print", Some(Value::Str(s))) => write!(self.out, "{s}"),
+            ("println", Some(Value::Str(s))) => writeln!(self.out, "{s}"),
+            ("print_int", Some(arg)) => write!(self.out, "{}", arg.as_int()?),
+            ("arg_count", None) => return Ok(Value::Int(self.args.len() as i64)),
+            ("arg", Some(arg)) => {
+                let arg = usize::try_from(arg.as_int()?)
+                    .ok()
+                    .and_then(|i| self.args.get(i));
+                return Ok(Value::Str(arg.map_or("", String::as_str).into()));
+            }
+            ("env", Some(Value::Str(name))) => {
+                let valid = !name.is_empty() && !name.contains(['=', '\0']);
+                let value = valid
+                    .then(|| std::env::var(&**name).ok())
+                    .flatten()
+                    .unwrap_or_default();
+                return Ok(Value::Str(value.into()));
+            }
+            (_, arg) => return Err(format!("'{name}' takes a str, not {:?}", arg.unwrap())),
+        };
+        written.map_err(|e| format!("could not write to stdout: {e}"))?;
+        Ok(Value::Int(0))
+    }
+
+    fn block(&mut self, block: &ExprBlock) -> Eval {
+        self.scopes.push(HashMap::new());
+        let mut value = Value::Int(0);
+        for Statement { stmt, .. } in block.stmts.iter() {
+            value = self.expr(stmt)?;
+        }
+        self.scopes.pop();
+        Ok(value)
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Eval {
+        match expr {
+            Expr::Lit(ExprLit { lit }) => Ok(lit_value(lit)?),
+            Expr::Binary(expr_binary) => self.binary(expr_binary),
+            Expr::Call(expr_call) => match &*expr_call.caller {
+                Expr::Path(path) => self.variant(path, &expr_call.args),
+                _ => {
+                    let (name, args) = self.call_args(expr_call)?;
+                    Ok(self.call(&name, args)?)
+                }
+            },
+            Expr::Var(ExprVar { name }) => Ok(self.cells[self.var(name)?].clone()),
+            Expr::If(expr_if) => self.expr_if(expr_if),
+            Expr::While(ExprWhile { cond, body, .. }) => loop {
+                let cond = self.expr(cond)?;
+                if !cond.is_true()? {
+                    return Ok(cond);
+                }
+                self.block(body)?;
+            },
+            Expr::Block(block) => self.block(block),
+            Expr::Return(ExprReturn { expr, .. }) => match &**expr {
+                Expr::Call(expr_call) if !matches!(*expr_call.caller, Expr::Path(..)) => {
+                    let (name, args) = self.call_args(expr_call)?;
+                    Err(Unwind::TailCall(name, args))
+                }
+                expr => Err(Unwind::Return(self.expr(expr)?)),
+            },
+            Expr::Let(ExprLet { name, value, .. }) => {
+                let value = self.expr(value)?;
+                let cell = self.cell(site(expr), value.clone());
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(name.value.clone(), cell);
+                }
+                Ok(value)
+            }
+            Expr::Assign(ExprAssign { target, value }) => {
+                let assignable = matches!(
+                    **target,
+                    Expr::Var(..) | Expr::Field(..) | Expr::Index(..) | Expr::Deref(..)
+                );
+                if !assignable {
+                    return Err(format!("can not assign to '{target}'").into());
+                }
+                let place = self.locate(target)?;
+                let value = self.expr(value)?;
+                if let Place::At(cell, path, ty) = place {
+                    *self.resolve_mut(cell, &path)? = narrow(value.clone(), ty);
+                }
+                Ok(value)
+            }
+            Expr::Struct(expr_struct) => self.expr_struct(expr_struct),
+            Expr::Cast(ExprCast { expr, ty, .. }) => {
+                let value = self.expr(expr)?;
+                Ok(cast(value, ty)?)
+            }
+            Expr::Array(ExprArray { elems, .. }) => elems
+                .iter()
+                .map(|elem| self.expr(elem))
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            Expr::Repeat(ExprRepeat { value, len, .. }) => {
+                let value = self.expr(value)?;
+                let len = len
+                    .parse::<u32>()
+                    .map_err(|_| format!("array length {len} is too big"))?;
+                Ok(Value::Array(vec![value; len as usize]))
+            }
+            Expr::Field(..) | Expr::Index(..) | Expr::Deref(..) => {
+                let place = self.locate(expr)?;
+                Ok(self.load(place)?)
+            }
+            Expr::Path(path) => self.variant(path, &[]),
+            Expr::Match(expr_match) => self.expr_match(expr_match),
+            Expr::Ref(ExprRef { expr: inner, .. }) => Ok(match self.locate(inner)? {
+                Place::At(cell, path, _) => Value::Ref(cell, path),
+                Place::Temp(value) => Value::Ref(self.cell(site(expr), value), vec![]),
+            }),
+        }
+    }
+
+    fn var(&self, name: &Ident) -> Result<usize, String> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.value).copied())
+            .ok_or_else(|| format!("undefined variable '{name}'"))
+    }
+
+    /// The cell of `site` in the running function, holding `value`.
+    fn cell(&mut self, site: *const (), value: Value) -> usize {
+        if let Some(&cell) = self.slots.get(&site) {
+            self.cells[cell] = value;
+            return cell;
+        }
+        self.cells.push(value);
+        self.slots.insert(site, self.cells.len() - 1);
+        self.cells.len() - 1
+    }
+
+    /// Where the value of `expr` is, a field or element of a temporary is a
+    /// temporary too.
+    fn locate(&mut self, expr: &Expr) -> Result<Place<'a>, Unwind> {
+        match expr {
+            Expr::Var(ExprVar { name }) => Ok(Place::At(self.var(name)?, vec![], None)),
+            Expr::Deref(ExprDeref { expr, .. }) => match self.expr(expr)? {
+                Value::Ref(cell, path) => Ok(Place::At(cell, path, None)),
+                _ => Err(format!("can not dereference '{expr}', it is not a reference").into()),
+            },
+            Expr::Field(ExprField { expr, name, .. }) => {
+                let (cell, mut path) = match self.base(expr)? {
+                    Place::At(cell, path, _) => (cell, path),
+                    Place::Temp(value) => {
+                        return Ok(Place::Temp(field(&value, &name.value)?.clone()))
+                    }
+                };
+                let value = self.resolve(cell, &path)?;
+                field(value, &name.value)?;
+                let ty = match value {
+                    Value::Struct(kind, _) => self
+                        .structs
+                        .get(kind.as_str())
+                        .copied()
+                        .and_then(|item| item.fields.iter().find(|p| p.name.value == name.value))
+                        .map(|param| &param.kind),
+                    _ => None,
+                };
+                path.push(Step::Field(name.value.clone()));
+                Ok(Place::At(cell, path, ty))
+            }
+            Expr::Index(ExprIndex { expr, index, .. }) => {
+                let base = self.base(expr)?;
+                let index = self.expr(index)?.as_int()?;
+                let (cell, mut path, ty) = match base {
+                    Place::At(cell, path, ty) => (cell, path, ty),
+                    Place::Temp(value) => return Ok(Place::Temp(element(value, index)?)),
+                };
+                let Value::Array(elems) = self.resolve(cell, &path)? else {
+                    return Err(String::from("can not index a value that is not an array").into());
+                };
+                let len = elems.len();
+                let i = usize::try_from(index)
+                    .ok()
+                    .filter(|i| *i < len)
+                    .ok_or_else(|| out_of_bounds(index, len))?;
+                path.push(Step::Index(i));
+                let ty = match ty {
+                    Some(Type::Array(array)) => Some(&array.elem),
+                    _ => None,
+                };
+                Ok(Place::At(cell, path, ty))
+            }
+            expr => Ok(Place::Temp(self.expr(expr)?)),
+        }
+    }
+
+    /// Like `locate`, with a reference followed to what it refers to the way
+    /// a field or an index does.
+    fn base(&mut self, expr: &Expr) -> Result<Place<'a>, Unwind> {
+        Ok(match self.locate(expr)? {
+            Place::At(cell, path, ty) => match self.resolve(cell, &path)? {
+                Value::Ref(cell, path) => Place::At(*cell, path.clone(), None),
+                _ => Place::At(cell, path, ty),
+            },
+            Place::Temp(Value::Ref(cell, path)) => Place::At(cell, path, None),
+            place => place,
+        })
+    }
+
+    fn load(&self, place: Place) -> Result<Value, String> {
+        match place {
+            Place::At(cell, path, _) => self.resolve(cell, &path).cloned(),
+            Place::Temp(value) => Ok(value),
+        }
+    }
+
+    /// The value `path` leads to from `cell`.
+    fn resolve(&self, cell: usize, path: &[Step]) -> Result<&Value, String> {
+        let mut value = self.cells.get(cell);
+        for step in path {
+            value = match (value, step) {
+                (Some(Value::Struct(_, fields)), Step::Field(name)) => {
+                    fields.iter().find(|(f, _)| f == name).map(|(_, v)| v)
+                }
+                (Some(Value::Array(elems)), Step::Index(i)) => elems.get(*i),
+                _ => None,
+            };
+        }
+        value.ok_or_else(|| DANGLING.into())
+    }
+
+    fn resolve_mut(&mut self, cell: usize, path: &[Step]) -> Result<&mut Value, String> {
+        let mut value = self.cells.get_mut(cell);
+        for step in path {
+            value = match (value, step) {
+                (Some(Value::Struct(_, fields)), Step::Field(name)) => {
+                    fields.iter_mut().find(|(f, _)| f == name).map(|(_, v)| v)
+                }
+                (Some(Value::Array(elems)), Step::Index(i)) => elems.get_mut(*i),
+                _ => None,
+            };
+        }
+        value.ok_or_else(|| DANGLING.into())
+    }
+
+    /// The variant `path` holding `args`.
+    fn variant(&mut self, path: &ExprPath, args: &[Expr]) -> Eval {
+        let ExprPath { ty, variant, .. } = path;
+        let Some(item) = self.enums.get(ty.value.as_str()).copied() else {
+            return Err(format!("unknown enum '{ty}'").into());
+        };
+        let Some(decl) = item.variants.iter().find(|v| v.name.value == variant.value) else {
+            return Err(format!("enum '{ty}' has no variant '{variant}'").into());
+        };
+        if decl.fields.len() != args.len() {
+            let error = format!(
+                "'{path:#}' takes {} fields but is given {}",
+                decl.fields.len(),
+                args.len()
+            );
+            return Err(error.into());
+        }
+        let mut fields = vec![];
+        for (arg, ty) in args.iter().zip(decl.fields.iter()) {
+            fields.push(narrow(self.expr(arg)?, Some(ty)));
+        }
+        Ok(Value::Enum(ty.value.clone(), variant.value.clone(), fields))
+    }
+
+    /// Runs the first arm whose pattern matches, with the names it binds in
+    /// a scope of their own.
+    fn expr_match(&mut self, expr_match: &ExprMatch) -> Eval {
+        let ExprMatch { expr, arms, .. } = expr_match;
+        let place = self.base(expr)?;
+        let value = self.load(place)?;
+        for arm in arms.iter() {
+            let Some(bindings) = matches(&arm.pat, &value)? else {
+                continue;
+            };
+            let scope = bindings
+                .into_iter()
+                .map(|(name, value)| (name.value.clone(), self.cell(site(name), value)))
+                .collect();
+            self.scopes.push(scope);
+            let value = self.expr(&arm.body)?;
+            self.scopes.pop();
+            return Ok(value);
+        }
+        let value = match &value {
+            Value::Enum(name, variant, _) => format!("{name}::{variant}"),
+            value => value.as_int()?.to_string(),
+        };
+        Err(format!("no arm of the match matches {value}").into())
+    }
+
+    /// Without an `else` the value is the condition, as in the ir.
+    fn expr_if(&mut self, expr_if: &ExprIf) -> Eval {
+        let ExprIf {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        } = expr_if;
+        let cond = self.expr(cond)?;
+        if cond.is_true()? {
+            let value = self.block(then_branch)?;
+            return Ok(if else_branch.is_some() { value } else { cond });
+        }
+        match else_branch {
+            Some((_, else_branch)) => self.expr(else_branch),
+            None => Ok(cond),
+        }
+    }
+
+    fn expr_struct(&mut self, expr_struct: &ExprStruct) -> Eval {
+        let ExprStruct { name, fields, .. } = expr_struct;
+        let Some(item) = self.structs.get(name.value.as_str()).copied() else {
+            return Err(format!("unknown struct '{name}'").into());
+        };
+        let mut given: Vec<(&str, Value)> = vec![];
+        for (field, value) in fields.iter() {
+            if !item.fields.iter().any(|p| p.name.value == field.value) {
+                return Err(format!("struct '{name}' has no field '{field}'").into());
+            }
+            if given.iter().any(|(f, _)| *f == field.value) {
+                return Err(format!("field '{field}' of '{name}' is given more than once").into());
+            }
+            given.push((&field.value, self.expr(value)?));
+        }
+        let mut values = vec![];
+        let mut missing = vec![];
+        for param in item.fields.iter() {
+            let field = param.name.value.as_str();
+            match given.iter().position(|(f, _)| *f == field) {
+                Some(i) => {
+                    let value = narrow(given.swap_remove(i).1, Some(&param.kind));
+                    values.push((field.to_string(), value));
+                }
+                None => missing.push(format!("'{field}'")),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!("missing {} in '{name}'", missing.join(", ")).into());
+        }
+        Ok(Value::Struct(name.value.clone(), values))
+    }
+
+    fn binary(&mut self, expr_binary: &ExprBinary) -> Eval {
+        let ExprBinary { left, right, op } = expr_binary;
+        let lhs = self.expr(left)?.as_int()?;
+        let rhs = self.expr(right)?.as_int()?;
+        let value = match op {
+            Op::Add(_) => Value::Int(lhs.wrapping_add(rhs)),
+            Op::Sub(_) => Value::Int(lhs.wrapping_sub(rhs)),
+            Op::Mul(_) => Value::Int(lhs.wrapping_mul(rhs)),
+            Op::Div(_) if rhs == 0 => return Err(String::from("division by zero").into()),
+            Op::Div(_) => Value::Int(lhs.wrapping_div(rhs)),
+            Op::Grt(_) => Value::Bool(lhs > rhs),
+            Op::Les(_) => Value::Bool(lhs < rhs),
+            Op::Geq(_) => Value::Bool(lhs >= rhs),
+            Op::Leq(_) => Value::Bool(lhs <= rhs),
+            Op::Neq(_) => Value::Bool(lhs != rhs),
+            Op::EqualEqual(_) => Value::Bool(lhs == rhs),
+            Op::Not(_) | Op::Equal(_) => {
+                return Err(format!("'{op}' is not a binary operator").into())
+            }
+        };
+        Ok(value)
+    }
+
+    fn call_args(&mut self, expr_call: &ExprCall) -> Result<(String, Vec<Value>), Unwind> {
+        let ExprCall { caller, args, .. } = expr_call;
+        let Expr::Var(ExprVar { name }) = &**caller else {
+            return Err(format!("can not call '{caller}'").into());
+        };
+        let args = args
+            .iter()
+            .map(|arg| self.expr(arg))
+            .collect::<Result<Vec<Value>, Unwind>>()?;
+        Ok((name.value.clone(), args))
+    }
+}
+
+/// The names `pat` binds if it matches `value`.
+fn matches<'p>(pat: &'p Pat, value: &Value) -> Result<Option<Vec<(&'p Ident, Value)>>, String> {
+    let mut bindings = vec![];
+    match pat {
+        Pat::Wild(..) => {}
+        Pat::Bind(name) => bindings.push((name, value.clone())),
+        Pat::Lit(lit) => {
+            if lit_value(lit)?.as_int()? != value.as_int()? {
+                return Ok(None);
+            }
+        }
+        Pat::Variant(PatVariant { path, fields }) => {
+            let Value::Enum(name, variant, values) = value else {
+                let error = format!("'{path:#}' can not match a value that is not an enum");
+                return Err(error);
+            };
+            if path.ty.value != *name {
+                return Err(format!("'{path:#}' is not a variant of '{name}'"));
+            }
+            if path.variant.value != *variant {
+                return Ok(None);
+            }
+            if fields.len() != values.len() {
+                let error = format!(
+                    "'{path:#}' has {} fields, not {}",
+                    values.len(),
+                    fields.len()
+                );
+                return Err(error);
+            }
+            for (field, value) in fields.iter().zip(values) {
+                if field.value != "_" {
+                    bindings.push((field, value.clone()));
+                }
+            }
+        }
+    }
+    Ok(Some(bindings))
+}
+
+/// The address of `node`, which stays the same while the program runs.
+fn site<T>(node: &T) -> *const () {
+    (node as *const T).cast()
+}
+
+/// Whether `value` holds a reference to a cell from `base` on.
+fn refers_to(value: &Value, base: usize) -> bool {
+    match value {
+        Value::Ref(cell, _) => *cell >= base,
+        Value::Struct(_, fields) => fields.iter().any(|(_, value)| refers_to(value, base)),
+        Value::Array(values) | Value::Enum(_, _, values) => {
+            values.iter().any(|value| refers_to(value, base))
+        }
+        _ => false,
+    }
+}
+
+/// `value` as it reads back from memory holding a `ty`.
+fn narrow(value: Value, ty: Option<&Type>) -> Value {
+    match (value, ty) {
+        (Value::Int(i), Some(ty)) if ty.name() == Some("u8") => Value::Int(i as u8 as i64),
+        (Value::Int(i), Some(ty)) if ty.name() == Some("u32") => Value::Int(i as u32 as i64),
+        (Value::Array(elems), Some(Type::Array(array))) => Value::Array(
+            elems
+                .into_iter()
+                .map(|elem| narrow(elem, Some(&array.elem)))
+                .collect(),
+        ),
+        (value, _) => value,
+    }
+}
+
+/// Like `narrow` for an array, which is passed in memory, a scalar is passed
+/// whole in a register.
+fn narrow_array(value: Value, ty: &Type) -> Value {
+    match ty {
+        Type::Array(_) => narrow(value, Some(ty)),
+        _ => value,
+    }
+}
+
+fn element(value: Value, index: i64) -> Result<Value, String> {
+    let Value::Array(mut elems) = value else {
+        return Err("can not index a value that is not an array".into());
+    };
+    let len = elems.len();
+    match usize::try_from(index) {
+        Ok(i) if i < len => Ok(elems.swap_remove(i)),
+        _ => Err(out_of_bounds(index, len)),
+    }
+}
+
+fn out_of_bounds(index: i64, len: usize) -> String {
+    format!("index {index} is out of bounds for length {len}")
+}
+
+fn field<'v>(value: &'v Value, name: &str) -> Result<&'v Value, String> {
+    let Value::Struct(kind, fields) = value else {
+        return Err(format!("no field '{name}' on a value that is not a struct"));
+    };
+    fields
+        .iter()
+        .find(|(f, _)| f == name)
+        .map(|(_, value)| value)
+        .ok_or_else(|| format!("struct '{kind}' has no field '{name}'"))
+}
+
+fn lit_value(lit: &Lit) -> Result<Value, String> {
+    match lit {
+        Lit::Int(lit_int) => lit_int
+            .parse::<u64>()
+            .map(|i| Value::Int(i as i64))
+            .map_err(|e| format!("'{lit_int}' {e}")),
+        Lit::Bool(lit_bool) => lit_bool
+            .parse::<bool>()
+            .map(Value::Bool)
+            .map_err(|e| format!("'{lit_bool}' {e}")),
+        Lit::Str(lit_str) => Ok(Value::Str(lit_str.value.as_str().into())),
+        Lit::Char(lit_char) => lit_char
+            .value
+            .chars()
+            .next()
+            .map(Value::Char)
+            .ok_or_else(|| "empty char literal".into()),
+    }
+}
+
+fn cast(value: Value, ty: &Type) -> Result<Value, String> {
+    let int = value.as_int()?;
+    match ty.name().unwrap_or_default() {
+        "char" => match value {
+            Value::Char(_) => Ok(value),
+            _ => Ok(Value::Char(int as u8 as char)),
+        },
+        "u32" => Ok(Value::Int(int as u32 as i64)),
+        "u64" | "i64" => Ok(Value::Int(int)),
+        _ => Err(format!("can not cast to '{ty:#}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use pretty_assertions::assert_eq;
+
+    fn run_src(src: &str) -> Result<i64, Vec<String>> {
+        lex(src).and_then(parse).and_then(|items| run(items, &[]))
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run_src("fn main() { return 1 + 2 * 3 - 8 / 2; }"), Ok(3));
+        assert_eq!(run_src("fn main() { return 2 - 3; }"), Ok(-1));
+        assert_eq!(run_src("fn main() { return (2 > 1) + (1 > 2); }"), Ok(1));
+    }
+
+    #[test]
+    fn control_flow() {
+        let src = "
+            fn max(a: u64, b: u64) -> u64 { if a > b { a; } else { b; }; }
+            fn sum(n: u64) -> u64 {
+                let i = 0;
+                let total = 0;
+                while n > i {
+                    i = i + 1;
+                    total = total + i;
+                };
+                return total;
+            }
+            fn main() { return max(sum(10), 7) + max(1, 2); }";
+        assert_eq!(run_src(src), Ok(57));
+    }
+
+    #[test]
+    fn scopes() {
+        let src = "
+            fn main() {
+                let x = 1;
+                if true { let x = 10; x = x + 1; };
+                if true { x = x + 2; };
+                return x;
+            }";
+        assert_eq!(run_src(src), Ok(3));
+    }
+
+    #[test]
+    fn tail_calls_do_not_overflow() {
+        let src = "
+            fn count(n: u64, acc: u64) -> u64 {
+                if n > 0 { return count(n - 1, acc + 1); };
+                return acc;
+            }
+            fn even(n: u64) -> u64 { if n > 0 { return odd(n - 1); }; return 1; }
+            fn odd(n: u64) -> u64 { if n > 0 { return even(n - 1); }; return 0; }
+            fn main() { return count(100000, 0) + even(100001); }";
+        assert_eq!(run_src(src), Ok(100000));
+    }
+
+    #[test]
+    fn strings() {
+        let src = r#"fn main() { let s = "a\tb"; if s { return 1; }; }"#;
+        assert_eq!(
+            run_src(src),
+            Err(vec![r#""a\tb" is not a number"#.to_string()])
+        );
+        assert_eq!(run_src(r#"fn main() { "s"; return 2; }"#), Ok(2));
+    }
+
+    #[test]
+    fn chars() {
+        let src = r"fn main() { return ('a' as u32) + (66 as char == 'B') + ('\n' != '\t'); }";
+        assert_eq!(run_src(src), Ok(99));
+        let src = "fn main() { return ('a' < 'b') + ('b' <= 'b') + ('c' >= 'd') + ('c' > 'b'); }";
+        assert_eq!(run_src(src), Ok(3));
+        assert_eq!(run_src("fn main() { return 300 as char; }"), Ok(44));
+        let src = "fn main() { let c = 0 - 1; return (c as char) as u32; }";
+        assert_eq!(run_src(src), Ok(255));
+        let src = "fn main() { return (4294967296 as u32) + ((0 - 1) as u32 > 65536); }";
+        assert_eq!(run_src(src), Ok(1));
+        assert_eq!(
+            run_src("fn main() { return 'a' as u8; }"),
+            Err(vec!["can not cast to 'u8'".to_string()])
+        );
+    }
+
+    #[test]
+    fn structs() {
+        let src = "
+            struct Point { x: i64, y: u8 }
+            struct Rect { min: Point, max: Point, tag: u32 }
+            fn area(r: Rect) -> i64 { return (r.max.x - r.min.x) * (r.max.y - r.min.y); }
+            fn grow(r: Rect, by: u8) -> Rect {
+                r.max.x = r.max.x + by;
+                r.max.y = r.max.y + by;
+                return r;
+            }
+            fn origin() -> Point { return Point { y: 0, x: 0 }; }
+            fn main() {
+                let r = Rect { min: origin(), max: Point { x: 3, y: 4 }, tag: 7 };
+                let g = grow(r, 2);
+                let p = g.max;
+                p.y = 255;
+                p.y = p.y + 1;
+                return area(g) + r.max.x * 100 + g.max.y * 1000 + p.y + r.tag * 10000;
+            }";
+        assert_eq!(run_src(src), Ok(30 + 300 + 6000 + 70000));
+        assert_eq!(
+            run_src("struct P { x: i64 } fn main() { let p = P { x: 1 }; return p; }"),
+            Err(vec!["a 'P' is not a number".to_string()])
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let src = "
+            struct Bag { tag: u8, items: [u32; 3] }
+            fn sum(xs: [u64; 10]) -> u64 {
+                let total = 0;
+                let i = 0;
+                while 10 > i { total = total + xs[i]; i = i + 1; };
+                return total;
+            }
+            fn bytes(n: u64) -> [u8; 2] { return [n, n + 255]; }
+            fn main() {
+                let xs = [5; 10];
+                xs[9] = 100;
+                let copy = xs;
+                copy[0] = 0;
+                let bag = Bag { tag: 300, items: [1, 2, 3] };
+                bag.items[2] = 4294967297;
+                let grid = [[1, 2], [3, 4]];
+                grid[1][0] = 30;
+                let b = bytes(2);
+                return sum(xs) + bag.tag + bag.items[2] * 1000 + grid[1][0] * 10000 + b[1] * 1000000;
+            }";
+        assert_eq!(run_src(src), Ok(145 + 44 + 1000 + 300000 + 1000000));
+        assert_eq!(
+            run_src("fn main() { let xs = [1, 2]; xs[0 - 1] = 3; }"),
+            Err(vec!["index -1 is out of bounds for length 2".to_string()])
+        );
+        assert_eq!(
+            run_src("fn main() { return [1]; }"),
+            Err(vec!["an array is not a number".to_string()])
+        );
+    }
+
+    #[test]
+    fn enums() {
+        let src = "
+            struct P { x: u8, y: u64 }
+            enum Inner { A(u8), B([u32; 3]) }
+            enum Outer { Wrap(Inner, P), Nothing }
+            fn pick(o: Outer) -> Inner {
+                return match o { Outer::Wrap(i, _) => i, Outer::Nothing => Inner::A(9) };
+            }
+            fn sum(i: Inner) -> u64 {
+                return match i { Inner::A(b) => b, Inner::B(xs) => xs[0] + xs[1] + xs[2] };
+            }
+            fn main() {
+                let o = Outer::Wrap(Inner::B([10, 20, 30]), P { x: 300, y: 7 });
+                let p = match o { Outer::Wrap(_, p) => p.x + p.y, Outer::Nothing => 0 };
+                let copy = o;
+                o = Outer::Nothing;
+                let c = match 'b' { 'a' => 1, 'b' => 2, _ => 0 };
+                let b = match copy { Outer::Nothing => false, other => true };
+                return sum(pick(copy)) + p * 100 + sum(pick(o)) * 1000 + c * 10000 + b * 100000;
+            }";
+        assert_eq!(run_src(src), Ok(60 + 5100 + 9000 + 20000 + 100000));
+        assert_eq!(
+            run_src("enum E { A, B } fn main() { return match E::B { E::A => 1 }; }"),
+            Err(vec!["no arm of the match matches E::B".to_string()])
+        );
+    }
+
+    #[test]
+    fn references() {
+        let src = "
+            struct P { x: u64, y: u64 }
+            struct Pair { a: &u64, b: &mut u64 }
+            fn swap(a: &mut u64, b: &mut u64) { let t = *a; *a = *b; *b = t; }
+            fn shift(p: &mut P, by: u64) { p.x = p.x + by; p.y = p.y + by; }
+            fn sum(a: &[u64; 3]) -> u64 { return a[0] + a[1] + a[2]; }
+            fn larger(a: &u64, b: &u64) -> &u64 { if *a > *b { return a; }; return b; }
+            fn main() {
+                let x = 1;
+                let y = 2;
+                swap(&mut x, &mut y);
+                let p = P { x: 10, y: 20 };
+                shift(&mut p, 5);
+                let a = [1, 2, 3];
+                let r = &mut a;
+                r[0] = 100;
+                let n = 0;
+                let pair = Pair { a: &p.x, b: &mut n };
+                *pair.b = *pair.a + 1;
+                return x + y * 10 + p.y * 100 + sum(&a) * 10000 + *larger(&x, &y) * 10000000 + n * 100000000;
+            }";
+        assert_eq!(
+            run_src(src),
+            Ok(2 + 10 + 2500 + 1050000 + 20000000 + 1600000000)
+        );
+        assert_eq!(
+            run_src("fn f() -> &u64 { let x = 1; return &x; } fn main() { return *f(); }"),
+            Err(vec![
+                "'f' returned a reference to one of its locals".to_string()
+            ])
+        );
+        let src = "fn g(r: &u64) -> &u64 { return r; }
+            fn f() -> &u64 { let x = 1; return g(&x); }
+            fn main() { return *f(); }";
+        assert_eq!(
+            run_src(src),
+            Err(vec![
+                "'f' returned a reference to one of its locals".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn main_without_return_exits_with_zero() {
+        assert_eq!(run_src("fn main() { 5; }"), Ok(0));
+        assert_eq!(run_src("fn main() -> u64 { 5; }"), Ok(5));
+    }
+
+    #[test]
+    fn builtins() {
+        let src = r#"
+            fn main() {
+                print("a\tb");
+                println("");
+                print_int(0 - 42);
+                return println("");
+            }"#;
+        let mut out = vec![];
+        assert_eq!(
+            lex(src)
+                .and_then(parse)
+                .and_then(|items| run_to(items, &[], &mut out)),
+            Ok(0)
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "a\tb\n-42\n");
+        let src = "fn print(n: u64) -> u64 { return n; } fn main() { return print(7); }";
+        assert_eq!(run_src(src), Ok(7));
+        assert_eq!(
+            run_src("fn main() { return print(1); }"),
+            Err(vec!["'print' takes a str, not Int(1)".to_string()])
+        );
+    }
+
+    #[test]
+    fn args_and_env() {
+        let src = r#"
+            fn main(argc: u64, argv: u64) {
+                println(arg(1));
+                println(arg(2));
+                println(env("CARGO_PKG_NAME"));
+                return argc * 10 + arg_count();
+            }"#;
+        let args = ["prog".to_string(), "one".to_string()];
+        let mut out = vec![];
+        assert_eq!(
+            lex(src)
+                .and_then(parse)
+                .and_then(|items| run_to(items, &args, &mut out)),
+            Ok(22)
+        );
+        let expected = format!("one\n\n{}\n", env!("CARGO_PKG_NAME"));
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            run_src("fn main() { return 1 / 0; }"),
+            Err(vec!["division by zero".to_string()])
+        );
+        assert_eq!(
+            run_src("fn main() { return f(1); }"),
+            Err(vec!["call to unknown function 'f'".to_string()])
+        );
+        assert_eq!(
+            run_src("fn f() { return 1; }"),
+            Err(vec!["program has no 'main' function".to_string()])
+        );
+        assert_eq!(
+            run_src("fn f(n: u64) -> u64 { return n; } fn main() { return f(); }"),
+            Err(vec!["'f' takes 1 arguments but is given 0".to_string()])
+        );
+        assert_eq!(
+            run_src("fn f(n: u64) -> u64 { return f(n) + 1; } fn main() { return f(1); }"),
+            Err(vec!["stack overflow calling 'f'".to_string()])
+        );
+    }
+}
@@ -0,0 +1,735 @@
+//! Control-flow graph of a single function body.
+//!
+//! A function body is split into basic blocks at every `DefLabel` and after
+//! every terminator (`Jump`, `Conditional`, `Return` and `TailCall`). Blocks
+//! keep the order they had in the body, so a block without a terminator falls
+//! through into the next one and flattening the blocks back gives a valid body
+//! again.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+
+use super::{Conditional, DefFunc, DefLabel, Instruction, Jump, Label, Phi, Reg};
+
+/// Label every function body ends with; `Return` jumps here.
+pub const EXIT_LABEL: &str = ".exit";
+
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+impl std::fmt::Display for BlockId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub body: Vec<Instruction>,
+}
+
+impl BasicBlock {
+    pub fn label(&self) -> Option<&Label> {
+        self.body.first().and_then(Instruction::as_label)
+    }
+
+    pub fn terminator(&self) -> Option<&Instruction> {
+        self.body.last().filter(|i| i.is_terminator())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub name: String,
+    pub blocks: Vec<BasicBlock>,
+    succs: Vec<Vec<BlockId>>,
+    preds: Vec<Vec<BlockId>>,
+}
+
+impl Cfg {
+    pub fn new(func: &DefFunc) -> Self {
+        Self::from_body(func.name.clone(), &func.body)
+    }
+
+    pub fn from_body(name: impl Into<String>, body: &[Instruction]) -> Self {
+        let mut blocks = vec![];
+        let mut current: Vec<Instruction> = vec![];
+        for instruction in body.iter() {
+            if instruction.as_label().is_some() && !current.is_empty() {
+                blocks.push(BasicBlock {
+                    body: std::mem::take(&mut current),
+                });
+            }
+            current.push(instruction.clone());
+            if instruction.is_terminator() {
+                blocks.push(BasicBlock {
+                    body: std::mem::take(&mut current),
+                });
+            }
+        }
+        if !current.is_empty() || blocks.is_empty() {
+            blocks.push(BasicBlock { body: current });
+        }
+        let mut cfg = Self {
+            name: name.into(),
+            blocks,
+            succs: vec![],
+            preds: vec![],
+        };
+        cfg.rebuild_edges();
+        cfg
+    }
+
+    /// Recomputes successors and predecessors, call this after changing blocks.
+    pub fn rebuild_edges(&mut self) {
+        let labels = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, b)| b.label().map(|l| (l.clone(), BlockId(i))))
+            .collect::<HashMap<Label, BlockId>>();
+        let exit = labels.get(&Label::from(EXIT_LABEL)).copied();
+        let len = self.blocks.len();
+        self.succs = vec![vec![]; len];
+        self.preds = vec![vec![]; len];
+        for (i, block) in self.blocks.iter().enumerate() {
+            let fallthrough = (i + 1 < len).then_some(BlockId(i + 1));
+            let succs = match block.body.last() {
+                Some(Instruction::Jump(Jump(label))) => {
+                    labels.get(label).copied().into_iter().collect()
+                }
+                Some(Instruction::Conditional(Conditional { label, .. })) => fallthrough
+                    .into_iter()
+                    .chain(labels.get(label).copied())
+                    .collect(),
+                Some(Instruction::Return(..) | Instruction::TailCall(..)) => {
+                    exit.into_iter().collect()
+                }
+                Some(Instruction::Leave(..)) => vec![],
+                _ => fallthrough.into_iter().collect::<Vec<_>>(),
+            };
+            for succ in succs {
+                if !self.succs[i].contains(&succ) {
+                    self.succs[i].push(succ);
+                    self.preds[succ.0].push(BlockId(i));
+                }
+            }
+        }
+    }
+
+    pub fn entry(&self) -> BlockId {
+        BlockId(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item = BlockId> {
+        (0..self.blocks.len()).map(BlockId)
+    }
+
+    pub fn block(&self, id: BlockId) -> &BasicBlock {
+        &self.blocks[id.0]
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut BasicBlock {
+        &mut self.blocks[id.0]
+    }
+
+    pub fn successors(&self, id: BlockId) -> &[BlockId] {
+        &self.succs[id.0]
+    }
+
+    pub fn predecessors(&self, id: BlockId) -> &[BlockId] {
+        &self.preds[id.0]
+    }
+
+    pub fn block_by_label(&self, label: &Label) -> Option<BlockId> {
+        self.ids().find(|id| self.block(*id).label() == Some(label))
+    }
+
+    /// Blocks reachable from the entry in reverse postorder.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.len()];
+        let mut order = vec![];
+        // (block, index of the next successor to visit)
+        let mut stack = vec![(self.entry(), 0)];
+        visited[0] = true;
+        while let Some((id, next)) = stack.pop() {
+            if let Some(succ) = self.successors(id).get(next).copied() {
+                stack.push((id, next + 1));
+                if !visited[succ.0] {
+                    visited[succ.0] = true;
+                    stack.push((succ, 0));
+                }
+                continue;
+            }
+            order.push(id);
+        }
+        order.reverse();
+        order
+    }
+
+    /// The number after the highest `.L{n}` label, used to make new labels
+    /// that don't clash with the ones from `IrGenerator::gen_label`.
+    pub fn next_label_number(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter_map(|b| b.label()?.0.strip_prefix(".L")?.parse::<usize>().ok())
+            .map(|n| n + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Gives every block without a label a new one so phi nodes can refer to it.
+    pub fn label_blocks(&mut self) {
+        let numbers = self.next_label_number()..;
+        let unlabeled = self.blocks.iter_mut().filter(|b| b.label().is_none());
+        for (number, block) in numbers.zip(unlabeled) {
+            block
+                .body
+                .insert(0, DefLabel(Label(format!(".L{number}"))).into());
+        }
+    }
+
+    /// The only block entering `lp` from outside, if that block has no other
+    /// successor.
+    pub fn preheader(&self, lp: &Loop) -> Option<BlockId> {
+        let mut outside = self
+            .predecessors(lp.header)
+            .iter()
+            .filter(|p| !lp.contains(**p));
+        let (Some(pred), None) = (outside.next(), outside.next()) else {
+            return None;
+        };
+        (self.successors(*pred) == [lp.header]).then_some(*pred)
+    }
+
+    /// Puts an empty block right before the header of `lp` that every edge
+    /// from outside the loop goes through. Gives up and returns false when
+    /// more than one block enters the loop, as the header's phis would have
+    /// to be split, or when a block inside the loop falls through into the
+    /// header. Block ids from the header on move up by one.
+    pub fn insert_preheader(&mut self, lp: &Loop) -> bool {
+        let header = lp.header;
+        let mut outside = self.preds[header.0].iter().filter(|p| !lp.contains(**p));
+        let (Some(pred), None) = (outside.next().copied(), outside.next()) else {
+            return false;
+        };
+        let (Some(header_label), Some(pred_label)) = (
+            self.blocks[header.0].label().cloned(),
+            self.blocks[pred.0].label().cloned(),
+        ) else {
+            return false;
+        };
+        if header.0 > 0 && BlockId(header.0 - 1) != pred {
+            let before = &self.blocks[header.0 - 1];
+            let falls_through = !matches!(
+                before.body.last(),
+                Some(
+                    Instruction::Jump(..)
+                        | Instruction::Return(..)
+                        | Instruction::TailCall(..)
+                        | Instruction::Leave(..)
+                )
+            );
+            if falls_through {
+                return false;
+            }
+        }
+        let label = Label(format!(".L{}", self.next_label_number()));
+        match self.blocks[pred.0].body.last_mut() {
+            Some(Instruction::Jump(Jump(target)))
+            | Some(Instruction::Conditional(Conditional { label: target, .. }))
+                if *target == header_label =>
+            {
+                *target = label.clone();
+            }
+            _ => {}
+        }
+        for instruction in self.blocks[header.0].body.iter_mut() {
+            if let Instruction::Phi(Phi { args, .. }) = instruction {
+                for (_, from) in args.iter_mut().filter(|(_, from)| *from == pred_label) {
+                    *from = label.clone();
+                }
+            }
+        }
+        self.blocks.insert(
+            header.0,
+            BasicBlock {
+                body: vec![DefLabel(label).into()],
+            },
+        );
+        self.rebuild_edges();
+        true
+    }
+
+    /// Removes blocks that can't be reached from the entry, the exit block is
+    /// always kept. Returns the number of instructions removed.
+    pub fn remove_unreachable(&mut self) -> usize {
+        let reachable = self
+            .reverse_postorder()
+            .into_iter()
+            .collect::<HashSet<BlockId>>();
+        let exit = Label::from(EXIT_LABEL);
+        let mut removed = 0;
+        let blocks = std::mem::take(&mut self.blocks);
+        for (i, block) in blocks.into_iter().enumerate() {
+            if reachable.contains(&BlockId(i)) || block.label() == Some(&exit) {
+                self.blocks.push(block);
+            } else {
+                removed += block.body.len();
+            }
+        }
+        self.rebuild_edges();
+        self.prune_phis();
+        removed
+    }
+
+    /// Drops phi arguments for edges that no longer exist.
+    pub fn prune_phis(&mut self) {
+        for id in self.ids() {
+            let preds = self.preds[id.0]
+                .iter()
+                .filter_map(|p| self.blocks[p.0].label().cloned())
+                .collect::<HashSet<Label>>();
+            for instruction in self.blocks[id.0].body.iter_mut() {
+                if let Instruction::Phi(Phi { args, .. }) = instruction {
+                    args.retain(|(_, label)| preds.contains(label));
+                }
+            }
+        }
+    }
+
+    /// Flattens the blocks back into a function body.
+    pub fn into_body(self) -> Vec<Instruction> {
+        self.blocks.into_iter().flat_map(|b| b.body).collect()
+    }
+
+    pub fn to_dot(&self) -> String {
+        let doms = Dominators::new(self);
+        let loops = LoopNest::new(self, &doms);
+        let name = &self.name;
+        let mut dot = String::new();
+        let _ = writeln!(dot, "  subgraph \"cluster_{name}\" {{");
+        let _ = writeln!(dot, "    label=\"{name}\";");
+        for id in self.ids() {
+            let mut text = format!("{id} (loop depth {})\\l", loops.depth(id));
+            for instruction in self.block(id).body.iter() {
+                text += &escape_dot(&format!("{instruction:?}"));
+                text += "\\l";
+            }
+            let _ = writeln!(dot, "    \"{name}.{id}\" [label=\"{text}\"];");
+        }
+        for id in self.ids() {
+            for succ in self.successors(id) {
+                let style = if doms.dominates(*succ, id) {
+                    " [style=dashed]"
+                } else {
+                    ""
+                };
+                let _ = writeln!(dot, "    \"{name}.{id}\" -> \"{name}.{succ}\"{style};");
+            }
+        }
+        let _ = writeln!(dot, "  }}");
+        dot
+    }
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Prints the control-flow graph of every function as one Graphviz digraph.
+pub fn emit_dot(code: &[Instruction]) -> String {
+    let clusters = code
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::DefFunc(func) => Some(Cfg::new(func).to_dot()),
+            _ => None,
+        })
+        .collect::<String>();
+    format!("digraph cfg {{\n  node [shape=box, fontname=monospace];\n{clusters}}}\n")
+}
+
+/// Dominator tree, computed with the Cooper, Harvey and Kennedy algorithm.
+#[derive(Debug, Clone)]
+pub struct Dominators {
+    idom: Vec<Option<BlockId>>,
+    children: Vec<Vec<BlockId>>,
+    rpo: Vec<BlockId>,
+}
+
+impl Dominators {
+    pub fn new(cfg: &Cfg) -> Self {
+        let rpo = cfg.reverse_postorder();
+        let mut order = vec![usize::MAX; cfg.len()];
+        for (i, id) in rpo.iter().enumerate() {
+            order[id.0] = i;
+        }
+        let mut idom: Vec<Option<BlockId>> = vec![None; cfg.len()];
+        idom[cfg.entry().0] = Some(cfg.entry());
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in rpo.iter().skip(1) {
+                let new_idom = cfg
+                    .predecessors(*id)
+                    .iter()
+                    .filter(|p| idom[p.0].is_some())
+                    .copied()
+                    .reduce(|a, b| intersect(&idom, &order, a, b));
+                if new_idom.is_some() && idom[id.0] != new_idom {
+                    idom[id.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        // The entry is its own idom while solving, but has none in the tree.
+        idom[cfg.entry().0] = None;
+        let mut children = vec![vec![]; cfg.len()];
+        for id in cfg.ids() {
+            if let Some(parent) = idom[id.0] {
+                children[parent.0].push(id);
+            }
+        }
+        Self {
+            idom,
+            children,
+            rpo,
+        }
+    }
+
+    pub fn idom(&self, id: BlockId) -> Option<BlockId> {
+        self.idom[id.0]
+    }
+
+    pub fn children(&self, id: BlockId) -> &[BlockId] {
+        &self.children[id.0]
+    }
+
+    pub fn reverse_postorder(&self) -> &[BlockId] {
+        &self.rpo
+    }
+
+    pub fn is_reachable(&self, id: BlockId) -> bool {
+        self.rpo.first() == Some(&id) || self.idom[id.0].is_some()
+    }
+
+    /// The dominance frontier of every block, where a definition stops
+    /// dominating and phi nodes are needed.
+    pub fn frontiers(&self, cfg: &Cfg) -> Vec<BTreeSet<BlockId>> {
+        let mut frontiers = vec![BTreeSet::new(); cfg.len()];
+        for id in self.rpo.iter().copied() {
+            let preds = cfg.predecessors(id);
+            if preds.len() < 2 {
+                continue;
+            }
+            for pred in preds.iter().copied().filter(|p| self.is_reachable(*p)) {
+                let mut runner = pred;
+                while Some(runner) != self.idom(id) {
+                    frontiers[runner.0].insert(id);
+                    let Some(next) = self.idom(runner) else {
+                        break;
+                    };
+                    runner = next;
+                }
+            }
+        }
+        frontiers
+    }
+
+    /// Does `a` dominate `b`. Every block dominates itself.
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        let mut current = Some(b);
+        while let Some(id) = current {
+            if id == a {
+                return true;
+            }
+            current = self.idom[id.0];
+        }
+        false
+    }
+}
+
+fn intersect(idom: &[Option<BlockId>], order: &[usize], a: BlockId, b: BlockId) -> BlockId {
+    let (mut a, mut b) = (a, b);
+    while a != b {
+        while order[a.0] > order[b.0] {
+            a = idom[a.0].expect("processed block has an idom");
+        }
+        while order[b.0] > order[a.0] {
+            b = idom[b.0].expect("processed block has an idom");
+        }
+    }
+    a
+}
+
+/// A natural loop, all back edges into the same header are merged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: BlockId,
+    pub latches: Vec<BlockId>,
+    pub blocks: BTreeSet<BlockId>,
+    /// Index of the innermost enclosing loop in `LoopNest::loops`.
+    pub parent: Option<usize>,
+    /// Outermost loops have a depth of 1.
+    pub depth: usize,
+}
+
+impl Loop {
+    pub fn contains(&self, id: BlockId) -> bool {
+        self.blocks.contains(&id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopNest {
+    pub loops: Vec<Loop>,
+    innermost: Vec<Option<usize>>,
+}
+
+impl LoopNest {
+    pub fn new(cfg: &Cfg, doms: &Dominators) -> Self {
+        let mut loops: Vec<Loop> = vec![];
+        for latch in doms.reverse_postorder().iter().copied() {
+            for header in cfg.successors(latch).iter().copied() {
+                if !doms.dominates(header, latch) {
+                    continue;
+                }
+                let body = natural_loop(cfg, header, latch);
+                match loops.iter_mut().find(|l| l.header == header) {
+                    Some(l) => {
+                        l.latches.push(latch);
+                        l.blocks.extend(body);
+                    }
+                    None => loops.push(Loop {
+                        header,
+                        latches: vec![latch],
+                        blocks: body,
+                        parent: None,
+                        depth: 0,
+                    }),
+                }
+            }
+        }
+        // Outer loops first so a parent always comes before its children.
+        loops.sort_by_key(|l| std::cmp::Reverse(l.blocks.len()));
+        for i in 0..loops.len() {
+            let parent = (0..i).rev().find(|p| {
+                loops[*p].contains(loops[i].header) && loops[*p].header != loops[i].header
+            });
+            loops[i].parent = parent;
+            loops[i].depth = parent.map(|p| loops[p].depth).unwrap_or(0) + 1;
+        }
+        let mut innermost = vec![None; cfg.len()];
+        for (i, l) in loops.iter().enumerate() {
+            for id in l.blocks.iter() {
+                innermost[id.0] = Some(i);
+            }
+        }
+        Self { loops, innermost }
+    }
+
+    pub fn innermost(&self, id: BlockId) -> Option<&Loop> {
+        self.innermost[id.0].map(|i| &self.loops[i])
+    }
+
+    pub fn depth(&self, id: BlockId) -> usize {
+        self.innermost(id).map(|l| l.depth).unwrap_or(0)
+    }
+}
+
+fn natural_loop(cfg: &Cfg, header: BlockId, latch: BlockId) -> BTreeSet<BlockId> {
+    let mut body = BTreeSet::from([header]);
+    let mut stack = vec![latch];
+    while let Some(id) = stack.pop() {
+        if body.insert(id) {
+            stack.extend(cfg.predecessors(id).iter().copied());
+        }
+    }
+    body
+}
+
+/// Registers live on entry to and exit from every block.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    pub live_in: Vec<HashSet<Reg>>,
+    pub live_out: Vec<HashSet<Reg>>,
+}
+
+impl Liveness {
+    /// Phi arguments count as used at the end of the block they come from.
+    pub fn new(cfg: &Cfg) -> Self {
+        let len = cfg.len();
+        let mut uses = vec![HashSet::new(); len];
+        let mut defs = vec![HashSet::new(); len];
+        let mut phi_uses: Vec<HashSet<Reg>> = vec![HashSet::new(); len];
+        let labels = cfg
+            .ids()
+            .filter_map(|id| cfg.block(id).label().map(|l| (l.clone(), id)))
+            .collect::<HashMap<Label, BlockId>>();
+        for id in cfg.ids() {
+            for instruction in cfg.block(id).body.iter() {
+                if let Instruction::Phi(Phi { args, .. }) = instruction {
+                    for (reg, label) in args.iter() {
+                        if let Some(pred) = labels.get(label) {
+                            phi_uses[pred.0].insert(*reg);
+                        }
+                    }
+                } else {
+                    for reg in instruction.uses() {
+                        if !defs[id.0].contains(&reg) {
+                            uses[id.0].insert(reg);
+                        }
+                    }
+                }
+                if let Some(reg) = instruction.def() {
+                    defs[id.0].insert(reg);
+                }
+            }
+        }
+        let mut live_in: Vec<HashSet<Reg>> = vec![HashSet::new(); len];
+        let mut live_out: Vec<HashSet<Reg>> = vec![HashSet::new(); len];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in cfg.ids().rev() {
+                let mut out = phi_uses[id.0].clone();
+                for succ in cfg.successors(id) {
+                    out.extend(live_in[succ.0].iter().copied());
+                }
+                let mut new_in = uses[id.0].clone();
+                new_in.extend(out.difference(&defs[id.0]).copied());
+                if new_in != live_in[id.0] || out != live_out[id.0] {
+                    live_in[id.0] = new_in;
+                    live_out[id.0] = out;
+                    changed = true;
+                }
+            }
+        }
+        Self { live_in, live_out }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{DefLabel, Enter, Grt, Imm, Leave, LoadImm, Reg, Return};
+    use pretty_assertions::assert_eq;
+
+    fn label(name: &str) -> Instruction {
+        DefLabel(name.into()).into()
+    }
+
+    fn jump(name: &str) -> Instruction {
+        Jump(name.into()).into()
+    }
+
+    fn cond(name: &str, reg: usize) -> Instruction {
+        Conditional {
+            label: name.into(),
+            reg: Reg(reg),
+        }
+        .into()
+    }
+
+    fn imm(des: usize, imm: u64) -> Instruction {
+        LoadImm {
+            des: Reg(des),
+            imm: Imm(imm),
+        }
+        .into()
+    }
+
+    fn ids(ids: &[usize]) -> Vec<BlockId> {
+        ids.iter().copied().map(BlockId).collect()
+    }
+
+    #[test]
+    fn split_if_else() {
+        // if 1 > 2 { return 1; } else { return 2; }
+        let body = vec![
+            Enter.into(),
+            imm(0, 1),
+            imm(1, 2),
+            Grt {
+                des: Reg(2),
+                lhs: Reg(0),
+                rhs: Reg(1),
+            }
+            .into(),
+            cond(".L0", 2),
+            imm(3, 1),
+            Return(Reg(3)).into(),
+            label(".L0"),
+            imm(4, 2),
+            Return(Reg(4)).into(),
+            label(EXIT_LABEL),
+            Leave.into(),
+        ];
+        let cfg = Cfg::from_body("main", &body);
+        assert_eq!(cfg.len(), 4);
+        assert_eq!(cfg.successors(BlockId(0)), ids(&[1, 2]));
+        assert_eq!(cfg.successors(BlockId(1)), ids(&[3]));
+        assert_eq!(cfg.successors(BlockId(2)), ids(&[3]));
+        assert_eq!(cfg.successors(BlockId(3)), ids(&[]));
+        assert_eq!(cfg.predecessors(BlockId(3)), ids(&[1, 2]));
+        assert_eq!(cfg.block(BlockId(2)).label(), Some(&".L0".into()));
+
+        let doms = Dominators::new(&cfg);
+        assert_eq!(doms.idom(BlockId(0)), None);
+        assert_eq!(doms.idom(BlockId(3)), Some(BlockId(0)));
+        assert_eq!(doms.children(BlockId(0)), ids(&[1, 2, 3]));
+        assert!(!doms.dominates(BlockId(1), BlockId(3)));
+        assert!(LoopNest::new(&cfg, &doms).loops.is_empty());
+        assert_eq!(cfg.into_body(), body);
+    }
+
+    #[test]
+    fn unreachable_block() {
+        let body = vec![
+            Enter.into(),
+            jump(EXIT_LABEL),
+            imm(0, 1),
+            label(EXIT_LABEL),
+            Leave.into(),
+        ];
+        let cfg = Cfg::from_body("main", &body);
+        let doms = Dominators::new(&cfg);
+        assert_eq!(cfg.reverse_postorder(), ids(&[0, 2]));
+        assert!(!doms.is_reachable(BlockId(1)));
+        assert!(!doms.dominates(BlockId(0), BlockId(1)));
+    }
+
+    #[test]
+    fn nested_loops() {
+        // b0 -> b1(.L0) -> b2(.L1) -> b3 -> b1 / b2, b1 -> b4(.exit)
+        let body = vec![
+            Enter.into(),
+            label(".L0"),
+            cond(EXIT_LABEL, 0),
+            label(".L1"),
+            cond(".L0", 1),
+            jump(".L1"),
+            label(EXIT_LABEL),
+            Leave.into(),
+        ];
+        let cfg = Cfg::from_body("main", &body);
+        assert_eq!(cfg.len(), 5);
+        let doms = Dominators::new(&cfg);
+        let nest = LoopNest::new(&cfg, &doms);
+        assert_eq!(nest.loops.len(), 2);
+        let outer = &nest.loops[0];
+        assert_eq!(outer.header, BlockId(1));
+        assert_eq!(outer.blocks, BTreeSet::from_iter(ids(&[1, 2, 3])));
+        assert_eq!(outer.depth, 1);
+        let inner = &nest.loops[1];
+        assert_eq!(inner.header, BlockId(2));
+        assert_eq!(inner.latches, ids(&[3]));
+        assert_eq!(inner.parent, Some(0));
+        assert_eq!(nest.depth(BlockId(3)), 2);
+        assert_eq!(nest.depth(BlockId(1)), 1);
+        assert_eq!(nest.depth(BlockId(4)), 0);
+    }
+}
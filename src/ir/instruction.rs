@@ -3,12 +3,16 @@ use super::{Imm, Label, Reg};
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     I64,
+    /// The address of a struct, array, enum or `str` of this many bytes,
+    /// which native code passes by value as System V does.
+    Aggregate(u32),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     DefFunc(DefFunc),
     LoadImm(LoadImm),
+    LoadStr(LoadStr),
     Add(Add),
     Sub(Sub),
     Mul(Mul),
@@ -19,26 +23,104 @@ pub enum Instruction {
     Jump(Jump),
     DefLabel(DefLabel),
     Call(Call),
+    TailCall(TailCall),
     Return(Return),
     Enter(Enter),
     Leave(Leave),
+    Phi(Phi),
+    Alloca(Alloca),
+    Load(Load),
+    Store(Store),
 }
 
-// impl Instruction {
-//     pub fn _is_exit(&self) -> bool {
-//         match self {
-//             Self::Conditional(..) | Self::Jump(..) => true,
-//             _ => false,
-//         }
-//     }
-//
-//     pub fn is_enter(&self) -> bool {
-//         match self {
-//             Self::DefLabel(..) => true,
-//             _ => false,
-//         }
-//     }
-// }
+impl Instruction {
+    /// Instructions that end a basic block.
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Self::Conditional(..) | Self::Jump(..) | Self::Return(..) | Self::TailCall(..)
+        )
+    }
+
+    pub fn as_label(&self) -> Option<&Label> {
+        match self {
+            Self::DefLabel(DefLabel(label)) => Some(label),
+            _ => None,
+        }
+    }
+
+    /// The register this instruction writes to.
+    pub fn def(&self) -> Option<Reg> {
+        match self {
+            Self::LoadImm(LoadImm { des, .. }) | Self::LoadStr(LoadStr { des, .. }) => Some(*des),
+            Self::Add(Add { des, .. })
+            | Self::Sub(Sub { des, .. })
+            | Self::Mul(Mul { des, .. })
+            | Self::Div(Div { des, .. })
+            | Self::Grt(Grt { des, .. }) => Some(*des),
+            Self::Copy(Copy { to, .. }) => Some(*to),
+            Self::Call(Call { ret, .. }) => Some(*ret),
+            Self::Phi(Phi { des, .. }) => Some(*des),
+            Self::Alloca(Alloca { des, .. }) | Self::Load(Load { des, .. }) => Some(*des),
+            _ => None,
+        }
+    }
+
+    pub fn def_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Self::LoadImm(LoadImm { des, .. }) | Self::LoadStr(LoadStr { des, .. }) => Some(des),
+            Self::Add(Add { des, .. })
+            | Self::Sub(Sub { des, .. })
+            | Self::Mul(Mul { des, .. })
+            | Self::Div(Div { des, .. })
+            | Self::Grt(Grt { des, .. }) => Some(des),
+            Self::Copy(Copy { to, .. }) => Some(to),
+            Self::Call(Call { ret, .. }) => Some(ret),
+            Self::Phi(Phi { des, .. }) => Some(des),
+            Self::Alloca(Alloca { des, .. }) | Self::Load(Load { des, .. }) => Some(des),
+            _ => None,
+        }
+    }
+
+    /// The registers this instruction reads from.
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Self::Add(Add { lhs, rhs, .. })
+            | Self::Sub(Sub { lhs, rhs, .. })
+            | Self::Mul(Mul { lhs, rhs, .. })
+            | Self::Div(Div { lhs, rhs, .. })
+            | Self::Grt(Grt { lhs, rhs, .. }) => vec![*lhs, *rhs],
+            Self::Copy(Copy { from, .. }) => vec![*from],
+            Self::Conditional(Conditional { reg, .. }) => vec![*reg],
+            Self::Call(Call { args, .. }) | Self::TailCall(TailCall { args, .. }) => args.clone(),
+            Self::Return(Return(reg)) => vec![*reg],
+            Self::Phi(Phi { args, .. }) => args.iter().map(|(reg, _)| *reg).collect(),
+            Self::Load(Load { addr, .. }) => vec![*addr],
+            Self::Store(Store { addr, src, .. }) => vec![*addr, *src],
+            _ => vec![],
+        }
+    }
+
+    pub fn uses_mut(&mut self) -> Vec<&mut Reg> {
+        match self {
+            Self::Add(Add { lhs, rhs, .. })
+            | Self::Sub(Sub { lhs, rhs, .. })
+            | Self::Mul(Mul { lhs, rhs, .. })
+            | Self::Div(Div { lhs, rhs, .. })
+            | Self::Grt(Grt { lhs, rhs, .. }) => vec![lhs, rhs],
+            Self::Copy(Copy { from, .. }) => vec![from],
+            Self::Conditional(Conditional { reg, .. }) => vec![reg],
+            Self::Call(Call { args, .. }) | Self::TailCall(TailCall { args, .. }) => {
+                args.iter_mut().collect()
+            }
+            Self::Return(Return(reg)) => vec![reg],
+            Self::Phi(Phi { args, .. }) => args.iter_mut().map(|(reg, _)| reg).collect(),
+            Self::Load(Load { addr, .. }) => vec![addr],
+            Self::Store(Store { addr, src, .. }) => vec![addr, src],
+            _ => vec![],
+        }
+    }
+}
 
 macro_rules! from_to {
     ($from:ident, $to:ident) => {
@@ -52,14 +134,20 @@ macro_rules! from_to {
 
 from_to!(DefFunc, Instruction);
 from_to!(LoadImm, Instruction);
+from_to!(LoadStr, Instruction);
 from_to!(Copy, Instruction);
 from_to!(Conditional, Instruction);
 from_to!(Jump, Instruction);
 from_to!(DefLabel, Instruction);
 from_to!(Call, Instruction);
+from_to!(TailCall, Instruction);
 from_to!(Return, Instruction);
 from_to!(Enter, Instruction);
 from_to!(Leave, Instruction);
+from_to!(Phi, Instruction);
+from_to!(Alloca, Instruction);
+from_to!(Load, Instruction);
+from_to!(Store, Instruction);
 
 macro_rules! op_instruction {
     ($name:ident) => {
@@ -89,6 +177,17 @@ pub struct DefFunc {
     pub ret: Type,
     pub params: Vec<(Reg, Type)>,
     pub body: Vec<Instruction>,
+    pub inline: Inline,
+}
+
+/// Overrides the size heuristic of `ir::opt::inline`, set with `#[inline]`
+/// and `#[inline(never)]`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Inline {
+    #[default]
+    Auto,
+    Always,
+    Never,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -97,6 +196,23 @@ pub struct LoadImm {
     pub imm: Imm,
 }
 
+/// Loads the address of the utf-8 bytes of a string literal, which a `str`
+/// keeps along with their number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadStr {
+    pub des: Reg,
+    pub value: String,
+}
+
+/// Appends the bytes of a literal to `data`, followed by a nul for C, and
+/// gives back the offset of the first one.
+pub fn write_str(data: &mut Vec<u8>, value: &str) -> usize {
+    let at = data.len();
+    data.extend_from_slice(value.as_bytes());
+    data.push(0);
+    at
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Copy {
     pub to: Reg,
@@ -116,6 +232,14 @@ pub struct Call {
     pub ret: Reg,
 }
 
+/// A call in tail position. The callee reuses the frame of the caller and
+/// returns straight to the caller's caller, so control never comes back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailCall {
+    pub caller: Label,
+    pub args: Vec<Reg>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Jump(pub Label);
 
@@ -142,3 +266,38 @@ pub struct Enter;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Leave;
+
+/// Picks the value of `des` based on which block control came from, only
+/// exists while the code is in ssa form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phi {
+    pub des: Reg,
+    pub args: Vec<(Reg, Label)>,
+}
+
+/// Reserves `size` bytes in the frame of the function and loads their
+/// address. Every `Alloca` is its own slot for the whole call, so a function
+/// with one can not reuse its frame for a tail call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alloca {
+    pub des: Reg,
+    pub size: u32,
+}
+
+/// Reads `size` bytes, 1, 4 or 8, at `addr + offset`, zero extended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Load {
+    pub des: Reg,
+    pub addr: Reg,
+    pub offset: u32,
+    pub size: u8,
+}
+
+/// Writes the low `size` bytes of `src` to `addr + offset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Store {
+    pub addr: Reg,
+    pub offset: u32,
+    pub src: Reg,
+    pub size: u8,
+}
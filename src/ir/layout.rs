@@ -0,0 +1,577 @@
+//! Sizes, alignment and field offsets of values kept in memory.
+//!
+//! Fields are laid out in the order they are declared, each at the next
+//! offset that is a multiple of its alignment, like a C struct. A struct is
+//! as aligned as its most aligned field and its size is padded to a multiple
+//! of that. An array is its elements one after the other, as aligned as one
+//! of them. An enum starts with a `u32` tag numbering its variants in the
+//! order they are declared, the fields of each variant follow it as if the
+//! tag was the first field of a struct. It is as big as its biggest variant.
+//! Sizes too big for a `u32` saturate, the frame refuses them.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::parse::{Item, ItemEnum, ItemStruct, Type, TypeArray};
+
+/// The size of the tag at the start of an enum.
+pub const TAG_SIZE: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ty {
+    /// Integers, `bool` and `char`, kept in a register and stored in this
+    /// many bytes.
+    Scalar(u8),
+    /// The address of utf-8 bytes followed by their number, kept in memory
+    /// like a struct.
+    Str,
+    Struct(String),
+    Array(Box<Ty>, u32),
+    Enum(String),
+    /// The address of a `Ty`, kept in a register like a scalar, and whether
+    /// it may be written through.
+    Ref(Box<Ty>, bool),
+}
+
+impl Ty {
+    /// Structs, arrays, enums and `str`s are kept in memory, a register holds
+    /// their address.
+    pub fn is_scalar(&self) -> bool {
+        self.scalar_size().is_some()
+    }
+
+    /// How many bytes a value kept in a register is stored in.
+    pub fn scalar_size(&self) -> Option<u8> {
+        match self {
+            Self::Scalar(size) => Some(*size),
+            Self::Ref(..) => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// Scalars of one size can be used for each other, so they are written as
+/// the unsigned integer of that size.
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scalar(size) => write!(f, "u{}", *size as u32 * 8),
+            Self::Str => write!(f, "str"),
+            Self::Struct(name) => write!(f, "{name}"),
+            Self::Array(elem, len) => write!(f, "[{elem}; {len}]"),
+            Self::Enum(name) => write!(f, "{name}"),
+            Self::Ref(ty, true) => write!(f, "&mut {ty}"),
+            Self::Ref(ty, false) => write!(f, "&{ty}"),
+        }
+    }
+}
+
+/// The size of the scalar types.
+fn scalar(name: &str) -> Option<u8> {
+    match name {
+        "u8" | "bool" => Some(1),
+        "u32" | "char" => Some(4),
+        "u64" | "i64" => Some(8),
+        _ => None,
+    }
+}
+
+/// The types known without a declaration.
+fn builtin(name: &str) -> Option<Ty> {
+    match name {
+        "str" => Some(Ty::Str),
+        name => scalar(name).map(Ty::Scalar),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub ty: Ty,
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StructLayout {
+    pub fields: Vec<Field>,
+    pub size: u32,
+    pub align: u32,
+}
+
+impl StructLayout {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnumLayout {
+    /// Each variant laid out like a struct starting with the tag, its fields
+    /// named by their position.
+    pub variants: Vec<(String, StructLayout)>,
+    pub size: u32,
+    pub align: u32,
+}
+
+impl EnumLayout {
+    /// The tag of the variant `name` and its layout.
+    pub fn variant(&self, name: &str) -> Option<(u32, &StructLayout)> {
+        self.variants
+            .iter()
+            .enumerate()
+            .find(|(_, (variant, _))| variant == name)
+            .map(|(tag, (_, layout))| (tag as u32, layout))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Layouts {
+    structs: HashMap<String, StructLayout>,
+    enums: HashMap<String, EnumLayout>,
+}
+
+impl Layouts {
+    /// Lays out every struct and enum in `items`. One with an error is still
+    /// laid out, with the fields that are fine, so its uses don't report
+    /// errors of their own.
+    pub fn new(items: &[Item]) -> (Self, Vec<String>) {
+        let mut errors = vec![];
+        let mut decls = HashMap::new();
+        let mut names = vec![];
+        for item in items {
+            let decl = match item {
+                Item::Struct(item_struct) => Decl::Struct(item_struct),
+                Item::Enum(item_enum) => Decl::Enum(item_enum),
+                Item::Fn(..) => continue,
+            };
+            let name = decl.name();
+            names.push(name);
+            if decls.contains_key(name) {
+                errors.push(format!(
+                    "{} '{name}' is defined more than once",
+                    decl.kind()
+                ));
+                continue;
+            }
+            decls.insert(name, decl);
+        }
+        let mut builder = Builder {
+            decls,
+            layouts: Self::default(),
+            visiting: vec![],
+            errors,
+        };
+        for name in names {
+            builder.layout(name);
+        }
+        (builder.layouts, builder.errors)
+    }
+
+    /// `ty`, if it is made of scalars, structs, enums, arrays and references.
+    pub fn ty(&self, ty: &Type) -> Option<Ty> {
+        match ty {
+            Type::Name(name) => match builtin(&name.value) {
+                Some(ty) => Some(ty),
+                None if self.structs.contains_key(&name.value) => {
+                    Some(Ty::Struct(name.value.clone()))
+                }
+                None => self
+                    .enums
+                    .contains_key(&name.value)
+                    .then(|| Ty::Enum(name.value.clone())),
+            },
+            Type::Array(array) => Some(Ty::Array(
+                Box::new(self.ty(&array.elem)?),
+                array_len(&array.len)?,
+            )),
+            Type::Ref(reference) => Some(Ty::Ref(
+                Box::new(self.ty(&reference.ty)?),
+                reference.mutable.is_some(),
+            )),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StructLayout> {
+        self.structs.get(name)
+    }
+
+    pub fn get_enum(&self, name: &str) -> Option<&EnumLayout> {
+        self.enums.get(name)
+    }
+
+    /// Whether a `ty` is or holds a reference.
+    pub fn holds_ref(&self, ty: &Ty) -> bool {
+        let any = |fields: &[Field]| fields.iter().any(|field| self.holds_ref(&field.ty));
+        match ty {
+            Ty::Scalar(_) | Ty::Str => false,
+            Ty::Ref(..) => true,
+            Ty::Struct(name) => self.get(name).is_some_and(|layout| any(&layout.fields)),
+            Ty::Array(elem, _) => self.holds_ref(elem),
+            Ty::Enum(name) => self
+                .get_enum(name)
+                .is_some_and(|layout| layout.variants.iter().any(|(_, v)| any(&v.fields))),
+        }
+    }
+
+    pub fn size_of(&self, ty: &Ty) -> u32 {
+        self.size_align(ty).0
+    }
+
+    /// The size and alignment of a `ty`.
+    pub fn size_align(&self, ty: &Ty) -> (u32, u32) {
+        match ty {
+            Ty::Scalar(size) => (*size as u32, *size as u32),
+            Ty::Ref(..) => (8, 8),
+            Ty::Str => (16, 8),
+            Ty::Struct(name) => self
+                .structs
+                .get(name)
+                .map_or((0, 1), |layout| (layout.size, layout.align)),
+            Ty::Array(elem, len) => {
+                let (size, align) = self.size_align(elem);
+                (size.saturating_mul(*len), align)
+            }
+            Ty::Enum(name) => self
+                .enums
+                .get(name)
+                .map_or((TAG_SIZE as u32, TAG_SIZE as u32), |layout| {
+                    (layout.size, layout.align)
+                }),
+        }
+    }
+}
+
+fn array_len(len: &crate::parse::LitInt) -> Option<u32> {
+    len.parse().ok()
+}
+
+/// `n` rounded up to a multiple of `align`, saturating like sizes do.
+fn align_up(n: u32, align: u32) -> u32 {
+    n.checked_next_multiple_of(align).unwrap_or(u32::MAX)
+}
+
+#[derive(Clone, Copy)]
+enum Decl<'a> {
+    Struct(&'a ItemStruct),
+    Enum(&'a ItemEnum),
+}
+
+impl<'a> Decl<'a> {
+    fn name(self) -> &'a str {
+        match self {
+            Self::Struct(item) => &item.name.value,
+            Self::Enum(item) => &item.name.value,
+        }
+    }
+
+    fn kind(self) -> &'static str {
+        match self {
+            Self::Struct(..) => "struct",
+            Self::Enum(..) => "enum",
+        }
+    }
+
+    fn ty(self) -> Ty {
+        match self {
+            Self::Struct(item) => Ty::Struct(item.name.value.clone()),
+            Self::Enum(item) => Ty::Enum(item.name.value.clone()),
+        }
+    }
+}
+
+struct Builder<'a> {
+    decls: HashMap<&'a str, Decl<'a>>,
+    layouts: Layouts,
+    /// Structs and enums whose fields are being laid out, to catch one
+    /// holding itself.
+    visiting: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Builder<'_> {
+    fn layout(&mut self, name: &str) {
+        if self.layouts.structs.contains_key(name) || self.layouts.enums.contains_key(name) {
+            return;
+        }
+        let Some(decl) = self.decls.get(name).copied() else {
+            return;
+        };
+        self.visiting.push(name.into());
+        match decl {
+            Decl::Struct(item) => {
+                let layout = self.struct_layout(item);
+                self.layouts.structs.insert(name.into(), layout);
+            }
+            Decl::Enum(item) => {
+                let layout = self.enum_layout(item);
+                self.layouts.enums.insert(name.into(), layout);
+            }
+        }
+        self.visiting.pop();
+    }
+
+    fn struct_layout(&mut self, item: &ItemStruct) -> StructLayout {
+        let name = item.name.value.as_str();
+        let mut layout = StructLayout {
+            fields: vec![],
+            size: 0,
+            align: 1,
+        };
+        for param in item.fields.iter() {
+            let field = param.name.value.as_str();
+            if layout.field(field).is_some() {
+                self.errors.push(format!(
+                    "field '{field}' is declared more than once in '{name}'"
+                ));
+                continue;
+            }
+            if let Some(ty) = self.resolve(&param.kind, field, name) {
+                self.push_field(&mut layout, field, ty);
+            }
+        }
+        layout.size = align_up(layout.size, layout.align);
+        layout
+    }
+
+    /// A field of a variant with an error is kept as a `u64`, so the others
+    /// keep their position.
+    fn enum_layout(&mut self, item: &ItemEnum) -> EnumLayout {
+        let name = item.name.value.as_str();
+        let mut layout = EnumLayout {
+            variants: vec![],
+            size: TAG_SIZE as u32,
+            align: TAG_SIZE as u32,
+        };
+        for variant in item.variants.iter() {
+            let variant_name = variant.name.value.as_str();
+            if layout.variant(variant_name).is_some() {
+                self.errors.push(format!(
+                    "variant '{variant_name}' is declared more than once in '{name}'"
+                ));
+                continue;
+            }
+            let path = format!("{name}::{variant_name}");
+            let mut fields = StructLayout {
+                fields: vec![],
+                size: TAG_SIZE as u32,
+                align: TAG_SIZE as u32,
+            };
+            for (i, kind) in variant.fields.iter().enumerate() {
+                let field = i.to_string();
+                let ty = self.resolve(kind, &field, &path);
+                self.push_field(&mut fields, &field, ty.unwrap_or(Ty::Scalar(8)));
+            }
+            fields.size = align_up(fields.size, fields.align);
+            layout.size = layout.size.max(fields.size);
+            layout.align = layout.align.max(fields.align);
+            layout.variants.push((variant_name.into(), fields));
+        }
+        layout.size = align_up(layout.size, layout.align);
+        layout
+    }
+
+    /// Places `field` at the next offset aligned for its type.
+    fn push_field(&self, layout: &mut StructLayout, field: &str, ty: Ty) {
+        let (size, align) = self.layouts.size_align(&ty);
+        let offset = align_up(layout.size, align);
+        layout.size = offset.saturating_add(size);
+        layout.align = layout.align.max(align);
+        layout.fields.push(Field {
+            name: field.into(),
+            ty,
+            offset,
+        });
+    }
+
+    /// The type of `field` in the struct or variant `name`, laying out the
+    /// structs and enums it holds first.
+    fn resolve(&mut self, kind: &Type, field: &str, name: &str) -> Option<Ty> {
+        let kind = match kind {
+            Type::Name(kind) => kind.value.as_str(),
+            Type::Array(array) => {
+                let TypeArray { elem, len, .. } = &**array;
+                let elem = self.resolve(elem, field, name)?;
+                let Some(len) = array_len(len) else {
+                    self.errors.push(format!(
+                        "length {len} of field '{field}' in '{name}' is too big"
+                    ));
+                    return None;
+                };
+                return Some(Ty::Array(Box::new(elem), len));
+            }
+            Type::Ref(reference) => {
+                let ty = self.pointee(&reference.ty, field, name)?;
+                return Some(Ty::Ref(Box::new(ty), reference.mutable.is_some()));
+            }
+        };
+        if let Some(ty) = builtin(kind) {
+            return Some(ty);
+        }
+        let Some(decl) = self.decls.get(kind).copied() else {
+            self.errors.push(format!(
+                "unknown type '{kind}' of field '{field}' in '{name}'"
+            ));
+            return None;
+        };
+        if self.visiting.iter().any(|v| v == kind) {
+            self.errors.push(format!(
+                "{} '{kind}' contains itself through field '{field}' of '{name}'",
+                decl.kind()
+            ));
+            return None;
+        }
+        self.layout(kind);
+        Some(decl.ty())
+    }
+
+    /// What a reference in `field` points to, which does not need to be laid
+    /// out first, so a struct can refer to itself.
+    fn pointee(&mut self, kind: &Type, field: &str, name: &str) -> Option<Ty> {
+        match kind {
+            Type::Name(ident) if builtin(&ident.value).is_none() => {
+                match self.decls.get(ident.value.as_str()) {
+                    Some(decl) => Some(decl.ty()),
+                    None => self.resolve(kind, field, name),
+                }
+            }
+            kind => self.resolve(kind, field, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use pretty_assertions::assert_eq;
+
+    fn setup(src: &str) -> (Layouts, Vec<String>) {
+        let items = lex(src).and_then(parse).unwrap();
+        Layouts::new(&items)
+    }
+
+    fn offsets(layout: &StructLayout) -> Vec<(&str, u32)> {
+        layout
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.offset))
+            .collect()
+    }
+
+    #[test]
+    fn padding() {
+        let (layouts, errors) = setup(
+            "struct Inner { a: u8, b: u32 }
+             struct Outer { c: bool, inner: Inner, d: u64, e: char }",
+        );
+        assert_eq!(errors, Vec::<String>::new());
+        let inner = layouts.get("Inner").unwrap();
+        assert_eq!(offsets(inner), [("a", 0), ("b", 4)]);
+        assert_eq!((inner.size, inner.align), (8, 4));
+        let outer = layouts.get("Outer").unwrap();
+        assert_eq!(
+            offsets(outer),
+            [("c", 0), ("inner", 4), ("d", 16), ("e", 24)]
+        );
+        assert_eq!((outer.size, outer.align), (32, 8));
+    }
+
+    #[test]
+    fn arrays() {
+        let (layouts, errors) = setup(
+            "struct Inner { a: u8, b: u32 }
+             struct Outer { c: [u8; 3], inner: [Inner; 2], d: [[char; 2]; 2] }",
+        );
+        assert_eq!(errors, Vec::<String>::new());
+        let outer = layouts.get("Outer").unwrap();
+        assert_eq!(offsets(outer), [("c", 0), ("inner", 4), ("d", 20)]);
+        assert_eq!((outer.size, outer.align), (36, 4));
+        let inner = Ty::Struct("Inner".into());
+        assert_eq!(outer.fields[1].ty, Ty::Array(Box::new(inner), 2));
+        assert_eq!(outer.fields[2].ty.to_string(), "[[u32; 2]; 2]");
+        let (layouts, errors) =
+            setup("struct A { a: [A; 2], b: [u8; 4294967296], c: [[u64; 4294967295]; 2] }");
+        assert_eq!(
+            errors,
+            [
+                "struct 'A' contains itself through field 'a' of 'A'",
+                "length 4294967296 of field 'b' in 'A' is too big",
+            ]
+        );
+        assert_eq!(layouts.get("A").unwrap().size, u32::MAX);
+    }
+
+    #[test]
+    fn errors() {
+        let (layouts, errors) = setup(
+            "struct A { b: B, x: Foo }
+             struct B { a: A, y: u8, y: u8 }
+             struct A { }",
+        );
+        assert_eq!(
+            errors,
+            [
+                "struct 'A' is defined more than once",
+                "struct 'A' contains itself through field 'a' of 'B'",
+                "field 'y' is declared more than once in 'B'",
+                "unknown type 'Foo' of field 'x' in 'A'",
+            ]
+        );
+        assert_eq!(layouts.get("A").unwrap().size, 1);
+    }
+
+    #[test]
+    fn enums() {
+        let (layouts, errors) = setup(
+            "enum Color { Red, Green }
+             struct P { x: u8, y: u64 }
+             enum Shape { Dot(u8), Line(P, u32), Color(Color) }
+             enum Tiny { A(u8), B }",
+        );
+        assert_eq!(errors, Vec::<String>::new());
+        let color = layouts.get_enum("Color").unwrap();
+        assert_eq!((color.size, color.align), (4, 4));
+        assert_eq!(color.variant("Green").map(|(tag, _)| tag), Some(1));
+        let shape = layouts.get_enum("Shape").unwrap();
+        let line = shape.variant("Line").unwrap();
+        assert_eq!((line.0, offsets(line.1)), (1, vec![("0", 8), ("1", 24)]));
+        assert_eq!(offsets(shape.variant("Dot").unwrap().1), [("0", 4)]);
+        assert_eq!((shape.size, shape.align), (32, 8));
+        let tiny = layouts.get_enum("Tiny").unwrap();
+        assert_eq!((tiny.size, tiny.align), (8, 4));
+    }
+
+    #[test]
+    fn enum_errors() {
+        let (layouts, errors) = setup(
+            "enum L { Cons(u64, L), Nil, Nil }
+             struct L { }
+             enum M { A(Foo, u8) }",
+        );
+        assert_eq!(
+            errors,
+            [
+                "struct 'L' is defined more than once",
+                "enum 'L' contains itself through field '1' of 'L::Cons'",
+                "variant 'Nil' is declared more than once in 'L'",
+                "unknown type 'Foo' of field '0' in 'M::A'",
+            ]
+        );
+        let a = layouts.get_enum("M").unwrap().variant("A").unwrap().1;
+        assert_eq!(offsets(a), [("0", 8), ("1", 16)]);
+    }
+
+    #[test]
+    fn refs() {
+        let (layouts, errors) = setup(
+            "struct Node { value: u8, next: &Node, back: &mut Node }
+             enum L { Cons(u64, &L), Nil }",
+        );
+        assert_eq!(errors, Vec::<String>::new());
+        let node = layouts.get("Node").unwrap();
+        assert_eq!(offsets(node), [("value", 0), ("next", 8), ("back", 16)]);
+        assert_eq!((node.size, node.align), (24, 8));
+        let cons = layouts.get_enum("L").unwrap().variant("Cons").unwrap().1;
+        assert_eq!(offsets(cons), [("0", 8), ("1", 16)]);
+    }
+}
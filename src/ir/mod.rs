@@ -1,24 +1,92 @@
+pub mod cfg;
 mod instruction;
+pub mod layout;
+pub mod opt;
+pub mod ssa;
+mod tail_call;
 #[cfg(test)]
 mod test;
-use std::collections::HashMap;
+pub mod text;
+pub mod verify;
+use std::collections::{HashMap, HashSet};
 
 pub use instruction::*;
+use layout::{EnumLayout, Layouts, Ty, TAG_SIZE};
+pub use tail_call::tail_calls;
 
 use crate::lexer::*;
 
 use crate::parse::{
-    Expr, ExprBinary, ExprBlock, ExprCall, ExprIf, ExprLit, ExprReturn, ExprVar, Ident, Item,
-    ItemFn, Lit, LitBool, LitInt, Op, Param, Statement,
+    Attribute, Expr, ExprArray, ExprAssign, ExprBinary, ExprBlock, ExprCall, ExprCast, ExprDeref,
+    ExprField, ExprIf, ExprIndex, ExprLet, ExprLit, ExprMatch, ExprPath, ExprRef, ExprRepeat,
+    ExprReturn, ExprStruct, ExprVar, ExprWhile, Ident, Item, ItemFn, Lit, LitBool, LitChar, LitInt,
+    LitStr, Op, Param, Pat, PatVariant, Statement,
 };
 
 pub fn code_gen(ast: Vec<Item>) -> Result<Vec<Instruction>, Vec<String>> {
+    generate(&ast, false)
+}
+
+/// Like `code_gen`, for an object file, whose functions are all exported.
+pub fn code_gen_exported(ast: Vec<Item>) -> Result<Vec<Instruction>, Vec<String>> {
+    generate(&ast, true)
+}
+
+/// The errors `code_gen` reports for `ast`, for running it without compiling
+/// it.
+pub fn check(ast: &[Item]) -> Result<(), Vec<String>> {
+    generate(ast, false).map(drop)
+}
+
+fn generate(ast: &[Item], exported: bool) -> Result<Vec<Instruction>, Vec<String>> {
     let mut gen = IrGenerator::default();
-    gen.visit(&ast);
+    gen.visit(ast);
+    if exported {
+        let called = gen
+            .code
+            .iter()
+            .filter_map(|inst| match inst {
+                Instruction::DefFunc(func) => Some(func.body.iter()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|inst| match inst {
+                Instruction::Call(Call { caller, .. }) => Some(caller.0.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<String>>();
+        let defined = |name: &str| {
+            ast.iter()
+                .any(|item| matches!(item, Item::Fn(item_fn) if item_fn.name.value() == name))
+        };
+        for name in START_BUILTINS {
+            if called.contains(name) && !defined(name) {
+                gen.errors.push(format!(
+                    "'{name}' can not be called in an object file, only an executable is given the arguments and environment"
+                ));
+            }
+        }
+    }
+    if !gen.errors.is_empty() {
+        return Err(gen.errors);
+    }
     Ok(gen.code)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Runs `f` over every function in `code`.
+pub fn map_funcs(
+    code: Vec<Instruction>,
+    mut f: impl FnMut(DefFunc) -> DefFunc,
+) -> Vec<Instruction> {
+    code.into_iter()
+        .map(|i| match i {
+            Instruction::DefFunc(func) => f(func).into(),
+            i => i,
+        })
+        .collect()
+}
+
+#[derive(Debug, Hash, Clone, PartialEq, Eq)]
 pub struct Label(pub String);
 
 impl std::fmt::Display for Label {
@@ -48,9 +116,6 @@ impl From<String> for Label {
 #[derive(Debug, Hash, Clone, Copy, PartialEq, Eq)]
 pub struct Reg(pub usize);
 
-#[derive(Debug, Hash, Clone, PartialEq, Eq)]
-pub struct Var(pub String);
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Imm(pub u64);
 impl From<u64> for Imm {
@@ -63,8 +128,10 @@ trait Ir {
     fn def_label(&mut self, label: Label);
     fn jump(&mut self, label: Label);
     fn load_imm(&mut self, imm: Imm) -> Reg;
+    fn load_str(&mut self, value: String) -> Reg;
     fn binary(&mut self, op: &Op, lhs: Reg, rhs: Reg) -> Reg;
     fn conditional(&mut self, label: Label, reg: Reg) -> Reg;
+    fn copy(&mut self, to: Reg, from: Reg) -> Reg;
     fn call(&mut self, label: Label, args: Vec<Reg>, ret: Reg) -> Reg;
     fn early_return(&mut self, reg: Reg) -> Reg;
 }
@@ -78,23 +145,41 @@ trait AstVisitor: Ir {
     fn visit_item_fn(&mut self, item_fn: &ItemFn);
     fn visit_lit_int(&mut self, lit_int: &LitInt) -> Reg;
     fn visit_lit_bool(&mut self, lit_bool: &LitBool) -> Reg;
+    fn visit_lit_str(&mut self, lit_str: &LitStr) -> Reg;
+    fn visit_lit_char(&mut self, lit_char: &LitChar) -> Reg;
+    fn visit_expr_cast(&mut self, expr_cast: &ExprCast) -> Reg;
+    fn visit_expr_struct(&mut self, expr_struct: &ExprStruct) -> Reg;
+    fn visit_expr_field(&mut self, expr_field: &ExprField) -> Reg;
+    fn visit_expr_array(&mut self, expr_array: &ExprArray) -> Reg;
+    fn visit_expr_repeat(&mut self, expr_repeat: &ExprRepeat) -> Reg;
+    fn visit_expr_index(&mut self, expr_index: &ExprIndex) -> Reg;
+    fn visit_expr_path(&mut self, expr_path: &ExprPath) -> Reg;
+    fn visit_expr_match(&mut self, expr_match: &ExprMatch) -> Reg;
+    fn visit_expr_ref(&mut self, expr_ref: &ExprRef) -> Reg;
+    fn visit_expr_deref(&mut self, expr_deref: &ExprDeref) -> Reg;
+    /// Learns about every struct, enum and function before any body is
+    /// visited.
+    fn declare(&mut self, items: &[Item]);
 
     fn visit_expr_if(&mut self, expr_if: &ExprIf) -> Reg;
+    fn visit_expr_while(&mut self, expr_while: &ExprWhile) -> Reg;
+    fn visit_expr_let(&mut self, expr_let: &ExprLet) -> Reg;
+    fn visit_expr_assign(&mut self, expr_assign: &ExprAssign) -> Reg;
+    fn push_scope(&mut self);
+    fn pop_scope(&mut self);
 
     fn visit_expr_return(&mut self, expr_ret: &ExprReturn) -> Reg {
         let ExprReturn { expr, .. } = expr_ret;
         let reg = self.visit_expr(expr);
-        self.early_return(reg);
-        self.jump(".exit".into());
-        reg
+        self.early_return(reg)
     }
 
     fn visit_lit(&mut self, lit: &Lit) -> Reg {
         match lit {
             Lit::Int(ref lint) => self.visit_lit_int(lint),
             Lit::Bool(ref lbool) => self.visit_lit_bool(lbool),
-            Lit::Str(_lstr) => unimplemented!(),
-            Lit::Char(_lchar) => unimplemented!(),
+            Lit::Str(ref lstr) => self.visit_lit_str(lstr),
+            Lit::Char(ref lchar) => self.visit_lit_char(lchar),
         }
     }
 
@@ -110,8 +195,21 @@ trait AstVisitor: Ir {
             Expr::Call(ref ecall) => self.visit_expr_call(ecall),
             Expr::Var(evar) => self.visit_expr_var(evar),
             Expr::If(eif) => self.visit_expr_if(eif),
+            Expr::While(ewhile) => self.visit_expr_while(ewhile),
             Expr::Block(eblock) => self.visit_expr_block(eblock),
             Expr::Return(ereturn) => self.visit_expr_return(ereturn),
+            Expr::Let(elet) => self.visit_expr_let(elet),
+            Expr::Assign(eassign) => self.visit_expr_assign(eassign),
+            Expr::Cast(ecast) => self.visit_expr_cast(ecast),
+            Expr::Struct(estruct) => self.visit_expr_struct(estruct),
+            Expr::Field(efield) => self.visit_expr_field(efield),
+            Expr::Array(earray) => self.visit_expr_array(earray),
+            Expr::Repeat(erepeat) => self.visit_expr_repeat(erepeat),
+            Expr::Index(eindex) => self.visit_expr_index(eindex),
+            Expr::Path(epath) => self.visit_expr_path(epath),
+            Expr::Match(ematch) => self.visit_expr_match(ematch),
+            Expr::Ref(eref) => self.visit_expr_ref(eref),
+            Expr::Deref(ederef) => self.visit_expr_deref(ederef),
         }
     }
 
@@ -123,9 +221,11 @@ trait AstVisitor: Ir {
     fn visit_expr_block(&mut self, block: &ExprBlock) -> Reg {
         // FIXME: This should return a Reg if we keep the current pattern
         let mut reg: Option<Reg> = None;
+        self.push_scope();
         for stmt in block.stmts.iter() {
             reg = Some(self.visit_stmt(stmt));
         }
+        self.pop_scope();
         let Some(reg) = reg else {
             panic!("WHAT DO I DO HERE!");
         };
@@ -133,26 +233,216 @@ trait AstVisitor: Ir {
     }
 
     fn visit(&mut self, items: &[Item]) {
+        self.declare(items);
         for item in items.iter() {
             match item {
                 Item::Fn(ref item_fn) => self.visit_item_fn(item_fn),
+                Item::Struct(..) | Item::Enum(..) => {}
+            }
+        }
+    }
+}
+
+/// Unknown attributes and arguments are ignored.
+fn inline_hint(attrs: &[Attribute]) -> Inline {
+    let Some(attr) = attrs.iter().rev().find(|a| a.name.value == "inline") else {
+        return Inline::Auto;
+    };
+    match attr.arg.as_ref().map(|arg| arg.value.as_str()) {
+        Some("never") => Inline::Never,
+        _ => Inline::Always,
+    }
+}
+
+/// Types a value can be cast to with `as`.
+pub const CAST_TYPES: [&str; 4] = ["u32", "u64", "i64", "char"];
+
+/// Integer types `main` may take `argc` and `argv` as and return.
+const MAIN_TYPES: [&str; 3] = ["u32", "u64", "i64"];
+
+/// Builtins reading what `_start` finds on the initial stack, which does not
+/// run when C links the object file.
+const START_BUILTINS: [&str; 3] = ["arg_count", "arg", "env"];
+
+/// The signatures of the builtins, a function of the same name replaces
+/// them.
+const BUILTINS: [(&str, &[Ty], Option<Ty>); 6] = [
+    ("print", &[Ty::Str], None),
+    ("println", &[Ty::Str], None),
+    ("print_int", &[Ty::Scalar(8)], None),
+    ("arg_count", &[], Some(Ty::Scalar(8))),
+    ("arg", &[Ty::Scalar(8)], Some(Ty::Str)),
+    ("env", &[Ty::Str], Some(Ty::Str)),
+];
+
+/// Called with the index and the length when an index is out of bounds, it
+/// does not return.
+pub const INDEX_OUT_OF_BOUNDS: &str = "__index_out_of_bounds";
+
+/// The most bytes a single struct or array may take on the stack.
+const MAX_SLOT: u32 = 1 << 24;
+
+/// Arrays up to this long are copied and filled one element after the
+/// other, longer ones in a loop.
+const UNROLL: u32 = 8;
+
+/// `main` takes nothing or `argc` and `argv`, and returns an integer or
+/// nothing.
+fn main_errors(item_fn: &ItemFn) -> Vec<String> {
+    let is_int = |ty: &crate::parse::Type| ty.name().is_some_and(|n| MAIN_TYPES.contains(&n));
+    let mut errors = vec![];
+    if !matches!(item_fn.params.len(), 0 | 2) {
+        errors.push(format!(
+            "'main' takes no arguments or 'argc' and 'argv', not {}",
+            item_fn.params.len()
+        ));
+    }
+    for param in item_fn.params.iter().filter(|p| !is_int(&p.kind)) {
+        errors.push(format!(
+            "'main' can not take '{:#}' as {}, expected an integer",
+            param.kind, param.name
+        ));
+    }
+    if let Some(ty) = item_fn.ret_type.as_ref().filter(|ty| !is_int(ty)) {
+        errors.push(format!(
+            "'main' can not return '{ty:#}', expected an integer"
+        ));
+    }
+    errors
+}
+
+/// The value a literal pattern is compared with.
+fn lit_value(lit: &Lit) -> u64 {
+    match lit {
+        Lit::Int(lit_int) => lit_int.parse().unwrap_or_default(),
+        Lit::Bool(lit_bool) => lit_bool.parse::<bool>().unwrap_or_default() as u64,
+        Lit::Char(lit_char) => lit_char.value.chars().next().unwrap_or_default() as u64,
+        Lit::Str(..) => 0,
+    }
+}
+
+/// Collects the variables `expr` takes the address of with `&` or `&mut`.
+fn addressed(expr: &Expr, names: &mut HashSet<String>) {
+    let mut walk = |expr: &Expr| addressed(expr, names);
+    match expr {
+        Expr::Ref(ExprRef { expr, .. }) => match &**expr {
+            Expr::Var(ExprVar { name, .. }) => {
+                names.insert(name.value());
+            }
+            expr => walk(expr),
+        },
+        Expr::Lit(..) | Expr::Var(..) | Expr::Path(..) => {}
+        Expr::Binary(ExprBinary { left, right, .. }) => {
+            walk(left);
+            walk(right);
+        }
+        Expr::Call(ExprCall { caller, args, .. }) => {
+            walk(caller);
+            args.iter().for_each(walk);
+        }
+        Expr::If(ExprIf {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        }) => {
+            walk(cond);
+            then_branch.stmts.iter().for_each(|s| walk(&s.stmt));
+            if let Some((_, expr)) = else_branch {
+                walk(expr);
             }
         }
+        Expr::While(ExprWhile { cond, body, .. }) => {
+            walk(cond);
+            body.stmts.iter().for_each(|s| walk(&s.stmt));
+        }
+        Expr::Block(ExprBlock { stmts, .. }) => stmts.iter().for_each(|s| walk(&s.stmt)),
+        Expr::Return(ExprReturn { expr, .. })
+        | Expr::Let(ExprLet { value: expr, .. })
+        | Expr::Cast(ExprCast { expr, .. })
+        | Expr::Field(ExprField { expr, .. })
+        | Expr::Repeat(ExprRepeat { value: expr, .. })
+        | Expr::Deref(ExprDeref { expr, .. }) => walk(expr),
+        Expr::Assign(ExprAssign { target, value }) => {
+            walk(target);
+            walk(value);
+        }
+        Expr::Struct(ExprStruct { fields, .. }) => fields.iter().for_each(|(_, e)| walk(e)),
+        Expr::Array(ExprArray { elems, .. }) => elems.iter().for_each(walk),
+        Expr::Index(ExprIndex { expr, index, .. }) => {
+            walk(expr);
+            walk(index);
+        }
+        Expr::Match(ExprMatch { expr, arms, .. }) => {
+            walk(expr);
+            arms.iter().for_each(|arm| walk(&arm.body));
+        }
     }
 }
 
+/// The types a function takes and returns, known before its body is
+/// visited.
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Ty>,
+    ret: Option<Ty>,
+}
+
+/// Structs, arrays, enums and `str`s are kept in memory and a register
+/// holding one holds its address. To pass one the caller makes a copy and passes its
+/// address, and the parameter is typed with its size, so native code can pass
+/// it in registers or on the stack as SysV does instead. One is returned
+/// through an address the caller passes as a hidden first argument.
+///
+/// A reference is an address kept in a register. A variable whose address
+/// is taken lives in a slot of its own instead of a register.
 #[derive(Debug, Default)]
 struct IrGenerator {
     code: Vec<Instruction>,
     block: Vec<Instruction>,
     reg_counter: usize,
     vars: HashMap<String, Reg>,
+    scopes: Vec<HashMap<String, Reg>>,
     gen_label_number: usize,
+    errors: Vec<String>,
+    layouts: Layouts,
+    funcs: HashMap<String, Signature>,
+    /// The type of the struct, array or enum each register holding the
+    /// address of one points to.
+    memory: HashMap<Reg, Ty>,
+    /// The function being generated and the type it returns.
+    func: String,
+    ret: Option<Ty>,
+    /// Where a function returning a struct writes it.
+    sret: Option<Reg>,
+    /// The type of each register holding a reference.
+    refs: HashMap<Reg, Ty>,
+    /// The slot of each variable whose address is taken and the type in it.
+    spilled: HashMap<Reg, Ty>,
+    /// The variables of the function whose address is taken.
+    addressed: HashSet<String>,
+    /// References that may point into the frame, and memory that may hold
+    /// one.
+    local: HashSet<Reg>,
+    /// The reference an address into memory was reached through.
+    through: HashMap<Reg, Reg>,
+    /// The struct or array each address of a field or element is part of.
+    within: HashMap<Reg, Reg>,
 }
 
 impl IrGenerator {
     fn push_to_block(&mut self, ir: impl Into<Instruction>) {
-        self.block.push(ir.into());
+        let ir = ir.into();
+        // Code after a `return` can't be reached, but still starts a block.
+        let unreachable = matches!(
+            self.block.last(),
+            Some(Instruction::Jump(..) | Instruction::Return(..) | Instruction::TailCall(..))
+        );
+        if unreachable && ir.as_label().is_none() {
+            let label = self.gen_label();
+            self.block.push(DefLabel(label).into());
+        }
+        self.block.push(ir);
     }
 
     fn push_fn(&mut self, ir: impl Into<Instruction>) {
@@ -165,10 +455,680 @@ impl IrGenerator {
         reg
     }
 
+    fn grt(&mut self, lhs: Reg, rhs: Reg) -> Reg {
+        let des = self.get_reg();
+        self.push_to_block(Grt { des, lhs, rhs });
+        des
+    }
+
+    fn add(&mut self, lhs: Reg, rhs: Reg) -> Reg {
+        let des = self.get_reg();
+        self.push_to_block(Add { des, lhs, rhs });
+        des
+    }
+
+    fn sub(&mut self, lhs: Reg, rhs: Reg) -> Reg {
+        let des = self.get_reg();
+        self.push_to_block(Sub { des, lhs, rhs });
+        des
+    }
+
+    fn mul(&mut self, lhs: Reg, rhs: Reg) -> Reg {
+        let des = self.get_reg();
+        self.push_to_block(Mul { des, lhs, rhs });
+        des
+    }
+
+    /// `(lhs > rhs) + (rhs > lhs)`, at most one of them is `1`.
+    fn neq(&mut self, lhs: Reg, rhs: Reg) -> Reg {
+        let grt = self.grt(lhs, rhs);
+        let les = self.grt(rhs, lhs);
+        let des = self.get_reg();
+        self.push_to_block(Add {
+            des,
+            lhs: grt,
+            rhs: les,
+        });
+        des
+    }
+
+    /// `1 - reg`, for a `reg` that is `0` or `1`.
+    fn not(&mut self, reg: Reg) -> Reg {
+        let one = self.load_imm(1.into());
+        let des = self.get_reg();
+        self.push_to_block(Sub {
+            des,
+            lhs: one,
+            rhs: reg,
+        });
+        des
+    }
+
+    /// Types the language does not know are treated as integers.
+    fn ty_of(&self, ty: &crate::parse::Type) -> Ty {
+        self.layouts.ty(ty).unwrap_or(Ty::Scalar(8))
+    }
+
+    /// Reserves a slot in the frame for a `ty`.
+    fn alloca(&mut self, ty: &Ty) -> Reg {
+        if !self.fits(ty) {
+            self.errors.push(format!("'{ty}' is too big for the stack"));
+        }
+        let size = self.layouts.size_of(ty);
+        let des = self.get_reg();
+        self.push_to_block(Alloca { des, size });
+        self.memory.insert(des, ty.clone());
+        des
+    }
+
+    /// How a function takes or returns a `ty`, nothing being an integer.
+    fn ir_type(&self, ty: Option<&Ty>) -> Type {
+        match ty {
+            Some(ty) if !ty.is_scalar() => Type::Aggregate(self.layouts.size_of(ty)),
+            _ => Type::I64,
+        }
+    }
+
+    /// Whether a `ty` fits in a slot, offsets into one can not overflow.
+    fn fits(&self, ty: &Ty) -> bool {
+        self.layouts.size_of(ty) <= MAX_SLOT
+    }
+
+    /// Copies a `ty` at `from` to `to`, both an address and an offset from it.
+    fn copy_memory(&mut self, to: (Reg, u32), from: (Reg, u32), ty: &Ty) {
+        if self.local.contains(&from.0) {
+            self.taint(to.0);
+        }
+        if !self.fits(ty) {
+            return;
+        }
+        match ty {
+            Ty::Scalar(size) => {
+                let des = self.get_reg();
+                self.push_to_block(Load {
+                    des,
+                    addr: from.0,
+                    offset: from.1,
+                    size: *size,
+                });
+                self.push_to_block(Store {
+                    addr: to.0,
+                    offset: to.1,
+                    src: des,
+                    size: *size,
+                });
+            }
+            Ty::Struct(name) => {
+                let fields = self.layouts.get(name).map_or(vec![], |l| l.fields.clone());
+                for field in fields {
+                    let to = (to.0, to.1 + field.offset);
+                    let from = (from.0, from.1 + field.offset);
+                    self.copy_memory(to, from, &field.ty);
+                }
+            }
+            Ty::Array(elem, len) => {
+                let size = self.layouts.size_of(elem);
+                self.each_elem([to, from], *len, size, |gen, [to, from]| {
+                    gen.copy_memory(to, from, elem)
+                });
+            }
+            Ty::Ref(..) => self.copy_memory(to, from, &Ty::Scalar(8)),
+            Ty::Str => {
+                let words = Ty::Array(Box::new(Ty::Scalar(8)), 2);
+                self.copy_memory(to, from, &words);
+            }
+            // Which variant is held is only known at run time, all of it is
+            // copied.
+            Ty::Enum(..) => {
+                let (size, align) = self.layouts.size_align(ty);
+                let chunk = align.min(8);
+                let words = Ty::Array(Box::new(Ty::Scalar(chunk as u8)), size / chunk);
+                self.copy_memory(to, from, &words);
+            }
+        }
+    }
+
+    /// Runs `f` on every element of arrays of `len` elements of `size` bytes
+    /// at `bases`, with a pointer moving along each array for long ones.
+    fn each_elem<const N: usize>(
+        &mut self,
+        bases: [(Reg, u32); N],
+        len: u32,
+        size: u32,
+        mut f: impl FnMut(&mut Self, [(Reg, u32); N]),
+    ) {
+        if len <= UNROLL {
+            for i in 0..len {
+                f(self, bases.map(|(reg, offset)| (reg, offset + i * size)));
+            }
+            return;
+        }
+        let ptrs = bases.map(|(reg, offset)| {
+            let offset = self.load_imm((offset as u64).into());
+            let start = self.add(reg, offset);
+            let ptr = self.get_reg();
+            self.copy(ptr, start)
+        });
+        let zero = self.load_imm(0u64.into());
+        let i = self.get_reg();
+        self.copy(i, zero);
+        let len = self.load_imm((len as u64).into());
+        let head = self.gen_label();
+        let done = self.gen_label();
+        self.def_label(head.clone());
+        let more = self.grt(len, i);
+        self.conditional(done.clone(), more);
+        f(self, ptrs.map(|ptr| (ptr, 0)));
+        let step = self.load_imm((size as u64).into());
+        for ptr in ptrs {
+            let next = self.add(ptr, step);
+            self.copy(ptr, next);
+        }
+        let one = self.load_imm(1u64.into());
+        let next = self.add(i, one);
+        self.copy(i, next);
+        self.jump(head);
+        self.def_label(done);
+    }
+
+    /// Like `visit_expr`, with an array literal taking the element type of
+    /// `ty` when it is an array.
+    fn visit_as(&mut self, expr: &Expr, ty: Option<&Ty>) -> Reg {
+        let elem = match ty {
+            Some(Ty::Array(elem, _)) => Some(&**elem),
+            _ => None,
+        };
+        match expr {
+            Expr::Array(expr_array) => self.array(expr_array, elem),
+            Expr::Repeat(expr_repeat) => self.repeat(expr_repeat, elem),
+            expr => self.visit_expr(expr),
+        }
+    }
+
+    /// The value of `expr` going where a `ty` is expected, with a struct or
+    /// array copied unless it is a temporary nothing else refers to.
+    fn visit_owned(&mut self, expr: &Expr, ty: Option<&Ty>) -> Reg {
+        let reg = self.visit_as(expr, ty);
+        let temporary = matches!(
+            expr,
+            Expr::Struct(..) | Expr::Call(..) | Expr::Array(..) | Expr::Repeat(..) | Expr::Path(..)
+        );
+        match self.memory.get(&reg).cloned() {
+            Some(ty) if !temporary => {
+                let copy = self.alloca(&ty);
+                self.copy_memory((copy, 0), (reg, 0), &ty);
+                copy
+            }
+            _ => reg,
+        }
+    }
+
+    /// `[a, b, c]` of `elem`, or of the type of `a` when nothing says.
+    fn array(&mut self, expr_array: &ExprArray, elem: Option<&Ty>) -> Reg {
+        let mut elem = elem.cloned();
+        let mut regs = vec![];
+        for value in expr_array.elems.iter() {
+            let reg = self.visit_as(value, elem.as_ref());
+            if elem.is_none() {
+                elem = Some(self.value_ty(reg).unwrap_or(Ty::Scalar(8)));
+            }
+            regs.push(reg);
+        }
+        let elem = elem.unwrap_or(Ty::Scalar(8));
+        let ty = Ty::Array(Box::new(elem.clone()), regs.len() as u32);
+        let des = self.alloca(&ty);
+        if !self.fits(&ty) {
+            return des;
+        }
+        let size = self.layouts.size_of(&elem);
+        for (i, reg) in regs.into_iter().enumerate() {
+            self.expect(reg, &elem, || format!("element {i} of the array"));
+            self.store(des, i as u32 * size, reg, &elem);
+        }
+        des
+    }
+
+    /// `[value; len]`, with `value` evaluated once.
+    fn repeat(&mut self, expr_repeat: &ExprRepeat, elem: Option<&Ty>) -> Reg {
+        let ExprRepeat { value, len, .. } = expr_repeat;
+        let value = self.visit_as(value, elem);
+        let elem = match elem {
+            Some(elem) => elem.clone(),
+            None => self.value_ty(value).unwrap_or(Ty::Scalar(8)),
+        };
+        self.expect(value, &elem, || "the value of the array".into());
+        let Ok(len) = len.parse::<u32>() else {
+            self.errors.push(format!("array length {len} is too big"));
+            return self.load_imm(0u64.into());
+        };
+        let ty = Ty::Array(Box::new(elem.clone()), len);
+        let des = self.alloca(&ty);
+        if !self.fits(&ty) {
+            return des;
+        }
+        let size = self.layouts.size_of(&elem);
+        self.each_elem([(des, 0)], len, size, |gen, [(addr, offset)]| {
+            gen.store(addr, offset, value, &elem)
+        });
+        des
+    }
+
+    /// Reports `what` holding a value that is not a `ty`.
+    fn expect(&mut self, reg: Reg, ty: &Ty, what: impl FnOnce() -> String) {
+        let found = self.value_ty(reg);
+        let error = match (ty, found.as_ref()) {
+            (Ty::Scalar(_), None) => return,
+            (ty, Some(found)) if ty == found => return,
+            // A `&mut T` can be used where a `&T` is.
+            (Ty::Ref(ty, false), Some(Ty::Ref(found, true))) if ty == found => return,
+            (Ty::Scalar(_), Some(found)) => format!("{} can not be a '{found}'", what()),
+            (ty, _) => format!("{} must be a '{ty}'", what()),
+        };
+        self.errors.push(error);
+    }
+
+    /// Writes `src`, a `ty`, to `addr + offset`.
+    fn store(&mut self, addr: Reg, offset: u32, src: Reg, ty: &Ty) {
+        if self.local.contains(&src) {
+            self.taint(addr);
+        }
+        match ty.scalar_size() {
+            Some(size) => self.push_to_block(Store {
+                addr,
+                offset,
+                src,
+                size,
+            }),
+            None => self.copy_memory((addr, offset), (src, 0), ty),
+        }
+    }
+
+    /// Reads the scalar or reference `ty` at `addr + offset`.
+    fn load(&mut self, addr: Reg, offset: u32, ty: &Ty) -> Reg {
+        let des = self.get_reg();
+        self.push_to_block(Load {
+            des,
+            addr,
+            offset,
+            size: ty.scalar_size().unwrap_or(8),
+        });
+        if let Ty::Ref(..) = ty {
+            self.refs.insert(des, ty.clone());
+            if self.local.contains(&addr) {
+                self.local.insert(des);
+            }
+        }
+        des
+    }
+
+    fn var(&mut self, name: &Ident) -> Option<Reg> {
+        let reg = self.vars.get(&name.value).copied();
+        if reg.is_none() {
+            self.errors.push(format!("undefined variable '{name}'"));
+        }
+        reg
+    }
+
+    /// The type of the struct, array, enum or reference `reg` holds.
+    fn value_ty(&self, reg: Reg) -> Option<Ty> {
+        self.memory.get(&reg).or(self.refs.get(&reg)).cloned()
+    }
+
+    /// `to` now holds the value of `from`.
+    fn flow(&mut self, to: Reg, from: Reg) {
+        if let Some(ty) = self.refs.get(&from).cloned() {
+            self.refs.insert(to, ty);
+        }
+        if self.local.contains(&from) {
+            self.local.insert(to);
+        }
+    }
+
+    /// `addr` points into the memory `base` points to.
+    fn inherit(&mut self, addr: Reg, base: Reg) {
+        if let Some(reference) = self.through.get(&base).copied() {
+            self.through.insert(addr, reference);
+        }
+        if self.local.contains(&base) {
+            self.local.insert(addr);
+        }
+        self.within.insert(addr, base);
+    }
+
+    /// Marks the memory at `addr`, and what it is part of, as holding a
+    /// reference into the frame.
+    fn taint(&mut self, addr: Reg) {
+        let mut addr = Some(addr);
+        while let Some(reg) = addr {
+            self.local.insert(reg);
+            addr = self.within.get(&reg).copied();
+        }
+    }
+
+    /// Puts `value`, a `ty` kept in a register, in a slot of its own.
+    fn slot(&mut self, value: Reg, ty: &Ty) -> Reg {
+        let des = self.get_reg();
+        self.push_to_block(Alloca { des, size: 8 });
+        self.store(des, 0, value, ty);
+        des
+    }
+
+    /// Binds `name` to `reg`, through a slot when its address is taken.
+    fn bind_var(&mut self, name: String, reg: Reg) {
+        if !self.addressed.contains(&name) || self.memory.contains_key(&reg) {
+            self.vars.insert(name, reg);
+            return;
+        }
+        let ty = self.refs.get(&reg).cloned().unwrap_or(Ty::Scalar(8));
+        let slot = self.slot(reg, &ty);
+        self.spilled.insert(slot, ty);
+        self.vars.insert(name, slot);
+    }
+
+    /// The struct, array or enum `reg` refers to when it is a reference to
+    /// one, `reg` itself otherwise.
+    fn auto_deref(&mut self, reg: Reg) -> Reg {
+        let Some(Ty::Ref(ty, _)) = self.refs.get(&reg).cloned() else {
+            return reg;
+        };
+        if ty.is_scalar() {
+            return reg;
+        }
+        let des = self.get_reg();
+        self.copy(des, reg);
+        self.memory.insert(des, *ty);
+        self.through.insert(des, reg);
+        des
+    }
+
+    /// The reference `expr` evaluates to, the type it refers to and whether
+    /// it may be written through.
+    fn reference(&mut self, expr: &Expr) -> Option<(Reg, Ty, bool)> {
+        let reg = self.visit_expr(expr);
+        match self.refs.get(&reg).cloned() {
+            Some(Ty::Ref(ty, mutable)) => Some((reg, *ty, mutable)),
+            _ => {
+                let error = format!("can not dereference '{expr}', it is not a reference");
+                self.errors.push(error);
+                None
+            }
+        }
+    }
+
+    /// Reports `what` writing to memory reached through a `&T`.
+    fn check_writable(&mut self, addr: Reg, what: impl FnOnce() -> String) {
+        let Some(reference) = self.through.get(&addr) else {
+            return;
+        };
+        if let Some(ty @ Ty::Ref(_, false)) = self.refs.get(reference) {
+            let error = format!("{}, it is behind a '{ty}'", what());
+            self.errors.push(error);
+        }
+    }
+
+    /// The address of what `expr` names and the type there. A value that is
+    /// not kept in memory is put in a slot of its own.
+    fn place(&mut self, expr: &Expr) -> (Reg, Ty) {
+        match expr {
+            Expr::Var(ExprVar { name, .. }) => {
+                let reg = self.vars.get(&name.value).copied();
+                if let Some(ty) = reg.and_then(|reg| self.spilled.get(&reg)).cloned() {
+                    return (reg.unwrap(), ty);
+                }
+            }
+            Expr::Field(ExprField { expr, name, .. }) => {
+                let base = self.visit_expr(expr);
+                let base = self.auto_deref(base);
+                let Some(field) = self.field(base, name) else {
+                    return (self.load_imm(0u64.into()), Ty::Scalar(8));
+                };
+                let offset = self.load_imm((field.offset as u64).into());
+                let addr = self.add(base, offset);
+                self.inherit(addr, base);
+                return (addr, field.ty);
+            }
+            Expr::Index(ExprIndex { expr, index, .. }) => {
+                let base = self.visit_expr(expr);
+                let base = self.auto_deref(base);
+                let index = self.visit_expr(index);
+                return match self.element(base, index) {
+                    Some(place) => place,
+                    None => (self.load_imm(0u64.into()), Ty::Scalar(8)),
+                };
+            }
+            Expr::Deref(ExprDeref { expr, .. }) => {
+                let Some((reference, ty, _)) = self.reference(expr) else {
+                    return (self.load_imm(0u64.into()), Ty::Scalar(8));
+                };
+                let addr = self.get_reg();
+                self.copy(addr, reference);
+                self.through.insert(addr, reference);
+                return (addr, ty);
+            }
+            _ => {}
+        }
+        let value = self.visit_expr(expr);
+        if let Some(ty) = self.memory.get(&value).cloned() {
+            return (value, ty);
+        }
+        let ty = self.refs.get(&value).cloned().unwrap_or(Ty::Scalar(8));
+        (self.slot(value, &ty), ty)
+    }
+
+    /// The address of element `index` of the array `base` points to and the
+    /// type of the element. An `index` out of bounds stops the program.
+    fn element(&mut self, base: Reg, index: Reg) -> Option<(Reg, Ty)> {
+        let Some(Ty::Array(elem, len)) = self.memory.get(&base).cloned() else {
+            self.errors
+                .push("can not index a value that is not an array".into());
+            return None;
+        };
+        self.expect(index, &Ty::Scalar(8), || "an index".into());
+        self.bounds_check(index, len);
+        let size = self.load_imm((self.layouts.size_of(&elem) as u64).into());
+        let offset = self.mul(index, size);
+        let addr = self.add(base, offset);
+        self.inherit(addr, base);
+        Some((addr, *elem))
+    }
+
+    /// Calls `INDEX_OUT_OF_BOUNDS` unless `-1 < index < len`, there is only a
+    /// signed `grt` to check with.
+    fn bounds_check(&mut self, index: Reg, len: u32) {
+        let minus_one = self.load_imm(u64::MAX.into());
+        let len = self.load_imm((len as u64).into());
+        let fail = self.gen_label();
+        let ok = self.gen_label();
+        let above = self.grt(index, minus_one);
+        self.conditional(fail.clone(), above);
+        let below = self.grt(len, index);
+        self.conditional(fail.clone(), below);
+        self.jump(ok.clone());
+        self.def_label(fail);
+        let ret = self.get_reg();
+        self.call(INDEX_OUT_OF_BOUNDS.into(), vec![index, len], ret);
+        self.def_label(ok);
+    }
+
+    /// The field `name` of the struct `base` points to.
+    fn field(&mut self, base: Reg, name: &Ident) -> Option<layout::Field> {
+        let Some(Ty::Struct(struct_name)) = self.memory.get(&base) else {
+            self.errors
+                .push(format!("no field '{name}' on a value that is not a struct"));
+            return None;
+        };
+        let field = self
+            .layouts
+            .get(struct_name)
+            .and_then(|layout| layout.field(&name.value))
+            .cloned();
+        if field.is_none() {
+            let error = format!("struct '{struct_name}' has no field '{name}'");
+            self.errors.push(error);
+        }
+        field
+    }
+
+    /// The variant `path` holding `args`.
+    fn variant(&mut self, path: &ExprPath, args: &[Expr]) -> Reg {
+        let ExprPath { ty, variant, .. } = path;
+        let Some(layout) = self.layouts.get_enum(&ty.value).cloned() else {
+            self.errors.push(format!("unknown enum '{ty}'"));
+            return self.load_imm(0u64.into());
+        };
+        let Some((tag, variant)) = layout.variant(&variant.value) else {
+            self.errors
+                .push(format!("enum '{ty}' has no variant '{variant}'"));
+            return self.load_imm(0u64.into());
+        };
+        if variant.fields.len() != args.len() {
+            self.errors.push(format!(
+                "'{path:#}' takes {} fields but is given {}",
+                variant.fields.len(),
+                args.len()
+            ));
+        }
+        let des = self.alloca(&Ty::Enum(ty.value.clone()));
+        let tag = self.load_imm((tag as u64).into());
+        self.push_to_block(Store {
+            addr: des,
+            offset: 0,
+            src: tag,
+            size: TAG_SIZE,
+        });
+        for (i, (field, arg)) in variant.fields.iter().zip(args).enumerate() {
+            let value = self.visit_as(arg, Some(&field.ty));
+            self.expect(value, &field.ty, || format!("field {i} of '{path:#}'"));
+            self.store(des, field.offset, value, &field.ty);
+        }
+        des
+    }
+
+    /// What the value matched on is compared with for `pat`, nothing when
+    /// `pat` matches anything. `on` is the enum matched on, if it is one.
+    fn pat_key(&mut self, pat: &Pat, on: Option<&(String, EnumLayout)>) -> Option<u64> {
+        let error = match (pat, on) {
+            (Pat::Wild(..) | Pat::Bind(..), _) => return None,
+            (Pat::Lit(lit), Some((name, _))) => format!("'{lit}' can not match a '{name}'"),
+            (Pat::Lit(lit), None) => return Some(lit_value(lit)),
+            (Pat::Variant(PatVariant { path, .. }), None) => {
+                format!("'{path:#}' can not match a value that is not an enum")
+            }
+            (Pat::Variant(PatVariant { path, fields }), Some((name, layout))) => {
+                let found = (path.ty.value == *name)
+                    .then(|| layout.variant(&path.variant.value))
+                    .flatten();
+                match found {
+                    Some((tag, variant)) if variant.fields.len() == fields.len() => {
+                        return Some(tag as u64)
+                    }
+                    Some((_, variant)) => format!(
+                        "'{path:#}' has {} fields, not {}",
+                        variant.fields.len(),
+                        fields.len()
+                    ),
+                    None => format!("'{path:#}' is not a variant of '{name}'"),
+                }
+            }
+        };
+        self.errors.push(error);
+        Some(0)
+    }
+
+    /// Reports the values no arm of a match without a catch-all matches.
+    fn exhaustive(
+        &mut self,
+        arms: &[&Pat],
+        keys: &[Option<u64>],
+        on: Option<&(String, EnumLayout)>,
+    ) {
+        if keys.iter().any(Option::is_none) {
+            return;
+        }
+        let error = match on {
+            Some((name, layout)) => {
+                let missing = (layout.variants.iter().enumerate())
+                    .filter(|(tag, _)| !keys.contains(&Some(*tag as u64)))
+                    .map(|(_, (variant, _))| format!("'{name}::{variant}'"))
+                    .collect::<Vec<String>>();
+                if missing.is_empty() {
+                    return;
+                }
+                format!("missing {} in match on '{name}'", missing.join(", "))
+            }
+            None => {
+                let bools = arms
+                    .iter()
+                    .filter_map(|pat| match pat {
+                        Pat::Lit(Lit::Bool(lit_bool)) => lit_bool.parse::<bool>().ok(),
+                        _ => None,
+                    })
+                    .collect::<Vec<bool>>();
+                if bools.is_empty() || bools.len() != arms.len() {
+                    "missing '_' in match".to_string()
+                } else if bools.contains(&true) && bools.contains(&false) {
+                    return;
+                } else {
+                    format!("missing '{}' in match on a bool", !bools[0])
+                }
+            }
+        };
+        self.errors.push(error);
+    }
+
+    /// Binds the names in `pat` to `value`, or to the fields of the variant
+    /// it holds.
+    fn bind(&mut self, pat: &Pat, value: Reg, on: Option<&(String, EnumLayout)>) {
+        match pat {
+            Pat::Bind(name) => {
+                let des = match self.memory.get(&value).cloned() {
+                    Some(ty) => {
+                        let des = self.alloca(&ty);
+                        self.copy_memory((des, 0), (value, 0), &ty);
+                        des
+                    }
+                    None => {
+                        let des = self.get_reg();
+                        self.flow(des, value);
+                        self.copy(des, value)
+                    }
+                };
+                self.bind_var(name.value(), des);
+            }
+            Pat::Variant(PatVariant { path, fields }) => {
+                let Some((_, variant)) = on.and_then(|(_, l)| l.variant(&path.variant.value))
+                else {
+                    return;
+                };
+                for (name, field) in fields.iter().zip(variant.fields.clone()) {
+                    if name.value == "_" {
+                        continue;
+                    }
+                    let des = match field.ty {
+                        ty if ty.is_scalar() => self.load(value, field.offset, &ty),
+                        ty => {
+                            let des = self.alloca(&ty);
+                            self.copy_memory((des, 0), (value, field.offset), &ty);
+                            des
+                        }
+                    };
+                    self.bind_var(name.value(), des);
+                }
+            }
+            Pat::Wild(..) | Pat::Lit(..) => {}
+        }
+    }
+
     fn reset_regester_count(&mut self) {
         self.reg_counter = 0;
     }
 
+    /// The last instruction was a `Jump`, `Conditional` or `Return`, anything
+    /// emitted now is unreachable until the next label.
+    fn is_terminated(&self) -> bool {
+        self.block.last().is_some_and(Instruction::is_terminator)
+    }
+
     fn gen_label(&mut self) -> Label {
         let number = self.gen_label_number;
         self.gen_label_number += 1;
@@ -193,14 +1153,43 @@ impl Ir for IrGenerator {
         des
     }
 
+    /// A slot holding the address of the bytes of `value` and their number.
+    fn load_str(&mut self, value: String) -> Reg {
+        let des = self.alloca(&Ty::Str);
+        let len = self.load_imm((value.len() as u64).into());
+        let bytes = self.get_reg();
+        self.push_to_block(LoadStr { des: bytes, value });
+        self.store(des, 0, bytes, &Ty::Scalar(8));
+        self.store(des, 8, len, &Ty::Scalar(8));
+        des
+    }
+
+    /// The ir only has `grt`, the other comparisons are built from it.
     fn binary(&mut self, op: &Op, lhs: Reg, rhs: Reg) -> Reg {
+        match op {
+            Op::Grt(_) => return self.grt(lhs, rhs),
+            Op::Les(_) => return self.grt(rhs, lhs),
+            Op::Geq(_) => {
+                let les = self.grt(rhs, lhs);
+                return self.not(les);
+            }
+            Op::Leq(_) => {
+                let grt = self.grt(lhs, rhs);
+                return self.not(grt);
+            }
+            Op::Neq(_) => return self.neq(lhs, rhs),
+            Op::EqualEqual(_) => {
+                let neq = self.neq(lhs, rhs);
+                return self.not(neq);
+            }
+            _ => {}
+        }
         let des = self.get_reg();
         let instruction: Instruction = match op {
             Op::Add(_) => Add { des, lhs, rhs }.into(),
             Op::Sub(_) => Sub { des, lhs, rhs }.into(),
             Op::Mul(_) => Mul { des, lhs, rhs }.into(),
             Op::Div(_) => Div { des, lhs, rhs }.into(),
-            Op::Grt(_) => Grt { des, lhs, rhs }.into(),
             _ => unimplemented!("{op:?}"),
         };
         self.push_to_block(instruction);
@@ -213,12 +1202,37 @@ impl Ir for IrGenerator {
         reg
     }
 
+    fn copy(&mut self, to: Reg, from: Reg) -> Reg {
+        let instruction: Instruction = Copy { to, from }.into();
+        self.push_to_block(instruction);
+        to
+    }
+
     fn call(&mut self, caller: Label, args: Vec<Reg>, ret: Reg) -> Reg {
         let instruction: Instruction = Call { caller, args, ret }.into();
         self.push_to_block(instruction);
         ret
     }
+    /// A struct is copied to where the caller asked for it.
     fn early_return(&mut self, reg: Reg) -> Reg {
+        if self.local.contains(&reg) {
+            let error = format!(
+                "'{}' can not return a reference to one of its locals",
+                self.func
+            );
+            self.errors.push(error);
+        }
+        if let Some(ty) = self.ret.clone() {
+            let func = self.func.clone();
+            self.expect(reg, &ty, || format!("the value returned by '{func}'"));
+        }
+        let reg = match (self.sret, self.ret.clone()) {
+            (Some(sret), Some(ty)) => {
+                self.copy_memory((sret, 0), (reg, 0), &ty);
+                sret
+            }
+            _ => reg,
+        };
         let instruction: Instruction = Return(reg).into();
         self.push_to_block(instruction);
         reg
@@ -228,27 +1242,84 @@ impl Ir for IrGenerator {
 impl AstVisitor for IrGenerator {
     fn visit_expr_var(&mut self, expr_var: &ExprVar) -> Reg {
         let ExprVar { name, .. } = expr_var;
-        *self.vars.get(&name.value()).unwrap()
+        let Some(reg) = self.var(name) else {
+            return self.load_imm(0u64.into());
+        };
+        let Some(ty) = self.spilled.get(&reg).cloned() else {
+            return reg;
+        };
+        let des = self.load(reg, 0, &ty);
+        if self.local.contains(&reg) {
+            self.local.insert(des);
+        }
+        des
     }
 
     fn visit_params(&mut self, params: &Param) -> Reg {
-        let Param { name, .. } = params;
+        let Param { name, kind, .. } = params;
         let des = self.get_reg();
+        match self.ty_of(kind) {
+            ty @ Ty::Ref(..) => {
+                self.refs.insert(des, ty);
+            }
+            ty if !ty.is_scalar() => {
+                self.memory.insert(des, ty);
+            }
+            _ => {}
+        }
         self.vars.insert(name.value(), des);
         des
     }
 
     fn visit_expr_call(&mut self, expr_call: &ExprCall) -> Reg {
         let ExprCall { caller, args, .. } = expr_call;
-        let Expr::Var(ExprVar { name, .. }) = &**caller else {
-            panic!("expected Ident");
+        let name = match &**caller {
+            Expr::Var(ExprVar { name, .. }) => name,
+            Expr::Path(path) => return self.variant(path, args),
+            _ => {
+                self.errors
+                    .push("only functions and enum variants can be called".into());
+                return self.load_imm(0u64.into());
+            }
         };
-        // FIXME: this reg needs to be stored with var in discriper?
-        let ret = Reg(1000);
-        let args = args
+        let signature = self.funcs.get(&name.value).cloned();
+        let params = signature.as_ref().map_or(vec![], |s| s.params.clone());
+        if signature.is_some() && params.len() != args.len() {
+            self.errors.push(format!(
+                "'{name}' takes {} arguments but is given {}",
+                params.len(),
+                args.len()
+            ));
+        }
+        let mut args = args
             .iter()
-            .map(|expr| self.visit_expr(expr))
+            .enumerate()
+            .map(|(i, expr)| self.visit_owned(expr, params.get(i)))
             .collect::<Vec<Reg>>();
+        // A reference returned may be one of those given to a local.
+        let local = args.iter().any(|arg| self.local.contains(arg));
+        if let Some(Signature { params, ret }) = signature.clone() {
+            for (i, (arg, ty)) in args.iter().zip(params.iter()).enumerate() {
+                self.expect(*arg, ty, || format!("argument {} of '{name}'", i + 1));
+            }
+            if let Some(ret) = ret.filter(|ret| !ret.is_scalar()) {
+                let sret = self.alloca(&ret);
+                if local && self.layouts.holds_ref(&ret) {
+                    self.taint(sret);
+                }
+                args.insert(0, sret);
+                let ret = self.get_reg();
+                self.call(name.into(), args, ret);
+                return sret;
+            }
+        }
+        let ret = self.get_reg();
+        if let Some(ty @ Ty::Ref(..)) = signature.and_then(|s| s.ret) {
+            self.refs.insert(ret, ty);
+            if local {
+                self.local.insert(ret);
+            }
+        }
         self.call(name.into(), args, ret)
     }
 
@@ -258,28 +1329,75 @@ impl AstVisitor for IrGenerator {
         } = bin;
         let lhs = self.visit_expr(left);
         let rhs = self.visit_expr(right);
+        for reg in [lhs, rhs] {
+            if let Some(ty) = self.value_ty(reg) {
+                let error = format!("'{op}' can not be used on a '{ty}'");
+                self.errors.push(error);
+            }
+        }
         self.binary(op, lhs, rhs)
     }
 
     fn visit_item_fn(&mut self, item_fn: &ItemFn) {
         let ItemFn {
+            attrs,
             name,
-            params,
             block,
-            ret_type: _,
+            ret_type,
             ..
         } = item_fn;
+        let is_main = name.value == "main";
+        if is_main {
+            self.errors.extend(main_errors(item_fn));
+        }
 
         self.gen_label_number = 0;
         self.reset_regester_count();
-        let params = params
-            .iter()
-            .map(|p| (self.visit_params(p), Type::I64))
-            .collect();
+        self.vars.clear();
+        self.memory.clear();
+        self.refs.clear();
+        self.spilled.clear();
+        self.local.clear();
+        self.through.clear();
+        self.within.clear();
+        self.addressed.clear();
+        for stmt in block.stmts.iter() {
+            addressed(&stmt.stmt, &mut self.addressed);
+        }
+        self.func = name.value();
+        self.ret = ret_type.as_ref().map(|ty| self.ty_of(ty));
+        self.sret = match self.ret {
+            Some(ref ty) if !ty.is_scalar() => Some(self.get_reg()),
+            _ => None,
+        };
+        let mut params = self
+            .sret
+            .into_iter()
+            .map(|reg| (reg, Type::I64))
+            .collect::<Vec<(Reg, Type)>>();
+        for param in item_fn.params.iter() {
+            let reg = self.visit_params(param);
+            let ty = self.memory.get(&reg).cloned();
+            params.push((reg, self.ir_type(ty.as_ref())));
+        }
+        let ret = self.ir_type(self.ret.clone().as_ref());
 
         self.push_to_block(Enter);
+        for param in item_fn.params.iter() {
+            let reg = self.vars[&param.name.value];
+            self.bind_var(param.name.value(), reg);
+        }
         self.visit_expr_block(block);
-        self.def_label(".exit".into());
+        // A `main` returning nothing exits with 0 when it runs off the end.
+        let returned = matches!(
+            self.block.last(),
+            Some(Instruction::Return(..) | Instruction::TailCall(..))
+        );
+        if is_main && ret_type.is_none() && !returned {
+            let zero = self.load_imm(0u64.into());
+            self.early_return(zero);
+        }
+        self.def_label(cfg::EXIT_LABEL.into());
         self.push_to_block(Leave);
 
         let body = self.block.clone();
@@ -287,8 +1405,9 @@ impl AstVisitor for IrGenerator {
         self.push_fn(DefFunc {
             name: name.value(),
             params,
-            ret: Type::I64,
+            ret,
             body,
+            inline: inline_hint(attrs),
         });
     }
 
@@ -303,26 +1422,416 @@ impl AstVisitor for IrGenerator {
         self.load_imm(imm)
     }
 
+    fn visit_lit_str(&mut self, lit_str: &LitStr) -> Reg {
+        self.load_str(lit_str.value.clone())
+    }
+
+    fn visit_lit_char(&mut self, lit_char: &LitChar) -> Reg {
+        let c = lit_char.value.chars().next().unwrap_or_default();
+        self.load_imm((c as u64).into())
+    }
+
+    /// A cast to `char` keeps the low 8 bits and one to `u32` the low 32,
+    /// read back from a slot. `u64` and `i64` keep every bit.
+    fn visit_expr_cast(&mut self, expr_cast: &ExprCast) -> Reg {
+        let ExprCast { expr, ty, .. } = expr_cast;
+        if !ty.name().is_some_and(|name| CAST_TYPES.contains(&name)) {
+            self.errors.push(format!("can not cast to '{ty:#}'"));
+        }
+        let reg = self.visit_expr(expr);
+        if let Some(found) = self.value_ty(reg) {
+            let error = format!("can not cast a '{found}' to '{ty:#}'");
+            self.errors.push(error);
+        }
+        let size = match ty.name() {
+            Some("char") => 1,
+            Some("u32") => 4,
+            _ => return reg,
+        };
+        let slot = self.slot(reg, &Ty::Scalar(8));
+        let des = self.get_reg();
+        self.push_to_block(Load {
+            des,
+            addr: slot,
+            offset: 0,
+            size,
+        });
+        des
+    }
+
     fn visit_expr_if(&mut self, expr_if: &ExprIf) -> Reg {
         let ExprIf {
             if_token: _,
             cond,
             then_branch,
-            else_branch: _,
+            else_branch,
         } = expr_if;
-        // pub struct ExprIf {
-        //     pub if_token: super::keyword::If,
-        //     pub cond: Box<Expr>,
-        //     pub then_branch: ExprBlock,
-        //     pub else_branch: Option<(super::keyword::Else, Box<Expr>)>,
-        // }
         let cond_reg = self.visit_expr(cond);
-        let label = self.gen_label();
-        let des = self.conditional(label.clone(), cond_reg);
-        self.visit_expr_block(then_branch);
-        self.def_label(label);
+        let else_label = self.gen_label();
+        let des = self.conditional(else_label.clone(), cond_reg);
+        let then_reg = self.visit_expr_block(then_branch);
+        let Some((_, else_branch)) = else_branch else {
+            self.def_label(else_label);
+            return des;
+        };
+        // Both branches copy their value into the same register, ssa::construct
+        // turns this into a phi.
+        let des = self.get_reg();
+        if let Some(ty) = self.memory.get(&then_reg).cloned() {
+            self.memory.insert(des, ty);
+        }
+        let end_label = self.gen_label();
+        if !self.is_terminated() {
+            self.flow(des, then_reg);
+            self.copy(des, then_reg);
+            self.jump(end_label.clone());
+        }
+        self.def_label(else_label);
+        let else_reg = self.visit_expr(else_branch);
+        if !self.is_terminated() {
+            self.flow(des, else_reg);
+            self.copy(des, else_reg);
+        }
+        self.def_label(end_label);
+        des
+    }
+
+    fn visit_expr_while(&mut self, expr_while: &ExprWhile) -> Reg {
+        let ExprWhile { cond, body, .. } = expr_while;
+        let header = self.gen_label();
+        let end = self.gen_label();
+        self.def_label(header.clone());
+        let cond_reg = self.visit_expr(cond);
+        self.conditional(end.clone(), cond_reg);
+        self.visit_expr_block(body);
+        if !self.is_terminated() {
+            self.jump(header);
+        }
+        self.def_label(end);
+        cond_reg
+    }
+
+    fn visit_expr_let(&mut self, expr_let: &ExprLet) -> Reg {
+        let ExprLet { name, value, .. } = expr_let;
+        let value = self.visit_owned(value, None);
+        if self.memory.contains_key(&value) {
+            self.vars.insert(name.value(), value);
+            return value;
+        }
+        let des = self.get_reg();
+        self.flow(des, value);
+        self.copy(des, value);
+        self.bind_var(name.value(), des);
         des
     }
+
+    fn visit_expr_assign(&mut self, expr_assign: &ExprAssign) -> Reg {
+        let ExprAssign { target, value } = expr_assign;
+        match &**target {
+            Expr::Var(ExprVar { name, .. }) => {
+                let Some(des) = self.var(name) else {
+                    return self.visit_expr(value);
+                };
+                if let Some(ty) = self.spilled.get(&des).cloned() {
+                    let value = self.visit_as(value, Some(&ty));
+                    self.expect(value, &ty, || format!("'{name}'"));
+                    self.store(des, 0, value, &ty);
+                    return value;
+                }
+                let ty = self.value_ty(des).unwrap_or(Ty::Scalar(8));
+                let value = self.visit_as(value, Some(&ty));
+                self.expect(value, &ty, || format!("'{name}'"));
+                if ty.is_scalar() {
+                    self.flow(des, value);
+                    return self.copy(des, value);
+                }
+                self.copy_memory((des, 0), (value, 0), &ty);
+                des
+            }
+            Expr::Field(ExprField { expr, name, .. }) => {
+                let base = self.visit_expr(expr);
+                let base = self.auto_deref(base);
+                let Some(field) = self.field(base, name) else {
+                    return self.visit_expr(value);
+                };
+                self.check_writable(base, || format!("can not assign to '{target}'"));
+                let value = self.visit_as(value, Some(&field.ty));
+                self.expect(value, &field.ty, || format!("field '{name}'"));
+                self.store(base, field.offset, value, &field.ty);
+                value
+            }
+            Expr::Index(ExprIndex { expr, index, .. }) => {
+                let base = self.visit_expr(expr);
+                let base = self.auto_deref(base);
+                let index = self.visit_expr(index);
+                let Some((addr, elem)) = self.element(base, index) else {
+                    return self.visit_expr(value);
+                };
+                self.check_writable(addr, || format!("can not assign to '{target}'"));
+                let value = self.visit_as(value, Some(&elem));
+                self.expect(value, &elem, || "an element".into());
+                self.store(addr, 0, value, &elem);
+                value
+            }
+            Expr::Deref(ExprDeref { expr, .. }) => {
+                let Some((reference, ty, mutable)) = self.reference(expr) else {
+                    return self.visit_expr(value);
+                };
+                if !mutable {
+                    let behind = Ty::Ref(Box::new(ty.clone()), false);
+                    let error = format!("can not assign to '{target}', it is behind a '{behind}'");
+                    self.errors.push(error);
+                }
+                let value = self.visit_as(value, Some(&ty));
+                self.expect(value, &ty, || format!("'{target}'"));
+                self.store(reference, 0, value, &ty);
+                value
+            }
+            target => {
+                self.errors.push(format!("can not assign to '{target}'"));
+                self.visit_expr(value)
+            }
+        }
+    }
+
+    fn visit_expr_struct(&mut self, expr_struct: &ExprStruct) -> Reg {
+        let ExprStruct { name, fields, .. } = expr_struct;
+        let Some(layout) = self.layouts.get(&name.value).cloned() else {
+            self.errors.push(format!("unknown struct '{name}'"));
+            return self.load_imm(0u64.into());
+        };
+        let des = self.alloca(&Ty::Struct(name.value.clone()));
+        let mut given: Vec<&str> = vec![];
+        for (field_name, value) in fields.iter() {
+            let field = layout.field(&field_name.value);
+            let value = self.visit_as(value, field.map(|field| &field.ty));
+            let Some(field) = field else {
+                let error = format!("struct '{name}' has no field '{field_name}'");
+                self.errors.push(error);
+                continue;
+            };
+            if given.contains(&field.name.as_str()) {
+                let error = format!("field '{field_name}' of '{name}' is given more than once");
+                self.errors.push(error);
+                continue;
+            }
+            given.push(&field.name);
+            self.expect(value, &field.ty, || {
+                format!("field '{field_name}' of '{name}'")
+            });
+            self.store(des, field.offset, value, &field.ty);
+        }
+        let missing = layout
+            .fields
+            .iter()
+            .filter(|field| !given.contains(&field.name.as_str()))
+            .map(|field| format!("'{}'", field.name))
+            .collect::<Vec<String>>();
+        if !missing.is_empty() {
+            let error = format!("missing {} in '{name}'", missing.join(", "));
+            self.errors.push(error);
+        }
+        des
+    }
+
+    fn visit_expr_field(&mut self, expr_field: &ExprField) -> Reg {
+        let ExprField { expr, name, .. } = expr_field;
+        let base = self.visit_expr(expr);
+        let base = self.auto_deref(base);
+        let Some(field) = self.field(base, name) else {
+            return self.load_imm(0u64.into());
+        };
+        match field.ty {
+            ty if ty.is_scalar() => self.load(base, field.offset, &ty),
+            // A struct or array inside another is addressed, not loaded.
+            ty => {
+                let offset = self.load_imm((field.offset as u64).into());
+                let des = self.get_reg();
+                self.push_to_block(Add {
+                    des,
+                    lhs: base,
+                    rhs: offset,
+                });
+                self.memory.insert(des, ty);
+                self.inherit(des, base);
+                des
+            }
+        }
+    }
+
+    fn visit_expr_array(&mut self, expr_array: &ExprArray) -> Reg {
+        self.array(expr_array, None)
+    }
+
+    fn visit_expr_repeat(&mut self, expr_repeat: &ExprRepeat) -> Reg {
+        self.repeat(expr_repeat, None)
+    }
+
+    fn visit_expr_index(&mut self, expr_index: &ExprIndex) -> Reg {
+        let ExprIndex { expr, index, .. } = expr_index;
+        let base = self.visit_expr(expr);
+        let base = self.auto_deref(base);
+        let index = self.visit_expr(index);
+        let Some((addr, elem)) = self.element(base, index) else {
+            return self.load_imm(0u64.into());
+        };
+        if !elem.is_scalar() {
+            self.memory.insert(addr, elem);
+            return addr;
+        }
+        self.load(addr, 0, &elem)
+    }
+
+    fn visit_expr_path(&mut self, expr_path: &ExprPath) -> Reg {
+        self.variant(expr_path, &[])
+    }
+
+    /// Lowered to a chain of comparisons with the tag of an enum or the
+    /// value of a scalar, each jumping to the body of its arm. The value of
+    /// every arm is copied into the same register, like the branches of an
+    /// `if`.
+    fn visit_expr_match(&mut self, expr_match: &ExprMatch) -> Reg {
+        let ExprMatch { expr, arms, .. } = expr_match;
+        let value = self.visit_expr(expr);
+        let value = self.auto_deref(value);
+        let (disc, on) = match self.value_ty(value) {
+            None => (value, None),
+            Some(Ty::Enum(name)) => {
+                let tag = self.get_reg();
+                self.push_to_block(Load {
+                    des: tag,
+                    addr: value,
+                    offset: 0,
+                    size: TAG_SIZE,
+                });
+                let layout = self.layouts.get_enum(&name).cloned().unwrap_or_default();
+                (tag, Some((name, layout)))
+            }
+            Some(ty) => {
+                self.errors.push(format!("can not match on a '{ty}'"));
+                return self.load_imm(0u64.into());
+            }
+        };
+        let keys = arms
+            .iter()
+            .map(|arm| self.pat_key(&arm.pat, on.as_ref()))
+            .collect::<Vec<Option<u64>>>();
+        let pats = arms.iter().map(|arm| &arm.pat).collect::<Vec<&Pat>>();
+        self.exhaustive(&pats, &keys, on.as_ref());
+        if arms.is_empty() {
+            return self.load_imm(0u64.into());
+        }
+        let bodies = arms
+            .iter()
+            .map(|_| self.gen_label())
+            .collect::<Vec<Label>>();
+        let end = self.gen_label();
+        // Without a catch-all the last arm is the only one left.
+        let last = keys
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(arms.len() - 1);
+        for (key, body) in keys[..last].iter().zip(bodies.iter()) {
+            let key = self.load_imm(key.unwrap_or_default().into());
+            let diff = self.sub(disc, key);
+            self.conditional(body.clone(), diff);
+        }
+        self.jump(bodies[last].clone());
+        let des = self.get_reg();
+        for (arm, body) in arms.iter().zip(bodies) {
+            self.def_label(body);
+            self.push_scope();
+            self.bind(&arm.pat, value, on.as_ref());
+            let reg = self.visit_expr(&arm.body);
+            if let Some(ty) = self.memory.get(&reg).cloned() {
+                self.memory.insert(des, ty);
+            }
+            if !self.is_terminated() {
+                self.flow(des, reg);
+                self.copy(des, reg);
+                self.jump(end.clone());
+            }
+            self.pop_scope();
+        }
+        self.def_label(end);
+        des
+    }
+
+    /// An array literal returned takes its type from the function.
+    fn visit_expr_return(&mut self, expr_ret: &ExprReturn) -> Reg {
+        let ExprReturn { expr, .. } = expr_ret;
+        let ret = self.ret.clone();
+        let reg = self.visit_as(expr, ret.as_ref());
+        self.early_return(reg)
+    }
+
+    /// A reference to something not reached through another reference
+    /// points into the frame.
+    fn visit_expr_ref(&mut self, expr_ref: &ExprRef) -> Reg {
+        let ExprRef { mutable, expr, .. } = expr_ref;
+        let mutable = mutable.is_some();
+        let (addr, ty) = self.place(expr);
+        let des = self.get_reg();
+        self.copy(des, addr);
+        self.refs.insert(des, Ty::Ref(Box::new(ty), mutable));
+        let Some(reference) = self.through.get(&addr).copied() else {
+            self.local.insert(des);
+            return des;
+        };
+        if self.local.contains(&reference) {
+            self.local.insert(des);
+        }
+        if mutable {
+            self.check_writable(addr, || format!("can not borrow '{expr}' as mutable"));
+        }
+        des
+    }
+
+    /// A struct, array or enum is used where it is, anything else is loaded.
+    fn visit_expr_deref(&mut self, expr_deref: &ExprDeref) -> Reg {
+        let ExprDeref { expr, .. } = expr_deref;
+        let Some((reference, ty, _)) = self.reference(expr) else {
+            return self.load_imm(0u64.into());
+        };
+        match ty.is_scalar() {
+            true => self.load(reference, 0, &ty),
+            false => self.auto_deref(reference),
+        }
+    }
+
+    fn declare(&mut self, items: &[Item]) {
+        let (layouts, errors) = Layouts::new(items);
+        self.layouts = layouts;
+        self.errors.extend(errors);
+        // Functions of the program shadow builtins of the same name.
+        for (name, params, ret) in BUILTINS {
+            let signature = Signature {
+                params: params.to_vec(),
+                ret,
+            };
+            self.funcs.insert(name.into(), signature);
+        }
+        for item in items.iter() {
+            let Item::Fn(item_fn) = item else {
+                continue;
+            };
+            let signature = Signature {
+                params: item_fn.params.iter().map(|p| self.ty_of(&p.kind)).collect(),
+                ret: item_fn.ret_type.as_ref().map(|ty| self.ty_of(ty)),
+            };
+            self.funcs.insert(item_fn.name.value(), signature);
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(self.vars.clone());
+    }
+
+    fn pop_scope(&mut self) {
+        if let Some(vars) = self.scopes.pop() {
+            self.vars = vars;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -345,15 +1854,286 @@ mod tests {
             #[test]
             fn $name() {
                 let left = setup($input);
-                let mut right: Vec<Instruction> = vec![];
-                $(
-                    right.push($t);
-                )*
+                let right: Vec<Instruction> = vec![$($t,)*];
                 assert_eq!(left, right);
             }
         };
     }
 
+    #[test]
+    fn main_signature() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen);
+        assert!(code_gen("fn main(argc: u64, argv: u64) -> i64 { return argc; }").is_ok());
+        assert_eq!(
+            code_gen("fn main(a: str) -> char { return 1; }"),
+            Err(vec![
+                "'main' takes no arguments or 'argc' and 'argv', not 1".to_string(),
+                "'main' can not take 'str' as a, expected an integer".to_string(),
+                "'main' can not return 'char', expected an integer".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn struct_errors() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen);
+        let src = "
+            struct P { x: i64, y: i64 }
+            struct Q { p: P }
+            fn f(p: P) -> Q { return Q { p: p }; }
+            fn main() {
+                let p = P { x: 1, z: 2 };
+                let q = P { x: 1, x: 2, y: 3 };
+                let r = R { };
+                let s = f(1);
+                let t = f(p).p.w;
+                let n = 1;
+                n = p;
+                p + 1;
+                return p.x.y;
+            }";
+        assert_eq!(
+            code_gen(src),
+            Err(vec![
+                "struct 'P' has no field 'z'".to_string(),
+                "missing 'y' in 'P'".to_string(),
+                "field 'x' of 'P' is given more than once".to_string(),
+                "unknown struct 'R'".to_string(),
+                "argument 1 of 'f' must be a 'P'".to_string(),
+                "struct 'P' has no field 'w'".to_string(),
+                "'n' can not be a 'P'".to_string(),
+                "'+' can not be used on a 'P'".to_string(),
+                "no field 'y' on a value that is not a struct".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn array_errors() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen);
+        let src = "
+            struct P { x: i64 }
+            fn f(a: [u8; 2]) -> [u8; 2] { return a; }
+            fn main() {
+                let a = [1, 2];
+                let b = [P { x: 1 }, 2];
+                a = [1, 2, 3];
+                let n = 5;
+                n[0];
+                a[P { x: 1 }];
+                let c = [0; 99999999999];
+                let d = [0; 9999999];
+                f([1, 2, 3]);
+                return a as u64;
+            }";
+        assert_eq!(
+            code_gen(src),
+            Err(vec![
+                "element 1 of the array must be a 'P'".to_string(),
+                "'a' must be a '[u64; 2]'".to_string(),
+                "can not index a value that is not an array".to_string(),
+                "an index can not be a 'P'".to_string(),
+                "array length 99999999999 is too big".to_string(),
+                "'[u64; 9999999]' is too big for the stack".to_string(),
+                "argument 1 of 'f' must be a '[u8; 2]'".to_string(),
+                "can not cast a '[u64; 2]' to 'u64'".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn match_errors() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen);
+        let src = r#"
+            enum E { A, B(u64), C(u64, u64) }
+            enum F { X }
+            struct P { x: i64 }
+            fn main() {
+                let e = E::B(1);
+                match e { E::A => 1 };
+                match e { F::X => 1, E::B(a, b) => 2, 3 => 4, _ => 5 };
+                match 1 { E::A => 1 };
+                match 1 { 1 => 1 };
+                match true { true => 1 };
+                match true { true => 1, false => 2 };
+                match (P { x: 1 }) { _ => 1 };
+                let f = E::D;
+                let g = G::A;
+                let h = E::C(1);
+                let i = E::B(P { x: 1 });
+                return e as u64;
+            }"#;
+        assert_eq!(
+            code_gen(src),
+            Err(vec![
+                "missing 'E::B', 'E::C' in match on 'E'".to_string(),
+                "'F::X' is not a variant of 'E'".to_string(),
+                "'E::B' has 1 fields, not 2".to_string(),
+                "'3' can not match a 'E'".to_string(),
+                "'E::A' can not match a value that is not an enum".to_string(),
+                "missing '_' in match".to_string(),
+                "missing '_' in match".to_string(),
+                "missing 'false' in match on a bool".to_string(),
+                "can not match on a 'P'".to_string(),
+                "enum 'E' has no variant 'D'".to_string(),
+                "unknown enum 'G'".to_string(),
+                "'E::C' takes 2 fields but is given 1".to_string(),
+                "field 0 of 'E::B' can not be a 'P'".to_string(),
+                "can not cast a 'E' to 'u64'".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn call_errors() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen);
+        let src = r#"
+            fn f(a: u64) -> u64 { return a; }
+            struct P { x: u64 }
+            fn main() {
+                let p = P { x: 1 };
+                p.x(2);
+                f(1, 2);
+                print_int(1, 2);
+                print_int();
+                print_int("x");
+                println(5);
+                arg("x");
+                env(arg_count());
+                return f();
+            }"#;
+        assert_eq!(
+            code_gen(src),
+            Err(vec![
+                "only functions and enum variants can be called".to_string(),
+                "'f' takes 1 arguments but is given 2".to_string(),
+                "'print_int' takes 1 arguments but is given 2".to_string(),
+                "'print_int' takes 1 arguments but is given 0".to_string(),
+                "argument 1 of 'print_int' can not be a 'str'".to_string(),
+                "argument 1 of 'println' must be a 'str'".to_string(),
+                "argument 1 of 'arg' can not be a 'str'".to_string(),
+                "argument 1 of 'env' must be a 'str'".to_string(),
+                "'f' takes 1 arguments but is given 0".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn check_without_code() {
+        let check = |src: &str| lex(src).and_then(parse).and_then(|items| check(&items));
+        assert_eq!(check("fn main() { return 1; }"), Ok(()));
+        assert_eq!(
+            check("enum E { A, B } fn main() { y = 1; return match E::A { E::A => 1 }; }"),
+            Err(vec![
+                "undefined variable 'y'".to_string(),
+                "missing 'E::B' in match on 'E'".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn exported_aggregates() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen_exported);
+        let src = "
+            struct P { x: u64 }
+            fn scalars(a: u64, p: &P) -> u64 { return a + p.x; }
+            fn take(p: P) -> u64 { return p.x; }
+            fn give() -> [u64; 3] { return [1, 2, 3]; }";
+        let types = code_gen(src)
+            .unwrap()
+            .into_iter()
+            .filter_map(|inst| match inst {
+                Instruction::DefFunc(func) => {
+                    let params = func.params.into_iter().map(|(_, ty)| ty);
+                    Some((params.collect::<Vec<Type>>(), func.ret))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                (vec![Type::I64, Type::I64], Type::I64),
+                (vec![Type::Aggregate(8)], Type::I64),
+                (vec![Type::I64], Type::Aggregate(24)),
+            ]
+        );
+        assert_eq!(
+            code_gen("fn f() -> u64 { print(env(\"HOME\")); return arg_count(); }"),
+            Err(vec![
+                "'arg_count' can not be called in an object file, only an executable is given the arguments and environment".to_string(),
+                "'env' can not be called in an object file, only an executable is given the arguments and environment".to_string(),
+            ])
+        );
+        assert!(code_gen(
+            "fn arg_count() -> u64 { return 0; } fn f() -> u64 { return arg_count(); }"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn ref_errors() {
+        let code_gen = |src: &str| lex(src).and_then(parse).and_then(code_gen);
+        let src = "
+            struct P { x: u64 }
+            fn local() -> &u64 { let x = 1; return &x; }
+            fn copied(p: P) -> &u64 { return &p.x; }
+            fn field(p: &P) -> &u64 { return &p.x; }
+            struct W { r: &u64 }
+            enum E { Some(&u64), None }
+            fn wrapped() -> W { let x = 4; return W { r: &x }; }
+            fn copy() -> W { let x = 4; let w = W { r: &x }; let v = w; return v; }
+            fn assigned(r: &u64) -> W { let x = 4; let w = W { r: r }; w.r = &x; return w; }
+            fn elements() -> [&u64; 1] { let x = 4; return [&x]; }
+            fn nested() -> [W; 1] { let x = 4; let a = [W { r: &x }]; return a; }
+            fn payload() -> E { let x = 4; return E::Some(&x); }
+            fn read() -> &u64 { let x = 4; let w = W { r: &x }; return w.r; }
+            fn passed(r: &u64) -> W { return W { r: r }; }
+            fn id(r: &u64) -> &u64 { return r; }
+            fn wrap(r: &u64) -> W { return W { r: r }; }
+            fn through() -> &u64 { let x = 1; return id(&x); }
+            fn through_struct() -> W { let x = 1; return wrap(&x); }
+            fn forwarded(r: &u64) -> &u64 { return id(r); }
+            fn unrelated() -> P { let x = 1; return make(&x); }
+            fn make(r: &u64) -> P { return P { x: *r }; }
+            fn shared(p: &P, r: &u64) {
+                p.x = 1;
+                let q = &mut p.x;
+                *r = 2;
+                *p = P { x: 3 };
+            }
+            fn main() {
+                let x = 1;
+                let y = *x;
+                let r = &x;
+                let z = r + 1;
+                let c = r as u64;
+            }";
+        assert_eq!(
+            code_gen(src),
+            Err(vec![
+                "'local' can not return a reference to one of its locals".to_string(),
+                "'copied' can not return a reference to one of its locals".to_string(),
+                "'wrapped' can not return a reference to one of its locals".to_string(),
+                "'copy' can not return a reference to one of its locals".to_string(),
+                "'assigned' can not return a reference to one of its locals".to_string(),
+                "'elements' can not return a reference to one of its locals".to_string(),
+                "'nested' can not return a reference to one of its locals".to_string(),
+                "'payload' can not return a reference to one of its locals".to_string(),
+                "'read' can not return a reference to one of its locals".to_string(),
+                "'through' can not return a reference to one of its locals".to_string(),
+                "'through_struct' can not return a reference to one of its locals".to_string(),
+                "can not assign to '(. p x)', it is behind a '&P'".to_string(),
+                "can not borrow '(. p x)' as mutable, it is behind a '&P'".to_string(),
+                "can not assign to '(* r)', it is behind a '&u64'".to_string(),
+                "can not assign to '(* p)', it is behind a '&P'".to_string(),
+                "can not dereference 'x', it is not a reference".to_string(),
+                "'+' can not be used on a '&u64'".to_string(),
+                "can not cast a '&u64' to 'u64'".to_string(),
+            ])
+        );
+    }
+
     test_builder! {
         test_name: test_binary_mul,
         input: "fn main() { 1+2*3; }",
@@ -376,10 +2156,12 @@ mod tests {
                     lhs: Reg(0),
                     rhs: Reg(3),
                 }.into(),
+                LoadImm{des: Reg(5), imm: Imm(0) }.into(),
+                Return(Reg(5)).into(),
                 DefLabel(".exit".into()).into(),
                 Leave.into(),
             ],
-
+            inline: Inline::Auto,
         }.into(),
     }
 
@@ -399,10 +2181,12 @@ mod tests {
                     lhs: Reg(0),
                     rhs: Reg(1),
                 }.into(),
+                LoadImm{des: Reg(3), imm: Imm(0) }.into(),
+                Return(Reg(3)).into(),
                 DefLabel(".exit".into()).into(),
                 Leave.into(),
             ],
-
+            inline: Inline::Auto,
         }.into(),
     }
 
@@ -426,7 +2210,7 @@ mod tests {
                 DefLabel(".exit".into()).into(),
                 Leave.into(),
             ],
-
+            inline: Inline::Auto,
         }.into(),
         DefFunc{
             name: "main".into(),
@@ -439,12 +2223,14 @@ mod tests {
                 Call {
                     caller: Label("add".into()),
                     args: vec![Reg(0), Reg(1)],
-                    ret: Reg(1000),
+                    ret: Reg(2),
                 }.into(),
+                LoadImm { des: Reg(3), imm: Imm(0) }.into(),
+                Return(Reg(3)).into(),
                 DefLabel(".exit".into()).into(),
                 Leave.into(),
             ],
-
+            inline: Inline::Auto,
         }.into(),
     }
 }
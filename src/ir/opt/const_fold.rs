@@ -0,0 +1,234 @@
+//! Constant folding and propagation.
+//!
+//! Operations whose operands all come from a `LoadImm` are done at compile
+//! time, copies are removed by reading from their source instead, and a
+//! `Conditional` on a constant becomes a `Jump` or falls through, after which
+//! the arm that can no longer run is removed.
+use std::collections::HashMap;
+
+use crate::ir::cfg::Cfg;
+use crate::ir::{
+    Add, Conditional, Copy, DefFunc, Div, Grt, Imm, Instruction, Jump, LoadImm, Mul, Phi, Reg, Sub,
+};
+
+pub fn const_fold(func: DefFunc) -> DefFunc {
+    let mut cfg = Cfg::new(&func);
+    while fold(&mut cfg) {}
+    DefFunc {
+        body: cfg.into_body(),
+        ..func
+    }
+}
+
+enum Fold {
+    Keep,
+    Remove,
+    Replace(Instruction),
+}
+
+/// One round of folding, returns true if anything changed.
+fn fold(cfg: &mut Cfg) -> bool {
+    let mut consts: HashMap<Reg, u64> = HashMap::new();
+    let mut copies: HashMap<Reg, Reg> = HashMap::new();
+    for instruction in cfg.blocks.iter().flat_map(|b| b.body.iter()) {
+        match instruction {
+            Instruction::LoadImm(LoadImm { des, imm: Imm(imm) }) => {
+                consts.insert(*des, *imm);
+            }
+            Instruction::Copy(Copy { to, from }) => {
+                copies.insert(*to, *from);
+            }
+            _ => {}
+        }
+    }
+    let resolve = |mut reg: Reg| {
+        while let Some(from) = copies.get(&reg) {
+            reg = *from;
+        }
+        reg
+    };
+
+    let mut changed = false;
+    let mut edges_changed = false;
+    for block in cfg.blocks.iter_mut() {
+        let mut body = Vec::with_capacity(block.body.len());
+        for mut instruction in block.body.drain(..) {
+            for reg in instruction.uses_mut() {
+                let source = resolve(*reg);
+                if source != *reg {
+                    *reg = source;
+                    changed = true;
+                }
+            }
+            match fold_instruction(&instruction, &consts) {
+                Fold::Keep => {
+                    body.push(instruction);
+                    continue;
+                }
+                Fold::Remove => {}
+                Fold::Replace(new) => body.push(new),
+            }
+            changed = true;
+            edges_changed |= matches!(instruction, Instruction::Conditional(..));
+        }
+        block.body = body;
+    }
+    if edges_changed {
+        cfg.rebuild_edges();
+        cfg.remove_unreachable();
+    }
+    changed
+}
+
+fn fold_instruction(instruction: &Instruction, consts: &HashMap<Reg, u64>) -> Fold {
+    let binary = |des: Reg, lhs: &Reg, rhs: &Reg, op: fn(u64, u64) -> Option<u64>| {
+        let (Some(lhs), Some(rhs)) = (consts.get(lhs), consts.get(rhs)) else {
+            return Fold::Keep;
+        };
+        match op(*lhs, *rhs) {
+            Some(imm) => Fold::Replace(LoadImm { des, imm: Imm(imm) }.into()),
+            None => Fold::Keep,
+        }
+    };
+    match instruction {
+        Instruction::Add(Add { des, lhs, rhs }) => {
+            binary(*des, lhs, rhs, |l, r| Some(l.wrapping_add(r)))
+        }
+        Instruction::Sub(Sub { des, lhs, rhs }) => {
+            binary(*des, lhs, rhs, |l, r| Some(l.wrapping_sub(r)))
+        }
+        Instruction::Mul(Mul { des, lhs, rhs }) => {
+            binary(*des, lhs, rhs, |l, r| Some(l.wrapping_mul(r)))
+        }
+        // Division by zero is left for the program to fault on at runtime.
+        Instruction::Div(Div { des, lhs, rhs }) => binary(*des, lhs, rhs, |l, r| {
+            (r != 0).then(|| (l as i64).wrapping_div(r as i64) as u64)
+        }),
+        Instruction::Grt(Grt { des, lhs, rhs }) => binary(*des, lhs, rhs, |l, r| {
+            Some(((l as i64) > (r as i64)) as u64)
+        }),
+        // Every use was just pointed at the source of the copy.
+        Instruction::Copy(..) => Fold::Remove,
+        Instruction::Phi(Phi { des, args }) => {
+            let Some(((first, _), rest)) = args.split_first() else {
+                return Fold::Keep;
+            };
+            if rest.iter().all(|(reg, _)| reg == first) {
+                return Fold::Replace(
+                    Copy {
+                        to: *des,
+                        from: *first,
+                    }
+                    .into(),
+                );
+            }
+            match consts.get(first) {
+                Some(imm) if rest.iter().all(|(reg, _)| consts.get(reg) == Some(imm)) => {
+                    Fold::Replace(
+                        LoadImm {
+                            des: *des,
+                            imm: Imm(*imm),
+                        }
+                        .into(),
+                    )
+                }
+                _ => Fold::Keep,
+            }
+        }
+        Instruction::Conditional(Conditional { label, reg }) => match consts.get(reg) {
+            Some(0) => Fold::Replace(Jump(label.clone()).into()),
+            Some(_) => Fold::Remove,
+            None => Fold::Keep,
+        },
+        _ => Fold::Keep,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::opt::run_pass;
+    use crate::ir::Return;
+    use pretty_assertions::assert_eq;
+
+    /// The constant returned by the only `Return` in `func`.
+    fn returned(func: &DefFunc) -> Option<u64> {
+        let mut returns = func.body.iter().filter_map(|i| match i {
+            Instruction::Return(Return(reg)) => Some(*reg),
+            _ => None,
+        });
+        let reg = returns.next()?;
+        assert_eq!(returns.next(), None, "more than one return");
+        func.body.iter().find_map(|i| match i {
+            Instruction::LoadImm(LoadImm { des, imm: Imm(imm) }) if *des == reg => Some(*imm),
+            _ => None,
+        })
+    }
+
+    fn count(func: &DefFunc, f: fn(&Instruction) -> bool) -> usize {
+        func.body.iter().filter(|i| f(i)).count()
+    }
+
+    #[test]
+    fn binary() {
+        let func = run_pass(const_fold, "fn main() { return 1 + 2 * 3; }");
+        assert_eq!(returned(&func), Some(7));
+        assert_eq!(
+            count(&func, |i| matches!(
+                i,
+                Instruction::Add(..) | Instruction::Mul(..)
+            )),
+            0
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_kept() {
+        let func = run_pass(const_fold, "fn main() { return 1 / 0; }");
+        assert_eq!(returned(&func), None);
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Div(..))), 1);
+    }
+
+    #[test]
+    fn through_copies() {
+        let func = run_pass(
+            const_fold,
+            "fn main() { let a = 4; let b = a; return b * 2; }",
+        );
+        assert_eq!(returned(&func), Some(8));
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Copy(..))), 0);
+    }
+
+    #[test]
+    fn constant_branch() {
+        let func = run_pass(
+            const_fold,
+            "fn main() { if 1 > 2 { return 1; } else { return 2; }; }",
+        );
+        assert_eq!(returned(&func), Some(2));
+        assert_eq!(
+            count(&func, |i| matches!(i, Instruction::Conditional(..))),
+            0
+        );
+    }
+
+    #[test]
+    fn phi_of_same_constant() {
+        let func = run_pass(
+            const_fold,
+            "fn f(x: u64) -> u64 { let a = 1; if x > 0 { a = 1; }; return a; }",
+        );
+        assert_eq!(returned(&func), Some(1));
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Phi(..))), 0);
+    }
+
+    #[test]
+    fn phi_after_dead_arm() {
+        let func = run_pass(
+            const_fold,
+            "fn main() { let a = 1; if 3 > 2 { a = 5; }; return a + 1; }",
+        );
+        assert_eq!(returned(&func), Some(6));
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Phi(..))), 0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Dead code elimination.
+//!
+//! Starting from the instructions that have an effect, every register they
+//! read is marked as needed and so are the registers read to compute those.
+//! Side effect free instructions defining anything else are removed, along
+//! with blocks control can never reach.
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::cfg::Cfg;
+use crate::ir::{DefFunc, Instruction, Reg};
+
+/// Returns the function without dead code and how many instructions were
+/// removed.
+pub fn dce(func: DefFunc) -> (DefFunc, usize) {
+    let mut cfg = Cfg::new(&func);
+    let mut eliminated = cfg.remove_unreachable();
+
+    let mut defs: HashMap<Reg, Vec<Reg>> = HashMap::new();
+    let mut work = Vec::new();
+    for instruction in cfg.blocks.iter().flat_map(|b| b.body.iter()) {
+        match instruction.def() {
+            Some(des) if is_pure(instruction) => {
+                defs.insert(des, instruction.uses());
+            }
+            _ => work.extend(instruction.uses()),
+        }
+    }
+    let mut needed = HashSet::new();
+    while let Some(reg) = work.pop() {
+        if needed.insert(reg) {
+            work.extend(defs.get(&reg).into_iter().flatten());
+        }
+    }
+
+    for block in cfg.blocks.iter_mut() {
+        let before = block.body.len();
+        block.body.retain(|instruction| match instruction.def() {
+            Some(des) => !is_pure(instruction) || needed.contains(&des),
+            None => true,
+        });
+        eliminated += before - block.body.len();
+    }
+    let func = DefFunc {
+        body: cfg.into_body(),
+        ..func
+    };
+    (func, eliminated)
+}
+
+/// Division is left out since dividing by zero traps.
+fn is_pure(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::LoadImm(..)
+            | Instruction::LoadStr(..)
+            | Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Grt(..)
+            | Instruction::Copy(..)
+            | Instruction::Phi(..)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::opt::run_pass;
+    use pretty_assertions::assert_eq;
+
+    fn count(func: &DefFunc, f: fn(&Instruction) -> bool) -> usize {
+        func.body.iter().filter(|i| f(i)).count()
+    }
+
+    #[test]
+    fn unused_expression() {
+        let (func, eliminated) = run_pass(dce, "fn main() { 1 + 2; return 3; }");
+        assert_eq!(eliminated, 3);
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Add(..))), 0);
+        assert_eq!(count(&func, |i| matches!(i, Instruction::LoadImm(..))), 1);
+    }
+
+    #[test]
+    fn unused_variable() {
+        let (func, eliminated) = run_pass(
+            dce,
+            "fn f(x: u64) -> u64 { let a = 1; if x > 0 { a = 2; }; return x; }",
+        );
+        assert_eq!(eliminated, 4);
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Copy(..))), 0);
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Grt(..))), 1);
+    }
+
+    #[test]
+    fn division_is_kept() {
+        let (func, eliminated) = run_pass(dce, "fn main() { 1 / 0; return 0; }");
+        assert_eq!(eliminated, 0);
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Div(..))), 1);
+    }
+}
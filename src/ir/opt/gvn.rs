@@ -0,0 +1,181 @@
+//! Global value numbering.
+//!
+//! Walks the dominator tree keeping a table of the pure computations seen on
+//! the way down. A computation already in the table is removed and its uses
+//! read the register computed earlier, which dominates them. Within a block
+//! this is plain local value numbering, the tree walk extends it to every
+//! block dominated by the one the value was first computed in.
+use std::collections::HashMap;
+
+use crate::ir::cfg::{BlockId, Cfg, Dominators};
+use crate::ir::{Add, DefFunc, Div, Grt, Instruction, Mul, Reg, Sub};
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum Value {
+    Add(Reg, Reg),
+    Sub(Reg, Reg),
+    Mul(Reg, Reg),
+    Div(Reg, Reg),
+    Grt(Reg, Reg),
+}
+
+impl Value {
+    fn new(instruction: &Instruction) -> Option<Self> {
+        // Operands of commutative operations are ordered so `a + b` and
+        // `b + a` get the same number.
+        let sorted = |lhs: &Reg, rhs: &Reg| {
+            if lhs.0 <= rhs.0 {
+                (*lhs, *rhs)
+            } else {
+                (*rhs, *lhs)
+            }
+        };
+        // Constants are left alone, loading them again is cheaper than
+        // keeping them in a register.
+        Some(match instruction {
+            Instruction::Add(Add { lhs, rhs, .. }) => {
+                let (lhs, rhs) = sorted(lhs, rhs);
+                Self::Add(lhs, rhs)
+            }
+            Instruction::Mul(Mul { lhs, rhs, .. }) => {
+                let (lhs, rhs) = sorted(lhs, rhs);
+                Self::Mul(lhs, rhs)
+            }
+            Instruction::Sub(Sub { lhs, rhs, .. }) => Self::Sub(*lhs, *rhs),
+            Instruction::Div(Div { lhs, rhs, .. }) => Self::Div(*lhs, *rhs),
+            Instruction::Grt(Grt { lhs, rhs, .. }) => Self::Grt(*lhs, *rhs),
+            _ => return None,
+        })
+    }
+}
+
+pub fn gvn(func: DefFunc) -> DefFunc {
+    let mut cfg = Cfg::new(&func);
+    let doms = Dominators::new(&cfg);
+    let entry = cfg.entry();
+    let mut numbering = Numbering::default();
+    numbering.walk(&mut cfg, &doms, entry);
+
+    // Phi arguments can be visited before the value they name is replaced.
+    for block in cfg.blocks.iter_mut() {
+        for instruction in block.body.iter_mut() {
+            for reg in instruction.uses_mut() {
+                *reg = numbering.resolve(*reg);
+            }
+        }
+    }
+    DefFunc {
+        body: cfg.into_body(),
+        ..func
+    }
+}
+
+#[derive(Debug, Default)]
+struct Numbering {
+    table: HashMap<Value, Reg>,
+    replaced: HashMap<Reg, Reg>,
+}
+
+impl Numbering {
+    fn resolve(&self, reg: Reg) -> Reg {
+        self.replaced.get(&reg).copied().unwrap_or(reg)
+    }
+
+    fn walk(&mut self, cfg: &mut Cfg, doms: &Dominators, id: BlockId) {
+        let mut added = vec![];
+        let body = std::mem::take(&mut cfg.block_mut(id).body);
+        let mut kept = Vec::with_capacity(body.len());
+        for mut instruction in body {
+            for reg in instruction.uses_mut() {
+                *reg = self.resolve(*reg);
+            }
+            let (Some(value), Some(des)) = (Value::new(&instruction), instruction.def()) else {
+                kept.push(instruction);
+                continue;
+            };
+            match self.table.get(&value) {
+                Some(existing) => {
+                    self.replaced.insert(des, *existing);
+                }
+                None => {
+                    self.table.insert(value, des);
+                    added.push(value);
+                    kept.push(instruction);
+                }
+            }
+        }
+        cfg.block_mut(id).body = kept;
+
+        for child in doms.children(id).to_vec() {
+            self.walk(cfg, doms, child);
+        }
+        for value in added {
+            self.table.remove(&value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::opt::run_pass;
+    use pretty_assertions::assert_eq;
+
+    fn count(func: &DefFunc, f: fn(&Instruction) -> bool) -> usize {
+        func.body.iter().filter(|i| f(i)).count()
+    }
+
+    #[test]
+    fn same_block() {
+        let func = run_pass(
+            gvn,
+            "fn f(a: u64, b: u64) -> u64 { return (a + b) * (a + b); }",
+        );
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Add(..))), 1);
+        let Some(Instruction::Mul(Mul { lhs, rhs, .. })) =
+            func.body.iter().find(|i| matches!(i, Instruction::Mul(..)))
+        else {
+            panic!("expected a mul");
+        };
+        assert_eq!(lhs, rhs);
+    }
+
+    #[test]
+    fn commutative() {
+        let func = run_pass(gvn, "fn f(a: u64, b: u64) -> u64 { return a * b - b * a; }");
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Mul(..))), 1);
+    }
+
+    #[test]
+    fn not_commutative() {
+        let func = run_pass(
+            gvn,
+            "fn f(a: u64, b: u64) -> u64 { return (a - b) * (b - a); }",
+        );
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Sub(..))), 2);
+    }
+
+    #[test]
+    fn dominating_block() {
+        let func = run_pass(
+            gvn,
+            "fn f(a: u64, b: u64) -> u64 {
+                let x = a + b;
+                if a > b { return a + b; };
+                return x + 1;
+            }",
+        );
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Add(..))), 2);
+    }
+
+    #[test]
+    fn sibling_blocks() {
+        let func = run_pass(
+            gvn,
+            "fn f(a: u64, b: u64) -> u64 {
+                if a > b { return a * b; } else { return b * a + 1; };
+            }",
+        );
+        assert_eq!(count(&func, |i| matches!(i, Instruction::Mul(..))), 2);
+    }
+}
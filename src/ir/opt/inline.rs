@@ -0,0 +1,336 @@
+//! Inlining of small functions at their call sites.
+//!
+//! Runs before ssa construction so the `Return`s of a callee can simply copy
+//! into the register defined by the call and jump past the inlined body.
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::cfg::{Cfg, EXIT_LABEL};
+use crate::ir::ssa::next_reg;
+use crate::ir::{
+    Call, Conditional, Copy, DefFunc, DefLabel, Inline, Instruction, Jump, Label, Phi, Reg, Return,
+};
+
+/// Functions with more instructions than this are only inlined when marked
+/// with `#[inline]`.
+const MAX_SIZE: usize = 12;
+
+pub fn inline(code: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    let funcs = code
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::DefFunc(func) => Some((func.name.clone(), func)),
+            _ => None,
+        })
+        .collect::<HashMap<String, &DefFunc>>();
+    let calls = funcs
+        .iter()
+        .map(|(name, func)| (name.as_str(), callees(func)))
+        .collect::<HashMap<&str, Vec<&str>>>();
+
+    // Callees are done before their callers so their bodies already have
+    // their own calls inlined.
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    let mut names = funcs.keys().map(String::as_str).collect::<Vec<&str>>();
+    names.sort();
+    for name in names {
+        postorder(name, &calls, &mut visited, &mut order);
+    }
+
+    let mut inlinable: HashMap<String, DefFunc> = HashMap::new();
+    let mut done: HashMap<String, DefFunc> = HashMap::new();
+    for name in order {
+        let func = inline_calls(funcs[name].clone(), &inlinable);
+        if should_inline(&func, &calls) {
+            inlinable.insert(name.to_string(), func.clone());
+        }
+        done.insert(name.to_string(), func);
+    }
+    Ok(code
+        .into_iter()
+        .map(|i| match i {
+            Instruction::DefFunc(func) => done.remove(&func.name).unwrap_or(func).into(),
+            i => i,
+        })
+        .collect())
+}
+
+fn callees(func: &DefFunc) -> Vec<&str> {
+    func.body
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::Call(Call { caller, .. }) => Some(caller.0.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn postorder<'a>(
+    name: &'a str,
+    calls: &HashMap<&'a str, Vec<&'a str>>,
+    visited: &mut HashSet<&'a str>,
+    order: &mut Vec<&'a str>,
+) {
+    let Some(callees) = calls.get(name) else {
+        return;
+    };
+    if !visited.insert(name) {
+        return;
+    }
+    for callee in callees {
+        postorder(callee, calls, visited, order);
+    }
+    order.push(name);
+}
+
+fn is_recursive(name: &str, calls: &HashMap<&str, Vec<&str>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut work = calls.get(name).cloned().unwrap_or_default();
+    while let Some(callee) = work.pop() {
+        if callee == name {
+            return true;
+        }
+        if seen.insert(callee) {
+            work.extend(calls.get(callee).into_iter().flatten());
+        }
+    }
+    false
+}
+
+fn should_inline(func: &DefFunc, calls: &HashMap<&str, Vec<&str>>) -> bool {
+    let wanted = match func.inline {
+        Inline::Never => false,
+        Inline::Always => true,
+        Inline::Auto => size(func) <= MAX_SIZE,
+    };
+    wanted && always_returns(func) && !is_recursive(&func.name, calls)
+}
+
+fn size(func: &DefFunc) -> usize {
+    func.body
+        .iter()
+        .filter(|i| {
+            !matches!(
+                i,
+                Instruction::Enter(..) | Instruction::Leave(..) | Instruction::DefLabel(..)
+            )
+        })
+        .count()
+}
+
+/// Every way out of the function goes through a `Return`, otherwise the
+/// register the call defines would be left unset on some path.
+fn always_returns(func: &DefFunc) -> bool {
+    let mut cfg = Cfg::new(func);
+    cfg.remove_unreachable();
+    let Some(exit) = cfg.block_by_label(&EXIT_LABEL.into()) else {
+        return false;
+    };
+    cfg.predecessors(exit)
+        .iter()
+        .all(|pred| matches!(cfg.block(*pred).terminator(), Some(Instruction::Return(..))))
+}
+
+fn inline_calls(func: DefFunc, inlinable: &HashMap<String, DefFunc>) -> DefFunc {
+    let mut reg_count = next_reg(&func);
+    let mut label_count = Cfg::new(&func).next_label_number();
+    let mut body = Vec::with_capacity(func.body.len());
+    for instruction in func.body {
+        let Instruction::Call(Call { caller, args, ret }) = &instruction else {
+            body.push(instruction);
+            continue;
+        };
+        match inlinable.get(&caller.0) {
+            Some(callee) if callee.params.len() == args.len() => {
+                body.extend(expand(callee, args, *ret, &mut reg_count, &mut label_count));
+            }
+            _ => body.push(instruction),
+        }
+    }
+    DefFunc { body, ..func }
+}
+
+/// The body of `callee` with its registers and labels moved past the ones
+/// already used by the caller.
+fn expand(
+    callee: &DefFunc,
+    args: &[Reg],
+    ret: Reg,
+    reg_count: &mut usize,
+    label_count: &mut usize,
+) -> Vec<Instruction> {
+    let base = *reg_count;
+    *reg_count += next_reg(callee);
+    let reg = |Reg(r): Reg| Reg(r + base);
+
+    let mut labels = HashMap::new();
+    for label in callee.body.iter().filter_map(Instruction::as_label) {
+        labels.insert(label.clone(), Label(format!(".L{}", *label_count)));
+        *label_count += 1;
+    }
+    let label = |l: &Label| labels.get(l).cloned().unwrap_or_else(|| l.clone());
+    let exit = label(&EXIT_LABEL.into());
+
+    let mut body = callee
+        .params
+        .iter()
+        .zip(args)
+        .map(|((param, _), arg)| {
+            Copy {
+                to: reg(*param),
+                from: *arg,
+            }
+            .into()
+        })
+        .collect::<Vec<Instruction>>();
+    for instruction in callee.body.iter() {
+        let mut instruction = match instruction {
+            Instruction::Enter(..) | Instruction::Leave(..) => continue,
+            Instruction::Return(Return(value)) => {
+                body.push(
+                    Copy {
+                        to: ret,
+                        from: reg(*value),
+                    }
+                    .into(),
+                );
+                body.push(Jump(exit.clone()).into());
+                continue;
+            }
+            instruction => instruction.clone(),
+        };
+        if let Some(des) = instruction.def_mut() {
+            *des = reg(*des);
+        }
+        for r in instruction.uses_mut() {
+            *r = reg(*r);
+        }
+        match &mut instruction {
+            Instruction::DefLabel(DefLabel(l))
+            | Instruction::Jump(Jump(l))
+            | Instruction::Conditional(Conditional { label: l, .. }) => *l = label(l),
+            Instruction::Phi(Phi { args, .. }) => {
+                for (_, l) in args.iter_mut() {
+                    *l = label(l);
+                }
+            }
+            _ => {}
+        }
+        body.push(instruction);
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::opt::const_fold;
+    use crate::ir::{code_gen, ssa, Imm, LoadImm};
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use pretty_assertions::assert_eq;
+
+    fn setup(src: &str) -> Vec<DefFunc> {
+        lex(src)
+            .and_then(parse)
+            .and_then(code_gen)
+            .and_then(inline)
+            .unwrap()
+            .into_iter()
+            .filter_map(|i| match i {
+                Instruction::DefFunc(func) => Some(func),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn main(funcs: &[DefFunc]) -> &DefFunc {
+        funcs.iter().find(|f| f.name == "main").unwrap()
+    }
+
+    fn calls(func: &DefFunc) -> usize {
+        callees(func).len()
+    }
+
+    /// What `main` returns once its body has been folded.
+    fn folded(func: &DefFunc) -> Option<u64> {
+        let func = const_fold(ssa::construct_func(func.clone()));
+        let reg = func.body.iter().find_map(|i| match i {
+            Instruction::Return(Return(reg)) => Some(*reg),
+            _ => None,
+        })?;
+        func.body.iter().find_map(|i| match i {
+            Instruction::LoadImm(LoadImm { des, imm: Imm(imm) }) if *des == reg => Some(*imm),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn small_function() {
+        let funcs = setup(
+            "fn add(x: u64, y: u64) -> u64 { return x + y; }
+             fn main() { return add(1, 2); }",
+        );
+        assert_eq!(calls(main(&funcs)), 0);
+        assert_eq!(folded(main(&funcs)), Some(3));
+    }
+
+    #[test]
+    fn labels_do_not_collide() {
+        let funcs = setup(
+            "fn max(a: u64, b: u64) -> u64 { if a > b { return a; } else { return b; }; }
+             fn main() { if 1 > 2 { return 0; }; return max(3, 4) + max(2, 1); }",
+        );
+        let main = main(&funcs);
+        assert_eq!(calls(main), 0);
+        let labels = main
+            .body
+            .iter()
+            .filter_map(Instruction::as_label)
+            .collect::<Vec<&Label>>();
+        let unique = labels.iter().collect::<HashSet<_>>();
+        assert_eq!(labels.len(), unique.len());
+        assert_eq!(folded(main), Some(6));
+    }
+
+    #[test]
+    fn nested_calls() {
+        let funcs = setup(
+            "fn one() -> u64 { return 1; }
+             fn two() -> u64 { return one() + one(); }
+             fn main() { return two() * 3; }",
+        );
+        assert_eq!(calls(main(&funcs)), 0);
+        assert_eq!(folded(main(&funcs)), Some(6));
+    }
+
+    #[test]
+    fn inline_never() {
+        let funcs = setup(
+            "#[inline(never)]
+             fn add(x: u64, y: u64) -> u64 { return x + y; }
+             fn main() { return add(1, 2); }",
+        );
+        assert_eq!(calls(main(&funcs)), 1);
+    }
+
+    #[test]
+    fn recursive() {
+        let funcs = setup(
+            "#[inline]
+             fn f(x: u64) -> u64 { return f(x); }
+             fn main() { return f(1); }",
+        );
+        assert_eq!(calls(main(&funcs)), 1);
+    }
+
+    #[test]
+    fn size_heuristic() {
+        let big =
+            "fn big(x: u64) -> u64 { return x + x + x + x + x + x + x + x + x + x + x + x + x; }";
+        let funcs = setup(&format!("{big} fn main() {{ return big(1); }}"));
+        assert_eq!(calls(main(&funcs)), 1);
+        let funcs = setup(&format!("#[inline] {big} fn main() {{ return big(1); }}"));
+        assert_eq!(calls(main(&funcs)), 0);
+    }
+}
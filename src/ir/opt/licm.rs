@@ -0,0 +1,225 @@
+//! Loop-invariant code motion.
+//!
+//! Pure computations inside a loop whose operands are all defined outside of
+//! it, or are themselves invariant, are moved to the loop's preheader so they
+//! run once instead of every iteration. Inner loops are done first so values
+//! can keep moving out through every loop they are invariant in.
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::cfg::{BasicBlock, BlockId, Cfg, Dominators, Loop, LoopNest};
+use crate::ir::{DefFunc, Instruction, Reg};
+
+pub fn licm(func: DefFunc) -> DefFunc {
+    let mut cfg = Cfg::new(&func);
+    let (doms, nest) = with_preheaders(&mut cfg);
+    let mut loops = nest.loops.iter().collect::<Vec<&Loop>>();
+    loops.sort_by_key(|l| Reverse(l.depth));
+    for lp in loops {
+        hoist(&mut cfg, &doms, lp);
+    }
+    DefFunc {
+        body: cfg.into_body(),
+        ..func
+    }
+}
+
+/// Gives every loop a preheader where possible and returns the analysis of
+/// the resulting graph.
+pub(super) fn with_preheaders(cfg: &mut Cfg) -> (Dominators, LoopNest) {
+    loop {
+        let doms = Dominators::new(cfg);
+        let nest = LoopNest::new(cfg, &doms);
+        let inserted = nest
+            .loops
+            .iter()
+            .any(|lp| cfg.preheader(lp).is_none() && cfg.insert_preheader(lp));
+        if !inserted {
+            return (doms, nest);
+        }
+    }
+}
+
+/// Adds `instructions` to the end of `block` but before its terminator.
+pub(super) fn push_before_terminator(
+    block: &mut BasicBlock,
+    instructions: impl IntoIterator<Item = Instruction>,
+) {
+    let at = block.body.len() - block.terminator().is_some() as usize;
+    block.body.splice(at..at, instructions);
+}
+
+/// Division is left in place since it traps when the loop might never have
+/// run it.
+fn is_hoistable(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::LoadImm(..)
+            | Instruction::LoadStr(..)
+            | Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Grt(..)
+            | Instruction::Copy(..)
+    )
+}
+
+fn hoist(cfg: &mut Cfg, doms: &Dominators, lp: &Loop) {
+    let Some(preheader) = cfg.preheader(lp) else {
+        return;
+    };
+    let defined_inside = lp
+        .blocks
+        .iter()
+        .flat_map(|id| cfg.block(*id).body.iter().filter_map(Instruction::def))
+        .collect::<HashSet<Reg>>();
+
+    let mut invariant = HashSet::new();
+    let mut candidates = vec![];
+    for id in doms
+        .reverse_postorder()
+        .iter()
+        .filter(|id| lp.contains(**id))
+    {
+        for (i, instruction) in cfg.block(*id).body.iter().enumerate() {
+            let Some(des) = instruction.def() else {
+                continue;
+            };
+            let operands_invariant = instruction
+                .uses()
+                .iter()
+                .all(|r| !defined_inside.contains(r) || invariant.contains(r));
+            if is_hoistable(instruction) && operands_invariant {
+                invariant.insert(des);
+                candidates.push((*id, i));
+            }
+        }
+    }
+
+    // Constants only move along with something that reads them.
+    let instruction = |(id, i): &(BlockId, usize)| &cfg.block(*id).body[*i];
+    let needed = candidates
+        .iter()
+        .map(instruction)
+        .filter(|i| !matches!(i, Instruction::LoadImm(..) | Instruction::LoadStr(..)))
+        .flat_map(Instruction::uses)
+        .collect::<HashSet<Reg>>();
+    let hoisted = candidates
+        .iter()
+        .filter(|c| match instruction(c) {
+            Instruction::LoadImm(..) | Instruction::LoadStr(..) => {
+                instruction(c).def().is_some_and(|d| needed.contains(&d))
+            }
+            _ => true,
+        })
+        .copied()
+        .collect::<Vec<(BlockId, usize)>>();
+    if hoisted.is_empty() {
+        return;
+    }
+
+    let moved = hoisted
+        .iter()
+        .map(|c| instruction(c).clone())
+        .collect::<Vec<Instruction>>();
+    let mut remove: HashMap<BlockId, HashSet<usize>> = HashMap::new();
+    for (id, i) in hoisted {
+        remove.entry(id).or_default().insert(i);
+    }
+    for (id, indices) in remove {
+        let body = std::mem::take(&mut cfg.block_mut(id).body);
+        cfg.block_mut(id).body = body
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !indices.contains(i))
+            .map(|(_, instruction)| instruction)
+            .collect();
+    }
+    push_before_terminator(cfg.block_mut(preheader), moved);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::opt::run_pass;
+    use pretty_assertions::assert_eq;
+
+    /// Instructions matching `f` and the loop depth of the block they are in.
+    fn depths(func: &DefFunc, f: fn(&Instruction) -> bool) -> Vec<usize> {
+        let cfg = Cfg::new(func);
+        let doms = Dominators::new(&cfg);
+        let nest = LoopNest::new(&cfg, &doms);
+        cfg.ids()
+            .flat_map(|id| {
+                let depth = nest.depth(id);
+                cfg.block(id)
+                    .body
+                    .iter()
+                    .filter(|i| f(i))
+                    .map(move |_| depth)
+                    .collect::<Vec<usize>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hoist_out_of_loop() {
+        let func = run_pass(
+            licm,
+            "fn f(n: u64) -> u64 {
+                let i = 0;
+                let sum = 0;
+                while n > i {
+                    sum = sum + n * n;
+                    i = i + 1;
+                };
+                return sum;
+            }",
+        );
+        assert_eq!(depths(&func, |i| matches!(i, Instruction::Mul(..))), [0]);
+        // The increment still depends on the loop.
+        assert_eq!(depths(&func, |i| matches!(i, Instruction::Add(..))), [1, 1]);
+    }
+
+    #[test]
+    fn hoist_through_nested_loops() {
+        let func = run_pass(
+            licm,
+            "fn f(n: u64) -> u64 {
+                let i = 0;
+                let sum = 0;
+                while n > i {
+                    let j = 0;
+                    while n > j {
+                        sum = sum + (n + 1) * i;
+                        j = j + 1;
+                    };
+                    i = i + 1;
+                };
+                return sum;
+            }",
+        );
+        // `n + 1` leaves both loops, `(n + 1) * i` only the inner one.
+        let mut adds = depths(&func, |i| matches!(i, Instruction::Add(..)));
+        adds.sort();
+        assert_eq!(adds, [0, 1, 2, 2]);
+        assert_eq!(depths(&func, |i| matches!(i, Instruction::Mul(..))), [1]);
+    }
+
+    #[test]
+    fn division_stays() {
+        let func = run_pass(
+            licm,
+            "fn f(n: u64, d: u64) -> u64 {
+                let i = 0;
+                let sum = 0;
+                while n > i {
+                    sum = sum + n / d;
+                    i = i + 1;
+                };
+                return sum;
+            }",
+        );
+        assert_eq!(depths(&func, |i| matches!(i, Instruction::Div(..))), [1]);
+    }
+}
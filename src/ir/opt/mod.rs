@@ -0,0 +1,72 @@
+//! Optimization passes over the ssa form of the ir, enabled with `-O`.
+mod const_fold;
+mod dce;
+mod gvn;
+mod inline;
+mod licm;
+mod strength_reduce;
+
+use super::verify::debug_verify;
+use super::{map_funcs, DefFunc, Instruction};
+
+pub use const_fold::const_fold;
+pub use dce::dce;
+pub use gvn::gvn;
+pub use inline::inline;
+pub use licm::licm;
+pub use strength_reduce::strength_reduce;
+
+/// What the passes did, printed with `--debug-ir`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub eliminated: usize,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dce: eliminated {} instructions", self.eliminated)
+    }
+}
+
+type Pass = fn(DefFunc) -> DefFunc;
+
+pub fn optimize(code: Vec<Instruction>) -> Result<(Vec<Instruction>, Stats), Vec<String>> {
+    let passes: [(_, Pass); 5] = [
+        ("const_fold", const_fold),
+        ("gvn", gvn),
+        ("licm", licm),
+        ("strength_reduce", strength_reduce),
+        ("const_fold", const_fold),
+    ];
+    let mut code = code;
+    for (name, pass) in passes {
+        code = map_funcs(code, pass);
+        debug_verify(&code, name)?;
+    }
+    let mut stats = Stats::default();
+    let code = map_funcs(code, |func| {
+        let (func, eliminated) = dce(func);
+        stats.eliminated += eliminated;
+        func
+    });
+    debug_verify(&code, "dce")?;
+    Ok((code, stats))
+}
+
+/// The last function of `src` in ssa form, after `pass`.
+#[cfg(test)]
+fn run_pass<T>(pass: impl FnOnce(DefFunc) -> T, src: &str) -> T {
+    use crate::ir::{code_gen, ssa};
+    use crate::lexer::lex;
+    use crate::parse::parse;
+
+    let code = lex(src)
+        .and_then(parse)
+        .and_then(code_gen)
+        .and_then(ssa::construct)
+        .unwrap();
+    let Some(Instruction::DefFunc(func)) = code.into_iter().last() else {
+        panic!("expected a function");
+    };
+    pass(func)
+}
@@ -0,0 +1,285 @@
+//! Induction variable strength reduction.
+//!
+//! A basic induction variable is a phi in a loop header that is stepped by a
+//! loop-invariant amount every iteration, `i = phi(init, i + step)`. A
+//! multiplication `i * k` by an invariant `k` then moves in steps of
+//! `step * k`, so it is replaced by a new induction variable that starts at
+//! `init * k` and is stepped with an add instead of a multiply.
+use std::collections::HashMap;
+
+use super::licm::{push_before_terminator, with_preheaders};
+use crate::ir::cfg::{BlockId, Cfg, Loop};
+use crate::ir::ssa::next_reg;
+use crate::ir::{Add, DefFunc, Instruction, Label, LoadImm, Mul, Phi, Reg, Sub};
+
+pub fn strength_reduce(func: DefFunc) -> DefFunc {
+    let mut cfg = Cfg::new(&func);
+    let (_, nest) = with_preheaders(&mut cfg);
+    let mut reg_count = next_reg(&func);
+    let mut replaced = HashMap::new();
+    for lp in nest.loops.iter() {
+        while let Some(iv) = find(&cfg, lp) {
+            reduce(&mut cfg, iv, &mut reg_count, &mut replaced);
+        }
+    }
+    for block in cfg.blocks.iter_mut() {
+        for instruction in block.body.iter_mut() {
+            for reg in instruction.uses_mut() {
+                *reg = replaced.get(reg).copied().unwrap_or(*reg);
+            }
+        }
+    }
+    DefFunc {
+        body: cfg.into_body(),
+        ..func
+    }
+}
+
+/// A multiplication of a basic induction variable by an invariant.
+#[derive(Debug)]
+struct Candidate {
+    preheader: BlockId,
+    preheader_label: Label,
+    header: BlockId,
+    latch_label: Label,
+    /// The register starting the induction variable.
+    init: Reg,
+    step: Invariant,
+    /// Whether the variable counts down.
+    sub: bool,
+    /// Where the next value of the induction variable is computed.
+    next: (BlockId, usize),
+    mul: (BlockId, usize),
+    factor: Invariant,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Invariant {
+    Reg(Reg),
+    /// Constants inside the loop are loaded again in the preheader.
+    Imm(u64),
+}
+
+fn find(cfg: &Cfg, lp: &Loop) -> Option<Candidate> {
+    let preheader = cfg.preheader(lp)?;
+    let [latch] = lp.latches[..] else {
+        return None;
+    };
+    let preheader_label = cfg.block(preheader).label()?.clone();
+    let latch_label = cfg.block(latch).label()?.clone();
+
+    let mut defs = HashMap::new();
+    for id in cfg.ids() {
+        for (i, instruction) in cfg.block(id).body.iter().enumerate() {
+            if let Some(des) = instruction.def() {
+                defs.insert(des, (id, i));
+            }
+        }
+    }
+    let def = |reg: &Reg| defs.get(reg).map(|(id, i)| &cfg.block(*id).body[*i]);
+    let invariant = |reg: &Reg| match (defs.get(reg), def(reg)) {
+        (_, Some(Instruction::LoadImm(LoadImm { imm, .. }))) => Some(Invariant::Imm(imm.0)),
+        (Some((id, _)), _) if lp.contains(*id) => None,
+        _ => Some(Invariant::Reg(*reg)),
+    };
+
+    let mut ivs = HashMap::new();
+    for instruction in cfg.block(lp.header).body.iter() {
+        let Instruction::Phi(Phi { des, args }) = instruction else {
+            continue;
+        };
+        let arg = |label: &Label| args.iter().find(|(_, l)| l == label).map(|(r, _)| *r);
+        let (Some(init), Some(next), 2) = (arg(&preheader_label), arg(&latch_label), args.len())
+        else {
+            continue;
+        };
+        let (step, sub) = match def(&next) {
+            Some(Instruction::Add(Add { lhs, rhs, .. })) if lhs == des => (rhs, false),
+            Some(Instruction::Add(Add { lhs, rhs, .. })) if rhs == des => (lhs, false),
+            Some(Instruction::Sub(Sub { lhs, rhs, .. })) if lhs == des => (rhs, true),
+            _ => continue,
+        };
+        if let Some(step) = invariant(step) {
+            ivs.insert(*des, (init, step, sub, defs[&next]));
+        }
+    }
+
+    for id in lp.blocks.iter() {
+        for (i, instruction) in cfg.block(*id).body.iter().enumerate() {
+            let Instruction::Mul(Mul { lhs, rhs, .. }) = instruction else {
+                continue;
+            };
+            let found = [(lhs, rhs), (rhs, lhs)]
+                .into_iter()
+                .find_map(|(iv, factor)| {
+                    let iv = ivs.get(iv)?;
+                    Some((iv, invariant(factor)?))
+                });
+            let Some((&(init, step, sub, next), factor)) = found else {
+                continue;
+            };
+            return Some(Candidate {
+                preheader,
+                preheader_label,
+                header: lp.header,
+                latch_label,
+                init,
+                step,
+                sub,
+                next,
+                mul: (*id, i),
+                factor,
+            });
+        }
+    }
+    None
+}
+
+fn reduce(cfg: &mut Cfg, iv: Candidate, reg_count: &mut usize, replaced: &mut HashMap<Reg, Reg>) {
+    let mut new_reg = || {
+        *reg_count += 1;
+        Reg(*reg_count - 1)
+    };
+    let mut preheader = vec![];
+    let mut materialize = |value: Invariant, preheader: &mut Vec<Instruction>| match value {
+        Invariant::Reg(reg) => reg,
+        Invariant::Imm(imm) => {
+            let des = new_reg();
+            preheader.push(
+                LoadImm {
+                    des,
+                    imm: imm.into(),
+                }
+                .into(),
+            );
+            des
+        }
+    };
+    let factor = materialize(iv.factor, &mut preheader);
+    let step = materialize(iv.step, &mut preheader);
+    let (start, scaled_step, current, next) = (new_reg(), new_reg(), new_reg(), new_reg());
+    preheader.push(
+        Mul {
+            des: start,
+            lhs: iv.init,
+            rhs: factor,
+        }
+        .into(),
+    );
+    preheader.push(
+        Mul {
+            des: scaled_step,
+            lhs: step,
+            rhs: factor,
+        }
+        .into(),
+    );
+
+    // The multiply goes first so the indices of the other edits stay valid.
+    let (mul_block, mul_index) = iv.mul;
+    let Some(des) = cfg.block_mut(mul_block).body.remove(mul_index).def() else {
+        unreachable!("a mul always defines a register");
+    };
+    replaced.insert(des, current);
+    let (next_block, mut next_index) = iv.next;
+    if next_block == mul_block && next_index > mul_index {
+        next_index -= 1;
+    }
+    let step_instruction: Instruction = if iv.sub {
+        Sub {
+            des: next,
+            lhs: current,
+            rhs: scaled_step,
+        }
+        .into()
+    } else {
+        Add {
+            des: next,
+            lhs: current,
+            rhs: scaled_step,
+        }
+        .into()
+    };
+    cfg.block_mut(next_block)
+        .body
+        .insert(next_index + 1, step_instruction);
+    let phi = Phi {
+        des: current,
+        args: vec![(start, iv.preheader_label), (next, iv.latch_label)],
+    };
+    cfg.block_mut(iv.header).body.insert(1, phi.into());
+    push_before_terminator(cfg.block_mut(iv.preheader), preheader);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::cfg::{Dominators, LoopNest};
+    use crate::ir::opt::{const_fold, run_pass};
+    use crate::ir::ssa;
+    use pretty_assertions::assert_eq;
+
+    /// Copies of the stepped value are folded away first, as in `optimize`.
+    fn setup(src: &str) -> DefFunc {
+        run_pass(|func| strength_reduce(const_fold(func)), src)
+    }
+
+    fn in_loops(func: &DefFunc, f: fn(&Instruction) -> bool) -> usize {
+        let cfg = Cfg::new(func);
+        let doms = Dominators::new(&cfg);
+        let nest = LoopNest::new(&cfg, &doms);
+        cfg.ids()
+            .filter(|id| nest.depth(*id) > 0)
+            .map(|id| cfg.block(id).body.iter().filter(|i| f(i)).count())
+            .sum()
+    }
+
+    #[test]
+    fn multiply_by_constant() {
+        let func = setup(
+            "fn f(n: u64) -> u64 {
+                let i = 0;
+                let sum = 0;
+                while n > i {
+                    sum = sum + i * 4;
+                    i = i + 1;
+                };
+                return sum;
+            }",
+        );
+        assert_eq!(in_loops(&func, |i| matches!(i, Instruction::Mul(..))), 0);
+        assert_eq!(in_loops(&func, |i| matches!(i, Instruction::Phi(..))), 3);
+        ssa::destruct_func(func);
+    }
+
+    #[test]
+    fn multiply_by_parameter_counting_down() {
+        let func = setup(
+            "fn f(n: u64, k: u64) -> u64 {
+                let sum = 0;
+                while n > 0 {
+                    sum = sum + k * n;
+                    n = n - 1;
+                };
+                return sum;
+            }",
+        );
+        assert_eq!(in_loops(&func, |i| matches!(i, Instruction::Mul(..))), 0);
+        assert_eq!(in_loops(&func, |i| matches!(i, Instruction::Sub(..))), 2);
+    }
+
+    #[test]
+    fn not_an_induction_variable() {
+        let func = setup(
+            "fn f(n: u64) -> u64 {
+                let i = 1;
+                while n > i {
+                    i = i * 2;
+                    n = n - i * 3;
+                };
+                return n;
+            }",
+        );
+        assert_eq!(in_loops(&func, |i| matches!(i, Instruction::Mul(..))), 2);
+    }
+}
@@ -0,0 +1,487 @@
+//! Conversion into and out of static single assignment form.
+//!
+//! `IrGenerator` writes to the same register more than once for `let`
+//! variables that get assigned to and for the value of an `if` expression.
+//! `construct` gives every one of those writes its own register and adds phi
+//! nodes where control flow joins, using the dominance frontier algorithm from
+//! Cytron et al. Phi nodes are only placed where the register is still live so
+//! no phi ever reads a register that was never written. `destruct` turns phi
+//! nodes back into copies at the end of the predecessor blocks so the backend
+//! never sees them.
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::{BlockId, Cfg, Dominators, Liveness};
+use super::{map_funcs, Copy, DefFunc, DefLabel, Instruction, Jump, Label, Phi, Reg};
+
+pub fn construct(code: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    Ok(map_funcs(code, construct_func))
+}
+
+pub fn destruct(code: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    Ok(map_funcs(code, destruct_func))
+}
+
+/// One past the highest register used in `func`.
+pub fn next_reg(func: &DefFunc) -> usize {
+    func.params
+        .iter()
+        .map(|(reg, _)| *reg)
+        .chain(
+            func.body
+                .iter()
+                .flat_map(|i| i.def().into_iter().chain(i.uses())),
+        )
+        .map(|Reg(r)| r + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+pub fn construct_func(func: DefFunc) -> DefFunc {
+    let mut cfg = Cfg::new(&func);
+    cfg.remove_unreachable();
+    cfg.label_blocks();
+    let doms = Dominators::new(&cfg);
+    let frontiers = doms.frontiers(&cfg);
+    let liveness = Liveness::new(&cfg);
+
+    // Registers written once are already in ssa form and can keep their name.
+    let mut defsites: HashMap<Reg, HashSet<BlockId>> = HashMap::new();
+    let mut def_count: HashMap<Reg, usize> = HashMap::new();
+    for (reg, _) in func.params.iter() {
+        defsites.entry(*reg).or_default().insert(cfg.entry());
+        *def_count.entry(*reg).or_default() += 1;
+    }
+    for id in cfg.ids() {
+        for reg in cfg.block(id).body.iter().filter_map(Instruction::def) {
+            defsites.entry(reg).or_default().insert(id);
+            *def_count.entry(reg).or_default() += 1;
+        }
+    }
+    let mut multi = def_count
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(reg, _)| reg)
+        .collect::<Vec<Reg>>();
+    multi.sort_by_key(|Reg(r)| *r);
+
+    // Phi placement, phi_vars remembers which register each phi stands for.
+    let mut phi_vars: HashMap<(BlockId, Reg), Reg> = HashMap::new();
+    for reg in multi.iter().copied() {
+        let mut has_phi: HashSet<BlockId> = HashSet::new();
+        let mut worklist = defsites[&reg].iter().copied().collect::<Vec<BlockId>>();
+        worklist.sort();
+        while let Some(id) = worklist.pop() {
+            for frontier in frontiers[id.0].iter().copied() {
+                if has_phi.contains(&frontier) || !liveness.live_in[frontier.0].contains(&reg) {
+                    continue;
+                }
+                has_phi.insert(frontier);
+                let args = cfg
+                    .predecessors(frontier)
+                    .iter()
+                    .map(|pred| (reg, block_label(&cfg, *pred)))
+                    .collect();
+                let block = cfg.block_mut(frontier);
+                block.body.insert(1, Phi { des: reg, args }.into());
+                phi_vars.insert((frontier, reg), reg);
+                if !defsites[&reg].contains(&frontier) {
+                    worklist.push(frontier);
+                }
+            }
+        }
+    }
+
+    let mut renamer = Renamer {
+        multi: multi.into_iter().collect(),
+        stacks: HashMap::new(),
+        next_reg: next_reg(&func),
+        phi_vars,
+    };
+    // Parameters keep their name, it is the value passed in by the caller.
+    for (reg, _) in func.params.iter() {
+        if renamer.multi.contains(reg) {
+            renamer.stacks.entry(*reg).or_default().push(*reg);
+        }
+    }
+    let entry = cfg.entry();
+    renamer.rename(&mut cfg, &doms, entry);
+
+    DefFunc {
+        body: cfg.into_body(),
+        ..func
+    }
+}
+
+fn block_label(cfg: &Cfg, id: BlockId) -> Label {
+    cfg.block(id)
+        .label()
+        .cloned()
+        .expect("ssa::construct labels every block")
+}
+
+struct Renamer {
+    multi: HashSet<Reg>,
+    stacks: HashMap<Reg, Vec<Reg>>,
+    next_reg: usize,
+    phi_vars: HashMap<(BlockId, Reg), Reg>,
+}
+
+impl Renamer {
+    fn current(&self, reg: Reg) -> Option<Reg> {
+        self.stacks.get(&reg).and_then(|s| s.last()).copied()
+    }
+
+    fn rename(&mut self, cfg: &mut Cfg, doms: &Dominators, id: BlockId) {
+        let mut pushed = vec![];
+        for instruction in cfg.block_mut(id).body.iter_mut() {
+            if !matches!(instruction, Instruction::Phi(..)) {
+                for reg in instruction.uses_mut() {
+                    if let Some(current) = self.current(*reg) {
+                        *reg = current;
+                    }
+                }
+            }
+            let is_phi = matches!(instruction, Instruction::Phi(..));
+            let Some(des) = instruction.def_mut() else {
+                continue;
+            };
+            if !self.multi.contains(des) {
+                continue;
+            }
+            let new = Reg(self.next_reg);
+            self.next_reg += 1;
+            self.stacks.entry(*des).or_default().push(new);
+            pushed.push(*des);
+            // Keep track of the phi under its new name.
+            if let Some(var) = is_phi.then(|| self.phi_vars.remove(&(id, *des))).flatten() {
+                self.phi_vars.insert((id, new), var);
+            }
+            *des = new;
+        }
+
+        let label = block_label(cfg, id);
+        for succ in cfg.successors(id).to_vec() {
+            for instruction in cfg.block_mut(succ).body.iter_mut() {
+                let Instruction::Phi(Phi { des, args }) = instruction else {
+                    continue;
+                };
+                let Some(var) = self.phi_vars.get(&(succ, *des)).copied() else {
+                    continue;
+                };
+                for (reg, _) in args.iter_mut().filter(|(_, l)| l == &label) {
+                    *reg = self.current(var).unwrap_or(var);
+                }
+            }
+        }
+
+        for child in doms.children(id).to_vec() {
+            self.rename(cfg, doms, child);
+        }
+        for reg in pushed {
+            if let Some(stack) = self.stacks.get_mut(&reg) {
+                stack.pop();
+            }
+        }
+    }
+}
+
+pub fn destruct_func(func: DefFunc) -> DefFunc {
+    if !func.body.iter().any(|i| matches!(i, Instruction::Phi(..))) {
+        return func;
+    }
+    let mut next_reg = next_reg(&func);
+    let mut cfg = Cfg::new(&func);
+    let mut next_label = cfg.next_label_number();
+    let labels = cfg
+        .ids()
+        .filter_map(|id| cfg.block(id).label().map(|l| (l.clone(), id)))
+        .collect::<HashMap<Label, BlockId>>();
+
+    // Parallel copies that have to happen on each edge.
+    let mut edge_copies: HashMap<(BlockId, BlockId), Vec<(Reg, Reg)>> = HashMap::new();
+    for succ in cfg.ids() {
+        for instruction in cfg.block(succ).body.iter() {
+            let Instruction::Phi(Phi { des, args }) = instruction else {
+                continue;
+            };
+            for (reg, label) in args.iter() {
+                let pred = labels[label];
+                edge_copies
+                    .entry((pred, succ))
+                    .or_default()
+                    .push((*des, *reg));
+            }
+        }
+    }
+
+    let mut at_end: HashMap<BlockId, Vec<Instruction>> = HashMap::new();
+    let mut after: HashMap<BlockId, Vec<Instruction>> = HashMap::new();
+    let mut appended = vec![];
+    let mut edges = edge_copies.into_iter().collect::<Vec<_>>();
+    edges.sort_by_key(|((pred, succ), _)| (*pred, *succ));
+    for ((pred, succ), copies) in edges {
+        let copies = sequentialize(copies, &mut next_reg);
+        if copies.is_empty() {
+            continue;
+        }
+        let critical = cfg.successors(pred).len() > 1 && cfg.predecessors(succ).len() > 1;
+        if !critical {
+            at_end.entry(pred).or_default().extend(copies);
+            continue;
+        }
+        // Split the edge with a new block that does the copies.
+        let label = Label(format!(".L{next_label}"));
+        next_label += 1;
+        let mut split: Vec<Instruction> = vec![DefLabel(label.clone()).into()];
+        split.extend(copies);
+        split.push(Jump(block_label(&cfg, succ)).into());
+        let is_fallthrough = pred.0 + 1 == succ.0;
+        if is_fallthrough {
+            after.insert(pred, split);
+        } else {
+            if let Some(Instruction::Conditional(cond)) = cfg.block_mut(pred).body.last_mut() {
+                cond.label = label;
+            }
+            appended.push(split);
+        }
+    }
+
+    let mut body = vec![];
+    for id in cfg.ids() {
+        let mut block = std::mem::take(&mut cfg.block_mut(id).body);
+        block.retain(|i| !matches!(i, Instruction::Phi(..)));
+        if let Some(copies) = at_end.remove(&id) {
+            let at = match block.last() {
+                Some(i) if i.is_terminator() => block.len() - 1,
+                _ => block.len(),
+            };
+            block.splice(at..at, copies);
+        }
+        body.extend(block);
+        if let Some(split) = after.remove(&id) {
+            body.extend(split);
+        }
+    }
+    // The exit block ends in `Leave`, nothing falls through into these.
+    body.extend(appended.into_iter().flatten());
+    DefFunc { body, ..func }
+}
+
+/// Orders a set of copies that happen at the same time so no copy overwrites
+/// a register another copy still has to read, breaking cycles with a new
+/// register.
+fn sequentialize(copies: Vec<(Reg, Reg)>, next_reg: &mut usize) -> Vec<Instruction> {
+    let mut pending = copies
+        .into_iter()
+        .filter(|(to, from)| to != from)
+        .collect::<Vec<(Reg, Reg)>>();
+    let mut result = vec![];
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(to, _)| !pending.iter().any(|(_, from)| from == to));
+        if let Some(idx) = ready {
+            let (to, from) = pending.remove(idx);
+            result.push(Copy { to, from }.into());
+            continue;
+        }
+        // Every destination is still read by another copy, save one first.
+        let (to, _) = pending[0];
+        let tmp = Reg(*next_reg);
+        *next_reg += 1;
+        result.push(Copy { to: tmp, from: to }.into());
+        for (_, from) in pending.iter_mut().filter(|(_, from)| *from == to) {
+            *from = tmp;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{
+        code_gen, Add, Conditional, Enter, Imm, Inline, Leave, LoadImm, Return, Sub, Type,
+    };
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use pretty_assertions::assert_eq;
+
+    fn setup(src: &str) -> DefFunc {
+        let ast = lex(src).and_then(parse).unwrap();
+        let Some(Instruction::DefFunc(func)) = code_gen(ast).unwrap().pop() else {
+            panic!("expected a function");
+        };
+        func
+    }
+
+    fn assert_single_assignment(func: &DefFunc) {
+        let mut defined = func
+            .params
+            .iter()
+            .map(|(r, _)| *r)
+            .collect::<HashSet<Reg>>();
+        for reg in func.body.iter().filter_map(Instruction::def) {
+            assert!(defined.insert(reg), "{reg:?} is defined more than once");
+        }
+    }
+
+    fn phis(func: &DefFunc) -> Vec<&Phi> {
+        func.body
+            .iter()
+            .filter_map(|i| match i {
+                Instruction::Phi(phi) => Some(phi),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assignment_in_branch() {
+        let func = setup("fn main() { let a = 1; if a > 0 { a = 2; }; return a; }");
+        let ssa = construct_func(func);
+        assert_single_assignment(&ssa);
+        let phis = phis(&ssa);
+        assert_eq!(phis.len(), 1);
+        assert_eq!(phis[0].args.len(), 2);
+        let Some(Instruction::Return(Return(ret))) = ssa
+            .body
+            .iter()
+            .find(|i| matches!(i, Instruction::Return(..)))
+        else {
+            panic!("expected a return");
+        };
+        assert_eq!(*ret, phis[0].des);
+
+        let out = destruct_func(ssa);
+        assert!(out.body.iter().all(|i| !matches!(i, Instruction::Phi(..))));
+        // The edge from the condition to the join is critical and gets split.
+        let cfg = Cfg::new(&out);
+        let Some(Instruction::Conditional(Conditional { label, .. })) =
+            cfg.block(cfg.entry()).body.last()
+        else {
+            panic!("expected a conditional");
+        };
+        let split = cfg.block_by_label(label).unwrap();
+        assert!(matches!(cfg.block(split).body[1], Instruction::Copy(..)));
+        assert!(matches!(
+            cfg.block(split).terminator(),
+            Some(Instruction::Jump(..))
+        ));
+    }
+
+    #[test]
+    fn if_else_value() {
+        let func = setup("fn main() { let a = if 1 > 2 { 3; } else { 4; }; return a; }");
+        let ssa = construct_func(func);
+        assert_single_assignment(&ssa);
+        assert_eq!(phis(&ssa).len(), 1);
+    }
+
+    #[test]
+    fn dead_variable_gets_no_phi() {
+        let func = setup("fn main() { let a = 1; if a > 0 { a = 2; }; return 3; }");
+        let ssa = construct_func(func);
+        assert_single_assignment(&ssa);
+        assert!(phis(&ssa).is_empty());
+    }
+
+    #[test]
+    fn loop_header() {
+        // let acc = 0; while n { acc = acc + n; n = n - 1; } return acc;
+        let func = DefFunc {
+            name: "sum".into(),
+            ret: Type::I64,
+            params: vec![(Reg(0), Type::I64)],
+            body: vec![
+                Enter.into(),
+                LoadImm {
+                    des: Reg(1),
+                    imm: Imm(0),
+                }
+                .into(),
+                LoadImm {
+                    des: Reg(2),
+                    imm: Imm(1),
+                }
+                .into(),
+                DefLabel(".L0".into()).into(),
+                Conditional {
+                    label: ".L1".into(),
+                    reg: Reg(0),
+                }
+                .into(),
+                Add {
+                    des: Reg(1),
+                    lhs: Reg(1),
+                    rhs: Reg(0),
+                }
+                .into(),
+                Sub {
+                    des: Reg(0),
+                    lhs: Reg(0),
+                    rhs: Reg(2),
+                }
+                .into(),
+                Jump(".L0".into()).into(),
+                DefLabel(".L1".into()).into(),
+                Return(Reg(1)).into(),
+                DefLabel(".exit".into()).into(),
+                Leave.into(),
+            ],
+            inline: Inline::Auto,
+        };
+        let ssa = construct_func(func);
+        assert_single_assignment(&ssa);
+        let phis = phis(&ssa);
+        assert_eq!(phis.len(), 2);
+        let header = Label::from(".L0");
+        assert!(ssa
+            .body
+            .iter()
+            .skip_while(|i| i.as_label() != Some(&header))
+            .nth(1)
+            .is_some_and(|i| matches!(i, Instruction::Phi(..))));
+        // The parameter flows into the loop under its own name.
+        assert!(phis
+            .iter()
+            .any(|phi| phi.args.iter().any(|(reg, _)| *reg == Reg(0))));
+
+        let out = destruct_func(ssa);
+        let copies = out
+            .body
+            .iter()
+            .filter(|i| matches!(i, Instruction::Copy(..)))
+            .count();
+        assert_eq!(copies, 4);
+    }
+
+    #[test]
+    fn swap_copies() {
+        let mut next_reg = 10;
+        let copies = sequentialize(
+            vec![(Reg(1), Reg(2)), (Reg(2), Reg(1)), (Reg(3), Reg(3))],
+            &mut next_reg,
+        );
+        assert_eq!(
+            copies,
+            vec![
+                Copy {
+                    to: Reg(10),
+                    from: Reg(1)
+                }
+                .into(),
+                Copy {
+                    to: Reg(1),
+                    from: Reg(2)
+                }
+                .into(),
+                Copy {
+                    to: Reg(2),
+                    from: Reg(10)
+                }
+                .into(),
+            ]
+        );
+    }
+}
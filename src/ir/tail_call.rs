@@ -0,0 +1,189 @@
+//! Calls in tail position, `return f(x);`, become jumps.
+//!
+//! A function calling itself copies the arguments into its parameters and
+//! jumps back to the top of its body, turning the recursion into a loop. Any
+//! other call becomes a `TailCall`, which the backend lowers to a jump after
+//! tearing down the frame of the caller. Either way the stack does not grow.
+//! Runs before ssa construction so parameters can simply be assigned to.
+//!
+//! A function with an `Alloca` is left alone, the arguments may point into
+//! its slots which a jump would reuse or tear down.
+use crate::ir::cfg::Cfg;
+use crate::ir::ssa::next_reg;
+use crate::ir::{Call, Copy, DefFunc, DefLabel, Instruction, Jump, Label, Reg, Return, TailCall};
+
+/// Arguments past this are passed on the stack, which a sibling call can not
+/// reuse.
+const MAX_ARGS: usize = 6;
+
+pub fn tail_calls(code: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    Ok(super::map_funcs(code, tail_calls_func))
+}
+
+pub fn tail_calls_func(func: DefFunc) -> DefFunc {
+    if func
+        .body
+        .iter()
+        .any(|i| matches!(i, Instruction::Alloca(..)))
+    {
+        return func;
+    }
+    let mut reg_count = next_reg(&func);
+    let entry = Label(format!(".L{}", Cfg::new(&func).next_label_number()));
+    let mut loops = false;
+    let mut body = Vec::with_capacity(func.body.len());
+    let mut instructions = func.body.iter().peekable();
+    while let Some(instruction) = instructions.next() {
+        let (Instruction::Call(Call { caller, args, ret }), Some(Instruction::Return(Return(reg)))) =
+            (instruction, instructions.peek())
+        else {
+            body.push(instruction.clone());
+            continue;
+        };
+        if reg != ret {
+            body.push(instruction.clone());
+            continue;
+        }
+        if caller.0 == func.name && args.len() == func.params.len() {
+            // Every argument is read before any parameter is written since
+            // they may refer to each other, `f(b, a)`.
+            let temps = args
+                .iter()
+                .map(|_| {
+                    reg_count += 1;
+                    Reg(reg_count - 1)
+                })
+                .collect::<Vec<Reg>>();
+            for (to, from) in temps.iter().zip(args) {
+                body.push(
+                    Copy {
+                        to: *to,
+                        from: *from,
+                    }
+                    .into(),
+                );
+            }
+            for ((param, _), from) in func.params.iter().zip(temps) {
+                body.push(Copy { to: *param, from }.into());
+            }
+            body.push(Jump(entry.clone()).into());
+            loops = true;
+        } else if args.len() <= MAX_ARGS {
+            let caller = caller.clone();
+            let args = args.clone();
+            body.push(TailCall { caller, args }.into());
+        } else {
+            body.push(instruction.clone());
+            continue;
+        }
+        instructions.next();
+    }
+    if loops {
+        let at = body
+            .iter()
+            .position(|i| matches!(i, Instruction::Enter(..)))
+            .map_or(0, |i| i + 1);
+        body.insert(at, DefLabel(entry).into());
+    }
+    DefFunc { body, ..func }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::code_gen;
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use pretty_assertions::assert_eq;
+
+    fn setup(src: &str) -> Vec<DefFunc> {
+        lex(src)
+            .and_then(parse)
+            .and_then(code_gen)
+            .and_then(tail_calls)
+            .unwrap()
+            .into_iter()
+            .filter_map(|i| match i {
+                Instruction::DefFunc(func) => Some(func),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn count(func: &DefFunc, f: fn(&Instruction) -> bool) -> usize {
+        func.body.iter().filter(|i| f(i)).count()
+    }
+
+    #[test]
+    fn self_recursion_loops() {
+        let funcs = setup(
+            "fn count(n: u64, acc: u64) -> u64 {
+                if n > 0 { return count(n - 1, acc + 1); };
+                return acc;
+            }",
+        );
+        let func = &funcs[0];
+        assert_eq!(count(func, |i| matches!(i, Instruction::Call(..))), 0);
+        assert_eq!(func.body[1], DefLabel(Label(".L1".into())).into());
+        assert!(func.body.contains(&Jump(Label(".L1".into())).into()));
+    }
+
+    #[test]
+    fn sibling_call() {
+        let funcs = setup(
+            "fn even(n: u64) -> u64 { if n > 0 { return odd(n - 1); }; return 1; }
+             fn odd(n: u64) -> u64 { if n > 0 { return even(n - 1); }; return 0; }",
+        );
+        for func in funcs.iter() {
+            assert_eq!(count(func, |i| matches!(i, Instruction::Call(..))), 0);
+            assert_eq!(count(func, |i| matches!(i, Instruction::TailCall(..))), 1);
+        }
+    }
+
+    #[test]
+    fn not_in_tail_position() {
+        let funcs = setup("fn f(n: u64) -> u64 { if n > 0 { return f(n - 1) + 1; }; return 0; }");
+        assert_eq!(count(&funcs[0], |i| matches!(i, Instruction::Call(..))), 1);
+        assert_eq!(
+            count(&funcs[0], |i| matches!(i, Instruction::TailCall(..))),
+            0
+        );
+    }
+
+    #[test]
+    fn deep_recursion_in_constant_stack() {
+        use crate::x86_64_linux as x86;
+        let code = lex("fn count(n: u64, acc: u64) -> u64 {
+                if n > 0 { return count(n - 1, acc + 1); };
+                return acc;
+            }
+            fn even(n: u64) -> u64 { if n > 0 { return odd(n - 1); }; return 1; }
+            fn odd(n: u64) -> u64 { if n > 0 { return even(n - 1); }; return 0; }
+            fn main() { return count(10000000, 0) + even(10000000); }")
+        .and_then(parse)
+        .and_then(code_gen)
+        .and_then(tail_calls)
+        .and_then(crate::ir::ssa::construct)
+        .and_then(crate::ir::ssa::destruct)
+        .unwrap();
+        // Ten million frames are far more than the vm allows and than fit in
+        // the stack of a native program.
+        let program = crate::vm::lower(code.clone()).unwrap();
+        assert_eq!(crate::vm::run(&program, &[]), Ok(10000001));
+        let elf = x86::compile_ir_code(code)
+            .map(x86::with_runtime)
+            .and_then(x86::with_start_func)
+            .and_then(x86::assemble)
+            .and_then(x86::elf::write_executable)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("tail_call_{}", std::process::id()));
+        std::fs::write(&path, elf).unwrap();
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let status = std::process::Command::new(&path).status();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status.unwrap().code(), Some(10000001 & 0xff));
+    }
+}
@@ -24,3 +24,9 @@ macro_rules! snapshot {
 
 snapshot!(binary, "testdata/snapshots/binary.a");
 snapshot!(ifelse, "testdata/snapshots/ifelse.a");
+snapshot!(var, "testdata/snapshots/var.a");
+snapshot!(char, "testdata/snapshots/char.a");
+snapshot!(structs, "testdata/snapshots/struct.a");
+snapshot!(arrays, "testdata/snapshots/array.a");
+snapshot!(enums, "testdata/snapshots/enum.a");
+snapshot!(refs, "testdata/snapshots/ref.a");
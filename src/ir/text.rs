@@ -0,0 +1,545 @@
+//! Textual form of the ir, written with `--emit=ir` and read back with
+//! `--from-ir`.
+//!
+//! ```text
+//! #[inline]
+//! fn add(%0: i64, %1: i64) -> i64 {
+//!     enter
+//!     %2 = add %0, %1
+//!     ret %2
+//! .exit:
+//!     leave
+//! }
+//! ```
+//!
+//! Every instruction is on a line of its own and labels are not indented.
+//! `br %0, .L1` jumps to `.L1` when `%0` is zero. `load.4 %0, 8` reads the 4
+//! bytes 8 past the address in `%0` and `store.4 %0, 8, %1` writes them.
+//! A parameter or return type `agg16` is the address of 16 bytes passed by
+//! value.
+//! Lines starting with `;` are comments. String literals use the escapes of
+//! the source language.
+use std::fmt;
+
+use crate::lexer::lex;
+use crate::parse::LitStr;
+
+use super::{
+    Add, Alloca, Call, Conditional, Copy, DefFunc, DefLabel, Div, Enter, Grt, Imm, Inline,
+    Instruction, Jump, Label, Leave, Load, LoadImm, LoadStr, Mul, Phi, Reg, Return, Store, Sub,
+    TailCall, Type,
+};
+
+pub fn print(code: &[Instruction]) -> String {
+    code.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for Imm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::I64 => write!(f, "i64"),
+            Self::Aggregate(size) => write!(f, "agg{size}"),
+        }
+    }
+}
+
+fn join<T: fmt::Display>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl fmt::Display for DefFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let DefFunc {
+            name,
+            ret,
+            params,
+            body,
+            inline,
+        } = self;
+        match inline {
+            Inline::Auto => {}
+            Inline::Always => writeln!(f, "#[inline]")?,
+            Inline::Never => writeln!(f, "#[inline(never)]")?,
+        }
+        let params = join(params.iter().map(|(reg, ty)| format!("{reg}: {ty}")));
+        writeln!(f, "fn {name}({params}) -> {ret} {{")?;
+        for instruction in body {
+            match instruction {
+                Instruction::DefLabel(..) => writeln!(f, "{instruction}")?,
+                _ => writeln!(f, "    {instruction}")?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DefFunc(func) => write!(f, "{func}"),
+            Self::LoadImm(LoadImm { des, imm }) => write!(f, "{des} = imm {imm}"),
+            Self::LoadStr(LoadStr { des, value }) => write!(f, "{des} = str {value:?}"),
+            Self::Add(Add { des, lhs, rhs }) => write!(f, "{des} = add {lhs}, {rhs}"),
+            Self::Sub(Sub { des, lhs, rhs }) => write!(f, "{des} = sub {lhs}, {rhs}"),
+            Self::Mul(Mul { des, lhs, rhs }) => write!(f, "{des} = mul {lhs}, {rhs}"),
+            Self::Div(Div { des, lhs, rhs }) => write!(f, "{des} = div {lhs}, {rhs}"),
+            Self::Grt(Grt { des, lhs, rhs }) => write!(f, "{des} = grt {lhs}, {rhs}"),
+            Self::Copy(Copy { to, from }) => write!(f, "{to} = copy {from}"),
+            Self::Conditional(Conditional { label, reg }) => write!(f, "br {reg}, {label}"),
+            Self::Jump(Jump(label)) => write!(f, "jmp {label}"),
+            Self::DefLabel(DefLabel(label)) => write!(f, "{label}:"),
+            Self::Call(Call { caller, args, ret }) => {
+                write!(f, "{ret} = call {caller}({})", join(args))
+            }
+            Self::TailCall(TailCall { caller, args }) => {
+                write!(f, "tail {caller}({})", join(args))
+            }
+            Self::Return(Return(reg)) => write!(f, "ret {reg}"),
+            Self::Enter(..) => write!(f, "enter"),
+            Self::Leave(..) => write!(f, "leave"),
+            Self::Phi(Phi { des, args }) => {
+                let args = join(args.iter().map(|(reg, label)| format!("[{reg}, {label}]")));
+                write!(f, "{des} = phi {args}")
+            }
+            Self::Alloca(Alloca { des, size }) => write!(f, "{des} = alloca {size}"),
+            Self::Load(Load {
+                des,
+                addr,
+                offset,
+                size,
+            }) => write!(f, "{des} = load.{size} {addr}, {offset}"),
+            Self::Store(Store {
+                addr,
+                offset,
+                src,
+                size,
+            }) => write!(f, "store.{size} {addr}, {offset}, {src}"),
+        }
+    }
+}
+
+pub fn parse(src: impl AsRef<str>) -> Result<Vec<Instruction>, Vec<String>> {
+    let mut parser = Parser::default();
+    let mut errors = vec![];
+    for (i, line) in src.as_ref().lines().enumerate() {
+        if let Err(e) = parser.line(line) {
+            errors.push(format!("line {}: {e}", i + 1));
+        }
+    }
+    if let Some(func) = parser.func {
+        errors.push(format!(
+            "function '{}' is missing a closing '}}'",
+            func.name
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(parser.code)
+}
+
+const PUNCTUATION: &str = "()[],:={}#";
+
+fn tokenize(line: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut rest = line.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if PUNCTUATION.contains(c) {
+            1
+        } else {
+            rest.find(|c: char| c.is_whitespace() || PUNCTUATION.contains(c))
+                .unwrap_or(rest.len())
+        };
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    tokens
+}
+
+fn reg(token: &str) -> Result<Reg, String> {
+    token
+        .strip_prefix('%')
+        .and_then(|n| n.parse().ok())
+        .map(Reg)
+        .ok_or_else(|| format!("expected a register, found '{token}'"))
+}
+
+fn ty(token: &str) -> Result<Type, String> {
+    match token {
+        "i64" => Ok(Type::I64),
+        _ => token
+            .strip_prefix("agg")
+            .and_then(|size| size.parse().ok())
+            .map(Type::Aggregate)
+            .ok_or_else(|| format!("unknown type '{token}'")),
+    }
+}
+
+/// Splits `a, b, c)` into its comma separated items and whatever comes after
+/// the closing parenthesis.
+fn list<'a, 'b>(tokens: &'a [&'b str]) -> Result<(Vec<&'a [&'b str]>, &'a [&'b str]), String> {
+    let Some(close) = tokens.iter().position(|t| *t == ")") else {
+        return Err("expected ')'".into());
+    };
+    let items = match &tokens[..close] {
+        [] => vec![],
+        inner => inner.split(|t| *t == ",").collect(),
+    };
+    Ok((items, &tokens[close + 1..]))
+}
+
+/// A quoted string literal, decoded by the lexer.
+fn string(literal: &str) -> Result<String, String> {
+    let tokens = lex(literal).map_err(|e| e.join(", "))?;
+    match &tokens.stream[..] {
+        [token] if literal.starts_with('"') => token
+            .as_any()
+            .downcast_ref::<LitStr>()
+            .map(|lit| lit.value.clone())
+            .ok_or_else(|| format!("expected a string, found '{literal}'")),
+        _ => Err(format!("expected a string, found '{literal}'")),
+    }
+}
+
+fn number<T: std::str::FromStr>(token: &str) -> Result<T, String> {
+    token
+        .parse::<T>()
+        .map_err(|_| format!("expected a number, found '{token}'"))
+}
+
+/// The size in `load.<size>` and `store.<size>`.
+fn access(op: &str, token: &str) -> Result<u8, String> {
+    match token.strip_prefix(op).and_then(|t| t.strip_prefix('.')) {
+        Some(size @ ("1" | "4" | "8")) => number(size),
+        _ => Err(format!(
+            "expected {op}.1, {op}.4 or {op}.8, found '{token}'"
+        )),
+    }
+}
+
+fn args(tokens: &[&str]) -> Result<Vec<Reg>, String> {
+    match list(tokens)? {
+        (items, []) => items
+            .into_iter()
+            .map(|item| match item {
+                [r] => reg(r),
+                _ => Err(format!("expected a register, found '{}'", item.join(" "))),
+            })
+            .collect(),
+        (_, rest) => Err(format!("unexpected '{}' after ')'", rest.join(" "))),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+    code: Vec<Instruction>,
+    func: Option<DefFunc>,
+    inline: Inline,
+}
+
+impl Parser {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        // The literal may contain anything, so it is split off before the
+        // rest of the line is tokenized.
+        if let Some((des, literal)) = line.split_once(" = str ") {
+            let instruction = LoadStr {
+                des: reg(des.trim())?,
+                value: string(literal.trim())?,
+            };
+            return self.push(instruction.into());
+        }
+        let tokens = tokenize(line);
+        match tokens[..] {
+            [] => Ok(()),
+            [t, ..] if t.starts_with(';') => Ok(()),
+            ["#", "[", "inline", "]"] => {
+                self.inline = Inline::Always;
+                Ok(())
+            }
+            ["#", "[", "inline", "(", "never", ")", "]"] => {
+                self.inline = Inline::Never;
+                Ok(())
+            }
+            ["fn", name, "(", ref rest @ ..] => self.start_func(name, rest),
+            ["}"] => match self.func.take() {
+                Some(func) => {
+                    self.code.push(func.into());
+                    Ok(())
+                }
+                None => Err("unexpected '}'".into()),
+            },
+            _ => self.push(instruction(&tokens)?),
+        }
+    }
+
+    fn push(&mut self, instruction: Instruction) -> Result<(), String> {
+        let Some(func) = self.func.as_mut() else {
+            return Err("instruction outside of a function".into());
+        };
+        func.body.push(instruction);
+        Ok(())
+    }
+
+    fn start_func(&mut self, name: &str, rest: &[&str]) -> Result<(), String> {
+        if let Some(func) = &self.func {
+            return Err(format!(
+                "function '{}' is missing a closing '}}'",
+                func.name
+            ));
+        }
+        let (items, rest) = list(rest)?;
+        let params = items
+            .into_iter()
+            .map(|item| match item {
+                [r, ":", t] => Ok((reg(r)?, ty(t)?)),
+                _ => Err(format!("expected a parameter, found '{}'", item.join(" "))),
+            })
+            .collect::<Result<Vec<(Reg, Type)>, String>>()?;
+        let ["->", ret, "{"] = rest[..] else {
+            return Err("expected '-> <type> {' after the parameters".into());
+        };
+        self.func = Some(DefFunc {
+            name: name.into(),
+            ret: ty(ret)?,
+            params,
+            body: vec![],
+            inline: std::mem::take(&mut self.inline),
+        });
+        Ok(())
+    }
+}
+
+fn instruction(tokens: &[&str]) -> Result<Instruction, String> {
+    Ok(match *tokens {
+        [label, ":"] => DefLabel(label.into()).into(),
+        ["enter"] => Enter.into(),
+        ["leave"] => Leave.into(),
+        ["ret", r] => Return(reg(r)?).into(),
+        ["jmp", label] => Jump(label.into()).into(),
+        ["br", r, ",", label] => Conditional {
+            label: label.into(),
+            reg: reg(r)?,
+        }
+        .into(),
+        ["tail", caller, "(", ref rest @ ..] => TailCall {
+            caller: caller.into(),
+            args: args(rest)?,
+        }
+        .into(),
+        [store, addr, ",", offset, ",", src] if store.starts_with("store") => Store {
+            addr: reg(addr)?,
+            offset: number(offset)?,
+            src: reg(src)?,
+            size: access("store", store)?,
+        }
+        .into(),
+        [des, "=", ref rest @ ..] => assignment(reg(des)?, rest)?,
+        _ => return Err(format!("unknown instruction '{}'", tokens.join(" "))),
+    })
+}
+
+fn assignment(des: Reg, tokens: &[&str]) -> Result<Instruction, String> {
+    Ok(match *tokens {
+        ["imm", n] => {
+            let imm = n
+                .parse::<u64>()
+                .map_err(|_| format!("expected a number, found '{n}'"))?;
+            LoadImm {
+                des,
+                imm: imm.into(),
+            }
+            .into()
+        }
+        [op, lhs, ",", rhs] if matches!(op, "add" | "sub" | "mul" | "div" | "grt") => {
+            let (lhs, rhs) = (reg(lhs)?, reg(rhs)?);
+            match op {
+                "add" => Add { des, lhs, rhs }.into(),
+                "sub" => Sub { des, lhs, rhs }.into(),
+                "mul" => Mul { des, lhs, rhs }.into(),
+                "div" => Div { des, lhs, rhs }.into(),
+                _ => Grt { des, lhs, rhs }.into(),
+            }
+        }
+        ["alloca", size] => Alloca {
+            des,
+            size: number(size)?,
+        }
+        .into(),
+        [load, addr, ",", offset] if load.starts_with("load") => Load {
+            des,
+            addr: reg(addr)?,
+            offset: number(offset)?,
+            size: access("load", load)?,
+        }
+        .into(),
+        ["copy", from] => Copy {
+            to: des,
+            from: reg(from)?,
+        }
+        .into(),
+        ["call", caller, "(", ref rest @ ..] => Call {
+            caller: caller.into(),
+            args: args(rest)?,
+            ret: des,
+        }
+        .into(),
+        ["phi", ref rest @ ..] => Phi {
+            des,
+            args: rest
+                .split(|t| *t == ",")
+                .collect::<Vec<&[&str]>>()
+                .chunks(2)
+                .map(|arg| match arg {
+                    [["[", r], [label, "]"]] => Ok((reg(r)?, Label::from(*label))),
+                    _ => Err("expected '[%reg, label]' in phi".to_string()),
+                })
+                .collect::<Result<Vec<(Reg, Label)>, String>>()?,
+        }
+        .into(),
+        _ => {
+            return Err(format!(
+                "unknown instruction '{des} = {}'",
+                tokens.join(" ")
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{code_gen, ssa};
+    use crate::lexer::lex;
+    use crate::parse::parse as parse_source;
+    use pretty_assertions::assert_eq;
+
+    const SOURCE: &str = "
+        #[inline(never)]
+        fn max(a: u64, b: u64) -> u64 { if a > b { return a; } else { return b; }; }
+        fn count(n: u64) -> u64 {
+            let i = 0;
+            while n > i { i = i + max(i, 1) * 2 / 2 - 1; };
+            return i;
+        }
+        fn main() { return count(10); }";
+
+    fn code_gen_source(src: &str) -> Vec<Instruction> {
+        lex(src).and_then(parse_source).and_then(code_gen).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let code = code_gen_source(SOURCE);
+        assert_eq!(parse(print(&code)), Ok(code));
+    }
+
+    #[test]
+    fn round_trip_ssa() {
+        let code = ssa::construct(code_gen_source(SOURCE)).unwrap();
+        assert!(print(&code).contains(" = phi ["));
+        assert_eq!(parse(print(&code)), Ok(code));
+    }
+
+    #[test]
+    fn round_trip_strings() {
+        let code = code_gen_source(r#"fn main() { "a\"b\n\u{e9}"; return 0; }"#);
+        assert!(print(&code).contains(r#" = str "a\"b\né""#));
+        assert_eq!(parse(print(&code)), Ok(code));
+    }
+
+    #[test]
+    fn round_trip_structs() {
+        let code = code_gen_source(
+            "struct P { x: u8, y: u32, z: i64 }
+             fn get(p: P) -> u32 { return p.y; }
+             fn main() { let p = P { x: 1, y: 2, z: 3 }; p.z = 4; return get(p); }",
+        );
+        let text = print(&code);
+        assert!(text.contains(" = alloca 16"));
+        assert!(text.contains(" = load.4 %0, 4"));
+        assert!(text.contains("store.1 %"));
+        assert_eq!(parse(text), Ok(code));
+    }
+
+    #[test]
+    fn round_trip_arrays() {
+        let code =
+            code_gen_source("fn main() { let xs = [0; 9]; xs[arg_count()] = 1; return xs[8]; }");
+        let text = print(&code);
+        assert!(text.contains(" = alloca 72"));
+        assert!(text.contains("call __index_out_of_bounds("));
+        assert_eq!(parse(text), Ok(code));
+    }
+
+    #[test]
+    fn hand_written() {
+        let ir = "#[inline]
+fn inc(%0: i64) -> i64 {
+    enter
+    %1 = imm 1
+    %2 = add %0, %1
+    ret %2
+.exit:
+    leave
+}
+";
+        let Ok(code) = parse(format!("; adds one to its argument\n\n{ir}")) else {
+            panic!("expected the ir to parse");
+        };
+        let [Instruction::DefFunc(func)] = &code[..] else {
+            panic!("expected one function");
+        };
+        assert_eq!(func.inline, Inline::Always);
+        assert_eq!(func.params, [(Reg(0), Type::I64)]);
+        assert_eq!(
+            func.body[2],
+            Add {
+                des: Reg(2),
+                lhs: Reg(0),
+                rhs: Reg(1)
+            }
+            .into()
+        );
+        assert_eq!(print(&code), ir);
+    }
+
+    #[test]
+    fn errors() {
+        let src = "fn f(%0: i64) -> i64 {
+    %1 = imm x
+    %2 = add %0
+    jmp
+}
+    ret %0
+fn g() -> i64 {";
+        assert_eq!(
+            parse(src),
+            Err(vec![
+                "line 2: expected a number, found 'x'".to_string(),
+                "line 3: unknown instruction '%2 = add %0'".to_string(),
+                "line 4: unknown instruction 'jmp'".to_string(),
+                "line 6: instruction outside of a function".to_string(),
+                "function 'g' is missing a closing '}'".to_string(),
+            ])
+        );
+    }
+}
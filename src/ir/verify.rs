@@ -0,0 +1,629 @@
+//! Checks that ir is well formed.
+//!
+//! Run on the output of every pass in debug builds so a broken pass is caught
+//! where it happens instead of as a crash somewhere in the backend.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::cfg::{BlockId, Cfg, Dominators, EXIT_LABEL};
+use super::{
+    Add, Call, Conditional, Copy, DefFunc, DefLabel, Div, Grt, Instruction, Jump, Label, Mul, Phi,
+    Reg, Return, Sub, TailCall, Type,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Name of the function the error is in, empty for instructions outside
+    /// of any function.
+    pub func: String,
+    /// Index of the offending instruction in the function body.
+    pub at: Option<usize>,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    OutsideFunction,
+    NestedFunction,
+    /// The body does not start with `enter`.
+    MissingEnter,
+    /// The body has no exit label followed by `leave`. Blocks `ssa::destruct`
+    /// splits off may come after it.
+    MissingExit,
+    UndefinedLabel(Label),
+    DuplicateLabel(Label),
+    ArgumentCount {
+        callee: Label,
+        expected: usize,
+        found: usize,
+    },
+    /// A register is read on a path where it was never written.
+    UseBeforeDef(Reg),
+    TypeMismatch {
+        reg: Reg,
+        expected: Type,
+        found: Type,
+    },
+    /// Something other than a label follows a `jmp`, `ret` or `tail`.
+    AfterTerminator,
+    /// A phi comes after something other than a label or another phi.
+    MisplacedPhi,
+    /// The labels of a phi do not match the predecessors of its block.
+    PhiPredecessors(Reg),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutsideFunction => write!(f, "instruction outside of a function"),
+            Self::NestedFunction => write!(f, "function defined inside a function"),
+            Self::MissingEnter => write!(f, "body does not start with 'enter'"),
+            Self::MissingExit => write!(f, "body has no '{EXIT_LABEL}: leave' block"),
+            Self::UndefinedLabel(label) => write!(f, "label '{label}' is never defined"),
+            Self::DuplicateLabel(label) => write!(f, "label '{label}' is defined more than once"),
+            Self::ArgumentCount {
+                callee,
+                expected,
+                found,
+            } => write!(
+                f,
+                "'{callee}' takes {expected} arguments but is given {found}"
+            ),
+            Self::UseBeforeDef(reg) => write!(f, "{reg} is used before it is defined"),
+            Self::TypeMismatch {
+                reg,
+                expected,
+                found,
+            } => write!(f, "{reg} is {found} but {expected} is expected"),
+            Self::AfterTerminator => write!(f, "instruction after a terminator needs a label"),
+            Self::MisplacedPhi => write!(f, "phi after the start of a block"),
+            Self::PhiPredecessors(reg) => {
+                write!(f, "phi {reg} does not match the predecessors of its block")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.at {
+            Some(at) => write!(f, "{} at {at}: {}", self.func, self.kind),
+            None => write!(f, "{}: {}", self.func, self.kind),
+        }
+    }
+}
+
+pub fn verify(code: &[Instruction]) -> Result<(), Vec<Error>> {
+    let signatures = code
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::DefFunc(func) => Some((func.name.as_str(), func)),
+            _ => None,
+        })
+        .collect::<HashMap<&str, &DefFunc>>();
+    let mut errors = vec![];
+    for (i, instruction) in code.iter().enumerate() {
+        match instruction {
+            Instruction::DefFunc(func) => {
+                let mut verifier = Verifier {
+                    func,
+                    signatures: &signatures,
+                    errors: vec![],
+                };
+                verifier.verify();
+                errors.extend(verifier.errors);
+            }
+            _ => errors.push(Error {
+                func: String::new(),
+                at: Some(i),
+                kind: ErrorKind::OutsideFunction,
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Verifies `code` in debug builds, naming the pass that produced it in the
+/// errors.
+pub fn debug_verify(code: &[Instruction], pass: &str) -> Result<(), Vec<String>> {
+    if !cfg!(debug_assertions) {
+        return Ok(());
+    }
+    verify(code).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| format!("invalid ir after {pass}: {e}"))
+            .collect()
+    })
+}
+
+struct Verifier<'a> {
+    func: &'a DefFunc,
+    signatures: &'a HashMap<&'a str, &'a DefFunc>,
+    errors: Vec<Error>,
+}
+
+impl Verifier<'_> {
+    fn error(&mut self, at: Option<usize>, kind: ErrorKind) {
+        self.errors.push(Error {
+            func: self.func.name.clone(),
+            at,
+            kind,
+        });
+    }
+
+    fn verify(&mut self) {
+        self.layout();
+        self.labels();
+        self.calls();
+        self.types();
+        self.definitions();
+    }
+
+    fn layout(&mut self) {
+        let body = &self.func.body;
+        let first = body.iter().position(|i| i.as_label().is_none());
+        if !first.is_some_and(|i| matches!(body[i], Instruction::Enter(..))) {
+            self.error(first, ErrorKind::MissingEnter);
+        }
+        let exit = Label::from(EXIT_LABEL);
+        let has_exit = body.windows(2).any(|pair| {
+            matches!(pair, [Instruction::DefLabel(DefLabel(label)), Instruction::Leave(..)] if *label == exit)
+        });
+        if !has_exit {
+            self.error(None, ErrorKind::MissingExit);
+        }
+        for (i, pair) in body.windows(2).enumerate() {
+            let kind = match pair {
+                [_, Instruction::DefFunc(..)] => ErrorKind::NestedFunction,
+                [Instruction::Jump(..) | Instruction::Return(..) | Instruction::TailCall(..), next]
+                    if next.as_label().is_none() =>
+                {
+                    ErrorKind::AfterTerminator
+                }
+                [before, Instruction::Phi(..)]
+                    if !matches!(before, Instruction::DefLabel(..) | Instruction::Phi(..)) =>
+                {
+                    ErrorKind::MisplacedPhi
+                }
+                _ => continue,
+            };
+            self.error(Some(i + 1), kind);
+        }
+    }
+
+    fn labels(&mut self) {
+        let mut defined = HashSet::new();
+        for (i, label) in self
+            .func
+            .body
+            .iter()
+            .enumerate()
+            .filter_map(|(i, inst)| inst.as_label().map(|l| (i, l)))
+        {
+            if !defined.insert(label) {
+                self.error(Some(i), ErrorKind::DuplicateLabel(label.clone()));
+            }
+        }
+        for (i, instruction) in self.func.body.iter().enumerate() {
+            let targets = match instruction {
+                Instruction::Jump(Jump(label))
+                | Instruction::Conditional(Conditional { label, .. }) => vec![label],
+                Instruction::Phi(Phi { args, .. }) => args.iter().map(|(_, l)| l).collect(),
+                _ => vec![],
+            };
+            for label in targets.into_iter().filter(|l| !defined.contains(l)) {
+                self.error(Some(i), ErrorKind::UndefinedLabel(label.clone()));
+            }
+        }
+    }
+
+    fn calls(&mut self) {
+        for (i, instruction) in self.func.body.iter().enumerate() {
+            let (Instruction::Call(Call { caller, args, .. })
+            | Instruction::TailCall(TailCall { caller, args })) = instruction
+            else {
+                continue;
+            };
+            // Functions not defined here may come from another object file,
+            // they are checked when linking.
+            let Some(callee) = self.signatures.get(caller.0.as_str()) else {
+                continue;
+            };
+            if callee.params.len() != args.len() {
+                let kind = ErrorKind::ArgumentCount {
+                    callee: caller.clone(),
+                    expected: callee.params.len(),
+                    found: args.len(),
+                };
+                self.error(Some(i), kind);
+            }
+        }
+    }
+
+    fn types(&mut self) {
+        // Everything but parameters and calls produces an integer.
+        let mut types = self
+            .func
+            .params
+            .iter()
+            .cloned()
+            .collect::<HashMap<Reg, Type>>();
+        for instruction in self.func.body.iter() {
+            match instruction {
+                Instruction::Call(Call { caller, ret, .. }) => {
+                    let ty = self
+                        .signatures
+                        .get(caller.0.as_str())
+                        .map_or(Type::I64, |f| f.ret.clone());
+                    types.insert(*ret, ty);
+                }
+                instruction => {
+                    if let Some(des) = instruction.def() {
+                        types.entry(des).or_insert(Type::I64);
+                    }
+                }
+            }
+        }
+
+        for (i, instruction) in self.func.body.iter().enumerate() {
+            let expected = match instruction {
+                Instruction::Add(Add { lhs, rhs, .. })
+                | Instruction::Sub(Sub { lhs, rhs, .. })
+                | Instruction::Mul(Mul { lhs, rhs, .. })
+                | Instruction::Div(Div { lhs, rhs, .. })
+                | Instruction::Grt(Grt { lhs, rhs, .. }) => {
+                    vec![(*lhs, Type::I64), (*rhs, Type::I64)]
+                }
+                Instruction::Conditional(Conditional { reg, .. }) => vec![(*reg, Type::I64)],
+                Instruction::Return(Return(reg)) => vec![(*reg, self.func.ret.clone())],
+                Instruction::Copy(Copy { to, from }) => types
+                    .get(to)
+                    .map(|t| (*from, t.clone()))
+                    .into_iter()
+                    .collect(),
+                Instruction::Phi(Phi { des, args }) => args
+                    .iter()
+                    .filter_map(|(r, _)| types.get(des).map(|t| (*r, t.clone())))
+                    .collect(),
+                Instruction::Call(Call { caller, args, .. })
+                | Instruction::TailCall(TailCall { caller, args }) => self
+                    .signatures
+                    .get(caller.0.as_str())
+                    .map(|f| f.params.iter().map(|(_, t)| t.clone()))
+                    .into_iter()
+                    .flatten()
+                    .zip(args)
+                    .map(|(t, r)| (*r, t))
+                    .collect(),
+                _ => vec![],
+            };
+            for (reg, expected) in expected {
+                match types.get(&reg) {
+                    // Any integer may be the address of an aggregate.
+                    Some(Type::Aggregate(_)) | None => {}
+                    Some(_) if matches!(expected, Type::Aggregate(_)) => {}
+                    Some(found) if *found != expected => {
+                        let found = found.clone();
+                        self.error(
+                            Some(i),
+                            ErrorKind::TypeMismatch {
+                                reg,
+                                expected,
+                                found,
+                            },
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Every register read must be written on all paths leading to the read.
+    /// Blocks that can't be reached are never complained about.
+    fn definitions(&mut self) {
+        let cfg = Cfg::new(self.func);
+        let doms = Dominators::new(&cfg);
+        let params = self
+            .func
+            .params
+            .iter()
+            .map(|(r, _)| *r)
+            .collect::<HashSet<Reg>>();
+        let all = self
+            .func
+            .body
+            .iter()
+            .filter_map(Instruction::def)
+            .chain(params.iter().copied())
+            .collect::<HashSet<Reg>>();
+        let defs = |id: BlockId| cfg.block(id).body.iter().filter_map(Instruction::def);
+
+        let mut defined_out = vec![all; cfg.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &id in doms.reverse_postorder() {
+                let mut defined = if id == cfg.entry() {
+                    params.clone()
+                } else {
+                    let mut preds = cfg.predecessors(id).iter();
+                    let first = preds.next().map(|p| defined_out[p.0].clone());
+                    preds.fold(first.unwrap_or_default(), |acc, p| {
+                        acc.intersection(&defined_out[p.0]).copied().collect()
+                    })
+                };
+                defined.extend(defs(id));
+                if defined != defined_out[id.0] {
+                    defined_out[id.0] = defined;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut start = 0;
+        let mut errors = vec![];
+        for id in cfg.ids() {
+            let block = cfg.block(id);
+            let offset = start;
+            start += block.body.len();
+            if !doms.is_reachable(id) {
+                continue;
+            }
+            let pred_labels = cfg
+                .predecessors(id)
+                .iter()
+                .filter(|p| doms.is_reachable(**p))
+                .map(|p| (cfg.block(*p).label(), *p))
+                .collect::<Vec<(Option<&Label>, BlockId)>>();
+            let mut defined = if id == cfg.entry() {
+                params.clone()
+            } else {
+                let mut preds = pred_labels.iter().map(|(_, p)| &defined_out[p.0]);
+                let first = preds.next().cloned().unwrap_or_default();
+                preds.fold(first, |acc, d| acc.intersection(d).copied().collect())
+            };
+            for (i, instruction) in block.body.iter().enumerate() {
+                let at = Some(offset + i);
+                if let Instruction::Phi(Phi { des, args }) = instruction {
+                    let matches = pred_labels.len() == args.len()
+                        && pred_labels.iter().all(|(label, _)| {
+                            label.is_some_and(|l| args.iter().any(|(_, a)| a == l))
+                        });
+                    if !matches {
+                        errors.push((at, ErrorKind::PhiPredecessors(*des)));
+                    }
+                    for (reg, label) in args {
+                        let pred = pred_labels.iter().find(|(l, _)| *l == Some(label));
+                        if pred.is_some_and(|(_, p)| !defined_out[p.0].contains(reg)) {
+                            errors.push((at, ErrorKind::UseBeforeDef(*reg)));
+                        }
+                    }
+                } else {
+                    for reg in instruction.uses() {
+                        if !defined.contains(&reg) {
+                            errors.push((at, ErrorKind::UseBeforeDef(reg)));
+                        }
+                    }
+                }
+                defined.extend(instruction.def());
+            }
+        }
+        for (at, kind) in errors {
+            self.error(at, kind);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::text::parse;
+    use pretty_assertions::assert_eq;
+
+    fn errors(src: &str) -> Vec<(Option<usize>, ErrorKind)> {
+        let code = parse(src).unwrap();
+        match verify(&code) {
+            Ok(()) => vec![],
+            Err(errors) => errors.into_iter().map(|e| (e.at, e.kind)).collect(),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let src = "
+fn max(%0: i64, %1: i64) -> i64 {
+.L0:
+    enter
+    %2 = grt %0, %1
+    br %2, .L1
+.L2:
+    jmp .L3
+.L1:
+    jmp .L3
+.L3:
+    %3 = phi [%0, .L2], [%1, .L1]
+    ret %3
+.exit:
+    leave
+}
+fn main() -> i64 {
+    enter
+    %0 = imm 1
+    %1 = call max(%0, %0)
+    tail max(%1, %0)
+.exit:
+    leave
+}";
+        assert_eq!(errors(src), []);
+    }
+
+    #[test]
+    fn every_pass() {
+        use crate::ir::{code_gen, opt, ssa, tail_calls};
+        use crate::lexer::lex;
+        let code = lex("fn f(n: u64) -> u64 {
+                let i = 0;
+                let sum = 0;
+                while n > i {
+                    sum = sum + i * 4 + n * n;
+                    i = i + 1;
+                };
+                return sum;
+                sum;
+            }
+            fn count(n: u64, acc: u64) -> u64 {
+                if n > 0 { return count(n - 1, acc + 1); };
+                return acc;
+            }
+            fn main() { if f(3) > 2 { return count(3, 0) / 2; } else { return f(2); }; }")
+        .and_then(crate::parse::parse)
+        .and_then(code_gen)
+        .unwrap();
+        let passes: [(&str, fn(_) -> _); 5] = [
+            ("inline", opt::inline),
+            ("tail_calls", tail_calls),
+            ("ssa::construct", ssa::construct),
+            ("optimize", |code| opt::optimize(code).map(|(code, _)| code)),
+            ("ssa::destruct", ssa::destruct),
+        ];
+        let mut code = code;
+        assert_eq!(verify(&code), Ok(()));
+        for (name, pass) in passes {
+            code = pass(code).unwrap();
+            assert_eq!(verify(&code), Ok(()), "after {name}");
+        }
+    }
+
+    /// `ssa::destruct` puts the blocks it splits edges with after the exit.
+    #[test]
+    fn split_edges_after_exit() {
+        use crate::ir::{code_gen, opt, ssa};
+        use crate::lexer::lex;
+        for path in [
+            "src/ir/testdata/snapshots/var.a",
+            "src/vm/testdata/snapshots/loop.a",
+        ] {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+            let src = std::fs::read_to_string(path).unwrap();
+            let code = lex(&src)
+                .and_then(crate::parse::parse)
+                .and_then(code_gen)
+                .and_then(ssa::construct)
+                .unwrap();
+            let optimized = opt::optimize(code.clone()).unwrap().0;
+            for code in [code, optimized] {
+                let code = ssa::destruct(code).unwrap();
+                assert_eq!(debug_verify(&code, "ssa::destruct"), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn use_on_one_path_only() {
+        let src = "
+fn f(%0: i64) -> i64 {
+    enter
+    br %0, .L0
+    %1 = imm 1
+.L0:
+    ret %1
+.exit:
+    leave
+}";
+        assert_eq!(errors(src), [(Some(4), ErrorKind::UseBeforeDef(Reg(1)))]);
+    }
+
+    #[test]
+    fn loops() {
+        let src = "
+fn f(%0: i64) -> i64 {
+    enter
+    %1 = imm 0
+.L0:
+    %2 = add %1, %3
+    %3 = imm 1
+    br %0, .L0
+    ret %2
+.exit:
+    leave
+}";
+        assert_eq!(errors(src), [(Some(3), ErrorKind::UseBeforeDef(Reg(3)))]);
+    }
+
+    #[test]
+    fn labels_and_calls() {
+        let src = "
+fn f(%0: i64) -> i64 {
+    enter
+    %1 = call g(%0)
+    %2 = call f(%0, %1)
+    jmp .L9
+.L0:
+.L0:
+.exit:
+    leave
+}";
+        assert_eq!(
+            errors(src),
+            [
+                (Some(5), ErrorKind::DuplicateLabel(".L0".into())),
+                (Some(3), ErrorKind::UndefinedLabel(".L9".into())),
+                (
+                    Some(2),
+                    ErrorKind::ArgumentCount {
+                        callee: "f".into(),
+                        expected: 1,
+                        found: 2
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn layout() {
+        let src = "
+fn f(%0: i64) -> i64 {
+    %1 = imm 0
+    %2 = phi [%0, .L0]
+    ret %1
+    %3 = imm 1
+.L0:
+    leave
+}";
+        assert_eq!(
+            errors(src),
+            [
+                (Some(0), ErrorKind::MissingEnter),
+                (None, ErrorKind::MissingExit),
+                (Some(1), ErrorKind::MisplacedPhi),
+                (Some(3), ErrorKind::AfterTerminator),
+                (Some(1), ErrorKind::PhiPredecessors(Reg(2))),
+            ]
+        );
+    }
+
+    #[test]
+    fn phi_predecessors() {
+        let src = "
+fn f(%0: i64) -> i64 {
+.L0:
+    enter
+    br %0, .L1
+.L2:
+    %1 = imm 1
+.L1:
+    %2 = phi [%1, .L2]
+    ret %2
+.exit:
+    leave
+}";
+        assert_eq!(errors(src), [(Some(6), ErrorKind::PhiPredecessors(Reg(2)))]);
+    }
+}
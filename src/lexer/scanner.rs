@@ -1,12 +1,15 @@
 use super::Span;
 use crate::parse::{
     keyword,
+    CtrlAmpersand,
     CtrlColon,
     CtrlComma,
     CtrlDot,
+    CtrlDoubleColon,
     CtrlLBrace,
     CtrlLBracet,
     CtrlLParan,
+    CtrlPound,
     CtrlRBrace,
     CtrlRBracet,
     CtrlRParan,
@@ -14,6 +17,7 @@ use crate::parse::{
     // CtrlStar,
     // CtrlSlash,
     CtrlSemiColon,
+    CtrlThickRightArrow,
     Ident,
     LitBool,
     LitChar,
@@ -41,6 +45,7 @@ pub struct Lexer<'a> {
     src: Peekable<Chars<'a>>,
     span: Span,
     last_chr_len: usize,
+    errors: Vec<String>,
 }
 
 impl<'a> Lexer<'a> {
@@ -49,6 +54,7 @@ impl<'a> Lexer<'a> {
             src: src.chars().peekable(),
             span: Span::default(),
             last_chr_len: 0,
+            errors: vec![],
         }
     }
 
@@ -57,11 +63,9 @@ impl<'a> Lexer<'a> {
     }
 
     fn next(&mut self) -> Option<char> {
-        let Some(ch) = self.src.next() else {
-            return None;
-        };
+        let ch = self.src.next()?;
         self.span.right_shift(ch);
-        self.last_chr_len = ch.to_string().as_bytes().len();
+        self.last_chr_len = ch.len_utf8();
         Some(ch)
     }
 
@@ -69,9 +73,7 @@ impl<'a> Lexer<'a> {
     where
         F: FnOnce(char) -> bool,
     {
-        let Some(c) = self.peek() else {
-            return None;
-        };
+        let c = self.peek()?;
         if func(*c) {
             return self.next();
         }
@@ -101,44 +103,132 @@ impl<'a> Lexer<'a> {
         Some(match id.as_str() {
             "fn" => Box::new(keyword::Fn(span)),
             "struct" => Box::new(keyword::Struct(span)),
+            "enum" => Box::new(keyword::Enum(span)),
+            "match" => Box::new(keyword::Match(span)),
             "if" => Box::new(keyword::If(span)),
             "else" => Box::new(keyword::Else(span)),
             "use" => Box::new(keyword::Use(span)),
             "return" => Box::new(keyword::Return(span)),
             "let" => Box::new(keyword::Let(span)),
+            "mut" => Box::new(keyword::Mut(span)),
+            "while" => Box::new(keyword::While(span)),
+            "as" => Box::new(keyword::As(span)),
             "true" => Box::new(LitBool::new(id, span)),
             "false" => Box::new(LitBool::new(id, span)),
             _ => Box::new(Ident::new(id, span)),
         })
     }
 
+    /// Errors point at the last character read.
+    fn error(&mut self, message: String) {
+        let Span {
+            row_end, col_end, ..
+        } = self.span;
+        self.errors
+            .push(format!("{}:{}: {message}", row_end + 1, col_end));
+    }
+
+    /// Errors point at the start of the token being read.
+    fn error_at_start(&mut self, message: &str) {
+        let Span {
+            row_start,
+            col_start,
+            ..
+        } = self.span;
+        self.errors
+            .push(format!("{}:{}: {message}", row_start + 1, col_start + 1));
+    }
+
+    /// Reads what follows a `\` in a string or char literal.
+    fn escape(&mut self) -> Result<char, String> {
+        Ok(match self.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('u') => return self.unicode_escape(),
+            Some(c) => return Err(format!("unknown escape '\\{c}'")),
+            None => return Err("unterminated escape".into()),
+        })
+    }
+
+    /// `\u{1F600}`, one to six hex digits naming a unicode scalar value.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        if self.next_if(|c| c == '{').is_none() {
+            return Err("expected '{' after '\\u'".into());
+        }
+        let mut hex = String::new();
+        while let Some(c) = self.next_if(|c| c.is_ascii_hexdigit()) {
+            hex.push(c);
+        }
+        if self.next_if(|c| c == '}').is_none() {
+            return Err("expected '}' to close '\\u{'".into());
+        }
+        if hex.is_empty() || hex.len() > 6 {
+            return Err(format!("'\\u{{{hex}}}' needs one to six hex digits"));
+        }
+        let value = u32::from_str_radix(&hex, 16).expect("only hex digits were read");
+        char::from_u32(value).ok_or_else(|| format!("'\\u{{{hex}}}' is not a unicode scalar value"))
+    }
+
     fn string(&mut self) -> Option<Token> {
         let mut string = String::new();
-        while let Some(c) = self.next_if(|c| c != '"') {
-            string.push(c);
+        loop {
+            match self.next() {
+                Some('"') => break,
+                Some('\\') => match self.escape() {
+                    Ok(c) => string.push(c),
+                    Err(e) => self.error(e),
+                },
+                Some(c) => string.push(c),
+                None => {
+                    self.error_at_start("unterminated string");
+                    break;
+                }
+            }
         }
-        self.next();
         Some(Box::new(LitStr::new(string, self.span())))
     }
 
+    /// A char literal holds exactly one unicode scalar value.
     fn chr(&mut self) -> Option<Token> {
-        let mut string = String::new();
-        while let Some(c) = self.next_if(|c| c != '\'') {
-            string.push(c);
+        let mut chars = vec![];
+        let errors = self.errors.len();
+        loop {
+            match self.next() {
+                Some('\'') => break,
+                Some('\\') => match self.escape() {
+                    Ok(c) => chars.push(c),
+                    Err(e) => self.error(e),
+                },
+                Some('\n') | None => {
+                    self.error_at_start("unterminated char literal");
+                    break;
+                }
+                Some(c) => chars.push(c),
+            }
         }
-        self.next();
-
+        // Only when the literal itself was read without errors.
+        match chars.len() {
+            _ if self.errors.len() > errors => {}
+            0 => self.error("empty char literal".into()),
+            1 => {}
+            _ => self.error("char literal holds more than one character".into()),
+        }
+        let string = chars.into_iter().take(1).collect::<String>();
         Some(Box::new(LitChar::new(string, self.span())))
     }
+
     fn take_while(&mut self, expected: char) {
         while self.next_if(|c| c != expected).is_some() {}
     }
 
     fn comment(&mut self) -> Option<Token> {
         self.take_while('\n');
-        let Some(ch) = self.next() else {
-            return None;
-        };
+        let ch = self.next()?;
         self.parse(ch)
     }
 
@@ -159,7 +249,7 @@ impl<'a> Lexer<'a> {
     fn parse(&mut self, ch: char) -> Option<Token> {
         match ch {
             n @ '0'..='9' => self.number(n),
-            i @ ('a'..='z' | 'A'..='Z') => self.ident(i),
+            i @ ('a'..='z' | 'A'..='Z' | '_') => self.ident(i),
             '"' => self.string(),
             '\'' => self.chr(),
             '/' if self.matched('/') => self.comment(),
@@ -167,6 +257,8 @@ impl<'a> Lexer<'a> {
             '>' if self.matched('=') => self.token::<OpGeq>(">="),
             '<' if self.matched('=') => self.token::<OpLeq>("<="),
             '=' if self.matched('=') => self.token::<OpEqualEqual>("=="),
+            '=' if self.matched('>') => self.token::<CtrlThickRightArrow>("=>"),
+            ':' if self.matched(':') => self.token::<CtrlDoubleColon>("::"),
             '!' if self.matched('=') => self.token::<OpNeq>("!="),
             '-' => self.token::<OpSub>("-"),
             '+' => self.token::<OpAdd>("+"),
@@ -179,19 +271,19 @@ impl<'a> Lexer<'a> {
             // '%' => self.op_token("%"),
             '.' => self.token::<CtrlDot>("."),
             ',' => self.token::<CtrlComma>(","),
+            '&' => self.token::<CtrlAmpersand>("&"),
             '(' => self.token::<CtrlLParan>("("),
             ')' => self.token::<CtrlRParan>(")"),
             '{' => self.token::<CtrlLBrace>("{"),
             '}' => self.token::<CtrlRBrace>("}"),
-            '[' => self.token::<CtrlLBracet>("{"),
-            ']' => self.token::<CtrlRBracet>("}"),
+            '[' => self.token::<CtrlLBracet>("["),
+            ']' => self.token::<CtrlRBracet>("]"),
             ':' => self.token::<CtrlColon>(":"),
+            '#' => self.token::<CtrlPound>("#"),
             ';' => self.token::<CtrlSemiColon>(";"),
             // 'λ' => self.op_token("λ"),
             '\n' | '\r' | ' ' | '\0' => {
-                let Some(ch) = self.next() else {
-                    return None;
-                };
+                let ch = self.next()?;
                 self.span.reset(Some(self.last_chr_len));
                 self.parse(ch)
             }
@@ -207,6 +299,9 @@ impl<'a> Lexer<'a> {
             };
             tokens.push(token);
         }
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
         Ok(tokens)
     }
 }
@@ -32,7 +32,7 @@ impl Span {
         if ch == '\0' {
             return;
         }
-        let len = ch.to_string().as_bytes().len();
+        let len = ch.len_utf8();
         if ch == '\n' {
             self.row_end += 1;
             self.col_end = 0;
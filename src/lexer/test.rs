@@ -46,3 +46,10 @@ macro_rules! snapshot {
 
 snapshot!(binary, "testdata/snapshots/binary.a");
 snapshot!(ifelse, "testdata/snapshots/ifelse.a");
+snapshot!(var, "testdata/snapshots/var.a");
+snapshot!(string, "testdata/snapshots/string.a");
+snapshot!(string_errors, "testdata/snapshots/string_errors.a");
+snapshot!(char, "testdata/snapshots/char.a");
+snapshot!(char_errors, "testdata/snapshots/char_errors.a");
+snapshot!(matches, "testdata/snapshots/match.a");
+snapshot!(refs, "testdata/snapshots/ref.a");
@@ -56,7 +56,7 @@ impl TokenStream {
     //     self.stream.get(self.idx.saturating_sub(1))
     // }
 
-    pub fn is_peek_a<Expected: 'static>(&self) -> bool
+    pub fn is_peek_a<Expected>(&self) -> bool
     where
         Expected: Token,
     {
@@ -1,19 +1,28 @@
-use std::process::Command;
-
+mod interp;
 mod ir;
 mod lexer;
 mod parse;
+mod vm;
 mod x86_64_linux;
 
 const HELP_MESSAGE: &str = "
 Usage: a <inputfile>.a [<flags>*]
+       a --from-ir <inputfile>.air [<flags>*]
+       a run <inputfile>.a [<flags>*] [-- <args>*]
+       a run <inputfile>.abc [<flags>*] [-- <args>*]
 
         SHORT   LONG            DESCRIPTION
         -h    | --help          print this message out
+        -O    | --optimize      run optimization passes over the ir
         -dtk  | --debug-tokens  print out token stream created by compiler
         -dast | --debug-ast     print out ast created by compiler
         -dir  | --debug-ir      print out ir code created by compiler
         -dasm | --debug-asm     print out assembly code created by compiler
+              | --emit=<kind>   what to output, one of: exe (default), obj, cfg-dot, ir
+              | --target=<name> what to compile to, one of: x86_64-linux (default), vm
+              | --from-ir       read textual ir instead of source code
+              | run             interpret the program, or run vm bytecode, exiting with
+                                what main returns
 ";
 
 fn print_output<T>(output: bool) -> impl FnOnce(T) -> Result<T, Vec<String>>
@@ -28,14 +37,64 @@ where
     }
 }
 
-fn print_output_asm(output: bool) -> impl FnOnce(String) -> Result<String, Vec<String>> {
-    move |t: String| {
+fn print_ir(
+    output: bool,
+) -> impl FnOnce(Vec<ir::Instruction>) -> Result<Vec<ir::Instruction>, Vec<String>> {
+    move |code| {
+        if output {
+            eprint!("{}", ir::text::print(&code));
+        }
+        Ok(code)
+    }
+}
+
+fn optimize(
+    enabled: bool,
+    output: bool,
+) -> impl FnOnce(Vec<ir::Instruction>) -> Result<Vec<ir::Instruction>, Vec<String>> {
+    move |code| {
+        if !enabled {
+            return Ok(code);
+        }
+        let (code, stats) = ir::opt::optimize(code)?;
+        if output {
+            eprintln!("{stats}");
+        }
+        Ok(code)
+    }
+}
+
+/// Checks the ir produced by `pass` in debug builds.
+fn verified(
+    pass: &'static str,
+) -> impl FnOnce(Vec<ir::Instruction>) -> Result<Vec<ir::Instruction>, Vec<String>> {
+    move |code| ir::verify::debug_verify(&code, pass).map(|()| code)
+}
+
+/// Runs `pass` only when `enabled`.
+fn run_if<T>(
+    enabled: bool,
+    pass: fn(T) -> Result<T, Vec<String>>,
+) -> impl FnOnce(T) -> Result<T, Vec<String>> {
+    move |t: T| {
+        if enabled {
+            return pass(t);
+        }
+        Ok(t)
+    }
+}
+
+fn print_output_asm(
+    output: bool,
+) -> impl FnOnce(Vec<x86_64_linux::Instruction>) -> Result<Vec<x86_64_linux::Instruction>, Vec<String>>
+{
+    move |code| {
         if output {
-            for line in t.lines() {
+            for line in x86_64_linux::instruction_to_string(code.clone())?.lines() {
                 eprintln!("{line}");
             }
         }
-        Ok(t)
+        Ok(code)
     }
 }
 
@@ -47,97 +106,198 @@ fn print_error_message(err: Vec<String>) -> Vec<String> {
 }
 
 fn compile(flags: Flags) -> Result<(), Vec<String>> {
+    let source = std::fs::read_to_string(&flags.filename).map_err(|e| vec![e.to_string()]);
+    let ir_code = if flags.from_ir {
+        source
+            .and_then(ir::text::parse)
+            .and_then(verified("parsing ir"))
+    } else {
+        source
+            .and_then(lexer::lex)
+            .and_then(print_output(flags.debug_tokens))
+            .and_then(parse::parse)
+            .and_then(print_output(flags.debug_ast))
+            .and_then(match flags.emit {
+                Emit::Obj => ir::code_gen_exported,
+                _ => ir::code_gen,
+            })
+            .and_then(verified("code_gen"))
+    };
+    let ir_code = ir_code
+        .and_then(run_if(flags.optimize, ir::opt::inline))
+        .and_then(verified("inline"))
+        .and_then(ir::tail_calls)
+        .and_then(verified("tail_calls"))
+        .and_then(ir::ssa::construct)
+        .and_then(verified("ssa::construct"))
+        .and_then(optimize(flags.optimize, flags.debug_ir))
+        .and_then(print_ir(flags.debug_ir))
+        .map_err(print_error_message)?;
+    match flags.emit {
+        Emit::CfgDot => {
+            print!("{}", ir::cfg::emit_dot(&ir_code));
+            Ok(())
+        }
+        Emit::Ir => {
+            print!("{}", ir::text::print(&ir_code));
+            Ok(())
+        }
+        Emit::Exe => match flags.target {
+            Target::X86_64Linux => compile_exe(flags, ir_code),
+            Target::Vm => compile_vm(flags, ir_code),
+        },
+        Emit::Obj => match flags.target {
+            Target::X86_64Linux => compile_obj(flags, ir_code),
+            Target::Vm => Err(print_error_message(vec![
+                "--emit=obj is only supported for --target=x86_64-linux".into(),
+            ])),
+        },
+    }
+}
+
+fn run(flags: Flags) -> Result<i64, Vec<String>> {
+    if flags.filename.ends_with(".abc") {
+        return run_vm(flags);
+    }
     std::fs::read_to_string(&flags.filename)
         .map_err(|e| vec![e.to_string()])
         .and_then(lexer::lex)
         .and_then(print_output(flags.debug_tokens))
         .and_then(parse::parse)
         .and_then(print_output(flags.debug_ast))
-        .and_then(ir::code_gen)
-        .and_then(print_output(flags.debug_ir))
+        // What the compiler rejects is not run either.
+        .and_then(|items| ir::check(&items).map(|()| items))
+        .and_then(|items| interp::run(items, &flags.program_args()))
+        .map_err(print_error_message)
+}
+
+fn run_vm(flags: Flags) -> Result<i64, Vec<String>> {
+    std::fs::read(&flags.filename)
+        .map_err(|e| vec![e.to_string()])
+        .and_then(|bytes| vm::decode(&bytes))
+        .and_then(print_disassembly(flags.debug_asm))
+        .and_then(|program| vm::run(&program, &flags.program_args()))
+        .map_err(print_error_message)
+}
+
+fn compile_vm(flags: Flags, ir_code: Vec<ir::Instruction>) -> Result<(), Vec<String>> {
+    ir::ssa::destruct(ir_code)
+        .and_then(verified("ssa::destruct"))
+        .and_then(vm::lower)
+        .and_then(print_disassembly(flags.debug_asm))
+        .map(|program| (flags.filename, vm::encode(&program)))
+        .and_then(write_bytecode_to_file)
+        .map_err(print_error_message)
+}
+
+fn print_disassembly(output: bool) -> impl FnOnce(vm::Program) -> Result<vm::Program, Vec<String>> {
+    move |program| {
+        if output {
+            eprint!("{program}");
+        }
+        Ok(program)
+    }
+}
+
+fn write_bytecode_to_file((filename, bytes): (String, Vec<u8>)) -> Result<(), Vec<String>> {
+    let Some((filename, _)) = filename.split_once('.') else {
+        return Err(vec!["file name has no extension".into()]);
+    };
+    std::fs::write(format!("{filename}.abc"), bytes).map_err(|e| vec![e.to_string()])
+}
+
+fn compile_native(
+    flags: &Flags,
+    ir_code: Vec<ir::Instruction>,
+) -> Result<Vec<x86_64_linux::Instruction>, Vec<String>> {
+    ir::ssa::destruct(ir_code)
+        .and_then(verified("ssa::destruct"))
         .and_then(x86_64_linux::compile_ir_code)
+        .and_then(run_if(flags.optimize, x86_64_linux::peephole))
+        .map(x86_64_linux::with_runtime)
+}
+
+fn compile_exe(flags: Flags, ir_code: Vec<ir::Instruction>) -> Result<(), Vec<String>> {
+    compile_native(&flags, ir_code)
+        .and_then(x86_64_linux::with_start_func)
         .and_then(print_output(flags.debug_asm))
-        .and_then(x86_64_linux::instruction_to_string)
         .and_then(print_output_asm(flags.debug_asm))
-        .map(|asm| (flags.filename, asm))
-        .and_then(write_asm_to_file)
-        .and_then(compile_asm_with_fasm)
+        .and_then(x86_64_linux::assemble)
+        .and_then(x86_64_linux::elf::write_executable)
+        .map(|elf| (flags.filename, elf))
+        .and_then(write_executable_to_file)
         .map_err(print_error_message)
 }
-fn start_func_assembly() -> String {
-    use x86_64_linux::{Instruction, X86Reg64};
-    [
-        Instruction::DefLabel("_start".into()),
-        Instruction::MoveReg(X86Reg64::RDI.into(), X86Reg64::RAX.into()),
-        Instruction::MoveImm(X86Reg64::RAX.into(), 60),
-        Instruction::Syscall,
-    ]
-    .iter()
-    .map(ToString::to_string)
-    .collect()
+
+fn compile_obj(flags: Flags, ir_code: Vec<ir::Instruction>) -> Result<(), Vec<String>> {
+    compile_native(&flags, ir_code)
+        .and_then(print_output(flags.debug_asm))
+        .and_then(print_output_asm(flags.debug_asm))
+        .and_then(x86_64_linux::assemble)
+        .and_then(x86_64_linux::elf::write_object)
+        .map(|elf| (flags.filename, elf))
+        .and_then(write_object_to_file)
+        .map_err(print_error_message)
 }
 
-fn write_asm_to_file((filename, asm_code): (String, String)) -> Result<String, Vec<String>> {
+fn write_object_to_file((filename, bytes): (String, Vec<u8>)) -> Result<(), Vec<String>> {
     let Some((filename, _)) = filename.split_once('.') else {
-        eprintln!("file name has no extension");
-        std::process::exit(1);
+        return Err(vec!["file name has no extension".into()]);
     };
+    std::fs::write(format!("{filename}.o"), bytes).map_err(|e| vec![e.to_string()])
+}
 
-    let asm_file = format!("{filename}.asm");
-    let header = if cfg!(target_os = "windows") {
-        "format pe64 gui"
-    } else {
-        "format ELF64 executable 3
-segment readable executable
-"
-    };
-    let footer = if cfg!(target_os = "windows") {
-        ""
-    } else {
-        "segment readable writable"
+fn write_executable_to_file((filename, bytes): (String, Vec<u8>)) -> Result<(), Vec<String>> {
+    let Some((filename, _)) = filename.split_once('.') else {
+        return Err(vec!["file name has no extension".into()]);
     };
-    let code = format!(
-        "{header}
-entry _start__
-{asm_code}
-
-{}
-{footer}",
-        start_func_assembly()
-    );
-
-    std::fs::write(&asm_file, code)
-        .map(|_| asm_file)
-        .map_err(|e| vec![e.to_string()])
+    std::fs::write(filename, bytes).map_err(|e| vec![e.to_string()])?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(filename, std::fs::Permissions::from_mode(0o755))
+            .map_err(|e| vec![e.to_string()])?;
+    }
+    Ok(())
 }
 
-fn compile_asm_with_fasm(asm_file: String) -> Result<(), Vec<String>> {
-    let fasm = if cfg!(target_os = "windows") {
-        "./fasm"
-    } else {
-        "fasm"
-    };
-    Command::new(fasm)
-        .arg(asm_file)
-        .output()
-        .map(|output| {
-            eprintln!(
-                "{}",
-                String::from_utf8(output.stdout)
-                    .unwrap_or("Failed to read from stdout".to_string())
-                    .trim()
-            );
-            eprintln!(
-                "{}",
-                String::from_utf8(output.stderr)
-                    .unwrap_or("Failed to read from stdout".to_string())
-                    .trim()
-            );
-            if !output.status.success() {
-                std::process::exit(1);
-            }
-        })
-        .map_err(|e| vec![e.to_string()])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Exe,
+    /// A relocatable object file to link with `ld` or `cc`.
+    Obj,
+    CfgDot,
+    Ir,
+}
+
+impl std::str::FromStr for Emit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exe" => Ok(Self::Exe),
+            "obj" => Ok(Self::Obj),
+            "cfg-dot" => Ok(Self::CfgDot),
+            "ir" => Ok(Self::Ir),
+            i => Err(format!("'{i}' Unknow kind given to --emit")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    X86_64Linux,
+    Vm,
+}
+
+impl std::str::FromStr for Target {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64-linux" => Ok(Self::X86_64Linux),
+            "vm" => Ok(Self::Vm),
+            i => Err(format!("'{i}' Unknow target given to --target")),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -147,6 +307,13 @@ struct Flags {
     pub debug_ast: bool,
     pub debug_ir: bool,
     pub debug_asm: bool,
+    pub emit: Emit,
+    pub target: Target,
+    pub optimize: bool,
+    pub from_ir: bool,
+    pub run: bool,
+    /// Given after `--` to the program started by `run`.
+    pub args: Vec<String>,
 }
 
 impl Flags {
@@ -155,16 +322,35 @@ impl Flags {
         let mut debug_ast = false;
         let mut debug_ir = false;
         let mut debug_asm = false;
-        let Some(filename) = std::env::args().nth(1) else {
+        let mut emit = Emit::Exe;
+        let mut target = Target::X86_64Linux;
+        let mut optimize = false;
+        let mut from_ir = false;
+        let mut run = false;
+        let mut program_args = vec![];
+        let mut args = std::env::args().skip(1).peekable();
+        match args.peek().map(String::as_str) {
+            Some("--from-ir") => from_ir = true,
+            Some("run") => run = true,
+            _ => {}
+        }
+        if from_ir || run {
+            args.next();
+        }
+        let Some(filename) = args.next() else {
             return Err("No file given to parse".into());
         };
-        for arg in std::env::args().skip(2) {
+        while let Some(arg) = args.next() {
             match arg.as_str() {
+                "--" if run => program_args.extend(args.by_ref()),
                 "-dtk" | "--debug-tokens" => debug_tokens = true,
                 "-dast" | "--debug-ast" => debug_ast = true,
                 "-dir" | "--debug-ir" => debug_ir = true,
                 "-dasm" | "--debug-asm" => debug_asm = true,
+                "-O" | "--optimize" => optimize = true,
                 "-h" | "--help" => return Err(HELP_MESSAGE.into()),
+                i if i.starts_with("--emit=") => emit = i["--emit=".len()..].parse()?,
+                i if i.starts_with("--target=") => target = i["--target=".len()..].parse()?,
                 i => return Err(format!("'{i}' Unknow argument given")),
             }
         }
@@ -174,8 +360,21 @@ impl Flags {
             debug_ast,
             debug_ir,
             debug_asm,
+            emit,
+            target,
+            optimize,
+            from_ir,
+            run,
+            args: program_args,
         })
     }
+
+    /// The command line of the program started by `run`, its name first.
+    fn program_args(&self) -> Vec<String> {
+        std::iter::once(self.filename.clone())
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
 }
 
 fn main() {
@@ -186,6 +385,12 @@ fn main() {
             std::process::exit(1);
         }
     };
+    if flags.run {
+        match run(flags) {
+            Ok(code) => std::process::exit(code as i32),
+            Err(_) => std::process::exit(1),
+        }
+    }
     if let Err(errs) = compile(flags) {
         for i in errs.into_iter() {
             eprintln!("{i}");
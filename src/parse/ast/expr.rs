@@ -1,4 +1,4 @@
-use super::{keyword, Ident, Lit, Op};
+use super::{keyword, Ident, Lit, Op, Type};
 use crate::lexer::{Span, Token};
 use std::fmt;
 
@@ -9,8 +9,21 @@ pub enum Expr {
     Call(ExprCall),
     Var(ExprVar),
     If(ExprIf),
+    While(ExprWhile),
     Block(ExprBlock),
     Return(ExprReturn),
+    Let(ExprLet),
+    Assign(ExprAssign),
+    Cast(ExprCast),
+    Struct(ExprStruct),
+    Field(ExprField),
+    Array(ExprArray),
+    Repeat(ExprRepeat),
+    Index(ExprIndex),
+    Path(ExprPath),
+    Match(ExprMatch),
+    Ref(ExprRef),
+    Deref(ExprDeref),
 }
 
 impl fmt::Display for Expr {
@@ -21,8 +34,21 @@ impl fmt::Display for Expr {
             Self::Call(ecall) => write!(f, "{ecall}"),
             Self::Var(evar) => write!(f, "{evar}"),
             Self::If(i) => write!(f, "{i}"),
+            Self::While(i) => write!(f, "{i}"),
             Self::Block(i) => write!(f, "{i}"),
             Self::Return(i) => write!(f, "{i}"),
+            Self::Let(i) => write!(f, "{i}"),
+            Self::Assign(i) => write!(f, "{i}"),
+            Self::Cast(i) => write!(f, "{i}"),
+            Self::Struct(i) => write!(f, "{i}"),
+            Self::Field(i) => write!(f, "{i}"),
+            Self::Array(i) => write!(f, "{i}"),
+            Self::Repeat(i) => write!(f, "{i}"),
+            Self::Index(i) => write!(f, "{i}"),
+            Self::Path(i) => write!(f, "{i}"),
+            Self::Match(i) => write!(f, "{i}"),
+            Self::Ref(i) => write!(f, "{i}"),
+            Self::Deref(i) => write!(f, "{i}"),
         }
     }
 }
@@ -35,8 +61,21 @@ impl Expr {
             Self::Call(i) => i.span(),
             Self::Var(i) => i.span(),
             Self::If(i) => i.span(),
+            Self::While(i) => i.span(),
             Self::Block(i) => i.span(),
             Self::Return(i) => i.span(),
+            Self::Let(i) => i.span(),
+            Self::Assign(i) => i.span(),
+            Self::Cast(i) => i.span(),
+            Self::Struct(i) => i.span(),
+            Self::Field(i) => i.span(),
+            Self::Array(i) => i.span(),
+            Self::Repeat(i) => i.span(),
+            Self::Index(i) => i.span(),
+            Self::Path(i) => i.span(),
+            Self::Match(i) => i.span(),
+            Self::Ref(i) => i.span(),
+            Self::Deref(i) => i.span(),
         }
     }
 }
@@ -121,6 +160,84 @@ impl From<ExprReturn> for Expr {
     }
 }
 
+impl From<ExprWhile> for Expr {
+    fn from(expr: ExprWhile) -> Self {
+        Self::While(expr)
+    }
+}
+
+impl From<ExprLet> for Expr {
+    fn from(expr: ExprLet) -> Self {
+        Self::Let(expr)
+    }
+}
+
+impl From<ExprAssign> for Expr {
+    fn from(expr: ExprAssign) -> Self {
+        Self::Assign(expr)
+    }
+}
+
+impl From<ExprCast> for Expr {
+    fn from(expr: ExprCast) -> Self {
+        Self::Cast(expr)
+    }
+}
+
+impl From<ExprStruct> for Expr {
+    fn from(expr: ExprStruct) -> Self {
+        Self::Struct(expr)
+    }
+}
+
+impl From<ExprField> for Expr {
+    fn from(expr: ExprField) -> Self {
+        Self::Field(expr)
+    }
+}
+
+impl From<ExprArray> for Expr {
+    fn from(expr: ExprArray) -> Self {
+        Self::Array(expr)
+    }
+}
+
+impl From<ExprRepeat> for Expr {
+    fn from(expr: ExprRepeat) -> Self {
+        Self::Repeat(expr)
+    }
+}
+
+impl From<ExprIndex> for Expr {
+    fn from(expr: ExprIndex) -> Self {
+        Self::Index(expr)
+    }
+}
+
+impl From<ExprPath> for Expr {
+    fn from(expr: ExprPath) -> Self {
+        Self::Path(expr)
+    }
+}
+
+impl From<ExprMatch> for Expr {
+    fn from(expr: ExprMatch) -> Self {
+        Self::Match(expr)
+    }
+}
+
+impl From<ExprRef> for Expr {
+    fn from(expr: ExprRef) -> Self {
+        Self::Ref(expr)
+    }
+}
+
+impl From<ExprDeref> for Expr {
+    fn from(expr: ExprDeref) -> Self {
+        Self::Deref(expr)
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ExprLit {
     pub lit: Lit,
@@ -287,6 +404,36 @@ impl ExprIf {
     }
 }
 
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprWhile {
+    pub while_token: keyword::While,
+    pub cond: Box<Expr>,
+    pub body: ExprBlock,
+}
+
+impl ExprWhile {
+    pub fn new(while_token: keyword::While, cond: Expr, body: ExprBlock) -> Self {
+        Self {
+            while_token,
+            cond: Box::new(cond),
+            body,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.while_token.span();
+        let end = self.body.span();
+        Span::from((start, end))
+    }
+}
+
+impl fmt::Display for ExprWhile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { cond, body, .. } = self;
+        write!(f, "(while {cond} {body})")
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ExprBlock {
     pub left_brace: super::CtrlLBrace,
@@ -352,3 +499,440 @@ impl std::fmt::Display for ExprReturn {
         write!(f, "{ret} {expr}")
     }
 }
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprLet {
+    pub let_token: keyword::Let,
+    pub name: Ident,
+    pub value: Box<Expr>,
+}
+
+impl ExprLet {
+    pub fn new(let_token: keyword::Let, name: Ident, value: Expr) -> Self {
+        Self {
+            let_token,
+            name,
+            value: Box::new(value),
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.let_token.span();
+        let end = self.value.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprLet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { name, value, .. } = self;
+        write!(f, "(let {name} {value})")
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprAssign {
+    pub target: Box<Expr>,
+    pub value: Box<Expr>,
+}
+
+impl ExprAssign {
+    pub fn new(target: Expr, value: Expr) -> Self {
+        Self {
+            target: Box::new(target),
+            value: Box::new(value),
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.target.span();
+        let end = self.value.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprAssign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { target, value } = self;
+        write!(f, "(= {target} {value})")
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprCast {
+    pub expr: Box<Expr>,
+    pub as_token: keyword::As,
+    pub ty: Type,
+}
+
+impl ExprCast {
+    pub fn new(expr: Expr, as_token: keyword::As, ty: Type) -> Self {
+        Self {
+            expr: Box::new(expr),
+            as_token,
+            ty,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.expr.span();
+        let end = self.ty.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprCast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { expr, ty, .. } = self;
+        write!(f, "(as {expr} {ty:#})")
+    }
+}
+
+/// `Name { field: value, ... }`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprStruct {
+    pub name: Ident,
+    pub left_brace: super::CtrlLBrace,
+    pub fields: Vec<(Ident, Expr)>,
+    pub right_brace: super::CtrlRBrace,
+}
+
+impl ExprStruct {
+    pub fn new(
+        name: Ident,
+        left_brace: super::CtrlLBrace,
+        fields: Vec<(Ident, Expr)>,
+        right_brace: super::CtrlRBrace,
+    ) -> Self {
+        Self {
+            name,
+            left_brace,
+            fields,
+            right_brace,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.name.span();
+        let end = self.right_brace.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprStruct {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { name, fields, .. } = self;
+        let fields = fields
+            .iter()
+            .map(|(field, value)| format!("{field}: {value}, "))
+            .collect::<String>();
+        write!(f, "({name} {{{fields}}})")
+    }
+}
+
+/// `expr.name`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprField {
+    pub expr: Box<Expr>,
+    pub dot: super::CtrlDot,
+    pub name: Ident,
+}
+
+impl ExprField {
+    pub fn new(expr: Expr, dot: super::CtrlDot, name: Ident) -> Self {
+        Self {
+            expr: Box::new(expr),
+            dot,
+            name,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.expr.span();
+        let end = self.name.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { expr, name, .. } = self;
+        write!(f, "(. {expr} {name})")
+    }
+}
+
+/// `[a, b, c]`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprArray {
+    pub left_bracket: super::CtrlLBracet,
+    pub elems: Vec<Expr>,
+    pub right_bracket: super::CtrlRBracet,
+}
+
+impl ExprArray {
+    pub fn new(
+        left_bracket: super::CtrlLBracet,
+        elems: Vec<Expr>,
+        right_bracket: super::CtrlRBracet,
+    ) -> Self {
+        Self {
+            left_bracket,
+            elems,
+            right_bracket,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.left_bracket.span();
+        let end = self.right_bracket.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprArray {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let elems = self
+            .elems
+            .iter()
+            .map(|elem| format!("{elem}, "))
+            .collect::<String>();
+        write!(f, "([{elems}])")
+    }
+}
+
+/// `[value; len]`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprRepeat {
+    pub left_bracket: super::CtrlLBracet,
+    pub value: Box<Expr>,
+    pub semicolon: super::CtrlSemiColon,
+    pub len: super::LitInt,
+    pub right_bracket: super::CtrlRBracet,
+}
+
+impl ExprRepeat {
+    pub fn span(&self) -> Span {
+        let start = self.left_bracket.span();
+        let end = self.right_bracket.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprRepeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { value, len, .. } = self;
+        write!(f, "([{value}; {len}])")
+    }
+}
+
+/// `expr[index]`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprIndex {
+    pub expr: Box<Expr>,
+    pub left_bracket: super::CtrlLBracet,
+    pub index: Box<Expr>,
+    pub right_bracket: super::CtrlRBracet,
+}
+
+impl ExprIndex {
+    pub fn new(
+        expr: Expr,
+        left_bracket: super::CtrlLBracet,
+        index: Expr,
+        right_bracket: super::CtrlRBracet,
+    ) -> Self {
+        Self {
+            expr: Box::new(expr),
+            left_bracket,
+            index: Box::new(index),
+            right_bracket,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.expr.span();
+        let end = self.right_bracket.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { expr, index, .. } = self;
+        write!(f, "([] {expr} {index})")
+    }
+}
+
+/// `&expr` or `&mut expr`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprRef {
+    pub ampersand: super::CtrlAmpersand,
+    pub mutable: Option<keyword::Mut>,
+    pub expr: Box<Expr>,
+}
+
+impl ExprRef {
+    pub fn new(ampersand: super::CtrlAmpersand, mutable: Option<keyword::Mut>, expr: Expr) -> Self {
+        Self {
+            ampersand,
+            mutable,
+            expr: Box::new(expr),
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.ampersand.span();
+        let end = self.expr.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { mutable, expr, .. } = self;
+        match mutable {
+            Some(_) => write!(f, "(&mut {expr})"),
+            None => write!(f, "(& {expr})"),
+        }
+    }
+}
+
+/// `*expr`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprDeref {
+    pub star: super::CtrlStar,
+    pub expr: Box<Expr>,
+}
+
+impl ExprDeref {
+    pub fn new(star: super::CtrlStar, expr: Expr) -> Self {
+        Self {
+            star,
+            expr: Box::new(expr),
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.star.span();
+        let end = self.expr.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprDeref {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { expr, .. } = self;
+        write!(f, "(* {expr})")
+    }
+}
+
+/// `Enum::Variant`, called with the fields of the variant unless it has
+/// none. `{:#}` writes it the way it is written in source.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprPath {
+    pub ty: Ident,
+    pub double_colon: super::CtrlDoubleColon,
+    pub variant: Ident,
+}
+
+impl ExprPath {
+    pub fn span(&self) -> Span {
+        let start = self.ty.span();
+        let end = self.variant.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { ty, variant, .. } = self;
+        if f.alternate() {
+            return write!(f, "{ty}::{variant}");
+        }
+        write!(f, "(:: {ty} {variant})")
+    }
+}
+
+/// `match expr { pattern => value, ... }`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExprMatch {
+    pub keyword_match: keyword::Match,
+    pub expr: Box<Expr>,
+    pub left_brace: super::CtrlLBrace,
+    pub arms: Vec<Arm>,
+    pub right_brace: super::CtrlRBrace,
+}
+
+impl ExprMatch {
+    pub fn span(&self) -> Span {
+        let start = self.keyword_match.span();
+        let end = self.right_brace.span();
+        Span::from((start, end))
+    }
+}
+
+impl std::fmt::Display for ExprMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { expr, arms, .. } = self;
+        let arms = arms.iter().map(|arm| format!(" {arm}")).collect::<String>();
+        write!(f, "(match {expr}{arms})")
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Arm {
+    pub pat: Pat,
+    pub arrow: super::CtrlThickRightArrow,
+    pub body: Expr,
+}
+
+impl std::fmt::Display for Arm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { pat, body, .. } = self;
+        write!(f, "({pat} => {body})")
+    }
+}
+
+/// What an arm of a `match` compares the value with.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pat {
+    /// `_`, matching anything.
+    Wild(Ident),
+    Lit(Lit),
+    /// A name, matching anything and binding the value to it.
+    Bind(Ident),
+    Variant(PatVariant),
+}
+
+impl std::fmt::Display for Pat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Wild(wild) => write!(f, "{wild}"),
+            Self::Lit(lit) => write!(f, "{lit}"),
+            Self::Bind(name) => write!(f, "{name}"),
+            Self::Variant(variant) => write!(f, "{variant}"),
+        }
+    }
+}
+
+/// `Enum::Variant(a, _, ...)`, binding the fields to names or ignoring
+/// them with `_`.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PatVariant {
+    pub path: ExprPath,
+    pub fields: Vec<Ident>,
+}
+
+impl std::fmt::Display for PatVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { path, fields } = self;
+        if fields.is_empty() {
+            return write!(f, "{path}");
+        }
+        let fields = fields
+            .iter()
+            .map(|field| format!(" {field}"))
+            .collect::<String>();
+        write!(f, "({path}{fields})")
+    }
+}
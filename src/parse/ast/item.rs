@@ -1,21 +1,42 @@
-use super::{ExprBlock, Ident, Param, Type};
+use super::{keyword, ExprBlock, Ident, Param, Type};
 use std::fmt;
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Item {
-    Fn(ItemFn),
+    Fn(Box<ItemFn>),
+    Struct(ItemStruct),
+    Enum(ItemEnum),
 }
 
 impl fmt::Display for Item {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Fn(item_fn) => write!(f, "{item_fn}"),
+            Self::Struct(item_struct) => write!(f, "{item_struct}"),
+            Self::Enum(item_enum) => write!(f, "{item_enum}"),
+        }
+    }
+}
+
+/// `#[name]` or `#[name(arg)]` in front of an item.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Attribute {
+    pub name: Ident,
+    pub arg: Option<Ident>,
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.arg {
+            Some(arg) => write!(f, "#[{}({arg})]", self.name),
+            None => write!(f, "#[{}]", self.name),
         }
     }
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ItemFn {
+    pub attrs: Vec<Attribute>,
     pub keyword_fn: super::keyword::Fn,
     pub name: Ident,
     pub params: Vec<Param>,
@@ -25,6 +46,7 @@ pub struct ItemFn {
 
 impl ItemFn {
     pub fn new(
+        attrs: Vec<Attribute>,
         keyword_fn: super::keyword::Fn,
         name: Ident,
         params: Vec<Param>,
@@ -32,6 +54,7 @@ impl ItemFn {
         ret_type: Option<Type>,
     ) -> Self {
         Self {
+            attrs,
             keyword_fn,
             name,
             params,
@@ -50,6 +73,7 @@ impl ItemFn {
 impl fmt::Display for ItemFn {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let Self {
+            attrs,
             name,
             params,
             block,
@@ -61,6 +85,54 @@ impl fmt::Display for ItemFn {
             .map(ToString::to_string)
             .unwrap_or("NULL".into());
         let params = params.iter().map(ToString::to_string).collect::<String>();
-        write!(f, "(func {name} <{ret}> ({params}) {block})")
+        let attrs = attrs.iter().map(|a| format!("{a} ")).collect::<String>();
+        write!(f, "(func {attrs}{name} <{ret}> ({params}) {block})")
+    }
+}
+
+/// `struct Name { field: Type, ... }`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ItemStruct {
+    pub keyword_struct: keyword::Struct,
+    pub name: Ident,
+    pub fields: Vec<Param>,
+}
+
+impl fmt::Display for ItemStruct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { name, fields, .. } = &self;
+        let fields = fields.iter().map(ToString::to_string).collect::<String>();
+        write!(f, "(struct {name} ({fields}))")
+    }
+}
+
+/// `enum Name { Variant, Variant(Type, ...), ... }`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ItemEnum {
+    pub keyword_enum: keyword::Enum,
+    pub name: Ident,
+    pub variants: Vec<Variant>,
+}
+
+impl fmt::Display for ItemEnum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { name, variants, .. } = &self;
+        let variants = variants.iter().map(ToString::to_string).collect::<String>();
+        write!(f, "(enum {name} ({variants}))")
+    }
+}
+
+/// A variant of an enum and the types of its fields, which have no names.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Variant {
+    pub name: Ident,
+    pub fields: Vec<Type>,
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { name, fields } = &self;
+        let fields = fields.iter().map(|ty| format!(" {ty}")).collect::<String>();
+        write!(f, "({name}{fields})")
     }
 }
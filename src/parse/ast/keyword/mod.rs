@@ -26,10 +26,13 @@ macro_rules! keyword {
 }
 keyword!(Use);
 keyword!(Let);
+keyword!(Mut);
 keyword!(Struct);
-keyword!(True);
-keyword!(False);
+keyword!(Enum);
+keyword!(Match);
 keyword!(Fn);
 keyword!(If);
 keyword!(Else);
 keyword!(Return);
+keyword!(While);
+keyword!(As);
@@ -16,8 +16,8 @@ impl fmt::Display for Lit {
         match self {
             Self::Int(i) => write!(f, "{i}"),
             Self::Bool(i) => write!(f, "{i}"),
-            Self::Str(i) => write!(f, "{i}"),
-            Self::Char(i) => write!(f, "{i}"),
+            Self::Str(i) => write!(f, "{:?}", i.value),
+            Self::Char(i) => write!(f, "'{}'", i.value.escape_debug()),
         }
     }
 }
@@ -4,8 +4,12 @@ pub mod keyword;
 mod lit;
 
 use crate::lexer::Span;
-pub use expr::{Expr, ExprBinary, ExprBlock, ExprCall, ExprIf, ExprLit, ExprReturn, ExprVar};
-pub use item::{Item, ItemFn};
+pub use expr::{
+    Arm, Expr, ExprArray, ExprAssign, ExprBinary, ExprBlock, ExprCall, ExprCast, ExprDeref,
+    ExprField, ExprIf, ExprIndex, ExprLet, ExprLit, ExprMatch, ExprPath, ExprRef, ExprRepeat,
+    ExprReturn, ExprStruct, ExprVar, ExprWhile, Pat, PatVariant,
+};
+pub use item::{Attribute, Item, ItemEnum, ItemFn, ItemStruct, Variant};
 pub use lit::{Lit, LitBool, LitChar, LitInt, LitStr};
 
 #[macro_export]
@@ -140,9 +144,11 @@ from_token!(Op, Equal, OpEqual);
 from_token!(Op, EqualEqual, OpEqualEqual);
 
 token!(CtrlStar);
+token!(CtrlAmpersand);
 token!(CtrlSlash);
 token!(CtrlSemiColon);
 token!(CtrlColon);
+token!(CtrlDoubleColon);
 token!(CtrlComma);
 token!(CtrlDot);
 token!(CtrlLBrace);
@@ -153,13 +159,16 @@ token!(CtrlLParan);
 token!(CtrlRParan);
 token!(CtrlRightArrow);
 token!(CtrlThickRightArrow);
+token!(CtrlPound);
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Ctrl {
     Star(CtrlStar),                       // *
+    Ampersand(CtrlAmpersand),             // &
     Slash(CtrlSlash),                     // /
     SemiColon(CtrlSemiColon),             // ;
     Colon(CtrlColon),                     // :
+    DoubleColon(CtrlDoubleColon),         // ::
     Comma(CtrlComma),                     // ,
     Dot(CtrlDot),                         // .
     LBrace(CtrlLBrace),                   // {
@@ -170,15 +179,18 @@ pub enum Ctrl {
     RParan(CtrlRParan),                   // )
     RightArrow(CtrlRightArrow),           // ->
     ThickRightArrow(CtrlThickRightArrow), // =>
+    Pound(CtrlPound),                     // #
 }
 
 impl std::fmt::Display for Ctrl {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Star(ctrl) => write!(f, "{ctrl}"),
+            Self::Ampersand(ctrl) => write!(f, "{ctrl}"),
             Self::Slash(ctrl) => write!(f, "{ctrl}"),
             Self::SemiColon(ctrl) => write!(f, "{ctrl}"),
             Self::Colon(ctrl) => write!(f, "{ctrl}"),
+            Self::DoubleColon(ctrl) => write!(f, "{ctrl}"),
             Self::Comma(ctrl) => write!(f, "{ctrl}"),
             Self::Dot(ctrl) => write!(f, "{ctrl}"),
             Self::LBrace(ctrl) => write!(f, "{ctrl}"),
@@ -189,14 +201,17 @@ impl std::fmt::Display for Ctrl {
             Self::RParan(ctrl) => write!(f, "{ctrl}"),
             Self::RightArrow(ctrl) => write!(f, "{ctrl}"),
             Self::ThickRightArrow(ctrl) => write!(f, "{ctrl}"),
+            Self::Pound(ctrl) => write!(f, "{ctrl}"),
         }
     }
 }
 
 from_token!(Ctrl, Star, CtrlStar);
+from_token!(Ctrl, Ampersand, CtrlAmpersand);
 from_token!(Ctrl, Slash, CtrlSlash);
 from_token!(Ctrl, SemiColon, CtrlSemiColon);
 from_token!(Ctrl, Colon, CtrlColon);
+from_token!(Ctrl, DoubleColon, CtrlDoubleColon);
 from_token!(Ctrl, Comma, CtrlComma);
 from_token!(Ctrl, Dot, CtrlDot);
 from_token!(Ctrl, LBrace, CtrlLBrace);
@@ -207,6 +222,7 @@ from_token!(Ctrl, LParan, CtrlLParan);
 from_token!(Ctrl, RParan, CtrlRParan);
 from_token!(Ctrl, RightArrow, CtrlRightArrow);
 from_token!(Ctrl, ThickRightArrow, CtrlThickRightArrow);
+from_token!(Ctrl, Pound, CtrlPound);
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Statement {
@@ -221,18 +237,76 @@ impl std::fmt::Display for Statement {
     }
 }
 
+/// `{:#}` writes a type the way it is written in source.
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Type(pub Ident);
+pub enum Type {
+    Name(Ident),
+    Array(Box<TypeArray>),
+    Ref(Box<TypeRef>),
+}
+
+impl Type {
+    /// The name of a type that is not an array.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Name(ident) => Some(&ident.value),
+            Self::Array(..) | Self::Ref(..) => None,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Name(ident) => ident.span,
+            Self::Array(array) => Span::from((array.left_bracket.span, array.right_bracket.span)),
+            Self::Ref(reference) => Span::from((reference.ampersand.span, reference.ty.span())),
+        }
+    }
+}
+
 impl std::fmt::Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let Self(ident) = &self;
-        write!(f, "({ident})")
+        match self {
+            Self::Name(ident) if f.alternate() => write!(f, "{ident}"),
+            Self::Name(ident) => write!(f, "({ident})"),
+            Self::Array(array) if f.alternate() => write!(f, "[{:#}; {}]", array.elem, array.len),
+            Self::Array(array) => write!(f, "([{}; {}])", array.elem, array.len),
+            Self::Ref(reference) if f.alternate() => write!(f, "{reference:#}"),
+            Self::Ref(reference) => write!(f, "({reference})"),
+        }
     }
 }
 
 impl From<&Ident> for Type {
     fn from(value: &Ident) -> Self {
-        Self(value.clone())
+        Self::Name(value.clone())
+    }
+}
+
+/// `[T; N]`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TypeArray {
+    pub left_bracket: CtrlLBracet,
+    pub elem: Type,
+    pub semicolon: CtrlSemiColon,
+    pub len: LitInt,
+    pub right_bracket: CtrlRBracet,
+}
+
+/// `&T` or `&mut T`
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TypeRef {
+    pub ampersand: CtrlAmpersand,
+    pub mutable: Option<keyword::Mut>,
+    pub ty: Type,
+}
+
+impl std::fmt::Display for TypeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mutable = if self.mutable.is_some() { "mut " } else { "" };
+        match f.alternate() {
+            true => write!(f, "&{mutable}{:#}", self.ty),
+            false => write!(f, "&{mutable}{}", self.ty),
+        }
     }
 }
 
@@ -250,12 +324,12 @@ impl std::fmt::Display for Param {
     }
 }
 
-impl From<(&Ident, &Ident)> for Param {
-    fn from((name, kind): (&Ident, &Ident)) -> Self {
-        let span = Span::from((name.span, kind.span));
+impl From<(&Ident, Type)> for Param {
+    fn from((name, kind): (&Ident, Type)) -> Self {
+        let span = Span::from((name.span, kind.span()));
         Self {
             name: name.clone(),
-            kind: kind.into(),
+            kind,
             span,
         }
     }
@@ -1,8 +1,12 @@
 use super::{
-    keyword, Ctrl, CtrlColon, CtrlComma, CtrlLBrace, CtrlLParan, CtrlRBrace, CtrlRParan,
-    CtrlRightArrow, CtrlSemiColon, Expr, ExprBinary, ExprBlock, ExprCall, ExprIf, ExprReturn,
-    Ident, Item, ItemFn, LitBool, LitChar, LitInt, LitStr, Op, OpAdd, OpDiv, OpEqualEqual, OpGeq,
-    OpGrt, OpLeq, OpLes, OpMul, OpNeq, OpSub, Param, Statement, Type,
+    keyword, Arm, Attribute, Ctrl, CtrlAmpersand, CtrlColon, CtrlComma, CtrlDot, CtrlDoubleColon,
+    CtrlLBrace, CtrlLBracet, CtrlLParan, CtrlPound, CtrlRBrace, CtrlRBracet, CtrlRParan,
+    CtrlRightArrow, CtrlSemiColon, CtrlStar, Expr, ExprArray, ExprAssign, ExprBinary, ExprBlock,
+    ExprCall, ExprCast, ExprDeref, ExprField, ExprIf, ExprIndex, ExprLet, ExprMatch, ExprPath,
+    ExprRef, ExprRepeat, ExprReturn, ExprStruct, ExprWhile, Ident, Item, ItemEnum, ItemFn,
+    ItemStruct, Lit, LitBool, LitChar, LitInt, LitStr, Op, OpAdd, OpDiv, OpEqual, OpEqualEqual,
+    OpGeq, OpGrt, OpLeq, OpLes, OpMul, OpNeq, OpSub, Param, Pat, PatVariant, Statement, Type,
+    TypeArray, TypeRef, Variant,
 };
 
 use crate::lexer::{Token, TokenStream};
@@ -11,15 +15,21 @@ type PResult<T> = std::result::Result<T, String>;
 pub struct Parser {
     stream: TokenStream,
     errors: Vec<String>,
+    /// Off while parsing the condition of an `if` or `while`, where a `{`
+    /// after a name starts the block instead of a struct literal.
+    struct_literals: bool,
 }
 
 // declaration
 // statement
 // expression
+// assignment
+// while
 // equality
 // comparison
 // term
 // factor
+// cast
 // unary
 // primary
 
@@ -28,6 +38,7 @@ impl Parser {
         Self {
             stream,
             errors: vec![],
+            struct_literals: true,
         }
     }
 
@@ -63,10 +74,29 @@ impl Parser {
             "expected '{'" => unimplemented!("expected a ident"),
             "expected '}'" => unimplemented!("expected a ident"),
             "functions params end with ')'" => unimplemented!("expected a ident"),
+            "expected '=' after let binding" => unimplemented!("expected '='"),
+            "expected '[' after '#'" => unimplemented!("expected '['"),
+            "expected attribute name" => unimplemented!("expected attribute name"),
+            "expected ')' after attribute argument" => unimplemented!("expected ')'"),
+            "expected ']' after attribute" => unimplemented!("expected ']'"),
             _ => unimplemented!("{error}"),
         }
     }
 
+    /// The next token if it is an `Expected`, otherwise `error` is reported and
+    /// one holding `value` is made up, so parsing can go on.
+    fn expect<Expected>(&mut self, value: &str, error: impl FnOnce() -> String) -> Expected
+    where
+        Expected: Token + Clone,
+    {
+        if let Some(token) = self.stream.next_if::<Expected>() {
+            return token.clone();
+        }
+        self.errors.push(error());
+        let span = self.stream.peek_blind().map(|token| token.span());
+        Expected::new(value.into(), span.unwrap_or_default())
+    }
+
     fn expr_next_if<Expected>(&mut self) -> Option<Expr>
     where
         Expected: Token + Clone,
@@ -103,10 +133,123 @@ impl Parser {
     }
 
     fn declaration(&mut self) -> PResult<Item> {
+        if self.stream.is_peek_a::<keyword::Struct>() {
+            return self.item_struct();
+        }
+        if self.stream.is_peek_a::<keyword::Enum>() {
+            return self.item_enum();
+        }
         self.item_fn()
     }
 
+    fn item_struct(&mut self) -> PResult<Item> {
+        let keyword_struct = self
+            .stream
+            .next_if::<keyword::Struct>()
+            .copied()
+            .ok_or::<String>("expected struct".into())?;
+        let name = self
+            .stream
+            .next_if::<Ident>()
+            .cloned()
+            .ok_or::<String>("expected a ident".into())?;
+        self.stream
+            .next_if::<CtrlLBrace>()
+            .ok_or::<String>("expected '{'".into())?;
+        let mut fields = vec![];
+        while let Some(field) = self.stream.next_if::<Ident>().cloned() {
+            self.ctrl_next_if::<CtrlColon>()
+                .ok_or::<String>("expected ':' after struct field name".into())?;
+            let kind = self.ty("expected a type after ':'")?;
+            fields.push((&field, kind).into());
+            if self.stream.next_if::<CtrlComma>().is_none() {
+                break;
+            }
+        }
+        self.stream
+            .next_if::<CtrlRBrace>()
+            .ok_or::<String>("expected '}'".into())?;
+        Ok(Item::Struct(ItemStruct {
+            keyword_struct,
+            name,
+            fields,
+        }))
+    }
+
+    fn item_enum(&mut self) -> PResult<Item> {
+        let keyword_enum = self
+            .stream
+            .next_if::<keyword::Enum>()
+            .copied()
+            .ok_or::<String>("expected enum".into())?;
+        let name = self
+            .stream
+            .next_if::<Ident>()
+            .cloned()
+            .ok_or::<String>("expected a ident".into())?;
+        self.stream
+            .next_if::<CtrlLBrace>()
+            .ok_or::<String>("expected '{'".into())?;
+        let mut variants = vec![];
+        while let Some(variant) = self.stream.next_if::<Ident>().cloned() {
+            let mut fields = vec![];
+            if self.stream.next_if::<CtrlLParan>().is_some() {
+                while !self.stream.is_peek_a::<CtrlRParan>() {
+                    fields.push(self.ty("expected the type of a field of a variant")?);
+                    if self.stream.next_if::<CtrlComma>().is_none() {
+                        break;
+                    }
+                }
+                self.stream
+                    .next_if::<CtrlRParan>()
+                    .ok_or::<String>(format!("expected ')' after the fields of '{variant}'"))?;
+            }
+            variants.push(Variant {
+                name: variant,
+                fields,
+            });
+            if self.stream.next_if::<CtrlComma>().is_none() {
+                break;
+            }
+        }
+        self.stream
+            .next_if::<CtrlRBrace>()
+            .ok_or::<String>("expected '}'".into())?;
+        Ok(Item::Enum(ItemEnum {
+            keyword_enum,
+            name,
+            variants,
+        }))
+    }
+
+    fn attributes(&mut self) -> PResult<Vec<Attribute>> {
+        let mut attrs = vec![];
+        while self.stream.next_if::<CtrlPound>().is_some() {
+            self.stream
+                .next_if::<CtrlLBracet>()
+                .ok_or::<String>("expected '[' after '#'".into())?;
+            let name = self
+                .stream
+                .next_if::<Ident>()
+                .cloned()
+                .ok_or::<String>("expected attribute name".into())?;
+            let mut arg = None;
+            if self.stream.next_if::<CtrlLParan>().is_some() {
+                arg = self.stream.next_if::<Ident>().cloned();
+                self.stream
+                    .next_if::<CtrlRParan>()
+                    .ok_or::<String>("expected ')' after attribute argument".into())?;
+            }
+            self.stream
+                .next_if::<CtrlRBracet>()
+                .ok_or::<String>("expected ']' after attribute".into())?;
+            attrs.push(Attribute { name, arg });
+        }
+        Ok(attrs)
+    }
+
     fn item_fn(&mut self) -> PResult<Item> {
+        let attrs = self.attributes()?;
         let keyword_fn = self
             .stream
             .next_if::<keyword::Fn>()
@@ -120,19 +263,56 @@ impl Parser {
         let params = self.params()?;
         let ret_type = self.ret_type()?;
         let block = self.block()?;
-        Ok(Item::Fn(ItemFn::new(
-            keyword_fn, name, params, block, ret_type,
-        )))
+        Ok(Item::Fn(Box::new(ItemFn::new(
+            attrs, keyword_fn, name, params, block, ret_type,
+        ))))
     }
 
     fn ret_type(&mut self) -> PResult<Option<Type>> {
         let Some(_) = self.stream.next_if::<CtrlRightArrow>() else {
             return Ok(None);
         };
-        let Some(t) = self.stream.next_if::<Ident>() else {
-            return Err("expected return type".into());
+        self.ty("expected return type").map(Some)
+    }
+
+    /// A name or `[T; N]`, `error` when there is neither.
+    fn ty(&mut self, error: &str) -> PResult<Type> {
+        if let Some(ampersand) = self.stream.next_if::<CtrlAmpersand>().cloned() {
+            let mutable = self.stream.next_if::<keyword::Mut>().copied();
+            let ty = self.ty("expected a type after '&'")?;
+            return Ok(Type::Ref(Box::new(TypeRef {
+                ampersand,
+                mutable,
+                ty,
+            })));
+        }
+        let Some(left_bracket) = self.stream.next_if::<CtrlLBracet>().cloned() else {
+            let name = self.stream.next_if::<Ident>().ok_or(error)?;
+            return Ok(name.into());
         };
-        Ok(Some(t.into()))
+        let elem = self.ty("expected the element type after '['")?;
+        let semicolon = self
+            .stream
+            .next_if::<CtrlSemiColon>()
+            .cloned()
+            .ok_or::<String>("expected ';' after the element type".into())?;
+        let len = self
+            .stream
+            .next_if::<LitInt>()
+            .cloned()
+            .ok_or::<String>("expected the length of the array after ';'".into())?;
+        let right_bracket = self
+            .stream
+            .next_if::<CtrlRBracet>()
+            .cloned()
+            .ok_or::<String>("expected ']' after the length of the array".into())?;
+        Ok(Type::Array(Box::new(TypeArray {
+            left_bracket,
+            elem,
+            semicolon,
+            len,
+            right_bracket,
+        })))
     }
 
     fn params(&mut self) -> PResult<Vec<Param>> {
@@ -148,10 +328,14 @@ impl Parser {
             self.ctrl_next_if::<CtrlColon>()
                 .ok_or::<String>("expected ':' after function param id".into())?;
 
-            let Some(kind) = self.stream.next_if::<Ident>().cloned() else {
+            if !self.stream.is_peek_a::<Ident>()
+                && !self.stream.is_peek_a::<CtrlLBracet>()
+                && !self.stream.is_peek_a::<CtrlAmpersand>()
+            {
                 break;
-            };
-            params.push((&name, &kind).into());
+            }
+            let kind = self.ty("expected a type after ':'")?;
+            params.push((&name, kind).into());
             // grabs trailing commas.
             self.stream.next_if::<CtrlComma>();
         }
@@ -182,7 +366,7 @@ impl Parser {
     }
 
     fn statement(&mut self) -> PResult<Statement> {
-        let stmt = self.expr_return()?;
+        let stmt = self.expr_let()?;
         let span = stmt.span();
         self.stream
             .next_if::<CtrlSemiColon>()
@@ -190,6 +374,22 @@ impl Parser {
         Ok(Statement { stmt, span })
     }
 
+    fn expr_let(&mut self) -> PResult<Expr> {
+        let Some(let_token) = self.stream.next_if::<keyword::Let>().copied() else {
+            return self.expr_return();
+        };
+        let name = self
+            .stream
+            .next_if::<Ident>()
+            .cloned()
+            .ok_or::<String>("expected a ident".into())?;
+        self.stream
+            .next_if::<OpEqual>()
+            .ok_or::<String>("expected '=' after let binding".into())?;
+        let value = self.expression();
+        Ok(ExprLet::new(let_token, name, value).into())
+    }
+
     fn expr_return(&mut self) -> PResult<Expr> {
         let ret = self.stream.next_if::<keyword::Return>().copied();
         let Some(ret) = ret else {
@@ -200,7 +400,25 @@ impl Parser {
     }
 
     fn expression(&mut self) -> Expr {
-        self.if_expression()
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> Expr {
+        let expr = self.while_expression();
+        if self.stream.next_if::<OpEqual>().is_none() {
+            return expr;
+        }
+        let value = self.assignment();
+        ExprAssign::new(expr, value).into()
+    }
+
+    fn while_expression(&mut self) -> Expr {
+        let Some(while_token) = self.stream.next_if::<keyword::While>().copied() else {
+            return self.if_expression();
+        };
+        let cond = self.condition();
+        let body = self.block().expect("failed to get block");
+        ExprWhile::new(while_token, cond, body).into()
     }
 
     // NOTE: Probably best that these functions return a Option over a Result cause then functions
@@ -210,11 +428,14 @@ impl Parser {
     // self.report(Error::MissingSimiColon(span))
     // ```
     fn if_expression(&mut self) -> Expr {
+        if let Some(keyword_match) = self.stream.next_if::<keyword::Match>().copied() {
+            return self.match_expression(keyword_match);
+        }
         if self.stream.peek::<keyword::If>().is_some() {
             // HACK: this implemention is a bit of a hack with all the funcitons not returning a
             // Result.
             let if_token = self.stream.next_as::<keyword::If>().cloned().unwrap();
-            let cond = Box::new(self.comparison());
+            let cond = Box::new(self.condition());
             let then_branch = self.block().expect("failed to get block");
             let else_branch = self.else_branch();
             return ExprIf::new(if_token, cond, then_branch, else_branch).into();
@@ -223,9 +444,7 @@ impl Parser {
     }
 
     fn else_branch(&mut self) -> Option<(keyword::Else, Box<Expr>)> {
-        let Some(keyword_else) = self.stream.next_if::<keyword::Else>().cloned() else {
-            return None;
-        };
+        let keyword_else = self.stream.next_if::<keyword::Else>().cloned()?;
         let block = if self.stream.peek::<keyword::If>().is_some() {
             self.if_expression()
         } else {
@@ -235,6 +454,94 @@ impl Parser {
         Some((keyword_else, Box::new(block)))
     }
 
+    /// `match value { pattern => value, ... }`, the `,` after an arm whose
+    /// value is a block can be left out.
+    fn match_expression(&mut self, keyword_match: keyword::Match) -> Expr {
+        let expr = Box::new(self.condition());
+        let left_brace = self.expect("{", || "expected '{' after the value of the match".into());
+        let mut arms = vec![];
+        while !self.stream.is_peek_a::<CtrlRBrace>() {
+            let pat = self.pattern();
+            let arrow = self.expect("=>", || format!("expected '=>' after the pattern '{pat}'"));
+            let body = match self.stream.is_peek_a::<CtrlLBrace>() {
+                true => Expr::Block(self.block().expect("failed to get block")),
+                false => self.with_struct_literals(true, Self::expression),
+            };
+            let is_block = matches!(body, Expr::Block(..));
+            arms.push(Arm { pat, arrow, body });
+            if self.stream.next_if::<CtrlComma>().is_none() && !is_block {
+                break;
+            }
+        }
+        let right_brace = self.expect("}", || "expected '}' after the arms of the match".into());
+        ExprMatch {
+            keyword_match,
+            expr,
+            left_brace,
+            arms,
+            right_brace,
+        }
+        .into()
+    }
+
+    /// `_`, an integer, bool or char literal, a name or
+    /// `Enum::Variant(a, _, ...)`.
+    fn pattern(&mut self) -> Pat {
+        let lit = (self.stream.next_if::<LitInt>().cloned().map(Lit::from))
+            .or_else(|| self.stream.next_if::<LitBool>().cloned().map(Lit::from))
+            .or_else(|| self.stream.next_if::<LitChar>().cloned().map(Lit::from));
+        if let Some(lit) = lit {
+            return Pat::Lit(lit);
+        }
+        if let Some(lit) = self.stream.next_if::<LitStr>() {
+            let span = lit.span;
+            self.errors.push("a str can not be a pattern".into());
+            return Pat::Wild(Ident::new("_", span));
+        }
+        let Some(name) = self.stream.next_if::<Ident>().cloned() else {
+            let (found, span) = match self.stream.peek_blind() {
+                Some(token) => (token.value(), token.span()),
+                None => Default::default(),
+            };
+            self.errors.push(format!("expected a pattern, found '{found}'"));
+            // Skipped, so the rest of the arm is parsed.
+            self.stream.next_as::<Ident>();
+            return Pat::Wild(Ident::new("_", span));
+        };
+        if name.value == "_" {
+            return Pat::Wild(name);
+        }
+        if !self.stream.is_peek_a::<CtrlDoubleColon>() {
+            return Pat::Bind(name);
+        }
+        let path = self.path(name);
+        let mut fields = vec![];
+        if self.stream.next_if::<CtrlLParan>().is_some() {
+            while let Some(field) = self.stream.next_if::<Ident>().cloned() {
+                fields.push(field);
+                if self.stream.next_if::<CtrlComma>().is_none() {
+                    break;
+                }
+            }
+            if self.stream.next_if::<CtrlRParan>().is_none() {
+                self.errors
+                    .push(format!("expected ')' after the fields of '{path:#}'"));
+            }
+        }
+        Pat::Variant(PatVariant { path, fields })
+    }
+
+    fn condition(&mut self) -> Expr {
+        self.with_struct_literals(false, Self::comparison)
+    }
+
+    fn with_struct_literals(&mut self, allowed: bool, f: impl FnOnce(&mut Self) -> Expr) -> Expr {
+        let outer = std::mem::replace(&mut self.struct_literals, allowed);
+        let expr = f(self);
+        self.struct_literals = outer;
+        expr
+    }
+
     fn comparison(&mut self) -> Expr {
         let mut expr = self.term();
         while let Some(op) = self
@@ -261,19 +568,60 @@ impl Parser {
     }
 
     fn factor(&mut self) -> Expr {
-        let mut expr = self.call();
+        let mut expr = self.cast();
         while let Some(op) = self.op_next_if::<OpMul>().or(self.op_next_if::<OpDiv>()) {
-            let right = self.call();
+            let right = self.cast();
             expr = Expr::from(ExprBinary::from((expr, right, op)))
         }
         expr
     }
 
+    fn cast(&mut self) -> Expr {
+        let mut expr = self.unary();
+        while let Some(as_token) = self.stream.next_if::<keyword::As>().copied() {
+            let Some(ty) = self.stream.next_if::<Ident>() else {
+                self.errors.push("expected a type after 'as'".into());
+                break;
+            };
+            expr = ExprCast::new(expr, as_token, ty.into()).into();
+        }
+        expr
+    }
+
+    fn unary(&mut self) -> Expr {
+        if let Some(ampersand) = self.stream.next_if::<CtrlAmpersand>().cloned() {
+            let mutable = self.stream.next_if::<keyword::Mut>().copied();
+            let expr = self.unary();
+            return ExprRef::new(ampersand, mutable, expr).into();
+        }
+        // `*` lexes as a multiplication, in front of an operand it dereferences.
+        if let Some(star) = self.stream.next_if::<OpMul>().cloned() {
+            let star = CtrlStar::new(star.value, star.span);
+            let expr = self.unary();
+            return ExprDeref::new(star, expr).into();
+        }
+        self.call()
+    }
+
     fn call(&mut self) -> Expr {
         let mut expr = self.primary();
 
-        if let Some(left_paran) = self.stream.next_if::<CtrlLParan>().cloned() {
-            expr = self.finish_call(expr, left_paran);
+        loop {
+            if let Some(left_paran) = self.stream.next_if::<CtrlLParan>().cloned() {
+                expr = self.finish_call(expr, left_paran);
+            } else if let Some(dot) = self.stream.next_if::<CtrlDot>().cloned() {
+                let Some(name) = self.stream.next_if::<Ident>().cloned() else {
+                    self.errors.push("expected a field name after '.'".into());
+                    break;
+                };
+                expr = ExprField::new(expr, dot, name).into();
+            } else if let Some(left_bracket) = self.stream.next_if::<CtrlLBracet>().cloned() {
+                let index = self.with_struct_literals(true, Self::expression);
+                let right_bracket = self.expect("]", || "expected ']' after the index".into());
+                expr = ExprIndex::new(expr, left_bracket, index, right_bracket).into();
+            } else {
+                break;
+            }
         }
 
         expr
@@ -283,7 +631,7 @@ impl Parser {
         let mut args = vec![];
         if !self.stream.is_peek_a::<CtrlRParan>() {
             while !self.stream.is_peek_a::<CtrlRParan>() {
-                args.push(self.expression());
+                args.push(self.with_struct_literals(true, Self::expression));
                 if self.stream.next_if::<CtrlComma>().is_none() {
                     break;
                 };
@@ -302,6 +650,16 @@ impl Parser {
     }
 
     fn primary(&mut self) -> Expr {
+        if self.stream.next_if::<CtrlLParan>().is_some() {
+            let expr = self.with_struct_literals(true, Self::expression);
+            if self.stream.next_if::<CtrlRParan>().is_none() {
+                self.errors.push("expected ')' after the expression".into());
+            }
+            return expr;
+        }
+        if let Some(left_bracket) = self.stream.next_if::<CtrlLBracet>().cloned() {
+            return self.with_struct_literals(true, |parser| parser.array_literal(left_bracket));
+        }
         let Some(expr) = self.expr_next_if::<LitInt>()
             .or(self.expr_next_if::<LitBool>())
             .or(self.expr_next_if::<LitStr>())
@@ -310,6 +668,77 @@ impl Parser {
                 // TODO: make this report an error
                 panic!("unknown expression '{:?}'", self.stream.peek_blind());
         };
-        expr
+        match expr {
+            Expr::Var(var) if self.stream.is_peek_a::<CtrlDoubleColon>() => {
+                self.path(var.name).into()
+            }
+            Expr::Var(var) if self.struct_literals && self.stream.is_peek_a::<CtrlLBrace>() => {
+                self.struct_literal(var.name)
+            }
+            expr => expr,
+        }
+    }
+
+    /// `[a, b, c]` or `[value; len]`, after the `[`.
+    fn array_literal(&mut self, left_bracket: CtrlLBracet) -> Expr {
+        let mut elems = vec![];
+        while !self.stream.is_peek_a::<CtrlRBracet>() {
+            elems.push(self.expression());
+            if elems.len() == 1 {
+                if let Some(semicolon) = self.stream.next_if::<CtrlSemiColon>().cloned() {
+                    return self.repeat(left_bracket, elems.remove(0), semicolon);
+                }
+            }
+            if self.stream.next_if::<CtrlComma>().is_none() {
+                break;
+            }
+        }
+        let right_bracket =
+            self.expect("]", || "expected ']' after the elements of an array".into());
+        ExprArray::new(left_bracket, elems, right_bracket).into()
+    }
+
+    fn repeat(&mut self, left_bracket: CtrlLBracet, value: Expr, semicolon: CtrlSemiColon) -> Expr {
+        let len = self.expect("0", || "expected the length of the array after ';'".into());
+        let right_bracket =
+            self.expect("]", || "expected ']' after the length of the array".into());
+        ExprRepeat {
+            left_bracket,
+            value: Box::new(value),
+            semicolon,
+            len,
+            right_bracket,
+        }
+        .into()
+    }
+
+    /// `Enum::Variant`, after the name of the enum.
+    fn path(&mut self, ty: Ident) -> ExprPath {
+        let double_colon = self.stream.next_if::<CtrlDoubleColon>().cloned().unwrap();
+        let variant = self.expect("", || format!("expected a variant of '{ty}' after '::'"));
+        ExprPath {
+            ty,
+            double_colon,
+            variant,
+        }
+    }
+
+    fn struct_literal(&mut self, name: Ident) -> Expr {
+        let left_brace = self.stream.next_if::<CtrlLBrace>().cloned().unwrap();
+        let mut fields = vec![];
+        while let Some(field) = self.stream.next_if::<Ident>().cloned() {
+            if self.stream.next_if::<CtrlColon>().is_none() {
+                self.errors
+                    .push(format!("expected ':' after field '{field}' of '{name}'"));
+                break;
+            }
+            fields.push((field, self.expression()));
+            if self.stream.next_if::<CtrlComma>().is_none() {
+                break;
+            }
+        }
+        let right_brace =
+            self.expect("}", || format!("expected '}}' after the fields of '{name}'"));
+        ExprStruct::new(name, left_brace, fields, right_brace).into()
     }
 }
@@ -22,3 +22,50 @@ macro_rules! snapshot {
 
 snapshot!(binary, "testdata/snapshots/binary.a");
 snapshot!(ifelse, "testdata/snapshots/ifelse.a");
+snapshot!(var, "testdata/snapshots/var.a");
+snapshot!(char, "testdata/snapshots/char.a");
+snapshot!(structs, "testdata/snapshots/struct.a");
+snapshot!(arrays, "testdata/snapshots/array.a");
+snapshot!(enums, "testdata/snapshots/enum.a");
+snapshot!(refs, "testdata/snapshots/ref.a");
+
+#[test]
+fn errors() {
+    use super::parse;
+    use crate::lexer::lex;
+    use pretty_assertions::assert_eq;
+    let errors = |src: &str| parse(lex(src).unwrap()).unwrap_err();
+    assert_eq!(
+        errors("struct P { x: u64 } fn main() { let p = P { x: 1 ; return p.x; }"),
+        vec!["expected '}' after the fields of 'P'".to_string()]
+    );
+    assert_eq!(
+        errors("fn main() { let a = [1, 2; let b = [0; ]; let c = [0; 2; return a[b[1]; }"),
+        vec![
+            "expected ']' after the elements of an array".to_string(),
+            "expected the length of the array after ';'".to_string(),
+            "expected ']' after the length of the array".to_string(),
+            "expected ']' after the index".to_string(),
+        ]
+    );
+    assert_eq!(
+        errors("fn main() { return (1 + 2 * 3; }"),
+        vec!["expected ')' after the expression".to_string()]
+    );
+    assert_eq!(
+        errors("fn main() { return match 1 { 1 2 }; }"),
+        vec!["expected '=>' after the pattern '1'".to_string()]
+    );
+    assert_eq!(
+        errors("fn main() { let a = match 1 2 => 3 }; return match a { E:: => 1, + => 2 }; }"),
+        vec![
+            "expected '{' after the value of the match".to_string(),
+            "expected a variant of 'E' after '::'".to_string(),
+            "expected a pattern, found '+'".to_string(),
+        ]
+    );
+    assert_eq!(
+        errors(r#"fn main() { return match "s" { "s" => 1, _ => 2 }; }"#),
+        vec!["a str can not be a pattern".to_string()]
+    );
+}
@@ -0,0 +1,485 @@
+//! The `.abc` file format.
+//!
+//! All numbers are little endian.
+//!
+//! ```text
+//! magic      b"abc\0"
+//! version    u16
+//! data       u32 length, bytes
+//! functions  u32 count, then per function:
+//!     name   u16 length, utf-8 bytes
+//!     params u8 count, u16 registers
+//!     regs   u16
+//!     frame  u32 bytes of stack memory
+//!     code   u32 count, then per op a u8 opcode and its operands
+//! ```
+use super::machine::validate;
+use super::{Builtin, Function, Op, Program};
+
+const MAGIC: &[u8; 4] = b"abc\0";
+/// Bumped whenever the layout or meaning of an opcode changes.
+const VERSION: u16 = 5;
+
+const LOAD_IMM: u8 = 0;
+const ADD: u8 = 1;
+const SUB: u8 = 2;
+const MUL: u8 = 3;
+const DIV: u8 = 4;
+const GRT: u8 = 5;
+const COPY: u8 = 6;
+const JUMP: u8 = 7;
+const JUMP_ZERO: u8 = 8;
+const CALL: u8 = 9;
+const TAIL_CALL: u8 = 10;
+const RETURN: u8 = 11;
+const LEAVE: u8 = 12;
+const DATA: u8 = 13;
+const BUILTIN: u8 = 14;
+const TAIL_BUILTIN: u8 = 15;
+const FRAME: u8 = 16;
+const LOAD: u8 = 17;
+const STORE: u8 = 18;
+
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes(MAGIC);
+    w.u16(VERSION);
+    w.u32(program.data.len() as u32);
+    w.bytes(&program.data);
+    w.u32(program.functions.len() as u32);
+    for func in program.functions.iter() {
+        w.u16(func.name.len() as u16);
+        w.bytes(func.name.as_bytes());
+        w.regs(&func.params);
+        w.u16(func.regs);
+        w.u32(func.frame);
+        w.u32(func.code.len() as u32);
+        for op in func.code.iter() {
+            w.op(op);
+        }
+    }
+    w.0
+}
+
+/// Reads a program and checks it can be run.
+pub fn decode(bytes: &[u8]) -> Result<Program, Vec<String>> {
+    let program = Reader { bytes, at: 0 }.program().map_err(|e| vec![e])?;
+    validate(&program)?;
+    Ok(program)
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn regs(&mut self, regs: &[u16]) {
+        self.u8(regs.len() as u8);
+        for r in regs {
+            self.u16(*r);
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        let mut three = |opcode, des, lhs, rhs| {
+            self.u8(opcode);
+            self.u16(des);
+            self.u16(lhs);
+            self.u16(rhs);
+        };
+        match op {
+            Op::Add { des, lhs, rhs } => three(ADD, *des, *lhs, *rhs),
+            Op::Sub { des, lhs, rhs } => three(SUB, *des, *lhs, *rhs),
+            Op::Mul { des, lhs, rhs } => three(MUL, *des, *lhs, *rhs),
+            Op::Div { des, lhs, rhs } => three(DIV, *des, *lhs, *rhs),
+            Op::Grt { des, lhs, rhs } => three(GRT, *des, *lhs, *rhs),
+            Op::LoadImm { des, imm } => {
+                self.u8(LOAD_IMM);
+                self.u16(*des);
+                self.bytes(&imm.to_le_bytes());
+            }
+            Op::Data { des, offset } => {
+                self.u8(DATA);
+                self.u16(*des);
+                self.u32(*offset);
+            }
+            Op::Frame { des, offset } => {
+                self.u8(FRAME);
+                self.u16(*des);
+                self.u32(*offset);
+            }
+            Op::Load {
+                des,
+                addr,
+                offset,
+                size,
+            } => {
+                self.u8(LOAD);
+                self.u16(*des);
+                self.u16(*addr);
+                self.u32(*offset);
+                self.u8(*size);
+            }
+            Op::Store {
+                addr,
+                offset,
+                src,
+                size,
+            } => {
+                self.u8(STORE);
+                self.u16(*addr);
+                self.u32(*offset);
+                self.u16(*src);
+                self.u8(*size);
+            }
+            Op::Copy { to, from } => {
+                self.u8(COPY);
+                self.u16(*to);
+                self.u16(*from);
+            }
+            Op::Jump { target } => {
+                self.u8(JUMP);
+                self.u32(*target);
+            }
+            Op::JumpZero { reg, target } => {
+                self.u8(JUMP_ZERO);
+                self.u16(*reg);
+                self.u32(*target);
+            }
+            Op::Call { func, args, ret } => {
+                self.u8(CALL);
+                self.u32(*func);
+                self.regs(args);
+                self.u16(*ret);
+            }
+            Op::TailCall { func, args } => {
+                self.u8(TAIL_CALL);
+                self.u32(*func);
+                self.regs(args);
+            }
+            Op::Builtin { builtin, args, ret } => {
+                self.u8(BUILTIN);
+                self.u8(*builtin as u8);
+                self.regs(args);
+                self.u16(*ret);
+            }
+            Op::TailBuiltin { builtin, args } => {
+                self.u8(TAIL_BUILTIN);
+                self.u8(*builtin as u8);
+                self.regs(args);
+            }
+            Op::Return { reg } => {
+                self.u8(RETURN);
+                self.u16(*reg);
+            }
+            Op::Leave => self.u8(LEAVE),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at + N)
+            .ok_or_else(|| format!("unexpected end of file at byte {}", self.at))?;
+        self.at += N;
+        Ok(bytes.try_into().expect("slice has length N"))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        self.bytes::<1>().map(|[b]| b)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        self.bytes().map(i64::from_le_bytes)
+    }
+
+    fn regs(&mut self) -> Result<Vec<u16>, String> {
+        let len = self.u8()?;
+        (0..len).map(|_| self.u16()).collect()
+    }
+
+    fn builtin(&mut self) -> Result<Builtin, String> {
+        let at = self.at;
+        let number = self.u8()?;
+        Builtin::ALL
+            .get(number as usize)
+            .copied()
+            .ok_or_else(|| format!("unknown builtin {number} at byte {at}"))
+    }
+
+    fn program(mut self) -> Result<Program, String> {
+        if &self.bytes()? != MAGIC {
+            return Err("not an abc file".into());
+        }
+        let version = self.u16()?;
+        if version != VERSION {
+            return Err(format!(
+                "abc version {version} is not supported, expected {VERSION}"
+            ));
+        }
+        let len = self.u32()? as usize;
+        let data = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or_else(|| format!("unexpected end of file at byte {}", self.at))?
+            .to_vec();
+        self.at += len;
+        let count = self.u32()?;
+        let functions = (0..count)
+            .map(|_| self.function())
+            .collect::<Result<Vec<Function>, String>>()?;
+        if self.at != self.bytes.len() {
+            return Err(format!("trailing bytes after byte {}", self.at));
+        }
+        Ok(Program { functions, data })
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let len = self.u16()? as usize;
+        let name = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or_else(|| format!("unexpected end of file at byte {}", self.at))?;
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| format!("function name at byte {} is not utf-8", self.at))?;
+        self.at += len;
+        let params = self.regs()?;
+        let regs = self.u16()?;
+        let frame = self.u32()?;
+        let count = self.u32()?;
+        let code = (0..count)
+            .map(|_| self.op())
+            .collect::<Result<Vec<Op>, String>>()?;
+        Ok(Function {
+            name,
+            params,
+            regs,
+            frame,
+            code,
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        let at = self.at;
+        let op = match self.u8()? {
+            LOAD_IMM => Op::LoadImm {
+                des: self.u16()?,
+                imm: self.i64()?,
+            },
+            ADD => Op::Add {
+                des: self.u16()?,
+                lhs: self.u16()?,
+                rhs: self.u16()?,
+            },
+            SUB => Op::Sub {
+                des: self.u16()?,
+                lhs: self.u16()?,
+                rhs: self.u16()?,
+            },
+            MUL => Op::Mul {
+                des: self.u16()?,
+                lhs: self.u16()?,
+                rhs: self.u16()?,
+            },
+            DIV => Op::Div {
+                des: self.u16()?,
+                lhs: self.u16()?,
+                rhs: self.u16()?,
+            },
+            GRT => Op::Grt {
+                des: self.u16()?,
+                lhs: self.u16()?,
+                rhs: self.u16()?,
+            },
+            DATA => Op::Data {
+                des: self.u16()?,
+                offset: self.u32()?,
+            },
+            FRAME => Op::Frame {
+                des: self.u16()?,
+                offset: self.u32()?,
+            },
+            LOAD => Op::Load {
+                des: self.u16()?,
+                addr: self.u16()?,
+                offset: self.u32()?,
+                size: self.u8()?,
+            },
+            STORE => Op::Store {
+                addr: self.u16()?,
+                offset: self.u32()?,
+                src: self.u16()?,
+                size: self.u8()?,
+            },
+            COPY => Op::Copy {
+                to: self.u16()?,
+                from: self.u16()?,
+            },
+            JUMP => Op::Jump {
+                target: self.u32()?,
+            },
+            JUMP_ZERO => Op::JumpZero {
+                reg: self.u16()?,
+                target: self.u32()?,
+            },
+            CALL => Op::Call {
+                func: self.u32()?,
+                args: self.regs()?,
+                ret: self.u16()?,
+            },
+            TAIL_CALL => Op::TailCall {
+                func: self.u32()?,
+                args: self.regs()?,
+            },
+            BUILTIN => Op::Builtin {
+                builtin: self.builtin()?,
+                args: self.regs()?,
+                ret: self.u16()?,
+            },
+            TAIL_BUILTIN => Op::TailBuiltin {
+                builtin: self.builtin()?,
+                args: self.regs()?,
+            },
+            RETURN => Op::Return { reg: self.u16()? },
+            LEAVE => Op::Leave,
+            opcode => return Err(format!("unknown opcode {opcode} at byte {at}")),
+        };
+        Ok(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn program() -> Program {
+        Program {
+            functions: vec![
+                Function {
+                    name: "main".into(),
+                    params: vec![],
+                    regs: 3,
+                    frame: 16,
+                    code: vec![
+                        Op::LoadImm { des: 0, imm: -7 },
+                        Op::Data { des: 2, offset: 0 },
+                        Op::Frame { des: 2, offset: 8 },
+                        Op::Store {
+                            addr: 2,
+                            offset: 4,
+                            src: 0,
+                            size: 4,
+                        },
+                        Op::Load {
+                            des: 0,
+                            addr: 2,
+                            offset: 4,
+                            size: 4,
+                        },
+                        Op::Call {
+                            func: 1,
+                            args: vec![0],
+                            ret: 1,
+                        },
+                        Op::JumpZero { reg: 1, target: 8 },
+                        Op::TailCall {
+                            func: 1,
+                            args: vec![1],
+                        },
+                        Op::Return { reg: 1 },
+                    ],
+                },
+                Function {
+                    name: "neg".into(),
+                    params: vec![1],
+                    regs: 3,
+                    frame: 0,
+                    code: vec![
+                        Op::LoadImm { des: 0, imm: 0 },
+                        Op::Sub {
+                            des: 2,
+                            lhs: 0,
+                            rhs: 1,
+                        },
+                        Op::Copy { to: 0, from: 2 },
+                        Op::Builtin {
+                            builtin: Builtin::PrintInt,
+                            args: vec![0],
+                            ret: 2,
+                        },
+                        Op::Return { reg: 0 },
+                    ],
+                },
+            ],
+            data: b"hi\0".to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program();
+        let bytes = encode(&program);
+        assert_eq!(&bytes[..6], b"abc\0\x05\x00");
+        assert_eq!(decode(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn bad_files() {
+        let bytes = encode(&program());
+        assert_eq!(decode(b"\x7fELF"), Err(vec!["not an abc file".to_string()]));
+        let mut old = bytes.clone();
+        old[4] = 0;
+        assert_eq!(
+            decode(&old),
+            Err(vec![
+                "abc version 0 is not supported, expected 5".to_string()
+            ])
+        );
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(vec![format!(
+                "unexpected end of file at byte {}",
+                bytes.len() - 2
+            )])
+        );
+        let mut bad_jump = program();
+        bad_jump.functions[0].code[6] = Op::JumpZero { reg: 1, target: 9 };
+        assert_eq!(
+            decode(&encode(&bad_jump)),
+            Err(vec![
+                "main at 0006: jump target 0009 is out of bounds".to_string()
+            ])
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! Lowers ir, after `ssa::destruct`, to bytecode.
+//!
+//! Every ir instruction becomes at most one op, so the position of a label is
+//! the number of ops emitted before it. Virtual registers are kept as they
+//! are, a frame simply has as many registers as the function uses. Every
+//! `Alloca` gets a slot of its own in the stack memory of the frame.
+use std::collections::HashMap;
+
+use super::{Builtin, Function, Op, Program};
+use crate::ir::{
+    write_str, Add, Alloca, Call, Conditional, Copy, DefFunc, DefLabel, Div, Grt, Instruction,
+    Jump, Label, Load, LoadImm, LoadStr, Mul, Reg, Return, Store, Sub, TailCall,
+};
+
+pub fn lower(code: Vec<Instruction>) -> Result<Program, Vec<String>> {
+    let funcs = code
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::DefFunc(func) => Some(func),
+            _ => None,
+        })
+        .collect::<Vec<&DefFunc>>();
+    let indices = funcs
+        .iter()
+        .enumerate()
+        .map(|(i, func)| (func.name.as_str(), i as u32))
+        .collect::<HashMap<&str, u32>>();
+    let mut errors = vec![];
+    if !indices.contains_key("main") {
+        errors.push("program has no 'main' function".to_string());
+    }
+    let mut functions = vec![];
+    let mut data = Data::default();
+    for func in funcs {
+        match lower_func(func, &indices, &mut data) {
+            Ok(function) => functions.push(function),
+            Err(e) => errors.push(format!("{}: {e}", func.name)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(Program {
+            functions,
+            data: data.bytes,
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+/// String literals, each one stored once.
+#[derive(Default)]
+struct Data {
+    bytes: Vec<u8>,
+    strings: HashMap<String, u32>,
+}
+
+impl Data {
+    fn offset(&mut self, load: &LoadStr) -> Result<u32, String> {
+        if let Some(offset) = self.strings.get(&load.value) {
+            return Ok(*offset);
+        }
+        let offset = u32::try_from(write_str(&mut self.bytes, &load.value))
+            .map_err(|_| format!("more than {} bytes of data", u32::MAX))?;
+        self.strings.insert(load.value.clone(), offset);
+        Ok(offset)
+    }
+}
+
+fn reg(reg: &Reg) -> Result<u16, String> {
+    u16::try_from(reg.0).map_err(|_| format!("more than {} registers", u16::MAX))
+}
+
+fn regs(regs: &[Reg]) -> Result<Vec<u16>, String> {
+    regs.iter().map(reg).collect()
+}
+
+fn lower_func(
+    func: &DefFunc,
+    indices: &HashMap<&str, u32>,
+    data: &mut Data,
+) -> Result<Function, String> {
+    let mut labels = HashMap::new();
+    let mut len = 0;
+    for instruction in func.body.iter() {
+        match instruction {
+            Instruction::DefLabel(DefLabel(label)) => {
+                labels.insert(label, len as u32);
+            }
+            Instruction::Enter(..) => {}
+            _ => len += 1,
+        }
+    }
+    let target = |label: &Label| {
+        labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("label '{label}' is never defined"))
+    };
+    // Functions of the program shadow builtins of the same name.
+    let callee = |name: &Label| match indices.get(name.0.as_str()) {
+        Some(index) => Ok(Ok(*index)),
+        None => Builtin::from_name(&name.0)
+            .map(Err)
+            .ok_or_else(|| format!("call to unknown function '{name}'")),
+    };
+
+    let mut frame = 0u32;
+    let mut code = Vec::with_capacity(len);
+    for instruction in func.body.iter() {
+        let op = match instruction {
+            Instruction::DefLabel(..) | Instruction::Enter(..) => continue,
+            Instruction::LoadImm(LoadImm { des, imm }) => Op::LoadImm {
+                des: reg(des)?,
+                imm: imm.0 as i64,
+            },
+            Instruction::LoadStr(load) => Op::Data {
+                des: reg(&load.des)?,
+                offset: data.offset(load)?,
+            },
+            Instruction::Add(Add { des, lhs, rhs }) => Op::Add {
+                des: reg(des)?,
+                lhs: reg(lhs)?,
+                rhs: reg(rhs)?,
+            },
+            Instruction::Sub(Sub { des, lhs, rhs }) => Op::Sub {
+                des: reg(des)?,
+                lhs: reg(lhs)?,
+                rhs: reg(rhs)?,
+            },
+            Instruction::Mul(Mul { des, lhs, rhs }) => Op::Mul {
+                des: reg(des)?,
+                lhs: reg(lhs)?,
+                rhs: reg(rhs)?,
+            },
+            Instruction::Div(Div { des, lhs, rhs }) => Op::Div {
+                des: reg(des)?,
+                lhs: reg(lhs)?,
+                rhs: reg(rhs)?,
+            },
+            Instruction::Grt(Grt { des, lhs, rhs }) => Op::Grt {
+                des: reg(des)?,
+                lhs: reg(lhs)?,
+                rhs: reg(rhs)?,
+            },
+            Instruction::Alloca(Alloca { des, size }) => {
+                let offset = frame;
+                frame = size
+                    .next_multiple_of(8)
+                    .max(8)
+                    .checked_add(frame)
+                    .ok_or_else(|| format!("more than {} bytes of stack", u32::MAX))?;
+                Op::Frame {
+                    des: reg(des)?,
+                    offset,
+                }
+            }
+            Instruction::Load(Load {
+                des,
+                addr,
+                offset,
+                size,
+            }) => Op::Load {
+                des: reg(des)?,
+                addr: reg(addr)?,
+                offset: *offset,
+                size: *size,
+            },
+            Instruction::Store(Store {
+                addr,
+                offset,
+                src,
+                size,
+            }) => Op::Store {
+                addr: reg(addr)?,
+                offset: *offset,
+                src: reg(src)?,
+                size: *size,
+            },
+            Instruction::Copy(Copy { to, from }) => Op::Copy {
+                to: reg(to)?,
+                from: reg(from)?,
+            },
+            Instruction::Jump(Jump(label)) => Op::Jump {
+                target: target(label)?,
+            },
+            Instruction::Conditional(Conditional { label, reg: cond }) => Op::JumpZero {
+                reg: reg(cond)?,
+                target: target(label)?,
+            },
+            Instruction::Call(Call { caller, args, ret }) => match callee(caller)? {
+                Ok(func) => Op::Call {
+                    func,
+                    args: regs(args)?,
+                    ret: reg(ret)?,
+                },
+                Err(builtin) => Op::Builtin {
+                    builtin,
+                    args: regs(args)?,
+                    ret: reg(ret)?,
+                },
+            },
+            Instruction::TailCall(TailCall { caller, args }) => match callee(caller)? {
+                Ok(func) => Op::TailCall {
+                    func,
+                    args: regs(args)?,
+                },
+                Err(builtin) => Op::TailBuiltin {
+                    builtin,
+                    args: regs(args)?,
+                },
+            },
+            Instruction::Return(Return(r)) => Op::Return { reg: reg(r)? },
+            Instruction::Leave(..) => Op::Leave,
+            Instruction::Phi(..) => return Err("phis must be removed by ssa::destruct".into()),
+            Instruction::DefFunc(..) => return Err("nested function".into()),
+        };
+        code.push(op);
+    }
+
+    let params = func.params.iter().map(|(r, _)| *r).collect::<Vec<Reg>>();
+    let used = code.iter().flat_map(Op::regs).map(usize::from);
+    let count = params
+        .iter()
+        .map(|r| r.0)
+        .chain(used)
+        .max()
+        .map_or(0, |r| r + 1);
+    Ok(Function {
+        name: func.name.clone(),
+        params: regs(&params)?,
+        regs: u16::try_from(count).map_err(|_| format!("more than {} registers", u16::MAX))?,
+        frame,
+        code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::text::parse;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn labels_become_indices() {
+        let code = parse(
+            "
+fn main(%0: i64) -> i64 {
+.L0:
+    enter
+    br %0, .L1
+    %1 = call main(%0)
+    ret %1
+.L1:
+    tail main(%0)
+.exit:
+    leave
+}",
+        )
+        .unwrap();
+        let program = lower(code).unwrap();
+        assert_eq!(
+            program,
+            Program {
+                functions: vec![Function {
+                    name: "main".into(),
+                    params: vec![0],
+                    regs: 2,
+                    frame: 0,
+                    code: vec![
+                        Op::JumpZero { reg: 0, target: 3 },
+                        Op::Call {
+                            func: 0,
+                            args: vec![0],
+                            ret: 1
+                        },
+                        Op::Return { reg: 1 },
+                        Op::TailCall {
+                            func: 0,
+                            args: vec![0]
+                        },
+                        Op::Leave,
+                    ],
+                }],
+                data: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn strings_are_stored_once() {
+        let code = parse(
+            r#"
+fn main() -> i64 {
+    enter
+    %0 = str "ab"
+    %1 = str "c"
+    %2 = str "ab"
+.exit:
+    leave
+}"#,
+        )
+        .unwrap();
+        let program = lower(code).unwrap();
+        assert_eq!(
+            program.functions[0].code[..3],
+            [
+                Op::Data { des: 0, offset: 0 },
+                Op::Data { des: 1, offset: 3 },
+                Op::Data { des: 2, offset: 0 },
+            ]
+        );
+        assert_eq!(program.data, b"ab\0c\0");
+    }
+
+    #[test]
+    fn rejects_phis() {
+        let code = parse(
+            "
+fn main(%0: i64) -> i64 {
+.L0:
+    enter
+    %1 = phi [%0, .L0]
+.exit:
+    leave
+}",
+        )
+        .unwrap();
+        assert_eq!(
+            lower(code),
+            Err(vec![
+                "main: phis must be removed by ssa::destruct".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn requires_main() {
+        let code = parse(
+            "
+fn f() -> i64 {
+    enter
+.exit:
+    leave
+}",
+        )
+        .unwrap();
+        assert_eq!(
+            lower(code),
+            Err(vec!["program has no 'main' function".to_string()])
+        );
+    }
+}
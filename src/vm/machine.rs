@@ -0,0 +1,683 @@
+//! Runs a program.
+//!
+//! Programs are checked once before they run, every register index, jump
+//! target and call is known to be in bounds after that so the loop itself
+//! only has to check the stack depth, division by zero and the addresses of
+//! loads and stores.
+//!
+//! The stack memory of the frames lives at `STACK` and up, far above the
+//! program's data, so an address tells which of the two it points into.
+use std::io::Write;
+
+use super::{Builtin, Function, Op, Program};
+
+/// Calls nested deeper than this stop the program.
+const MAX_FRAMES: usize = 1 << 16;
+/// Address of the first byte of stack memory.
+const STACK: i64 = 1 << 40;
+
+/// Runs `main` with the command line `args` and gives back what it returned.
+pub fn run(program: &Program, args: &[String]) -> Result<i64, Vec<String>> {
+    run_to(program, args, &mut std::io::stdout().lock())
+}
+
+/// Like `run`, with the output of the program going to `out`.
+pub fn run_to(program: &Program, args: &[String], out: &mut dyn Write) -> Result<i64, Vec<String>> {
+    validate(program)?;
+    let Some(main) = program.functions.iter().position(|f| f.name == "main") else {
+        return Err(vec!["program has no 'main' function".into()]);
+    };
+    // There is no argv in the vm, `arg` reads the arguments instead.
+    let main_args = match program.functions[main].params.len() {
+        0 => vec![],
+        2 => vec![args.len() as i64, 0],
+        _ => return Err(vec!["'main' takes no arguments or 'argc' and 'argv'".into()]),
+    };
+    let host = Host {
+        out,
+        memory: program.data.clone(),
+        args,
+    };
+    Machine::new(program, main, host)
+        .run(main_args)
+        .map_err(|e| vec![e])
+}
+
+/// Checks that every op only refers to things that exist.
+pub fn validate(program: &Program) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    for func in program.functions.iter() {
+        let mut error = |at: Option<usize>, e: String| match at {
+            Some(pc) => errors.push(format!("{} at {pc:04}: {e}", func.name)),
+            None => errors.push(format!("{}: {e}", func.name)),
+        };
+        if let Some(r) = func.params.iter().find(|r| **r >= func.regs) {
+            error(None, format!("parameter r{r} is out of bounds"));
+        }
+        for (pc, op) in func.code.iter().enumerate() {
+            if let Some(r) = op.regs().into_iter().find(|r| *r >= func.regs) {
+                error(Some(pc), format!("register r{r} is out of bounds"));
+            }
+            match op {
+                Op::Data { offset, .. } if *offset as usize > program.data.len() => {
+                    error(
+                        Some(pc),
+                        format!("data offset {offset:04} is out of bounds"),
+                    );
+                }
+                Op::Frame { offset, .. } if *offset >= func.frame => {
+                    error(Some(pc), format!("frame offset {offset} is out of bounds"));
+                }
+                Op::Load { size, .. } | Op::Store { size, .. } if !matches!(size, 1 | 4 | 8) => {
+                    error(Some(pc), format!("can not access {size} bytes at once"));
+                }
+                Op::Jump { target } | Op::JumpZero { target, .. }
+                    if *target as usize >= func.code.len() =>
+                {
+                    error(
+                        Some(pc),
+                        format!("jump target {target:04} is out of bounds"),
+                    );
+                }
+                Op::Call {
+                    func: callee, args, ..
+                }
+                | Op::TailCall { func: callee, args } => {
+                    match program.functions.get(*callee as usize) {
+                        None => error(Some(pc), format!("function @{callee} does not exist")),
+                        Some(callee) if callee.params.len() != args.len() => error(
+                            Some(pc),
+                            format!(
+                                "'{}' takes {} arguments but is given {}",
+                                callee.name,
+                                callee.params.len(),
+                                args.len()
+                            ),
+                        ),
+                        _ => {}
+                    }
+                }
+                Op::Builtin { builtin, args, .. } | Op::TailBuiltin { builtin, args }
+                    if args.len() != builtin.arity() =>
+                {
+                    error(
+                        Some(pc),
+                        format!(
+                            "'{builtin}' takes {} arguments but is given {}",
+                            builtin.arity(),
+                            args.len()
+                        ),
+                    );
+                }
+                _ => {}
+            }
+        }
+        let terminated = matches!(
+            func.code.last(),
+            Some(
+                Op::Jump { .. }
+                    | Op::TailCall { .. }
+                    | Op::TailBuiltin { .. }
+                    | Op::Return { .. }
+                    | Op::Leave
+            )
+        );
+        if !terminated {
+            error(None, "does not end in a jump or return".into());
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+struct Frame {
+    func: usize,
+    pc: usize,
+    /// Where the registers of this frame start.
+    base: usize,
+    /// Where the stack memory of this frame starts.
+    stack: usize,
+    /// Register of the caller receiving the returned value.
+    ret: u16,
+}
+
+/// What the builtins work with.
+struct Host<'a> {
+    out: &'a mut dyn Write,
+    /// The program's data followed by every `str` made by a builtin.
+    memory: Vec<u8>,
+    args: &'a [String],
+}
+
+struct Machine<'a> {
+    functions: &'a [Function],
+    host: Host<'a>,
+    frames: Vec<Frame>,
+    regs: Vec<i64>,
+    stack: Vec<u8>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(program: &'a Program, main: usize, host: Host<'a>) -> Self {
+        Self {
+            functions: &program.functions,
+            host,
+            frames: vec![Frame {
+                func: main,
+                pc: 0,
+                base: 0,
+                stack: 0,
+                ret: 0,
+            }],
+            regs: vec![],
+            stack: vec![],
+        }
+    }
+
+    fn run(&mut self, args: Vec<i64>) -> Result<i64, String> {
+        self.enter(args);
+        loop {
+            let functions = self.functions;
+            let Some(frame) = self.frames.last_mut() else {
+                unreachable!("returning from main stops the machine");
+            };
+            let func = &functions[frame.func];
+            let op = &func.code[frame.pc];
+            frame.pc += 1;
+            let base = frame.base;
+            let stack = frame.stack;
+            let regs = &mut self.regs[base..base + func.regs as usize];
+            match op {
+                Op::LoadImm { des, imm } => regs[*des as usize] = *imm,
+                Op::Data { des, offset } => regs[*des as usize] = *offset as i64,
+                Op::Add { des, lhs, rhs } => {
+                    regs[*des as usize] = regs[*lhs as usize].wrapping_add(regs[*rhs as usize])
+                }
+                Op::Sub { des, lhs, rhs } => {
+                    regs[*des as usize] = regs[*lhs as usize].wrapping_sub(regs[*rhs as usize])
+                }
+                Op::Mul { des, lhs, rhs } => {
+                    regs[*des as usize] = regs[*lhs as usize].wrapping_mul(regs[*rhs as usize])
+                }
+                Op::Div { des, lhs, rhs } => {
+                    let rhs = regs[*rhs as usize];
+                    if rhs == 0 {
+                        return Err(format!("division by zero in '{}'", func.name));
+                    }
+                    regs[*des as usize] = regs[*lhs as usize].wrapping_div(rhs)
+                }
+                Op::Grt { des, lhs, rhs } => {
+                    regs[*des as usize] = (regs[*lhs as usize] > regs[*rhs as usize]) as i64
+                }
+                Op::Frame { des, offset } => {
+                    regs[*des as usize] = STACK + (stack + *offset as usize) as i64
+                }
+                Op::Load {
+                    des,
+                    addr,
+                    offset,
+                    size,
+                } => {
+                    let address = regs[*addr as usize].wrapping_add(*offset as i64);
+                    let bytes = access(&mut self.host.memory, &mut self.stack, address, *size)
+                        .map_err(|e| format!("{e} in '{}'", func.name))?;
+                    let mut value = [0; 8];
+                    value[..bytes.len()].copy_from_slice(bytes);
+                    regs[*des as usize] = i64::from_le_bytes(value);
+                }
+                Op::Store {
+                    addr,
+                    offset,
+                    src,
+                    size,
+                } => {
+                    let address = regs[*addr as usize].wrapping_add(*offset as i64);
+                    let bytes = access(&mut self.host.memory, &mut self.stack, address, *size)
+                        .map_err(|e| format!("{e} in '{}'", func.name))?;
+                    let value = regs[*src as usize].to_le_bytes();
+                    bytes.copy_from_slice(&value[..bytes.len()]);
+                }
+                Op::Copy { to, from } => regs[*to as usize] = regs[*from as usize],
+                Op::Jump { target } => frame.pc = *target as usize,
+                Op::JumpZero { reg, target } => {
+                    if regs[*reg as usize] == 0 {
+                        frame.pc = *target as usize;
+                    }
+                }
+                Op::Call { func, args, ret } => {
+                    let args = args.iter().map(|r| regs[*r as usize]).collect::<Vec<i64>>();
+                    if self.frames.len() == MAX_FRAMES {
+                        return Err(format!(
+                            "stack overflow calling '{}'",
+                            functions[*func as usize].name
+                        ));
+                    }
+                    let base = self.regs.len();
+                    let stack = self.stack.len();
+                    self.frames.push(Frame {
+                        func: *func as usize,
+                        pc: 0,
+                        base,
+                        stack,
+                        ret: *ret,
+                    });
+                    self.enter(args);
+                }
+                Op::TailCall { func, args } => {
+                    let args = args.iter().map(|r| regs[*r as usize]).collect::<Vec<i64>>();
+                    frame.func = *func as usize;
+                    frame.pc = 0;
+                    self.regs.truncate(base);
+                    self.stack.truncate(stack);
+                    self.enter(args);
+                }
+                Op::Builtin { builtin, args, ret } => {
+                    let args = args.iter().map(|r| regs[*r as usize]).collect::<Vec<i64>>();
+                    regs[*ret as usize] = self.host.builtin(*builtin, &args, &mut self.stack)?;
+                }
+                Op::TailBuiltin { builtin, args } => {
+                    let args = args.iter().map(|r| regs[*r as usize]).collect::<Vec<i64>>();
+                    let value = self.host.builtin(*builtin, &args, &mut self.stack)?;
+                    if let Some(value) = self.leave(value) {
+                        return Ok(value);
+                    }
+                }
+                Op::Return { reg } => {
+                    let value = regs[*reg as usize];
+                    if let Some(value) = self.leave(value) {
+                        return Ok(value);
+                    }
+                }
+                Op::Leave => {
+                    if let Some(value) = self.leave(0) {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sets up the registers and zeroed stack memory of the newest frame.
+    fn enter(&mut self, args: Vec<i64>) {
+        let Some(frame) = self.frames.last() else {
+            return;
+        };
+        let func = &self.functions[frame.func];
+        self.regs.resize(frame.base + func.regs as usize, 0);
+        self.stack.resize(frame.stack + func.frame as usize, 0);
+        for (param, arg) in func.params.iter().zip(args) {
+            self.regs[frame.base + *param as usize] = arg;
+        }
+    }
+
+    /// Pops the newest frame, giving back `value` when it was the last one.
+    fn leave(&mut self, value: i64) -> Option<i64> {
+        let frame = self.frames.pop()?;
+        self.regs.truncate(frame.base);
+        self.stack.truncate(frame.stack);
+        let Some(caller) = self.frames.last() else {
+            return Some(value);
+        };
+        self.regs[caller.base + frame.ret as usize] = value;
+        None
+    }
+}
+
+impl Host<'_> {
+    /// `validate` checked the number of `args`. The print builtins give back
+    /// zero.
+    fn builtin(&mut self, builtin: Builtin, args: &[i64], stack: &mut [u8]) -> Result<i64, String> {
+        let written = match (builtin, args) {
+            (Builtin::Print, [s]) => self.out.write_all(str_at(&mut self.memory, stack, *s)?),
+            (Builtin::Println, [s]) => {
+                let s = str_at(&mut self.memory, stack, *s)?;
+                self.out
+                    .write_all(s)
+                    .and_then(|_| self.out.write_all(b"\n"))
+            }
+            (Builtin::PrintInt, [n]) => write!(self.out, "{n}"),
+            (Builtin::ArgCount, []) => return Ok(self.args.len() as i64),
+            (Builtin::Arg, [ret, i]) => {
+                let arg = usize::try_from(*i).ok().and_then(|i| self.args.get(i));
+                let arg = arg.map_or(String::new(), String::clone);
+                return self.new_str(stack, *ret, &arg);
+            }
+            (Builtin::Env, [ret, name]) => {
+                let name = str_at(&mut self.memory, stack, *name)?;
+                let name = String::from_utf8_lossy(name).into_owned();
+                let valid = !name.is_empty() && !name.contains(['=', '\0']);
+                let value = valid
+                    .then(|| std::env::var(name).ok())
+                    .flatten()
+                    .unwrap_or_default();
+                return self.new_str(stack, *ret, &value);
+            }
+            (Builtin::IndexOutOfBounds, [index, len]) => {
+                return Err(format!("index {index} is out of bounds for length {len}"));
+            }
+            _ => unreachable!("'{builtin}' is given {} arguments", args.len()),
+        };
+        written.map_err(|e| format!("could not write to stdout: {e}"))?;
+        Ok(0)
+    }
+
+    /// Appends the bytes of `value` to memory and puts a `str` of them at
+    /// `ret`, which is given back.
+    fn new_str(&mut self, stack: &mut [u8], ret: i64, value: &str) -> Result<i64, String> {
+        let bytes = crate::ir::write_str(&mut self.memory, value) as i64;
+        for (offset, word) in [(0, bytes), (8, value.len() as i64)] {
+            access(&mut self.memory, stack, ret.wrapping_add(offset), 8)?
+                .copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(ret)
+    }
+}
+
+/// The `size` bytes at `address`, in the stack memory or the program's.
+fn access<'m>(
+    memory: &'m mut [u8],
+    stack: &'m mut [u8],
+    address: i64,
+    size: u8,
+) -> Result<&'m mut [u8], String> {
+    let (memory, start) = match address.checked_sub(STACK) {
+        Some(start) if start >= 0 => (stack, start),
+        _ => (memory, address),
+    };
+    usize::try_from(start)
+        .ok()
+        .and_then(|start| memory.get_mut(start..start.checked_add(size as usize)?))
+        .ok_or_else(|| format!("{size} bytes at {address:#x} are out of bounds"))
+}
+
+/// The bytes of the `str` at `address`, which holds their address and their
+/// number. They are a literal or made by a builtin, so in the program's
+/// memory.
+fn str_at<'m>(memory: &'m mut [u8], stack: &mut [u8], address: i64) -> Result<&'m [u8], String> {
+    let mut word = |offset: i64| {
+        access(memory, stack, address.wrapping_add(offset), 8)
+            .map(|bytes| i64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let (start, len) = (word(0)?, word(8)?);
+    let error = || format!("{address:#x} is not the address of a str");
+    let start = usize::try_from(start).map_err(|_| error())?;
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| start.checked_add(len))
+        .filter(|end| *end <= memory.len())
+        .ok_or_else(error)?;
+    Ok(&memory[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{code_gen, ssa, tail_calls};
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use crate::vm::lower;
+    use pretty_assertions::assert_eq;
+
+    fn compile(src: &str) -> Result<Program, Vec<String>> {
+        lex(src)
+            .and_then(parse)
+            .and_then(code_gen)
+            .and_then(tail_calls)
+            .and_then(ssa::construct)
+            .and_then(ssa::destruct)
+            .and_then(lower)
+    }
+
+    fn run_src(src: &str) -> Result<i64, Vec<String>> {
+        compile(src).and_then(|program| run(&program, &[]))
+    }
+
+    #[test]
+    fn programs() {
+        let src = "
+            fn count(n: u64, acc: u64) -> u64 {
+                if n > 0 { return count(n - 1, acc + 1); };
+                return acc;
+            }
+            fn fib(n: u64) -> u64 {
+                if 2 > n { return n; };
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn sum(n: u64) -> u64 {
+                let i = 0;
+                let total = 0;
+                while n > i {
+                    i = i + 1;
+                    total = total + i * 2;
+                };
+                return total / 2;
+            }
+            fn main() { return count(1000000, 0) / 1000 + fib(15) - sum(10); }";
+        assert_eq!(run_src(src), Ok(1000 + 610 - 55));
+    }
+
+    #[test]
+    fn casts() {
+        let src = "fn main() { let c = 0 - 1; return (c as char) as u32; }";
+        assert_eq!(run_src(src), Ok(255));
+        let src = "fn main() { return (4294967296 as u32) + ((0 - 1) as u32 > 65536); }";
+        assert_eq!(run_src(src), Ok(1));
+    }
+
+    #[test]
+    fn builtins() {
+        let src = r#"
+            fn main() {
+                print("a\tb");
+                println("");
+                print_int(0 - 9223372036854775807 - 1);
+                return println("");
+            }"#;
+        let mut out = vec![];
+        assert_eq!(run_to(&compile(src).unwrap(), &[], &mut out), Ok(0));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a\tb\n-9223372036854775808\n"
+        );
+    }
+
+    #[test]
+    fn args_and_env() {
+        let src = r#"
+            fn main(argc: u64, argv: u64) {
+                println(arg(1));
+                println(arg(2));
+                println(arg(0 - 1));
+                println(env("CARGO_PKG_NAME"));
+                println(env("="));
+                return argc * 10 + arg_count();
+            }"#;
+        let args = ["prog".to_string(), "one".to_string()];
+        let mut out = vec![];
+        assert_eq!(run_to(&compile(src).unwrap(), &args, &mut out), Ok(22));
+        let expected = format!("one\n\n\n{}\n\n", env!("CARGO_PKG_NAME"));
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn structs() {
+        let src = "
+            struct Point { x: i64, y: u8 }
+            struct Rect { min: Point, max: Point, tag: u32 }
+            fn area(r: Rect) -> i64 { return (r.max.x - r.min.x) * (r.max.y - r.min.y); }
+            fn grow(r: Rect, by: u8) -> Rect {
+                r.max.x = r.max.x + by;
+                r.max.y = r.max.y + by;
+                return r;
+            }
+            fn origin() -> Point { return Point { y: 0, x: 0 }; }
+            fn main() {
+                let r = Rect { min: origin(), max: Point { x: 3, y: 4 }, tag: 7 };
+                let g = grow(r, 2);
+                let p = g.max;
+                p.y = 255;
+                p.y = p.y + 1;
+                return area(g) + r.max.x * 100 + g.max.y * 1000 + p.y + r.tag * 10000;
+            }";
+        assert_eq!(run_src(src), Ok(30 + 300 + 6000 + 70000));
+    }
+
+    #[test]
+    fn strs() {
+        let src = r#"
+            struct Named { name: str, n: u64 }
+            fn pick(a: str, b: str, first: bool) -> str { if first { return a; }; return b; }
+            fn main() {
+                let s = "héllo";
+                let names = [s, pick("a", "bc", 1 > 2), arg(1)];
+                let named = Named { name: names[1], n: 2 };
+                println(names[0]);
+                println(named.name);
+                return println(names[2]);
+            }"#;
+        let args = ["prog".to_string(), "one".to_string()];
+        let mut out = vec![];
+        assert_eq!(run_to(&compile(src).unwrap(), &args, &mut out), Ok(0));
+        assert_eq!(String::from_utf8(out).unwrap(), "héllo\nbc\none\n");
+    }
+
+    #[test]
+    fn arrays() {
+        let src = "
+            struct Bag { tag: u8, items: [u32; 3] }
+            fn sum(xs: [u64; 10]) -> u64 {
+                let total = 0;
+                let i = 0;
+                while 10 > i { total = total + xs[i]; i = i + 1; };
+                return total;
+            }
+            fn bytes(n: u64) -> [u8; 2] { return [n, n + 255]; }
+            fn main() {
+                let xs = [5; 10];
+                xs[9] = 100;
+                let copy = xs;
+                copy[0] = 0;
+                let bag = Bag { tag: 300, items: [1, 2, 3] };
+                bag.items[2] = 4294967297;
+                let grid = [[1, 2], [3, 4]];
+                grid[1][0] = 30;
+                let b = bytes(2);
+                return sum(xs) + bag.tag + bag.items[2] * 1000 + grid[1][0] * 10000 + b[1] * 1000000;
+            }";
+        assert_eq!(run_src(src), Ok(145 + 44 + 1000 + 300000 + 1000000));
+        assert_eq!(
+            run_src("fn main() { let xs = [1, 2]; return xs[2 + xs[0]]; }"),
+            Err(vec!["index 3 is out of bounds for length 2".to_string()])
+        );
+    }
+
+    #[test]
+    fn enums() {
+        let src = "
+            struct P { x: u8, y: u64 }
+            enum Inner { A(u8), B([u32; 3]) }
+            enum Outer { Wrap(Inner, P), Nothing }
+            fn pick(o: Outer) -> Inner {
+                return match o { Outer::Wrap(i, _) => i, Outer::Nothing => Inner::A(9) };
+            }
+            fn sum(i: Inner) -> u64 {
+                return match i { Inner::A(b) => b, Inner::B(xs) => xs[0] + xs[1] + xs[2] };
+            }
+            fn main() {
+                let o = Outer::Wrap(Inner::B([10, 20, 30]), P { x: 300, y: 7 });
+                let p = match o { Outer::Wrap(_, p) => p.x + p.y, Outer::Nothing => 0 };
+                let copy = o;
+                o = Outer::Nothing;
+                let c = match 'b' { 'a' => 1, 'b' => 2, _ => 0 };
+                let b = match copy { Outer::Nothing => false, other => true };
+                return sum(pick(copy)) + p * 100 + sum(pick(o)) * 1000 + c * 10000 + b * 100000;
+            }";
+        assert_eq!(run_src(src), Ok(60 + 5100 + 9000 + 20000 + 100000));
+    }
+
+    #[test]
+    fn references() {
+        let src = "
+            struct P { x: u64, y: u64 }
+            struct Pair { a: &u64, b: &mut u64 }
+            fn swap(a: &mut u64, b: &mut u64) { let t = *a; *a = *b; *b = t; }
+            fn shift(p: &mut P, by: u64) { p.x = p.x + by; p.y = p.y + by; }
+            fn sum(a: &[u64; 3]) -> u64 { return a[0] + a[1] + a[2]; }
+            fn larger(a: &u64, b: &u64) -> &u64 { if *a > *b { return a; }; return b; }
+            fn main() {
+                let x = 1;
+                let y = 2;
+                swap(&mut x, &mut y);
+                let p = P { x: 10, y: 20 };
+                shift(&mut p, 5);
+                let a = [1, 2, 3];
+                let r = &mut a;
+                r[0] = 100;
+                let n = 0;
+                let pair = Pair { a: &p.x, b: &mut n };
+                *pair.b = *pair.a + 1;
+                return x + y * 10 + p.y * 100 + sum(&a) * 10000 + *larger(&x, &y) * 10000000 + n * 100000000;
+            }";
+        assert_eq!(
+            run_src(src),
+            Ok(2 + 10 + 2500 + 1050000 + 20000000 + 1600000000)
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(
+            run_src("fn main() { return 1 / 0; }"),
+            Err(vec!["division by zero in 'main'".to_string()])
+        );
+        assert_eq!(
+            run_src("fn f(n: u64) -> u64 { return f(n) + 1; } fn main() { return f(1); }"),
+            Err(vec!["stack overflow calling 'f'".to_string()])
+        );
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let program = Program {
+            functions: vec![Function {
+                name: "main".into(),
+                params: vec![],
+                regs: 1,
+                frame: 8,
+                code: vec![
+                    Op::Copy { to: 0, from: 1 },
+                    Op::Jump { target: 7 },
+                    Op::Data { des: 0, offset: 9 },
+                    Op::Call {
+                        func: 2,
+                        args: vec![],
+                        ret: 0,
+                    },
+                    Op::Frame { des: 0, offset: 8 },
+                    Op::Load {
+                        des: 0,
+                        addr: 0,
+                        offset: 0,
+                        size: 2,
+                    },
+                ],
+            }],
+            data: vec![0; 8],
+        };
+        assert_eq!(
+            run(&program, &[]),
+            Err(vec![
+                "main at 0000: register r1 is out of bounds".to_string(),
+                "main at 0001: jump target 0007 is out of bounds".to_string(),
+                "main at 0002: data offset 0009 is out of bounds".to_string(),
+                "main at 0003: function @2 does not exist".to_string(),
+                "main at 0004: frame offset 8 is out of bounds".to_string(),
+                "main at 0005: can not access 2 bytes at once".to_string(),
+                "main: does not end in a jump or return".to_string(),
+            ])
+        );
+    }
+}
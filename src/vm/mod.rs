@@ -0,0 +1,304 @@
+//! A register based bytecode and the vm that runs it, selected with
+//! `--target=vm`.
+//!
+//! Every function has its own frame of 64 bit registers and its own code,
+//! jumps are indices into that code. A frame may also reserve bytes of stack
+//! memory for values that need an address. Programs are written to disk as `.abc`
+//! files and run with `a run <file>.abc`.
+mod file;
+mod lower;
+mod machine;
+#[cfg(test)]
+mod test;
+
+use std::fmt;
+
+pub use file::{decode, encode};
+pub use lower::lower;
+pub use machine::run;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    /// Read only memory holding string literals, addresses index into it.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    /// Registers the arguments are copied into.
+    pub params: Vec<u16>,
+    /// Number of registers in a frame.
+    pub regs: u16,
+    /// Bytes of stack memory in a frame.
+    pub frame: u32,
+    pub code: Vec<Op>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    LoadImm {
+        des: u16,
+        imm: i64,
+    },
+    /// Loads the address of `offset` in the program's data.
+    Data {
+        des: u16,
+        offset: u32,
+    },
+    Add {
+        des: u16,
+        lhs: u16,
+        rhs: u16,
+    },
+    Sub {
+        des: u16,
+        lhs: u16,
+        rhs: u16,
+    },
+    Mul {
+        des: u16,
+        lhs: u16,
+        rhs: u16,
+    },
+    Div {
+        des: u16,
+        lhs: u16,
+        rhs: u16,
+    },
+    Grt {
+        des: u16,
+        lhs: u16,
+        rhs: u16,
+    },
+    /// Loads the address of `offset` in the stack memory of the frame.
+    Frame {
+        des: u16,
+        offset: u32,
+    },
+    /// Reads `size` bytes at `addr + offset`, zero extended.
+    Load {
+        des: u16,
+        addr: u16,
+        offset: u32,
+        size: u8,
+    },
+    /// Writes the low `size` bytes of `src` to `addr + offset`.
+    Store {
+        addr: u16,
+        offset: u32,
+        src: u16,
+        size: u8,
+    },
+    Copy {
+        to: u16,
+        from: u16,
+    },
+    Jump {
+        target: u32,
+    },
+    /// Jumps when `reg` is zero.
+    JumpZero {
+        reg: u16,
+        target: u32,
+    },
+    Call {
+        func: u32,
+        args: Vec<u16>,
+        ret: u16,
+    },
+    /// Replaces the current frame with one for `func`.
+    TailCall {
+        func: u32,
+        args: Vec<u16>,
+    },
+    Builtin {
+        builtin: Builtin,
+        args: Vec<u16>,
+        ret: u16,
+    },
+    /// Runs the builtin and returns what it gave back.
+    TailBuiltin {
+        builtin: Builtin,
+        args: Vec<u16>,
+    },
+    Return {
+        reg: u16,
+    },
+    /// Returns zero, for functions that end without a `return`.
+    Leave,
+}
+
+impl Op {
+    /// Registers read and written by the instruction.
+    pub fn regs(&self) -> Vec<u16> {
+        match self {
+            Self::LoadImm { des, .. } | Self::Data { des, .. } | Self::Frame { des, .. } => {
+                vec![*des]
+            }
+            Self::Load { des, addr, .. } => vec![*des, *addr],
+            Self::Store { addr, src, .. } => vec![*addr, *src],
+            Self::Add { des, lhs, rhs }
+            | Self::Sub { des, lhs, rhs }
+            | Self::Mul { des, lhs, rhs }
+            | Self::Div { des, lhs, rhs }
+            | Self::Grt { des, lhs, rhs } => vec![*des, *lhs, *rhs],
+            Self::Copy { to, from } => vec![*to, *from],
+            Self::JumpZero { reg, .. } | Self::Return { reg } => vec![*reg],
+            Self::Call { args, ret, .. } | Self::Builtin { args, ret, .. } => {
+                args.iter().chain([ret]).copied().collect()
+            }
+            Self::TailCall { args, .. } | Self::TailBuiltin { args, .. } => args.clone(),
+            Self::Jump { .. } | Self::Leave => vec![],
+        }
+    }
+}
+
+fn list(regs: &[u16]) -> String {
+    regs.iter()
+        .map(|r| format!("r{r}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// The disassembly of a single instruction, calls name the index of the
+/// callee.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LoadImm { des, imm } => write!(f, "{:<8} r{des}, {imm}", "imm"),
+            Self::Data { des, offset } => write!(f, "{:<8} r{des}, {offset:04}", "data"),
+            Self::Add { des, lhs, rhs } => write!(f, "{:<8} r{des}, r{lhs}, r{rhs}", "add"),
+            Self::Sub { des, lhs, rhs } => write!(f, "{:<8} r{des}, r{lhs}, r{rhs}", "sub"),
+            Self::Mul { des, lhs, rhs } => write!(f, "{:<8} r{des}, r{lhs}, r{rhs}", "mul"),
+            Self::Div { des, lhs, rhs } => write!(f, "{:<8} r{des}, r{lhs}, r{rhs}", "div"),
+            Self::Grt { des, lhs, rhs } => write!(f, "{:<8} r{des}, r{lhs}, r{rhs}", "grt"),
+            Self::Frame { des, offset } => write!(f, "{:<8} r{des}, {offset}", "frame"),
+            Self::Load {
+                des,
+                addr,
+                offset,
+                size,
+            } => write!(
+                f,
+                "{:<8} r{des}, [r{addr}+{offset}]",
+                format!("load.{size}")
+            ),
+            Self::Store {
+                addr,
+                offset,
+                src,
+                size,
+            } => write!(
+                f,
+                "{:<8} [r{addr}+{offset}], r{src}",
+                format!("store.{size}")
+            ),
+            Self::Copy { to, from } => write!(f, "{:<8} r{to}, r{from}", "copy"),
+            Self::Jump { target } => write!(f, "{:<8} {target:04}", "jmp"),
+            Self::JumpZero { reg, target } => write!(f, "{:<8} r{reg}, {target:04}", "jz"),
+            Self::Call { func, args, ret } => {
+                write!(f, "{:<8} r{ret}, @{func}({})", "call", list(args))
+            }
+            Self::TailCall { func, args } => write!(f, "{:<8} @{func}({})", "tail", list(args)),
+            Self::Builtin { builtin, args, ret } => {
+                write!(f, "{:<8} r{ret}, {builtin}({})", "call", list(args))
+            }
+            Self::TailBuiltin { builtin, args } => {
+                write!(f, "{:<8} {builtin}({})", "tail", list(args))
+            }
+            Self::Return { reg } => write!(f, "{:<8} r{reg}", "ret"),
+            Self::Leave => write!(f, "leave"),
+        }
+    }
+}
+
+/// Functions provided by the vm itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// Writes a `str` to stdout.
+    Print,
+    /// Writes a `str` and a newline to stdout.
+    Println,
+    /// Writes an integer in decimal to stdout.
+    PrintInt,
+    /// The number of command line arguments, the program name included.
+    ArgCount,
+    /// A command line argument, or an empty `str`.
+    Arg,
+    /// The value of an environment variable, or an empty `str`.
+    Env,
+    /// Stops the program on an index out of bounds, given it and the length.
+    IndexOutOfBounds,
+}
+
+impl Builtin {
+    pub const ALL: [Self; 7] = [
+        Self::Print,
+        Self::Println,
+        Self::PrintInt,
+        Self::ArgCount,
+        Self::Arg,
+        Self::Env,
+        Self::IndexOutOfBounds,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Print => "print",
+            Self::Println => "println",
+            Self::PrintInt => "print_int",
+            Self::ArgCount => "arg_count",
+            Self::Arg => "arg",
+            Self::Env => "env",
+            Self::IndexOutOfBounds => crate::ir::INDEX_OUT_OF_BOUNDS,
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Self::ArgCount => 0,
+            // `arg` and `env` are given where to put the `str` they return.
+            Self::Arg | Self::Env | Self::IndexOutOfBounds => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.name() == name)
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The disassembly of the whole program.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.data.is_empty() {
+            writeln!(f, "data {} bytes", self.data.len())?;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            let Function {
+                name,
+                params,
+                regs,
+                frame,
+                code,
+            } = func;
+            write!(f, "@{i} {name}({}) regs={regs}", list(params))?;
+            if *frame > 0 {
+                write!(f, " frame={frame}")?;
+            }
+            writeln!(f)?;
+            for (pc, op) in code.iter().enumerate() {
+                writeln!(f, "    {pc:04}  {op}")?;
+            }
+        }
+        Ok(())
+    }
+}
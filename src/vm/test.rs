@@ -0,0 +1,28 @@
+macro_rules! snapshot {
+    ($name:tt, $path:tt) => {
+        #[test]
+        fn $name() {
+            use super::*;
+            use $crate::ir;
+            use $crate::lexer::lex;
+            use $crate::parse::parse;
+            let contents = include_str!($path);
+            let tokens = lex(contents).unwrap();
+            let ast = parse(tokens).unwrap();
+            let ir_code = ir::code_gen(ast)
+                .and_then(ir::tail_calls)
+                .and_then(ir::ssa::construct)
+                .and_then(ir::ssa::destruct)
+                .unwrap();
+            let program = lower(ir_code).unwrap();
+            let mut settings = insta::Settings::clone_current();
+            settings.set_snapshot_path("testdata/output/");
+            settings.bind(|| {
+                insta::assert_snapshot!(program.to_string());
+            });
+        }
+    };
+}
+
+snapshot!(calls, "testdata/snapshots/calls.a");
+snapshot!(loops, "testdata/snapshots/loop.a");
@@ -0,0 +1,226 @@
+//! Puts parameters, arguments and return values where System V does. The ir
+//! passes a struct, array, enum or `str` as the address of a copy and returns
+//! one through an address the caller passes first. Here one of up to 16 bytes
+//! is passed in as many registers as it has eightbytes, when that many are
+//! left, and copied onto the stack otherwise. One of up to 16 bytes is
+//! returned in rax and rdx, a bigger one still through the address in rdi.
+use std::collections::HashMap;
+
+use super::X86RegParam;
+use crate::ir::{self, Reg, Type};
+
+/// The biggest aggregate passed and returned in registers.
+const MAX_IN_REGS: u32 = 16;
+
+/// How a function is called, set apart from the ir version of it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CallAbi {
+    /// Arguments passed in registers, the ones after them are pushed.
+    pub in_regs: usize,
+    /// The eightbytes of the value returned in rax and rdx, whose first
+    /// argument is then where they are written instead of an argument.
+    pub ret_words: Option<u32>,
+}
+
+/// What `lower` changed about a function, for compiling it.
+#[derive(Debug, Default)]
+pub struct Abi {
+    pub params: CallAbi,
+    /// Every call to a function of the program, keyed by the register it
+    /// defines.
+    pub calls: HashMap<Reg, CallAbi>,
+}
+
+impl Abi {
+    pub fn call(&self, ret: &Reg, args: usize) -> CallAbi {
+        self.calls.get(ret).copied().unwrap_or(CallAbi {
+            in_regs: args.min(X86RegParam::COUNT),
+            ret_words: None,
+        })
+    }
+}
+
+/// The parameter and return types of every function of the program.
+pub type Signatures = HashMap<String, (Vec<Type>, Type)>;
+
+pub fn signatures(code: &[ir::Instruction]) -> Signatures {
+    code.iter()
+        .filter_map(|inst| match inst {
+            ir::Instruction::DefFunc(func) => {
+                let params = func.params.iter().map(|(_, ty)| ty.clone()).collect();
+                Some((func.name.clone(), (params, func.ret.clone())))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn words(size: u32) -> u32 {
+    size.div_ceil(8)
+}
+
+/// The eightbytes a value returned as `ty` comes back in, nothing when it is
+/// an integer or written through an address.
+fn ret_words(ty: &Type) -> Option<u32> {
+    match ty {
+        Type::Aggregate(size) if *size <= MAX_IN_REGS => Some(words(*size)),
+        _ => None,
+    }
+}
+
+/// The index of a value and of one of its eightbytes.
+type Eightbyte = (usize, u32);
+
+/// Splits values of `types` into the eightbytes passed in registers and
+/// those pushed.
+fn place(types: &[Type]) -> (Vec<Eightbyte>, Vec<Eightbyte>) {
+    let mut regs = vec![];
+    let mut stack = vec![];
+    for (i, ty) in types.iter().enumerate() {
+        let (words, fits) = match ty {
+            Type::I64 => (1, true),
+            Type::Aggregate(size) => (words(*size), *size <= MAX_IN_REGS),
+        };
+        let eightbytes = (0..words).map(|word| (i, word));
+        if fits && regs.len() + words as usize <= X86RegParam::COUNT {
+            regs.extend(eightbytes);
+        } else {
+            stack.extend(eightbytes);
+        }
+    }
+    (regs, stack)
+}
+
+/// Rewrites `func` so its parameters and the arguments of its calls are
+/// eightbytes in the order they are passed in.
+pub fn lower(func: &ir::DefFunc, signatures: &Signatures) -> (ir::DefFunc, Abi) {
+    let mut next = func
+        .body
+        .iter()
+        .flat_map(|inst| inst.def().into_iter().chain(inst.uses()))
+        .chain(func.params.iter().map(|(reg, _)| *reg))
+        .map(|Reg(r)| r + 1)
+        .max()
+        .unwrap_or(0);
+    let mut fresh = || {
+        next += 1;
+        Reg(next - 1)
+    };
+
+    let mut params = func.params.as_slice();
+    let mut prefix = vec![];
+    let ret_words = ret_words(&func.ret);
+    if let (Some(words), Some(((sret, _), rest))) = (ret_words, params.split_first()) {
+        // The caller gives no address, the value is kept here until it is
+        // returned.
+        prefix.push(alloca(*sret, words));
+        params = rest;
+    }
+    let types = params
+        .iter()
+        .map(|(_, ty)| ty.clone())
+        .collect::<Vec<Type>>();
+    let (regs, stack) = place(&types);
+    for (param, ty) in params.iter() {
+        if let Type::Aggregate(size) = ty {
+            prefix.push(alloca(*param, self::words(*size)));
+        }
+    }
+    let mut incoming = vec![];
+    for (i, word) in regs.iter().chain(stack.iter()).copied() {
+        let (param, ty) = &params[i];
+        if *ty == Type::I64 {
+            incoming.push((*param, Type::I64));
+            continue;
+        }
+        let reg = fresh();
+        incoming.push((reg, Type::I64));
+        prefix.push(store(*param, word, reg));
+    }
+
+    let mut abi = Abi {
+        params: CallAbi {
+            in_regs: regs.len(),
+            ret_words,
+        },
+        calls: HashMap::new(),
+    };
+    let mut body = vec![];
+    for inst in func.body.iter() {
+        let ir::Instruction::Call(ir::Call { caller, args, ret }) = inst else {
+            body.push(inst.clone());
+            if let ir::Instruction::Enter(_) = inst {
+                body.append(&mut prefix);
+            }
+            continue;
+        };
+        let Some((types, ret_ty)) = signatures.get(&caller.0) else {
+            body.push(inst.clone());
+            continue;
+        };
+        let ret_words = self::ret_words(ret_ty);
+        let (buffer, rest, types) = match ret_words {
+            Some(_) => (
+                args.first().copied(),
+                args.get(1..).unwrap_or_default(),
+                types.get(1..).unwrap_or_default(),
+            ),
+            None => (None, args.as_slice(), types.as_slice()),
+        };
+        let (regs, stack) = place(types);
+        let mut passed = buffer.into_iter().collect::<Vec<Reg>>();
+        for (i, word) in regs.iter().chain(stack.iter()).copied() {
+            let Some(arg) = rest.get(i) else {
+                continue;
+            };
+            if types[i] == Type::I64 {
+                passed.push(*arg);
+                continue;
+            }
+            let des = fresh();
+            body.push(ir::Instruction::Load(ir::Load {
+                des,
+                addr: *arg,
+                offset: word * 8,
+                size: 8,
+            }));
+            passed.push(des);
+        }
+        let call = CallAbi {
+            in_regs: regs.len(),
+            ret_words,
+        };
+        abi.calls.insert(*ret, call);
+        body.push(
+            ir::Call {
+                caller: caller.clone(),
+                args: passed,
+                ret: *ret,
+            }
+            .into(),
+        );
+    }
+    let lowered = ir::DefFunc {
+        params: incoming,
+        body,
+        ..func.clone()
+    };
+    (lowered, abi)
+}
+
+/// A slot of `words` eightbytes, so they can be read and written whole.
+fn alloca(des: Reg, words: u32) -> ir::Instruction {
+    ir::Instruction::Alloca(ir::Alloca {
+        des,
+        size: words.max(1) * 8,
+    })
+}
+
+fn store(addr: Reg, word: u32, src: Reg) -> ir::Instruction {
+    ir::Instruction::Store(ir::Store {
+        addr,
+        offset: word * 8,
+        src,
+        size: 8,
+    })
+}
@@ -0,0 +1,673 @@
+//! Writes ELF64 files for x86-64 linux, static executables and relocatable
+//! objects for `ld` or `cc`.
+//!
+//! The text segment of an executable is mapped from the start of the file,
+//! headers included, so code starts right after the headers. Read only data
+//! and then data follow, each starting on a new page.
+use std::collections::{BTreeSet, HashMap};
+
+use super::{runtime, Assembly, RelocationKind, DATA, RODATA};
+
+/// Where the file is loaded.
+pub const BASE: u64 = 0x400000;
+const PAGE: u64 = 0x1000;
+const ELF_HEADER_SIZE: u16 = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+const PROGRAM_HEADERS: u16 = 3;
+const SECTION_HEADER_SIZE: u16 = 64;
+const HEADERS_SIZE: u64 = (ELF_HEADER_SIZE + PROGRAM_HEADERS * PROGRAM_HEADER_SIZE) as u64;
+/// Where the first byte of code is loaded.
+pub const TEXT_ADDRESS: u64 = BASE + HEADERS_SIZE;
+/// The label the program starts at, `_start__` in the assembly output.
+pub const ENTRY: &str = "_start";
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const SYMBOL_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+/// Indices of the sections of an object file other sections refer to.
+const TEXT: u16 = 1;
+const DATA_SECTION: u16 = 2;
+const RODATA_SECTION: u16 = 3;
+const SYMTAB: u16 = 4;
+const STRTAB: u16 = 5;
+const SHSTRTAB: u16 = 8;
+const SECTIONS: u16 = 9;
+
+fn rodata_offset(text_len: usize) -> u64 {
+    (HEADERS_SIZE + text_len as u64).next_multiple_of(PAGE)
+}
+
+fn data_offset(text_len: usize, rodata_len: usize) -> u64 {
+    (rodata_offset(text_len) + rodata_len as u64).next_multiple_of(PAGE)
+}
+
+pub fn write_executable(mut assembly: Assembly) -> Result<Vec<u8>, Vec<String>> {
+    // Nothing else is linked in, so every call has to be resolved here.
+    let mut errors = assembly
+        .relocations
+        .iter()
+        .filter(|r| {
+            r.symbol != RODATA && r.symbol != DATA && !assembly.labels.contains_key(&r.symbol)
+        })
+        .map(|r| r.symbol.as_str())
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
+        .map(|name| format!("call to unknown function '{name}'"))
+        .collect::<Vec<String>>();
+    let entry = assembly.labels.get(ENTRY);
+    if entry.is_none() {
+        errors.push(format!("no '{ENTRY}' label to start the program at"));
+    }
+    let Some(entry) = entry.copied().filter(|_| errors.is_empty()) else {
+        return Err(errors);
+    };
+    let rodata = BASE + rodata_offset(assembly.code.len());
+    let data = BASE + data_offset(assembly.code.len(), assembly.rodata.len());
+    for relocation in assembly.relocations.iter() {
+        let start = match relocation.symbol.as_str() {
+            RODATA => rodata,
+            DATA => data,
+            _ => continue,
+        };
+        let at = relocation.at;
+        let target = start + relocation.offset as u64;
+        let rel = target as i64 - (TEXT_ADDRESS + at as u64 + 4) as i64;
+        assembly.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+    Ok(executable(
+        &assembly.code,
+        &assembly.rodata,
+        &assembly.data,
+        entry,
+    ))
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn align(&mut self, align: u64) {
+        self.0
+            .resize(self.len().next_multiple_of(align) as usize, 0);
+    }
+
+    fn header(&mut self, kind: u16, entry: u64, program_headers: u16, sections: Option<u64>) {
+        self.bytes(b"\x7fELF");
+        // 64 bit, little endian, version 1, System V abi and padding.
+        self.bytes(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        self.u16(kind);
+        self.u16(EM_X86_64);
+        self.u32(1);
+        self.u64(entry);
+        // Program headers right after this one.
+        self.u64(if program_headers == 0 {
+            0
+        } else {
+            ELF_HEADER_SIZE as u64
+        });
+        self.u64(sections.unwrap_or(0));
+        self.u32(0);
+        self.u16(ELF_HEADER_SIZE);
+        self.u16(if program_headers == 0 {
+            0
+        } else {
+            PROGRAM_HEADER_SIZE
+        });
+        self.u16(program_headers);
+        match sections {
+            Some(_) => {
+                self.u16(SECTION_HEADER_SIZE);
+                self.u16(SECTIONS);
+                self.u16(SHSTRTAB);
+            }
+            None => self.bytes(&[0; 6]),
+        }
+    }
+
+    fn program_header(&mut self, flags: u32, offset: u64, size: u64) {
+        self.u32(PT_LOAD);
+        self.u32(flags);
+        self.u64(offset);
+        // Virtual and physical address.
+        self.u64(BASE + offset);
+        self.u64(BASE + offset);
+        // Size in the file and in memory.
+        self.u64(size);
+        self.u64(size);
+        self.u64(PAGE);
+    }
+}
+
+/// `entry` is an offset into `text`.
+pub fn executable(text: &[u8], rodata: &[u8], data: &[u8], entry: usize) -> Vec<u8> {
+    let rodata_offset = rodata_offset(text.len());
+    let data_offset = data_offset(text.len(), rodata.len());
+    let mut elf = Writer::default();
+    elf.header(ET_EXEC, TEXT_ADDRESS + entry as u64, PROGRAM_HEADERS, None);
+    let text_size = HEADERS_SIZE + text.len() as u64;
+    elf.program_header(PF_R | PF_X, 0, text_size);
+    elf.program_header(PF_R, rodata_offset, rodata.len() as u64);
+    elf.program_header(PF_R | PF_W, data_offset, data.len() as u64);
+    elf.bytes(text);
+    elf.align(PAGE);
+    elf.bytes(rodata);
+    elf.align(PAGE);
+    elf.bytes(data);
+    elf.0
+}
+
+/// Names packed into a string table, the first one is the empty name.
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let at = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        at
+    }
+}
+
+struct Section<'a> {
+    kind: u32,
+    flags: u64,
+    bytes: &'a [u8],
+    link: u16,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl<'a> Section<'a> {
+    fn new(kind: u32, flags: u64, bytes: &'a [u8], align: u64) -> Self {
+        Self {
+            kind,
+            flags,
+            bytes,
+            link: 0,
+            info: 0,
+            align,
+            entry_size: 0,
+        }
+    }
+}
+
+/// A relocatable object. Every function is a global symbol and every call
+/// goes through a relocation, calls to functions not defined here are left
+/// for the linker.
+pub fn write_object(assembly: Assembly) -> Result<Vec<u8>, Vec<String>> {
+    let mut functions = assembly
+        .labels
+        .iter()
+        .filter(|(name, _)| !name.contains('.'))
+        .map(|(name, at)| (*at, name.as_str()))
+        .collect::<Vec<(usize, &str)>>();
+    functions.sort();
+    let undefined = assembly
+        .relocations
+        .iter()
+        .map(|r| r.symbol.as_str())
+        .filter(|name| ![RODATA, DATA].contains(name) && !assembly.labels.contains_key(*name))
+        .collect::<BTreeSet<&str>>();
+
+    let mut strings = Strings::new();
+    let mut symbols = Writer::default();
+    let mut indices = HashMap::new();
+    symbols.bytes(&[0; SYMBOL_SIZE as usize]);
+    // String literals and globals are referred to through their section.
+    for (name, section) in [(RODATA, RODATA_SECTION), (DATA, DATA_SECTION)] {
+        indices.insert(name, indices.len() as u64 + 1);
+        symbols.u32(0);
+        symbols.u8(STB_LOCAL << 4 | STT_SECTION);
+        symbols.u8(0);
+        symbols.u16(section);
+        symbols.u64(0);
+        symbols.u64(0);
+    }
+    for (i, (at, name)) in functions.iter().enumerate() {
+        let end = functions
+            .get(i + 1)
+            .map_or(assembly.code.len(), |(end, _)| *end);
+        indices.insert(*name, indices.len() as u64 + 1);
+        symbols.u32(strings.add(name));
+        // Every object calling a builtin carries its own copy.
+        let bind = if runtime::FUNCTIONS.iter().any(|(f, _)| f == name) {
+            STB_WEAK
+        } else {
+            STB_GLOBAL
+        };
+        symbols.u8(bind << 4 | STT_FUNC);
+        symbols.u8(0);
+        symbols.u16(TEXT);
+        symbols.u64(*at as u64);
+        symbols.u64((end - at) as u64);
+    }
+    for name in undefined {
+        indices.insert(name, indices.len() as u64 + 1);
+        symbols.u32(strings.add(name));
+        symbols.u8(STB_GLOBAL << 4 | STT_NOTYPE);
+        symbols.u8(0);
+        symbols.u16(0);
+        symbols.u64(0);
+        symbols.u64(0);
+    }
+
+    let mut relocations = Writer::default();
+    for relocation in assembly.relocations.iter() {
+        let kind = match relocation.kind {
+            RelocationKind::Plt32 => R_X86_64_PLT32,
+            RelocationKind::Pc32 => R_X86_64_PC32,
+        };
+        relocations.u64(relocation.at as u64);
+        relocations.u64(indices[relocation.symbol.as_str()] << 32 | kind as u64);
+        // The rel32 is relative to the end of the instruction, 4 bytes on.
+        relocations.u64((relocation.offset as i64 - 4) as u64);
+    }
+
+    let mut names = Strings::new();
+    // In the order of the sections below.
+    let names_at = [
+        ".text",
+        ".data",
+        ".rodata",
+        ".symtab",
+        ".strtab",
+        ".rela.text",
+        ".note.GNU-stack",
+        ".shstrtab",
+    ]
+    .map(|name| names.add(name));
+    let sections = [
+        Section::new(SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, &assembly.code, 16),
+        Section::new(SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, &assembly.data, 8),
+        Section::new(SHT_PROGBITS, SHF_ALLOC, &assembly.rodata, 8),
+        Section {
+            link: STRTAB,
+            // Index of the first global symbol, after the sections.
+            info: 3,
+            entry_size: SYMBOL_SIZE,
+            ..Section::new(SHT_SYMTAB, 0, &symbols.0, 8)
+        },
+        Section::new(SHT_STRTAB, 0, &strings.0, 1),
+        Section {
+            link: SYMTAB,
+            info: TEXT as u32,
+            entry_size: RELA_SIZE,
+            ..Section::new(SHT_RELA, SHF_INFO_LINK, &relocations.0, 8)
+        },
+        // Tells the linker the stack does not need to be executable.
+        Section::new(SHT_PROGBITS, 0, &[], 1),
+        Section::new(SHT_STRTAB, 0, &names.0, 1),
+    ];
+    debug_assert_eq!(sections.len(), SECTIONS as usize - 1);
+
+    let mut elf = Writer::default();
+    elf.header(ET_REL, 0, 0, Some(0));
+    let mut offsets = vec![];
+    for section in sections.iter() {
+        elf.align(section.align);
+        offsets.push(elf.len());
+        elf.bytes(section.bytes);
+    }
+    elf.align(8);
+    let section_headers = elf.len();
+    elf.bytes(&[0; SECTION_HEADER_SIZE as usize]);
+    for ((section, offset), name) in sections.iter().zip(offsets).zip(names_at) {
+        elf.u32(name);
+        elf.u32(section.kind);
+        elf.u64(section.flags);
+        // Address, objects are not loaded.
+        elf.u64(0);
+        elf.u64(offset);
+        elf.u64(section.bytes.len() as u64);
+        elf.u32(section.link as u32);
+        elf.u32(section.info);
+        elf.u64(section.align);
+        elf.u64(section.entry_size);
+    }
+    // Now that it is known where the section headers are.
+    elf.0[40..48].copy_from_slice(&section_headers.to_le_bytes());
+    Ok(elf.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Header {
+        kind: u16,
+        machine: u16,
+        entry: u64,
+        segments: Vec<Segment>,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Segment {
+        kind: u32,
+        flags: u32,
+        offset: u64,
+        address: u64,
+        file_size: u64,
+        memory_size: u64,
+        align: u64,
+    }
+
+    fn u16_at(elf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(elf[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(elf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(elf[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(elf: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(elf[at..at + 8].try_into().unwrap())
+    }
+
+    /// Reads the parts of the headers the kernel looks at.
+    fn parse(elf: &[u8]) -> Header {
+        assert_eq!(&elf[..7], b"\x7fELF\x02\x01\x01");
+        assert_eq!(u16_at(elf, 52) as usize, 64);
+        let phoff = u64_at(elf, 32) as usize;
+        let phentsize = u16_at(elf, 54) as usize;
+        let segments = (0..u16_at(elf, 56) as usize)
+            .map(|i| {
+                let at = phoff + i * phentsize;
+                Segment {
+                    kind: u32_at(elf, at),
+                    flags: u32_at(elf, at + 4),
+                    offset: u64_at(elf, at + 8),
+                    address: u64_at(elf, at + 16),
+                    file_size: u64_at(elf, at + 32),
+                    memory_size: u64_at(elf, at + 40),
+                    align: u64_at(elf, at + 48),
+                }
+            })
+            .collect();
+        Header {
+            kind: u16_at(elf, 16),
+            machine: u16_at(elf, 18),
+            entry: u64_at(elf, 24),
+            segments,
+        }
+    }
+
+    fn string_at(table: &[u8], at: usize) -> &str {
+        let len = table[at..].iter().position(|b| *b == 0).unwrap();
+        std::str::from_utf8(&table[at..at + len]).unwrap()
+    }
+
+    /// The name, kind, flags and contents of every section.
+    fn sections(elf: &[u8]) -> Vec<(&str, u32, u64, &[u8])> {
+        let shoff = u64_at(elf, 40) as usize;
+        let count = u16_at(elf, 60) as usize;
+        let header = |i: usize| {
+            let at = shoff + i * SECTION_HEADER_SIZE as usize;
+            let (offset, size) = (u64_at(elf, at + 24) as usize, u64_at(elf, at + 32) as usize);
+            (at, &elf[offset..offset + size])
+        };
+        let (_, names) = header(u16_at(elf, 62) as usize);
+        (1..count)
+            .map(|i| {
+                let (at, bytes) = header(i);
+                let name = string_at(names, u32_at(elf, at) as usize);
+                (name, u32_at(elf, at + 4), u64_at(elf, at + 8), bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headers() {
+        let text = [0x90, 0x90, 0x0f, 0x05];
+        let rodata = [4, 5];
+        let data = [1, 2, 3];
+        let elf = executable(&text, &rodata, &data, 2);
+        assert_eq!(
+            parse(&elf),
+            Header {
+                kind: ET_EXEC,
+                machine: EM_X86_64,
+                entry: 0x4000ea,
+                segments: vec![
+                    Segment {
+                        kind: PT_LOAD,
+                        flags: PF_R | PF_X,
+                        offset: 0,
+                        address: 0x400000,
+                        file_size: 0xec,
+                        memory_size: 0xec,
+                        align: 0x1000,
+                    },
+                    Segment {
+                        kind: PT_LOAD,
+                        flags: PF_R,
+                        offset: 0x1000,
+                        address: 0x401000,
+                        file_size: 2,
+                        memory_size: 2,
+                        align: 0x1000,
+                    },
+                    Segment {
+                        kind: PT_LOAD,
+                        flags: PF_R | PF_W,
+                        offset: 0x2000,
+                        address: 0x402000,
+                        file_size: 3,
+                        memory_size: 3,
+                        align: 0x1000,
+                    },
+                ],
+            }
+        );
+        assert_eq!(elf[0xe8..0xec], text);
+        assert_eq!(elf[0x1000..0x1002], rodata);
+        assert_eq!(elf[0x2000..], data);
+    }
+
+    #[test]
+    fn entry_label() {
+        use crate::x86_64_linux::{assemble, Instruction};
+        let assembly = assemble(vec![
+            Instruction::DefLabel("main".into()),
+            Instruction::Epilog,
+            Instruction::DefLabel(ENTRY.into()),
+            Instruction::Syscall,
+        ])
+        .unwrap();
+        let elf = write_executable(assembly).unwrap();
+        assert_eq!(parse(&elf).entry, TEXT_ADDRESS + 5);
+
+        let assembly = assemble(vec![Instruction::Syscall]).unwrap();
+        assert_eq!(
+            write_executable(assembly),
+            Err(vec!["no '_start' label to start the program at".to_string()])
+        );
+
+        let assembly = assemble(vec![
+            Instruction::DefLabel(ENTRY.into()),
+            Instruction::Call("main".into()),
+        ])
+        .unwrap();
+        assert_eq!(
+            write_executable(assembly),
+            Err(vec!["call to unknown function 'main'".to_string()])
+        );
+    }
+
+    #[test]
+    fn strings() {
+        use crate::x86_64_linux::{assemble, Instruction, X86Reg, X86Reg64};
+        let assembly = assemble(vec![
+            Instruction::DefLabel(ENTRY.into()),
+            Instruction::LoadStr(X86Reg::Reg64(X86Reg64::RAX), "hi".into()),
+        ])
+        .unwrap();
+        let elf = write_executable(assembly).unwrap();
+        let header = parse(&elf);
+        let rodata = &header.segments[1];
+        let start = rodata.offset as usize;
+        let end = start + rodata.file_size as usize;
+        assert_eq!(elf[start..end], *b"hi\0");
+        // lea rax, [rip + rel32], rip is the address after the instruction.
+        let rel = u32_at(&elf, HEADERS_SIZE as usize + 3) as i32 as i64;
+        let after = header.entry + 7;
+        assert_eq!(after as i64 + rel, rodata.address as i64);
+    }
+
+    #[test]
+    fn globals() {
+        use crate::x86_64_linux::{assemble, Instruction, X86Reg, X86Reg64};
+        let assembly = assemble(vec![
+            Instruction::DefLabel(ENTRY.into()),
+            Instruction::LoadGlobal(X86Reg::Reg64(X86Reg64::RAX), "a".into()),
+            Instruction::LoadGlobal(X86Reg::Reg64(X86Reg64::RAX), "b".into()),
+        ])
+        .unwrap();
+        let elf = write_executable(assembly).unwrap();
+        let header = parse(&elf);
+        let data = &header.segments[2];
+        assert_eq!(data.flags, PF_R | PF_W);
+        assert_eq!(data.file_size, 16);
+        let rel = u32_at(&elf, HEADERS_SIZE as usize + 10) as i32 as i64;
+        let after = header.entry + 14;
+        assert_eq!(after as i64 + rel, (data.address + 8) as i64);
+    }
+
+    #[test]
+    fn object() {
+        use crate::x86_64_linux::{assemble, Instruction, X86Reg, X86Reg64};
+        let assembly = assemble(vec![
+            Instruction::DefLabel("f".into()),
+            Instruction::Call("g".into()),
+            Instruction::DefLabel("main".into()),
+            Instruction::LoadStr(X86Reg::Reg64(X86Reg64::RAX), "hi".into()),
+            Instruction::LoadGlobal(X86Reg::Reg64(X86Reg64::RCX), "x".into()),
+            Instruction::DefLabel(".L0".into()),
+            Instruction::TailJump("f".into()),
+        ])
+        .unwrap();
+        let elf = write_object(assembly).unwrap();
+        assert_eq!(u16_at(&elf, 16), ET_REL);
+        assert_eq!(u16_at(&elf, 18), EM_X86_64);
+        let sections = sections(&elf);
+        let names = sections
+            .iter()
+            .map(|(name, kind, flags, _)| (*name, *kind, *flags))
+            .collect::<Vec<(&str, u32, u64)>>();
+        assert_eq!(
+            names,
+            [
+                (".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+                (".data", SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+                (".rodata", SHT_PROGBITS, SHF_ALLOC),
+                (".symtab", SHT_SYMTAB, 0),
+                (".strtab", SHT_STRTAB, 0),
+                (".rela.text", SHT_RELA, SHF_INFO_LINK),
+                (".note.GNU-stack", SHT_PROGBITS, 0),
+                (".shstrtab", SHT_STRTAB, 0),
+            ]
+        );
+        assert_eq!(sections[1].3, [0; 8]);
+        assert_eq!(sections[2].3, b"hi\0");
+
+        let (symtab, strtab) = (sections[3].3, sections[4].3);
+        let symbols = (1..symtab.len() / 24)
+            .map(|i| {
+                let at = i * 24;
+                (
+                    string_at(strtab, u32_at(symtab, at) as usize),
+                    symtab[at + 4],
+                    u16_at(symtab, at + 6),
+                    u64_at(symtab, at + 8),
+                    u64_at(symtab, at + 16),
+                )
+            })
+            .collect::<Vec<_>>();
+        let func = STB_GLOBAL << 4 | STT_FUNC;
+        assert_eq!(
+            symbols,
+            [
+                ("", STB_LOCAL << 4 | STT_SECTION, RODATA_SECTION, 0, 0),
+                ("", STB_LOCAL << 4 | STT_SECTION, DATA_SECTION, 0, 0),
+                ("f", func, TEXT, 0, 5),
+                ("main", func, TEXT, 5, 23),
+                ("g", STB_GLOBAL << 4 | STT_NOTYPE, 0, 0, 0),
+            ]
+        );
+
+        let rela = sections[5].3;
+        let relocations = (0..rela.len() / 24)
+            .map(|i| {
+                let at = i * 24;
+                (
+                    u64_at(rela, at),
+                    u64_at(rela, at + 8),
+                    u64_at(rela, at + 16) as i64,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            relocations,
+            [
+                (1, 5 << 32 | R_X86_64_PLT32 as u64, -4),
+                (8, 1 << 32 | R_X86_64_PC32 as u64, -4),
+                (15, 2 << 32 | R_X86_64_PC32 as u64, -4),
+                (24, 3 << 32 | R_X86_64_PLT32 as u64, -4),
+            ]
+        );
+    }
+}
@@ -0,0 +1,691 @@
+//! Encodes instructions to machine code, so no external assembler is needed.
+//!
+//! Labels follow fasm: a label starting with `.` belongs to the last label
+//! without one, `.L0` after `main` is `main.L0`. Branches always take a rel32
+//! and are patched once every label is known. Branches to other labels are
+//! kept as relocations too, they may be defined in another object file.
+//! String literals go to read only data and globals to writable data, both
+//! placed by the elf writer.
+use std::collections::HashMap;
+
+use super::{Instruction, X86Reg, X86Reg64, X86RegHigh8};
+use crate::ir::write_str;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub code: Vec<u8>,
+    /// Offset of every label in `code`, local labels by their full name.
+    pub labels: HashMap<String, usize>,
+    pub relocations: Vec<Relocation>,
+    pub rodata: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// The symbol of the start of `Assembly::rodata`.
+pub const RODATA: &str = ".rodata";
+/// The symbol of the start of `Assembly::data`.
+pub const DATA: &str = ".data";
+
+/// A rel32 pointing `offset` bytes past `symbol`, patched already if
+/// `symbol` is in `labels`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub at: usize,
+    pub symbol: String,
+    pub offset: usize,
+    pub kind: RelocationKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// Calls and tail jumps to a function.
+    Plt32,
+    /// Any other pc relative reference.
+    Pc32,
+}
+
+pub fn assemble(code: Vec<Instruction>) -> Result<Assembly, Vec<String>> {
+    let mut encoder = Encoder::default();
+    for instruction in code.iter() {
+        if let Err(e) = encoder.instruction(instruction) {
+            let text = instruction.to_string();
+            let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+            encoder.errors.push(format!("{text}: {e}"));
+        }
+    }
+    encoder.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Operand {
+    /// Register number, the top bit goes in the rex prefix.
+    num: u8,
+    size: Size,
+    /// ah, ch, dh and bh can't be used with a rex prefix.
+    high: bool,
+}
+
+impl Operand {
+    fn ext(self) -> u8 {
+        self.num >> 3
+    }
+
+    /// spl, bpl, sil and dil only exist with a rex prefix.
+    fn needs_rex(self) -> bool {
+        self.size == Size::Byte && !self.high && (4..8).contains(&self.num)
+    }
+}
+
+fn number(reg: X86Reg64) -> u8 {
+    match reg {
+        X86Reg64::RAX => 0,
+        X86Reg64::RCX => 1,
+        X86Reg64::RDX => 2,
+        X86Reg64::RBX => 3,
+        X86Reg64::RSP => 4,
+        X86Reg64::RBP => 5,
+        X86Reg64::RSI => 6,
+        X86Reg64::RDI => 7,
+        X86Reg64::R8 => 8,
+        X86Reg64::R9 => 9,
+        X86Reg64::R10 => 10,
+        X86Reg64::R11 => 11,
+        X86Reg64::R12 => 12,
+        X86Reg64::R13 => 13,
+        X86Reg64::R14 => 14,
+        X86Reg64::R15 => 15,
+    }
+}
+
+fn operand(reg: X86Reg) -> Operand {
+    let (size, high) = match reg {
+        X86Reg::RegHigh8(high) => {
+            let num = match high {
+                X86RegHigh8::AH => 4,
+                X86RegHigh8::CH => 5,
+                X86RegHigh8::DH => 6,
+                X86RegHigh8::BH => 7,
+            };
+            return Operand {
+                num,
+                size: Size::Byte,
+                high: true,
+            };
+        }
+        X86Reg::RegLow8(_) => (Size::Byte, false),
+        X86Reg::Reg16(_) => (Size::Word, false),
+        X86Reg::Reg32(_) => (Size::Dword, false),
+        X86Reg::RegRet(_) | X86Reg::RegParam(_) | X86Reg::Reg64(_) => (Size::Qword, false),
+    };
+    Operand {
+        num: number(reg.as_64_bit()),
+        size,
+        high,
+    }
+}
+
+fn qword(reg: X86Reg) -> Result<u8, String> {
+    let op = operand(reg);
+    if op.size != Size::Qword {
+        return Err(format!("{reg} has to be a 64 bit register"));
+    }
+    Ok(op.num)
+}
+
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    mode << 6 | (reg & 7) << 3 | (rm & 7)
+}
+
+fn rex(w: bool, r: u8, x: u8, b: u8) -> u8 {
+    0x40 | (w as u8) << 3 | r << 2 | x << 1 | b
+}
+
+#[derive(Default)]
+struct Encoder {
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    /// Where a rel32 to the label has to be written.
+    fixups: Vec<(usize, String, RelocationKind)>,
+    relocations: Vec<Relocation>,
+    rodata: Vec<u8>,
+    /// Offset of every string literal in `rodata`.
+    strings: HashMap<String, usize>,
+    data: Vec<u8>,
+    /// Offset of every global in `data`.
+    globals: HashMap<String, usize>,
+    /// The last label not starting with a `.`.
+    scope: String,
+    errors: Vec<String>,
+}
+
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn full_name(&self, label: &str) -> String {
+        if label.starts_with('.') {
+            format!("{}{label}", self.scope)
+        } else {
+            label.to_string()
+        }
+    }
+
+    /// `op rm, reg` between two registers of the same size. `op` is the
+    /// opcode of the 16 to 64 bit form, the byte form is the one before it.
+    fn reg_reg(&mut self, op: u8, rm: X86Reg, reg: X86Reg) -> Result<(), String> {
+        let (rm, reg) = (operand(rm), operand(reg));
+        if rm.size != reg.size {
+            return Err("operands differ in size".into());
+        }
+        if (rm.high || reg.high) && (rm.needs_rex() || reg.needs_rex() || rm.ext() | reg.ext() != 0)
+        {
+            return Err("ah, bh, ch and dh can't be used with this register".into());
+        }
+        if rm.size == Size::Word {
+            self.bytes(&[0x66]);
+        }
+        let w = rm.size == Size::Qword;
+        if w || rm.ext() | reg.ext() != 0 || rm.needs_rex() || reg.needs_rex() {
+            self.bytes(&[rex(w, reg.ext(), 0, rm.ext())]);
+        }
+        let op = if rm.size == Size::Byte { op - 1 } else { op };
+        self.bytes(&[op, modrm(0b11, reg.num, rm.num)]);
+        Ok(())
+    }
+
+    /// A 64 bit instruction with a register operand in the modrm byte.
+    fn qword_modrm(&mut self, opcode: &[u8], reg: u8, rm: u8) {
+        self.bytes(&[rex(true, reg >> 3, 0, rm >> 3)]);
+        self.bytes(opcode);
+        self.bytes(&[modrm(0b11, reg, rm)]);
+    }
+
+    /// `push` and `pop` take the register in the low bits of the opcode.
+    fn plus_reg(&mut self, opcode: u8, reg: u8) {
+        if reg >> 3 != 0 {
+            self.bytes(&[rex(false, 0, 0, 1)]);
+        }
+        self.bytes(&[opcode + (reg & 7)]);
+    }
+
+    fn branch(&mut self, opcode: &[u8], label: &str, kind: RelocationKind) {
+        self.bytes(opcode);
+        let label = self.full_name(label);
+        self.fixups.push((self.code.len(), label, kind));
+        self.bytes(&[0; 4]);
+    }
+
+    fn load_str(&mut self, des: X86Reg, value: &str) -> Result<(), String> {
+        let des = qword(des)?;
+        let offset = match self.strings.get(value) {
+            Some(offset) => *offset,
+            None => {
+                let offset = write_str(&mut self.rodata, value);
+                self.strings.insert(value.to_string(), offset);
+                offset
+            }
+        };
+        self.lea_rip(des, RODATA, offset);
+        Ok(())
+    }
+
+    fn load_global(&mut self, des: X86Reg, name: &str) -> Result<(), String> {
+        let des = qword(des)?;
+        let offset = match self.globals.get(name) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.data.len();
+                self.data.extend_from_slice(&[0; 8]);
+                self.globals.insert(name.to_string(), offset);
+                offset
+            }
+        };
+        self.lea_rip(des, DATA, offset);
+        Ok(())
+    }
+
+    /// `lea des, [rip + disp32]`, pointing `offset` bytes past `symbol`.
+    fn lea_rip(&mut self, des: u8, symbol: &str, offset: usize) {
+        self.bytes(&[rex(true, des >> 3, 0, 0), 0x8d, modrm(0b00, des, 0b101)]);
+        self.relocations.push(Relocation {
+            at: self.code.len(),
+            symbol: symbol.into(),
+            offset,
+            kind: RelocationKind::Pc32,
+        });
+        self.bytes(&[0; 4]);
+    }
+
+    /// `op reg, [base + offset]`, with the same opcodes as `reg_reg`.
+    fn memory(&mut self, op: u8, reg: X86Reg, base: X86Reg, offset: i32) -> Result<(), String> {
+        let (reg, base) = (operand(reg), qword(base)?);
+        if reg.high && base >> 3 != 0 {
+            return Err("ah, bh, ch and dh can't be used with this register".into());
+        }
+        if reg.size == Size::Word {
+            self.bytes(&[0x66]);
+        }
+        let w = reg.size == Size::Qword;
+        if w || reg.ext() | base >> 3 != 0 || reg.needs_rex() {
+            self.bytes(&[rex(w, reg.ext(), 0, base >> 3)]);
+        }
+        let op = if reg.size == Size::Byte { op - 1 } else { op };
+        // rbp and r13 without a displacement mean rip relative instead.
+        let mode = match offset {
+            0 if base & 7 != 5 => 0b00,
+            -128..=127 => 0b01,
+            _ => 0b10,
+        };
+        self.bytes(&[op, modrm(mode, reg.num, base)]);
+        // rsp and r12 as a base need a sib byte.
+        if base & 7 == 4 {
+            self.bytes(&[0x24]);
+        }
+        match mode {
+            0b01 => self.bytes(&[offset as i8 as u8]),
+            0b10 => self.bytes(&offset.to_le_bytes()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn move_imm(&mut self, des: X86Reg, imm: u64) -> Result<(), String> {
+        let des = operand(des);
+        let b = des.ext();
+        match des.size {
+            // `mov r32, imm32` clears the upper half, so it covers everything
+            // that fits in 32 bits unsigned.
+            Size::Qword | Size::Dword if imm <= u32::MAX as u64 => {
+                if b != 0 {
+                    self.bytes(&[rex(false, 0, 0, b)]);
+                }
+                self.bytes(&[0xb8 + (des.num & 7)]);
+                self.bytes(&(imm as u32).to_le_bytes());
+            }
+            Size::Qword if i32::try_from(imm as i64).is_ok() => {
+                self.bytes(&[rex(true, 0, 0, b), 0xc7, modrm(0b11, 0, des.num)]);
+                self.bytes(&(imm as i32).to_le_bytes());
+            }
+            Size::Qword => {
+                self.bytes(&[rex(true, 0, 0, b), 0xb8 + (des.num & 7)]);
+                self.bytes(&imm.to_le_bytes());
+            }
+            _ => return Err(format!("{imm} does not fit the register")),
+        }
+        Ok(())
+    }
+
+    fn lea(&mut self, des: X86Reg, base: X86Reg, index: X86Reg) -> Result<(), String> {
+        let (des, mut base, mut index) = (qword(des)?, qword(base)?, qword(index)?);
+        // rsp can't be an index, but the sum does not care about the order.
+        if index == 4 {
+            std::mem::swap(&mut base, &mut index);
+        }
+        if index == 4 {
+            return Err("rsp can only be used once".into());
+        }
+        // A base of rbp or r13 without a displacement means no base at all,
+        // so an explicit zero displacement is used instead.
+        let mode = if base & 7 == 5 { 0b01 } else { 0b00 };
+        self.bytes(&[rex(true, des >> 3, index >> 3, base >> 3), 0x8d]);
+        self.bytes(&[modrm(mode, des, 0b100), modrm(0b00, index, base)]);
+        if mode == 0b01 {
+            self.bytes(&[0]);
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::MoveImm(des, imm) => self.move_imm(*des, *imm)?,
+            Instruction::MoveReg(des, src) => self.reg_reg(0x89, *des, *src)?,
+            Instruction::MoveZx(des) => {
+                let des = operand(*des);
+                match des.size {
+                    Size::Qword => self.qword_modrm(&[0x0f, 0xb6], des.num, 0),
+                    Size::Dword => {
+                        if des.ext() != 0 {
+                            self.bytes(&[rex(false, 1, 0, 0)]);
+                        }
+                        self.bytes(&[0x0f, 0xb6, modrm(0b11, des.num, 0)]);
+                    }
+                    _ => return Err("movzx needs a 32 or 64 bit register".into()),
+                }
+            }
+            Instruction::Add(des, src) => self.reg_reg(0x01, *des, *src)?,
+            Instruction::Sub(des, src) => self.reg_reg(0x29, *des, *src)?,
+            Instruction::Xor(des, src) => self.reg_reg(0x31, *des, *src)?,
+            Instruction::And(des, src) => self.reg_reg(0x21, *des, *src)?,
+            Instruction::Cmp(lhs, rhs) => self.reg_reg(0x39, *lhs, *rhs)?,
+            Instruction::Test(lhs, rhs) => self.reg_reg(0x85, *lhs, *rhs)?,
+            Instruction::Mul(des, src) => {
+                self.qword_modrm(&[0x0f, 0xaf], qword(*des)?, qword(*src)?)
+            }
+            Instruction::Div(src) => self.qword_modrm(&[0xf7], 7, qword(*src)?),
+            Instruction::Cqo => self.bytes(&[0x48, 0x99]),
+            Instruction::Lea(des, lhs, rhs) => self.lea(*des, *lhs, *rhs)?,
+            Instruction::LeaOffset(des, base, offset) => {
+                if operand(*des).size != Size::Qword {
+                    return Err("lea needs a 64 bit register".into());
+                }
+                self.memory(0x8d, *des, *base, *offset)?
+            }
+            Instruction::LoadStr(des, value) => self.load_str(*des, value)?,
+            Instruction::LoadGlobal(des, name) => self.load_global(*des, name)?,
+            Instruction::Load(des, base, offset) => self.memory(0x8b, *des, *base, *offset)?,
+            Instruction::Store(base, offset, src) => self.memory(0x89, *src, *base, *offset)?,
+            Instruction::DefLabel(name) => {
+                if !name.starts_with('.') {
+                    self.scope = name.clone();
+                }
+                let name = self.full_name(name);
+                if self.labels.insert(name.clone(), self.code.len()).is_some() {
+                    return Err(format!("label '{name}' is defined more than once"));
+                }
+            }
+            Instruction::Call(name) => self.branch(&[0xe8], name, RelocationKind::Plt32),
+            Instruction::TailJump(name) => {
+                // mov rsp, rbp; pop rbp
+                self.bytes(&[0x48, 0x89, 0xec, 0x5d]);
+                self.branch(&[0xe9], name, RelocationKind::Plt32);
+            }
+            Instruction::Jump(name) => self.branch(&[0xe9], name, RelocationKind::Pc32),
+            Instruction::JumpZero(name) => self.branch(&[0x0f, 0x84], name, RelocationKind::Pc32),
+            Instruction::JumpLessEq(name) => self.branch(&[0x0f, 0x8e], name, RelocationKind::Pc32),
+            // setg al
+            Instruction::SetG => self.bytes(&[0x0f, 0x9f, 0xc0]),
+            Instruction::Push(reg) => self.plus_reg(0x50, qword(*reg)?),
+            Instruction::Pop(reg) => self.plus_reg(0x58, qword(*reg)?),
+            // push rbp; mov rbp, rsp
+            Instruction::ProLog => self.bytes(&[0x55, 0x48, 0x89, 0xe5]),
+            // mov rsp, rbp; pop rbp; ret
+            Instruction::Epilog => self.bytes(&[0x48, 0x89, 0xec, 0x5d, 0xc3]),
+            Instruction::Syscall => self.bytes(&[0x0f, 0x05]),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Assembly, Vec<String>> {
+        let mut relocations = std::mem::take(&mut self.relocations);
+        for (at, label, kind) in self.fixups.iter() {
+            if !label.contains('.') {
+                relocations.push(Relocation {
+                    at: *at,
+                    symbol: label.clone(),
+                    offset: 0,
+                    kind: *kind,
+                });
+            }
+            let Some(target) = self.labels.get(label) else {
+                if label.contains('.') {
+                    self.errors
+                        .push(format!("label '{label}' is never defined"));
+                }
+                continue;
+            };
+            let rel = *target as i64 - (*at as i64 + 4);
+            self.code[*at..*at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        relocations.sort_by_key(|r| r.at);
+        Ok(Assembly {
+            code: self.code,
+            labels: self.labels,
+            relocations,
+            rodata: self.rodata,
+            data: self.data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64_linux::{X86Reg16, X86Reg32, X86RegLow8};
+    use pretty_assertions::assert_eq;
+    use X86Reg64::*;
+
+    fn bytes(instruction: Instruction) -> Vec<u8> {
+        assemble(vec![instruction]).unwrap().code
+    }
+
+    /// Expected bytes are from GNU as.
+    #[test]
+    fn encodings() {
+        let r = |reg: X86Reg64| X86Reg::from(reg);
+        let cases: Vec<(Instruction, &[u8])> = vec![
+            (Instruction::MoveReg(r(RAX), r(RDI)), &[0x48, 0x89, 0xf8]),
+            (Instruction::MoveReg(r(R12), r(RSP)), &[0x49, 0x89, 0xe4]),
+            (Instruction::MoveReg(r(RBX), r(R15)), &[0x4c, 0x89, 0xfb]),
+            (
+                Instruction::MoveReg(X86Reg32::ECX.into(), X86Reg32::R9D.into()),
+                &[0x44, 0x89, 0xc9],
+            ),
+            (
+                Instruction::MoveReg(X86RegLow8::SIL.into(), X86RegLow8::AL.into()),
+                &[0x40, 0x88, 0xc6],
+            ),
+            (
+                Instruction::MoveReg(X86RegHigh8::AH.into(), X86RegLow8::BL.into()),
+                &[0x88, 0xdc],
+            ),
+            (Instruction::MoveImm(r(RDI), 1), &[0xbf, 1, 0, 0, 0]),
+            (Instruction::MoveImm(r(R10), 60), &[0x41, 0xba, 60, 0, 0, 0]),
+            (
+                Instruction::MoveImm(r(RAX), -2i64 as u64),
+                &[0x48, 0xc7, 0xc0, 0xfe, 0xff, 0xff, 0xff],
+            ),
+            (
+                Instruction::MoveImm(r(R11), 1 << 40),
+                &[0x49, 0xbb, 0, 0, 0, 0, 0, 1, 0, 0],
+            ),
+            (Instruction::MoveZx(r(RSI)), &[0x48, 0x0f, 0xb6, 0xf0]),
+            (Instruction::MoveZx(r(R9)), &[0x4c, 0x0f, 0xb6, 0xc8]),
+            (Instruction::Add(r(RSI), r(RDX)), &[0x48, 0x01, 0xd6]),
+            (Instruction::Sub(r(R8), r(RCX)), &[0x49, 0x29, 0xc8]),
+            (Instruction::Mul(r(RDI), r(R13)), &[0x49, 0x0f, 0xaf, 0xfd]),
+            (Instruction::Div(r(R11)), &[0x49, 0xf7, 0xfb]),
+            (Instruction::Cqo, &[0x48, 0x99]),
+            (Instruction::Xor(r(RAX), r(RAX)), &[0x48, 0x31, 0xc0]),
+            (Instruction::And(r(RSP), r(RAX)), &[0x48, 0x21, 0xc4]),
+            (
+                Instruction::Lea(r(RAX), r(RDI), r(RSI)),
+                &[0x48, 0x8d, 0x04, 0x37],
+            ),
+            (
+                Instruction::Lea(r(R10), r(RBP), r(R12)),
+                &[0x4e, 0x8d, 0x54, 0x25, 0x00],
+            ),
+            (
+                Instruction::Lea(r(RCX), r(R13), r(RSP)),
+                &[0x4a, 0x8d, 0x0c, 0x2c],
+            ),
+            (
+                Instruction::LeaOffset(r(RAX), r(RBP), -16),
+                &[0x48, 0x8d, 0x45, 0xf0],
+            ),
+            (
+                Instruction::LeaOffset(r(RSP), r(RSP), -32),
+                &[0x48, 0x8d, 0x64, 0x24, 0xe0],
+            ),
+            (
+                Instruction::LeaOffset(r(R12), r(RDI), 8),
+                &[0x4c, 0x8d, 0x67, 0x08],
+            ),
+            (
+                Instruction::Load(r(RDX), r(RDI), -8),
+                &[0x48, 0x8b, 0x57, 0xf8],
+            ),
+            (
+                Instruction::Load(r(RAX), r(RSP), 0),
+                &[0x48, 0x8b, 0x04, 0x24],
+            ),
+            (
+                Instruction::Load(r(R9), r(RBP), 0),
+                &[0x4c, 0x8b, 0x4d, 0x00],
+            ),
+            (
+                Instruction::Load(r(RCX), r(R12), 16),
+                &[0x49, 0x8b, 0x4c, 0x24, 0x10],
+            ),
+            (
+                Instruction::Load(X86Reg32::EAX.into(), r(R13), 1000),
+                &[0x41, 0x8b, 0x85, 0xe8, 0x03, 0x00, 0x00],
+            ),
+            (
+                Instruction::Store(r(RSI), 0, X86RegLow8::DL.into()),
+                &[0x88, 0x16],
+            ),
+            (
+                Instruction::Store(r(RSI), 0, X86RegLow8::SIL.into()),
+                &[0x40, 0x88, 0x36],
+            ),
+            (
+                Instruction::Store(r(RBX), 0, X86RegHigh8::AH.into()),
+                &[0x88, 0x23],
+            ),
+            (
+                Instruction::Store(r(RBP), -8, r(RDI)),
+                &[0x48, 0x89, 0x7d, 0xf8],
+            ),
+            (
+                Instruction::Store(r(R8), 4, X86Reg16::AX.into()),
+                &[0x66, 0x41, 0x89, 0x40, 0x04],
+            ),
+            (Instruction::Cmp(r(RDI), r(RSI)), &[0x48, 0x39, 0xf7]),
+            (Instruction::Test(r(R14), r(R14)), &[0x4d, 0x85, 0xf6]),
+            (Instruction::SetG, &[0x0f, 0x9f, 0xc0]),
+            (Instruction::Push(r(RBX)), &[0x53]),
+            (Instruction::Push(r(R15)), &[0x41, 0x57]),
+            (Instruction::Pop(r(R12)), &[0x41, 0x5c]),
+            (Instruction::ProLog, &[0x55, 0x48, 0x89, 0xe5]),
+            (Instruction::Epilog, &[0x48, 0x89, 0xec, 0x5d, 0xc3]),
+            (Instruction::Syscall, &[0x0f, 0x05]),
+        ];
+        for (instruction, expected) in cases {
+            let text = instruction.to_string();
+            assert_eq!(bytes(instruction), expected, "{}", text.trim());
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let code = vec![
+            Instruction::DefLabel("f".into()),
+            Instruction::Jump(".L0".into()),
+            Instruction::DefLabel(".L0".into()),
+            Instruction::JumpZero(".L0".into()),
+            Instruction::DefLabel("main".into()),
+            Instruction::DefLabel(".L0".into()),
+            Instruction::Call("f".into()),
+            Instruction::TailJump("f".into()),
+        ];
+        let assembly = assemble(code).unwrap();
+        assert_eq!(
+            assembly.code,
+            [
+                0xe9, 0, 0, 0, 0, // jmp f.L0
+                0x0f, 0x84, 0xfa, 0xff, 0xff, 0xff, // jz f.L0
+                0xe8, 0xf0, 0xff, 0xff, 0xff, // call f
+                0x48, 0x89, 0xec, 0x5d, 0xe9, 0xe7, 0xff, 0xff, 0xff, // tail jump f
+            ]
+        );
+        assert_eq!(assembly.labels["f.L0"], 5);
+        assert_eq!(assembly.labels["main.L0"], 11);
+        assert_eq!(
+            assembly.relocations,
+            [
+                Relocation {
+                    at: 12,
+                    symbol: "f".into(),
+                    offset: 0,
+                    kind: RelocationKind::Plt32
+                },
+                Relocation {
+                    at: 21,
+                    symbol: "f".into(),
+                    offset: 0,
+                    kind: RelocationKind::Plt32
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let code = vec![
+            Instruction::LoadStr(RDI.into(), "ab".into()),
+            Instruction::LoadStr(R8.into(), "c".into()),
+            Instruction::LoadStr(RAX.into(), "ab".into()),
+        ];
+        let assembly = assemble(code).unwrap();
+        assert_eq!(
+            assembly.code,
+            [
+                0x48, 0x8d, 0x3d, 0, 0, 0, 0, // lea rdi, [rip]
+                0x4c, 0x8d, 0x05, 0, 0, 0, 0, // lea r8, [rip]
+                0x48, 0x8d, 0x05, 0, 0, 0, 0, // lea rax, [rip]
+            ]
+        );
+        assert_eq!(assembly.rodata, b"ab\0c\0");
+        let relocation = |at, offset| Relocation {
+            at,
+            symbol: RODATA.into(),
+            offset,
+            kind: RelocationKind::Pc32,
+        };
+        assert_eq!(
+            assembly.relocations,
+            [relocation(3, 0), relocation(10, 3), relocation(17, 0)]
+        );
+    }
+
+    #[test]
+    fn globals() {
+        let code = vec![
+            Instruction::LoadGlobal(RDI.into(), "a".into()),
+            Instruction::LoadGlobal(R8.into(), "b".into()),
+            Instruction::LoadGlobal(RAX.into(), "a".into()),
+        ];
+        let assembly = assemble(code).unwrap();
+        assert_eq!(assembly.data, [0; 16]);
+        let offsets = assembly
+            .relocations
+            .iter()
+            .map(|r| (r.at, r.symbol.as_str(), r.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [(3, DATA, 0), (10, DATA, 8), (17, DATA, 0)]);
+    }
+
+    #[test]
+    fn errors() {
+        let code = vec![
+            Instruction::DefLabel("f".into()),
+            Instruction::DefLabel("f".into()),
+            Instruction::Jump(".L9".into()),
+            Instruction::Call("g".into()),
+            Instruction::Push(X86Reg32::EAX.into()),
+            Instruction::MoveReg(X86RegHigh8::AH.into(), X86RegLow8::SIL.into()),
+        ];
+        assert_eq!(
+            assemble(code),
+            Err(vec![
+                "f__:: label 'f' is defined more than once".to_string(),
+                "push eax: eax has to be a 64 bit register".to_string(),
+                "mov ah, sil: ah, bh, ch and dh can't be used with this register".to_string(),
+                "label 'f.L9' is never defined".to_string(),
+            ])
+        );
+    }
+}
@@ -1,8 +1,16 @@
+mod abi;
+pub mod elf;
+mod encode;
+mod peephole;
 mod reg_state;
+mod runtime;
 #[cfg(test)]
 mod test;
 pub mod x86reg;
+pub use encode::{assemble, Assembly, RelocationKind, DATA, RODATA};
+pub use peephole::peephole;
 use reg_state::RegState;
+pub use runtime::{with_runtime, with_start_func};
 pub use std::fmt;
 pub use x86reg::*;
 
@@ -13,13 +21,33 @@ use crate::ir;
 
 pub fn compile_ir_code(ir: Vec<ir::Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
     let mut state = RegState::default();
-    Ok(ir
-        .iter()
-        .map(|i| i.compile(&mut state))
-        .collect::<Vec<Vec<Instruction>>>()
-        .into_iter()
-        .flatten()
-        .collect::<Vec<Instruction>>())
+    let mut errors = vec![];
+    let mut result = vec![];
+    let signatures = abi::signatures(&ir);
+    for inst in ir.iter() {
+        match inst {
+            ir::Instruction::DefFunc(func) => match compile_func(func, &signatures) {
+                Ok(code) => result.extend(code),
+                Err(error) => errors.push(error),
+            },
+            inst => result.extend(inst.compile(&mut state)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(result)
+}
+
+fn compile_func(
+    func: &ir::DefFunc,
+    signatures: &abi::Signatures,
+) -> Result<Vec<Instruction>, String> {
+    let (func, abi) = abi::lower(func, signatures);
+    let (mut state, body) = RegState::new(&func, abi)?;
+    let mut result = vec![Instruction::DefLabel(func.name.clone())];
+    result.extend(body.iter().flat_map(|inst| inst.compile(&mut state)));
+    Ok(result)
 }
 
 pub fn instruction_to_string(ir: Vec<Instruction>) -> Result<String, Vec<String>> {
@@ -38,14 +66,39 @@ pub enum Instruction {
     Add(X86Reg, X86Reg),
     Sub(X86Reg, X86Reg),
     Mul(X86Reg, X86Reg),
-    Div(X86Reg, X86Reg),
+    /// `idiv src`, divides rdx:rax leaving the quotient in rax.
+    Div(X86Reg),
+    /// Sign extends rax into rdx.
+    Cqo,
+    Xor(X86Reg, X86Reg),
+    And(X86Reg, X86Reg),
+    /// `lea des, [lhs + rhs]`
+    Lea(X86Reg, X86Reg, X86Reg),
+    /// `lea des, [base + offset]`
+    LeaOffset(X86Reg, X86Reg, i32),
+    /// `lea des, [rip + literal]`, the assembler puts the literal in read
+    /// only data.
+    LoadStr(X86Reg, String),
+    /// `lea des, [rip + global]`, the assembler gives every global 8 zeroed
+    /// bytes of writable data.
+    LoadGlobal(X86Reg, String),
+    /// `mov des, [base + offset]`
+    Load(X86Reg, X86Reg, i32),
+    /// `mov [base + offset], src`, storing as many bytes as `src` holds.
+    Store(X86Reg, i32, X86Reg),
     DefLabel(String),
     Call(String),
+    /// Tears down the frame and jumps to a function, which then returns to
+    /// our caller.
+    TailJump(String),
     Jump(String),
     JumpZero(String),
+    JumpLessEq(String),
     Cmp(X86Reg, X86Reg),
     Test(X86Reg, X86Reg),
     SetG,
+    Push(X86Reg),
+    Pop(X86Reg),
     ProLog,
     Epilog,
     Syscall,
@@ -86,17 +139,67 @@ impl fmt::Display for Instruction {
                 des.to_string(),
                 reg.to_string()
             ),
-            Self::Div(des, reg) => writeln!(
+            Self::Div(reg) => writeln!(f, "{:>10}{:>10}", "idiv", reg.to_string()),
+            Self::Cqo => writeln!(f, "{:>10}", "cqo"),
+            Self::Xor(des, reg) => writeln!(
                 f,
                 "{:>10}{:>10},{:>10}",
-                "idiv",
+                "xor",
                 des.to_string(),
                 reg.to_string()
             ),
+            Self::And(des, reg) => writeln!(
+                f,
+                "{:>10}{:>10},{:>10}",
+                "and",
+                des.to_string(),
+                reg.to_string()
+            ),
+            Self::Lea(des, lhs, rhs) => writeln!(
+                f,
+                "{:>10}{:>10},{:>10}",
+                "lea",
+                des.to_string(),
+                format!("[{lhs}+{rhs}]")
+            ),
+            Self::LeaOffset(des, base, offset) => writeln!(
+                f,
+                "{:>10}{:>10},{:>10}",
+                "lea",
+                des.to_string(),
+                format!("[{base}{offset:+}]")
+            ),
+            Self::Load(des, base, offset) => writeln!(
+                f,
+                "{:>10}{:>10},{:>10}",
+                "mov",
+                des.to_string(),
+                format!("[{base}{offset:+}]")
+            ),
+            Self::Store(base, offset, src) => writeln!(
+                f,
+                "{:>10}{:>10},{:>10}",
+                "mov",
+                format!("[{base}{offset:+}]"),
+                src.to_string()
+            ),
             Self::DefLabel(name) => writeln!(f, "{}__:", name),
+            Self::LoadStr(des, value) => {
+                writeln!(f, "{:>10}{:>10}, {value:?}", "lea", des.to_string())
+            }
+            Self::LoadGlobal(des, name) => {
+                writeln!(f, "{:>10}{:>10}, [{name}]", "lea", des.to_string())
+            }
             Self::Call(name) => writeln!(f, "{:>10}{:>10}__", "call", name),
+            Self::TailJump(name) => {
+                let mov = format!("{:>10}{:>10},{:>10}", "mov", "rsp", "rbp");
+                let pop = format!("{:>10}{:>10}", "pop", "rbp");
+                let jmp = format!("{:>10}{:>10}__", "jmp", name);
+                writeln!(f, "{mov}\n{pop}\n{jmp}")
+            }
             Self::Jump(name) => writeln!(f, "{:>10}{:>10}__", "jmp", name),
             Self::JumpZero(name) => writeln!(f, "{:>10}{:>10}__", "jz", name),
+            Self::JumpLessEq(name) => writeln!(f, "{:>10}{:>10}__", "jle", name),
             Self::Cmp(lhs, rhs) => writeln!(
                 f,
                 "{:>10}{:>10},{:>10}",
@@ -112,6 +215,8 @@ impl fmt::Display for Instruction {
                 rhs.to_string()
             ),
             Self::SetG => writeln!(f, "{:>10}{:>10}", "setg", "al"),
+            Self::Push(reg) => writeln!(f, "{:>10}{:>10}", "push", reg.to_string()),
+            Self::Pop(reg) => writeln!(f, "{:>10}{:>10}", "pop", reg.to_string()),
             Self::ProLog => {
                 let push = format!("{:>10}{:>10}", "push", "rbp");
                 let mov = format!("{:>10}{:>10},{:>10}", "mov", "rbp", "rsp");
@@ -132,7 +237,8 @@ impl Compile for ir::Instruction {
     fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
         match self {
             ir::Instruction::LoadImm(i) => i.compile(state),
-            ir::Instruction::DefFunc(i) => i.compile(state),
+            ir::Instruction::LoadStr(i) => i.compile(state),
+            ir::Instruction::DefFunc(_) => unreachable!("functions are compiled by compile_func"),
             ir::Instruction::Add(i) => i.compile(state),
             ir::Instruction::Sub(i) => i.compile(state),
             ir::Instruction::Mul(i) => i.compile(state),
@@ -143,9 +249,14 @@ impl Compile for ir::Instruction {
             ir::Instruction::Jump(i) => i.compile(state),
             ir::Instruction::DefLabel(i) => i.compile(state),
             ir::Instruction::Call(i) => i.compile(state),
+            ir::Instruction::TailCall(i) => i.compile(state),
             ir::Instruction::Return(i) => i.compile(state),
             ir::Instruction::Enter(i) => i.compile(state),
             ir::Instruction::Leave(i) => i.compile(state),
+            ir::Instruction::Phi(i) => i.compile(state),
+            ir::Instruction::Alloca(i) => i.compile(state),
+            ir::Instruction::Load(i) => i.compile(state),
+            ir::Instruction::Store(i) => i.compile(state),
         }
     }
 }
@@ -161,28 +272,12 @@ impl Compile for ir::LoadImm {
         vec![Instruction::MoveImm(reg, *imm)]
     }
 }
-// DefFunc(DefFunc),
-impl Compile for ir::DefFunc {
+// LoadStr(LoadStr),
+impl Compile for ir::LoadStr {
     fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
-        let ir::DefFunc {
-            name,
-            ret: _,
-            params: _,
-            body,
-            ..
-        } = self;
-        // FIXME:getting regesters for the params is currently not implemented correctly.
-        let mut result = body
-            .iter()
-            .flat_map(|inst| inst.compile(state))
-            .collect::<Vec<Instruction>>();
-        result.insert(0, Instruction::DefLabel(name.into()));
-        // let ret_reg = state.get_ret_reg();
-        // let last_reg = state.last_used_reg();
-        // let instruction = Instruction::MoveReg(ret_reg, last_reg);
-        // result.insert(result.len().saturating_sub(1), instruction);
-        state.reset();
-        result
+        let ir::LoadStr { des, value } = self;
+        let reg = state.get_reg(des);
+        vec![Instruction::LoadStr(reg, value.clone())]
     }
 }
 // Add(Add),
@@ -191,9 +286,7 @@ impl Compile for ir::Add {
         let ir::Add { des, lhs, rhs } = self;
         let xdes = state.get_reg(des);
         let xlhs = state.get_reg(lhs);
-        state.release_reg(lhs);
         let xrhs = state.get_reg(rhs);
-        state.release_reg(rhs);
         vec![
             Instruction::MoveReg(xdes, xlhs),
             Instruction::Add(xdes, xrhs),
@@ -204,10 +297,13 @@ impl Compile for ir::Add {
 impl Compile for ir::Sub {
     fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
         let ir::Sub { des, lhs, rhs } = self;
-        let des = state.get_reg(des);
-        let lhs = state.get_reg(lhs);
-        let rhs = state.get_reg(rhs);
-        vec![Instruction::MoveReg(des, lhs), Instruction::Sub(des, rhs)]
+        let xdes = state.get_reg(des);
+        let xlhs = state.get_reg(lhs);
+        let xrhs = state.get_reg(rhs);
+        vec![
+            Instruction::MoveReg(xdes, xlhs),
+            Instruction::Sub(xdes, xrhs),
+        ]
     }
 }
 // Mul(Mul),
@@ -216,9 +312,7 @@ impl Compile for ir::Mul {
         let ir::Mul { des, lhs, rhs } = self;
         let xdes = state.get_reg(des);
         let xlhs = state.get_reg(lhs);
-        state.release_reg(lhs);
         let xrhs = state.get_reg(rhs);
-        state.release_reg(rhs);
         vec![
             Instruction::MoveReg(xdes, xlhs),
             Instruction::Mul(xdes, xrhs),
@@ -229,31 +323,117 @@ impl Compile for ir::Mul {
 impl Compile for ir::Div {
     fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
         let ir::Div { des, lhs, rhs } = self;
-        let des = state.get_reg(des);
-        let lhs = state.get_reg(lhs);
-        let rhs = state.get_reg(rhs);
-        vec![Instruction::MoveReg(des, lhs), Instruction::Div(des, rhs)]
+        let xdes = state.get_reg(des);
+        let xlhs = state.get_reg(lhs);
+        let xrhs = state.get_reg(rhs);
+        let rax = X86Reg64::RAX.into();
+        let rdx = X86Reg::from(X86Reg64::RDX);
+        let scratch = X86Reg64::R11.into();
+        // The divisor moves out of the way since rdx is overwritten by the
+        // sign of the dividend.
+        let mut result = vec![
+            Instruction::MoveReg(rax, xlhs),
+            Instruction::MoveReg(scratch, xrhs),
+        ];
+        let keep_rdx = xdes.as_64_bit() != rdx.as_64_bit();
+        if keep_rdx {
+            result.push(Instruction::Push(rdx));
+        }
+        result.extend([Instruction::Cqo, Instruction::Div(scratch)]);
+        if keep_rdx {
+            result.push(Instruction::Pop(rdx));
+        }
+        result.push(Instruction::MoveReg(xdes, rax));
+        result
     }
 }
 
 impl Compile for ir::Grt {
     fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
         let ir::Grt { des, lhs, rhs } = self;
-        let des = state.get_reg(des);
-        let lhs = state.get_reg(lhs);
-        let rhs = state.get_reg(rhs);
+        let xdes = state.get_reg(des);
+        let xlhs = state.get_reg(lhs);
+        let xrhs = state.get_reg(rhs);
         vec![
-            Instruction::MoveReg(des, lhs),
-            Instruction::Cmp(des, rhs),
+            Instruction::MoveReg(xdes, xlhs),
+            Instruction::Cmp(xdes, xrhs),
             Instruction::SetG,
-            Instruction::MoveZx(des),
+            Instruction::MoveZx(xdes),
         ]
     }
 }
 
 impl Compile for ir::Copy {
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let ir::Copy { to, from } = self;
+        let to = state.get_reg(to);
+        let from = state.get_reg(from);
+        vec![Instruction::MoveReg(to, from)]
+    }
+}
+
+impl Compile for ir::Alloca {
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let des = state.get_reg(&self.des);
+        let offset = state.slot(&self.des);
+        vec![Instruction::LeaOffset(des, X86Reg64::RBP.into(), offset)]
+    }
+}
+
+/// The base register and displacement of `addr + offset`, a slot is
+/// addressed from rbp directly.
+fn address(state: &mut RegState, addr: &ir::Reg, offset: u32) -> (X86Reg, i32) {
+    match state.slot_of(addr) {
+        Some(slot) => (X86Reg64::RBP.into(), slot + offset as i32),
+        None => (state.get_reg(addr), offset as i32),
+    }
+}
+
+/// Bytes are loaded through al since `movzx` only takes registers here, the
+/// 32 bit `mov` clears the upper half by itself.
+impl Compile for ir::Load {
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let ir::Load {
+            des,
+            addr,
+            offset,
+            size,
+        } = self;
+        let des = state.get_reg(des);
+        let (addr, offset) = address(state, addr, *offset);
+        match size {
+            1 => vec![
+                Instruction::Load(X86RegLow8::AL.into(), addr, offset),
+                Instruction::MoveZx(des),
+            ],
+            4 => vec![Instruction::Load(des.as_32_bit().into(), addr, offset)],
+            _ => vec![Instruction::Load(des, addr, offset)],
+        }
+    }
+}
+
+impl Compile for ir::Store {
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let ir::Store {
+            addr,
+            offset,
+            src,
+            size,
+        } = self;
+        let (addr, offset) = address(state, addr, *offset);
+        let src = state.get_reg(src);
+        let src = match size {
+            1 => src.as_low_8_bit().into(),
+            4 => src.as_32_bit().into(),
+            _ => src,
+        };
+        vec![Instruction::Store(addr, offset, src)]
+    }
+}
+
+impl Compile for ir::Phi {
     fn compile(&self, _state: &mut RegState) -> Vec<Instruction> {
-        unimplemented!("{:?}", self)
+        unreachable!("phi nodes are removed by ir::ssa::destruct")
     }
 }
 // Conditional(Conditional),
@@ -278,32 +458,174 @@ impl Compile for ir::DefLabel {
         vec![Instruction::DefLabel(self.name())]
     }
 }
+/// Moves every source into its destination as if all moves happened at
+/// once, breaking cycles through rax.
+fn parallel_move(moves: Vec<(X86Reg, X86Reg)>) -> Vec<Instruction> {
+    let mut pending = moves
+        .into_iter()
+        .filter(|(to, from)| to.as_64_bit() != from.as_64_bit())
+        .collect::<Vec<(X86Reg, X86Reg)>>();
+    let mut result = vec![];
+    while !pending.is_empty() {
+        let is_read = |reg: &X86Reg, pending: &[(X86Reg, X86Reg)]| {
+            pending
+                .iter()
+                .any(|(_, from)| from.as_64_bit() == reg.as_64_bit())
+        };
+        match pending.iter().position(|(to, _)| !is_read(to, &pending)) {
+            Some(i) => {
+                let (to, from) = pending.remove(i);
+                result.push(Instruction::MoveReg(to, from));
+            }
+            None => {
+                // Every destination is still read, so the moves form cycles.
+                let (to, _) = pending[0];
+                let rax = X86Reg64::RAX.into();
+                result.push(Instruction::MoveReg(rax, to));
+                for (_, from) in pending.iter_mut() {
+                    if from.as_64_bit() == to.as_64_bit() {
+                        *from = rax;
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Moves the arguments that fit into the parameter registers.
+fn move_args(args: &[ir::Reg], state: &mut RegState) -> Vec<Instruction> {
+    let moves = args
+        .iter()
+        .take(X86RegParam::COUNT)
+        .enumerate()
+        .map(|(i, arg)| (X86RegParam::from(i).into(), state.get_reg(arg)))
+        .collect();
+    parallel_move(moves)
+}
+
+/// Pushes the arguments passed on the stack, the last one first.
+fn push_args(args: &[ir::Reg], state: &mut RegState) -> Vec<Instruction> {
+    let rax = X86Reg64::RAX.into();
+    args.iter()
+        .rev()
+        .flat_map(|arg| match state.spilled(arg) {
+            Some(slot) => vec![
+                Instruction::Load(rax, X86Reg64::RBP.into(), slot),
+                Instruction::Push(rax),
+            ],
+            None => vec![Instruction::Push(state.get_reg(arg))],
+        })
+        .collect()
+}
+
 // Call(Call),
 impl Compile for ir::Call {
-    fn compile(&self, _state: &mut RegState) -> Vec<Instruction> {
-        vec![Instruction::Call(self.caller.0.to_string())]
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let ir::Call { caller, args, ret } = self;
+        let abi = state.call_abi(ret, args.len());
+        // A value returned in registers is written to the first argument.
+        let (buffer, args) = match abi.ret_words {
+            Some(_) => (args.first(), &args[1..]),
+            None => (None, args.as_slice()),
+        };
+        let (in_regs, on_stack) = args.split_at(abi.in_regs);
+        let saved = state.live_across(ret).to_vec();
+        let mut result = saved
+            .iter()
+            .map(|reg| Instruction::Push(*reg))
+            .collect::<Vec<Instruction>>();
+        result.extend(push_args(on_stack, state));
+        result.extend(move_args(in_regs, state));
+        result.push(Instruction::Call(caller.0.to_string()));
+        let r11 = X86Reg64::R11.into();
+        if abi.ret_words == Some(2) {
+            // rdx may be restored below.
+            result.push(Instruction::MoveReg(r11, X86Reg64::RDX.into()));
+        }
+        if !on_stack.is_empty() {
+            let rsp = X86Reg64::RSP.into();
+            result.push(Instruction::LeaOffset(rsp, rsp, 8 * on_stack.len() as i32));
+        }
+        result.extend(saved.iter().rev().map(|reg| Instruction::Pop(*reg)));
+        if let (Some(buffer), Some(words)) = (buffer, abi.ret_words) {
+            let (base, offset) = address(state, buffer, 0);
+            let rax = X86Reg64::RAX.into();
+            for (i, src) in [rax, r11].into_iter().take(words as usize).enumerate() {
+                result.push(Instruction::Store(base, offset + 8 * i as i32, src));
+            }
+        }
+        let ret_reg = state.get_ret_reg();
+        result.push(Instruction::MoveReg(state.get_reg(ret), ret_reg));
+        result
     }
 }
 
+impl Compile for ir::TailCall {
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let ir::TailCall { caller, args } = self;
+        let mut result = move_args(args, state);
+        result.extend(pop_callee_saved(state));
+        result.push(Instruction::TailJump(caller.0.to_string()));
+        result
+    }
+}
+
+fn pop_callee_saved(state: &RegState) -> impl Iterator<Item = Instruction> + '_ {
+    state
+        .callee_saved()
+        .iter()
+        .rev()
+        .map(|reg| Instruction::Pop(*reg))
+}
+
 // Return(Return),
 impl Compile for ir::Return {
     fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        if let Some(words) = state.ret_words() {
+            // The value is loaded from where it was written.
+            let (base, offset) = address(state, &self.0, 0);
+            let regs = [X86Reg64::RAX, X86Reg64::RDX];
+            let mut result = regs
+                .into_iter()
+                .take(words as usize)
+                .enumerate()
+                .map(|(i, reg)| Instruction::Load(reg.into(), base, offset + 8 * i as i32))
+                .collect::<Vec<Instruction>>();
+            result.push(Instruction::Jump(ir::cfg::EXIT_LABEL.into()));
+            return result;
+        }
         let reg = state.get_reg(&self.0);
         let ret = state.get_ret_reg();
-        vec![Instruction::MoveReg(ret, reg)]
+        vec![
+            Instruction::MoveReg(ret, reg),
+            Instruction::Jump(ir::cfg::EXIT_LABEL.into()),
+        ]
     }
 }
 
 // Enter(Enter),
 impl Compile for ir::Enter {
-    fn compile(&self, _state: &mut RegState) -> Vec<Instruction> {
-        vec![Instruction::ProLog]
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        let pushes = state
+            .callee_saved()
+            .iter()
+            .map(|reg| Instruction::Push(*reg));
+        let rsp = X86Reg64::RSP.into();
+        let frame =
+            (state.frame_size() > 0).then(|| Instruction::LeaOffset(rsp, rsp, -state.frame_size()));
+        std::iter::once(Instruction::ProLog)
+            .chain(frame)
+            .chain(pushes)
+            .collect()
     }
 }
 // Leave(Leave),
 impl Compile for ir::Leave {
-    fn compile(&self, _state: &mut RegState) -> Vec<Instruction> {
-        vec![Instruction::Epilog]
+    fn compile(&self, state: &mut RegState) -> Vec<Instruction> {
+        pop_callee_saved(state)
+            .chain(std::iter::once(Instruction::Epilog))
+            .collect()
     }
 }
 
@@ -0,0 +1,427 @@
+//! Peephole optimizations over the generated x86 code, enabled with `-O`.
+//!
+//! Each round looks at a small window of instructions and rewrites it into
+//! something shorter, using register liveness to know when a value written
+//! by the window is never read again.
+use std::collections::{HashMap, HashSet};
+
+use super::{Instruction, X86Reg, X86Reg64};
+
+pub fn peephole(code: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    let mut code = code;
+    loop {
+        let (next, changed) = round(&code);
+        code = next;
+        if !changed {
+            return Ok(code);
+        }
+    }
+}
+
+fn round(code: &[Instruction]) -> (Vec<Instruction>, bool) {
+    use Instruction as I;
+    let live = live_after(code);
+    let dead = |reg: &X86Reg, i: usize| !live[i].contains(&reg.as_64_bit());
+    let same = |a: &X86Reg, b: &X86Reg| a.as_64_bit() == b.as_64_bit();
+
+    let mut result = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        let window = &code[i..];
+        let (replacement, len) = match window {
+            [I::MoveReg(des, src), ..] if same(des, src) => (vec![], 1),
+            // Write the value straight into where it gets moved to.
+            [def, I::MoveReg(to, from), ..]
+                if only_def(def).is_some_and(|des| same(des, from))
+                    && !same(to, from)
+                    && dead(from, i + 1) =>
+            {
+                (vec![retarget(def, *to)], 2)
+            }
+            [I::MoveReg(des, lhs), I::Add(sum, rhs), ..]
+                if same(des, sum) && !same(des, rhs) && !flags_read(code, i + 2) =>
+            {
+                (vec![I::Lea(*des, *lhs, *rhs)], 2)
+            }
+            [I::MoveReg(des, lhs), I::Cmp(cmp, rhs), ..]
+                if same(des, cmp) && !same(des, rhs) && dead(des, i + 1) =>
+            {
+                (vec![I::Cmp(*lhs, *rhs)], 2)
+            }
+            [I::SetG, I::MoveZx(des), I::Test(lhs, rhs), I::JumpZero(label), ..]
+                if same(des, lhs) && same(des, rhs) =>
+            {
+                let rax = X86Reg::from(X86Reg64::RAX);
+                if dead(des, i + 3) && dead(&rax, i + 3) {
+                    (vec![I::JumpLessEq(label.clone())], 4)
+                } else {
+                    let fused = I::JumpLessEq(label.clone());
+                    (vec![I::SetG, I::MoveZx(*des), fused], 4)
+                }
+            }
+            [I::MoveImm(des, 0), ..] if !flags_read(code, i + 1) => (vec![I::Xor(*des, *des)], 1),
+            [I::Jump(label) | I::JumpZero(label) | I::JumpLessEq(label), ..]
+                if next_labels(&code[i + 1..]).any(|l| l == label) =>
+            {
+                (vec![], 1)
+            }
+            [inst, ..] => {
+                result.push(inst.clone());
+                i += 1;
+                continue;
+            }
+            [] => unreachable!(),
+        };
+        result.extend(replacement);
+        i += len;
+    }
+    let changed = result != code;
+    (result, changed)
+}
+
+/// The register written by an instruction that does nothing else.
+fn only_def(instruction: &Instruction) -> Option<&X86Reg> {
+    match instruction {
+        Instruction::MoveImm(des, _)
+        | Instruction::MoveReg(des, _)
+        | Instruction::Lea(des, ..)
+        | Instruction::LeaOffset(des, ..)
+        | Instruction::LoadStr(des, _)
+        | Instruction::LoadGlobal(des, _)
+        | Instruction::Load(des, ..) => Some(des),
+        Instruction::Xor(des, src) if des == src => Some(des),
+        _ => None,
+    }
+}
+
+fn retarget(instruction: &Instruction, to: X86Reg) -> Instruction {
+    match instruction {
+        Instruction::MoveImm(_, imm) => Instruction::MoveImm(to, *imm),
+        Instruction::MoveReg(_, src) => Instruction::MoveReg(to, *src),
+        Instruction::Lea(_, lhs, rhs) => Instruction::Lea(to, *lhs, *rhs),
+        Instruction::LeaOffset(_, base, offset) => Instruction::LeaOffset(to, *base, *offset),
+        Instruction::LoadStr(_, value) => Instruction::LoadStr(to, value.clone()),
+        Instruction::LoadGlobal(_, name) => Instruction::LoadGlobal(to, name.clone()),
+        // A narrower load keeps its size.
+        Instruction::Load(des, base, offset) => {
+            let to = match des {
+                X86Reg::Reg32(_) => to.as_32_bit().into(),
+                X86Reg::RegLow8(_) => to.as_low_8_bit().into(),
+                _ => to,
+            };
+            Instruction::Load(to, *base, *offset)
+        }
+        Instruction::Xor(..) => Instruction::Xor(to, to),
+        _ => unreachable!("only_def checks the instruction"),
+    }
+}
+
+/// The labels right after a jump. One without a dot starts another
+/// function, the labels after it are local to that one.
+fn next_labels(code: &[Instruction]) -> impl Iterator<Item = &String> {
+    let mut scope_ended = false;
+    code.iter().map_while(move |i| match i {
+        Instruction::DefLabel(name) if !scope_ended => {
+            scope_ended = !name.starts_with('.');
+            Some(name)
+        }
+        _ => None,
+    })
+}
+
+/// Whether the flags are read by the code starting at `start` before they
+/// are written again. The code generator never keeps flags alive across a
+/// label, jump or call.
+fn flags_read(code: &[Instruction], start: usize) -> bool {
+    for instruction in code.iter().skip(start) {
+        match instruction {
+            Instruction::SetG | Instruction::JumpZero(_) | Instruction::JumpLessEq(_) => {
+                return true
+            }
+            Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Div(..)
+            | Instruction::Xor(..)
+            | Instruction::And(..)
+            | Instruction::Cmp(..)
+            | Instruction::Test(..)
+            | Instruction::DefLabel(_)
+            | Instruction::Jump(_)
+            | Instruction::Call(_)
+            | Instruction::TailJump(_)
+            | Instruction::Epilog
+            | Instruction::Syscall => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+const PARAMS: [X86Reg64; 6] = [
+    X86Reg64::RDI,
+    X86Reg64::RSI,
+    X86Reg64::RDX,
+    X86Reg64::RCX,
+    X86Reg64::R8,
+    X86Reg64::R9,
+];
+
+const CALLER_SAVED: [X86Reg64; 9] = [
+    X86Reg64::RAX,
+    X86Reg64::RDI,
+    X86Reg64::RSI,
+    X86Reg64::RDX,
+    X86Reg64::RCX,
+    X86Reg64::R8,
+    X86Reg64::R9,
+    X86Reg64::R10,
+    X86Reg64::R11,
+];
+
+/// Registers read and written by an instruction.
+fn uses_defs(instruction: &Instruction) -> (Vec<X86Reg64>, Vec<X86Reg64>) {
+    use Instruction as I;
+    let rax = X86Reg64::RAX;
+    match instruction {
+        I::MoveImm(des, _) | I::LoadStr(des, _) | I::LoadGlobal(des, _) => {
+            (vec![], vec![des.as_64_bit()])
+        }
+        I::MoveReg(des, src) => (vec![src.as_64_bit()], vec![des.as_64_bit()]),
+        I::MoveZx(des) => (vec![rax], vec![des.as_64_bit()]),
+        I::Xor(des, src) if des == src => (vec![], vec![des.as_64_bit()]),
+        I::Add(des, src)
+        | I::Sub(des, src)
+        | I::Mul(des, src)
+        | I::Xor(des, src)
+        | I::And(des, src) => (
+            vec![des.as_64_bit(), src.as_64_bit()],
+            vec![des.as_64_bit()],
+        ),
+        I::Lea(des, lhs, rhs) => (
+            vec![lhs.as_64_bit(), rhs.as_64_bit()],
+            vec![des.as_64_bit()],
+        ),
+        I::LeaOffset(des, base, _) | I::Load(des, base, _) => {
+            (vec![base.as_64_bit()], vec![des.as_64_bit()])
+        }
+        I::Store(base, _, src) => (vec![base.as_64_bit(), src.as_64_bit()], vec![]),
+        I::Cmp(lhs, rhs) | I::Test(lhs, rhs) => (vec![lhs.as_64_bit(), rhs.as_64_bit()], vec![]),
+        // Only al is written, the rest of rax is kept.
+        I::SetG => (vec![rax], vec![rax]),
+        I::Div(src) => (
+            vec![rax, X86Reg64::RDX, src.as_64_bit()],
+            vec![rax, X86Reg64::RDX],
+        ),
+        I::Cqo => (vec![rax], vec![X86Reg64::RDX]),
+        I::Push(reg) => (vec![reg.as_64_bit()], vec![]),
+        I::Pop(reg) => (vec![], vec![reg.as_64_bit()]),
+        I::Call(_) => (PARAMS.to_vec(), CALLER_SAVED.to_vec()),
+        I::TailJump(_) => (PARAMS.to_vec(), vec![]),
+        I::Epilog => (vec![rax], vec![]),
+        I::Syscall => (
+            vec![
+                rax,
+                X86Reg64::RDI,
+                X86Reg64::RSI,
+                X86Reg64::RDX,
+                X86Reg64::R10,
+                X86Reg64::R8,
+                X86Reg64::R9,
+            ],
+            vec![rax, X86Reg64::RCX, X86Reg64::R11],
+        ),
+        I::DefLabel(_) | I::Jump(_) | I::JumpZero(_) | I::JumpLessEq(_) | I::ProLog => {
+            (vec![], vec![])
+        }
+    }
+}
+
+/// The registers live after each instruction.
+fn live_after(code: &[Instruction]) -> Vec<HashSet<X86Reg64>> {
+    // Labels starting with a dot are local to the last label without one.
+    let mut scope = "";
+    let mut scopes = Vec::with_capacity(code.len());
+    let mut labels = HashMap::new();
+    for (i, instruction) in code.iter().enumerate() {
+        if let Instruction::DefLabel(name) = instruction {
+            if !name.starts_with('.') {
+                scope = name;
+            }
+            labels.insert((scope, name.as_str()), i);
+        }
+        scopes.push(scope);
+    }
+    let target = |i: usize, name: &str| {
+        let scope = if name.starts_with('.') { scopes[i] } else { "" };
+        labels.get(&(scope, name)).copied()
+    };
+    let successors = |i: usize| -> Vec<usize> {
+        let next = (i + 1 < code.len()).then_some(i + 1);
+        match &code[i] {
+            Instruction::Jump(name) => target(i, name).into_iter().collect(),
+            Instruction::JumpZero(name) | Instruction::JumpLessEq(name) => {
+                next.into_iter().chain(target(i, name)).collect()
+            }
+            Instruction::Epilog | Instruction::TailJump(_) => vec![],
+            _ => next.into_iter().collect(),
+        }
+    };
+
+    let mut live_in = vec![HashSet::new(); code.len()];
+    let mut live_out = vec![HashSet::new(); code.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..code.len()).rev() {
+            let out = successors(i)
+                .into_iter()
+                .flat_map(|s| live_in[s].iter().copied())
+                .collect::<HashSet<X86Reg64>>();
+            let (uses, defs) = uses_defs(&code[i]);
+            let mut inn = out.clone();
+            for reg in defs {
+                inn.remove(&reg);
+            }
+            inn.extend(uses);
+            if inn != live_in[i] || out != live_out[i] {
+                changed = true;
+                live_in[i] = inn;
+                live_out[i] = out;
+            }
+        }
+    }
+    live_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64_linux::X86RegParam;
+    use pretty_assertions::assert_eq;
+
+    fn reg(i: usize) -> X86Reg {
+        X86RegParam::from(i).into()
+    }
+
+    fn rax() -> X86Reg {
+        X86Reg64::RAX.into()
+    }
+
+    fn function(body: Vec<Instruction>) -> Vec<Instruction> {
+        let mut code = vec![Instruction::DefLabel("f".into()), Instruction::ProLog];
+        code.extend(body);
+        code.extend([Instruction::DefLabel(".exit".into()), Instruction::Epilog]);
+        code
+    }
+
+    #[test]
+    fn add_then_return() {
+        use Instruction as I;
+        let left = peephole(function(vec![
+            I::MoveReg(reg(0), reg(1)),
+            I::Add(reg(0), reg(2)),
+            I::MoveReg(rax(), reg(0)),
+            I::Jump(".exit".into()),
+        ]))
+        .unwrap();
+        let right = function(vec![I::Lea(rax(), reg(1), reg(2))]);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn self_move_and_zero() {
+        use Instruction as I;
+        let left = peephole(function(vec![
+            I::MoveReg(reg(1), reg(1)),
+            I::MoveImm(reg(0), 0),
+            I::Add(reg(0), reg(1)),
+            I::MoveReg(rax(), reg(0)),
+        ]))
+        .unwrap();
+        let right = function(vec![
+            I::Xor(reg(0), reg(0)),
+            I::Add(reg(0), reg(1)),
+            I::MoveReg(rax(), reg(0)),
+        ]);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn move_chain() {
+        use Instruction as I;
+        let left = peephole(function(vec![
+            I::MoveImm(reg(0), 5),
+            I::MoveReg(reg(1), reg(0)),
+            I::MoveReg(reg(2), reg(1)),
+            I::MoveReg(rax(), reg(2)),
+        ]))
+        .unwrap();
+        let right = function(vec![I::MoveImm(rax(), 5)]);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn compare_and_branch() {
+        use Instruction as I;
+        let left = peephole(function(vec![
+            I::MoveReg(reg(2), reg(0)),
+            I::Cmp(reg(2), reg(1)),
+            I::SetG,
+            I::MoveZx(reg(2)),
+            I::Test(reg(2), reg(2)),
+            I::JumpZero(".L1".into()),
+            I::MoveReg(rax(), reg(0)),
+            I::Jump(".exit".into()),
+            I::DefLabel(".L1".into()),
+            I::MoveReg(rax(), reg(1)),
+            I::Jump(".exit".into()),
+        ]))
+        .unwrap();
+        let right = function(vec![
+            I::Cmp(reg(0), reg(1)),
+            I::JumpLessEq(".L1".into()),
+            I::MoveReg(rax(), reg(0)),
+            I::Jump(".exit".into()),
+            I::DefLabel(".L1".into()),
+            I::MoveReg(rax(), reg(1)),
+        ]);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn jump_into_next_function() {
+        use Instruction as I;
+        let mut code = function(vec![I::DefLabel(".L2".into()), I::MoveReg(rax(), reg(0))]);
+        code.extend([I::DefLabel(".L6".into()), I::Jump(".L2".into())]);
+        code.extend([
+            I::DefLabel("g".into()),
+            I::DefLabel(".L2".into()),
+            I::MoveReg(rax(), reg(1)),
+        ]);
+        assert_eq!(peephole(code.clone()).unwrap(), code);
+    }
+
+    #[test]
+    fn condition_still_used() {
+        use Instruction as I;
+        let left = peephole(function(vec![
+            I::Cmp(reg(0), reg(1)),
+            I::SetG,
+            I::MoveZx(reg(2)),
+            I::Test(reg(2), reg(2)),
+            I::JumpZero(".L1".into()),
+            I::DefLabel(".L1".into()),
+            I::MoveReg(rax(), reg(2)),
+        ]))
+        .unwrap();
+        let right = function(vec![
+            I::Cmp(reg(0), reg(1)),
+            I::SetG,
+            I::MoveZx(reg(2)),
+            I::DefLabel(".L1".into()),
+            I::MoveReg(rax(), reg(2)),
+        ]);
+        assert_eq!(left, right);
+    }
+}
@@ -1,76 +1,561 @@
 #![warn(clippy::upper_case_acronyms)]
-use super::{X86Reg, X86RegParam, X86RegRet};
-use crate::ir::Reg;
-use std::collections::HashMap;
+use super::abi::{Abi, CallAbi};
+use super::{X86Reg, X86Reg64, X86RegParam, X86RegRet};
+use crate::ir::cfg::{Cfg, Liveness};
+use crate::ir::{self, Reg};
+use std::collections::{HashMap, HashSet};
 
+/// Registers handed out after the parameter registers. rax and r11 are kept
+/// free as scratch registers for calls and division.
+const EXTRA_REGS: [X86Reg64; 6] = [
+    X86Reg64::R10,
+    X86Reg64::RBX,
+    X86Reg64::R12,
+    X86Reg64::R13,
+    X86Reg64::R14,
+    X86Reg64::R15,
+];
+const CALLEE_SAVED: [X86Reg64; 5] = [
+    X86Reg64::RBX,
+    X86Reg64::R12,
+    X86Reg64::R13,
+    X86Reg64::R14,
+    X86Reg64::R15,
+];
+const PARAM_COUNT: usize = X86RegParam::COUNT;
+const REG_COUNT: usize = PARAM_COUNT + EXTRA_REGS.len();
+
+fn color_to_reg(color: usize) -> X86Reg {
+    match color.checked_sub(PARAM_COUNT) {
+        None => X86RegParam::from(color).into(),
+        Some(i) => EXTRA_REGS[i].into(),
+    }
+}
+
+/// Maps the registers of one function to x86 registers. Two ir registers only
+/// share a x86 register when they are never live at the same time.
 #[derive(Debug, Default)]
 pub struct RegState {
-    args: [bool; 6],
-    // _scratch: [bool; 9],
-    // _preserved: [bool; 7],
-    ret: [bool; 2],
     in_use: HashMap<Reg, X86Reg>,
-    last_used: Option<(Reg, X86Reg)>,
+    /// Caller saved registers holding a value still needed after a call,
+    /// keyed by the register the call defines.
+    live_across: HashMap<Reg, Vec<X86Reg>>,
+    callee_saved: Vec<X86Reg>,
+    /// Where the slot of every `Alloca` starts, relative to rbp.
+    slots: HashMap<Reg, i32>,
+    /// Values kept in a slot instead of a register, relative to rbp. Calls
+    /// read the arguments they pass on the stack from there.
+    spilled: HashMap<Reg, i32>,
+    frame_size: i32,
+    abi: Abi,
 }
 
 impl RegState {
-    fn push_reg(&mut self, reg: &Reg, xreg: X86Reg) {
-        self.last_used = Some((*reg, xreg));
-        self.in_use.insert(*reg, xreg);
+    /// Colors the registers of `func`, lowered to `abi`, keeping values in
+    /// slots of the frame until the rest fit. Gives back the body with the
+    /// loads and stores of those values added.
+    pub fn new(func: &ir::DefFunc, mut abi: Abi) -> Result<(Self, Vec<ir::Instruction>), String> {
+        // Slots sit right below the saved rbp, the callee saved registers are
+        // pushed below them.
+        let mut slots = HashMap::new();
+        let mut frame_size = 0u32;
+        for instruction in func.body.iter() {
+            if let ir::Instruction::Alloca(ir::Alloca { des, size }) = instruction {
+                frame_size += size.next_multiple_of(8).max(8);
+                slots.insert(*des, -(frame_size as i32));
+            }
+        }
+        // A slot only ever used as an address is reached from rbp and does
+        // not need a register.
+        let mut skip = slots.keys().copied().collect::<HashSet<Reg>>();
+        for inst in func.body.iter() {
+            let used = match inst {
+                ir::Instruction::Load(_) => vec![],
+                ir::Instruction::Store(ir::Store { src, .. }) => vec![*src],
+                inst => inst.uses(),
+            };
+            for reg in used {
+                skip.remove(&reg);
+            }
+        }
+        let mut current = func.clone();
+        current.body.retain(|inst| {
+            !matches!(inst, ir::Instruction::Alloca(ir::Alloca { des, .. }) if skip.contains(des))
+        });
+
+        let params = func.params.iter().map(|(r, _)| *r).collect::<Vec<Reg>>();
+        let first = func
+            .body
+            .iter()
+            .flat_map(|inst| inst.def().into_iter().chain(inst.uses()))
+            .chain(params.iter().copied())
+            .map(|Reg(r)| r + 1)
+            .max()
+            .unwrap_or(0);
+        let mut next = first;
+        let mut spilled = HashMap::new();
+        let mut fresh = || {
+            next += 1;
+            Reg(next - 1)
+        };
+        // Parameters past the registers are on the stack above the return
+        // address already.
+        let (in_regs, on_stack) = params.split_at(abi.params.in_regs);
+        for (i, param) in on_stack.iter().enumerate() {
+            let slot = fresh();
+            let offset = 16 + 8 * i as i32;
+            slots.insert(slot, offset);
+            spilled.insert(*param, offset);
+            skip.extend([slot, *param]);
+            let body = std::mem::take(&mut current.body);
+            current.body = spill(body, *param, slot, &mut fresh, &mut abi);
+        }
+        let (interference, colors) = loop {
+            let interference = Interference::new(&current, &skip);
+            let reg = match color(&interference, in_regs) {
+                Ok(colors) => break (interference, colors),
+                Err(reg) => reg,
+            };
+            // Registers made by spilling only live for one instruction, so
+            // spill the busiest value around instead.
+            let victim = std::iter::once(reg)
+                .chain(interference.edges[&reg].iter().copied())
+                .filter(|r| r.0 < first && !slots.contains_key(r) && !spilled.contains_key(r))
+                .max_by_key(|r| (interference.edges[r].len(), std::cmp::Reverse(r.0)))
+                .ok_or_else(|| format!("'{}' needs more registers than there are", func.name))?;
+            frame_size += 8;
+            let slot = fresh();
+            slots.insert(slot, -(frame_size as i32));
+            skip.insert(slot);
+            spilled.insert(victim, -(frame_size as i32));
+            let mut value = victim;
+            if params.contains(&victim) {
+                // The parameter arrives in a register, so store it on entry
+                // and spill a copy of it.
+                value = fresh();
+                for inst in current.body.iter_mut() {
+                    if let Some(des) = inst.def_mut().filter(|des| **des == victim) {
+                        *des = value;
+                    }
+                    for r in inst.uses_mut().into_iter().filter(|r| **r == victim) {
+                        *r = value;
+                    }
+                }
+                let enter = current
+                    .body
+                    .iter()
+                    .position(|inst| matches!(inst, ir::Instruction::Enter(_)))
+                    .map_or(0, |i| i + 1);
+                current.body.insert(enter, store(slot, victim));
+                spilled.insert(value, -(frame_size as i32));
+            }
+            skip.insert(value);
+            let body = std::mem::take(&mut current.body);
+            current.body = spill(body, value, slot, &mut fresh, &mut abi);
+        };
+        let in_use = colors
+            .into_iter()
+            .map(|(reg, color)| (reg, color_to_reg(color)))
+            .collect::<HashMap<Reg, X86Reg>>();
+
+        let is_callee_saved = |reg: &X86Reg| CALLEE_SAVED.contains(&reg.as_64_bit());
+        let live_across = interference
+            .live_across
+            .into_iter()
+            .map(|(ret, live)| {
+                let mut saved = live
+                    .iter()
+                    .map(|r| in_use[r])
+                    .filter(|r| !is_callee_saved(r))
+                    .collect::<Vec<X86Reg>>();
+                saved.sort();
+                saved.dedup();
+                (ret, saved)
+            })
+            .collect();
+        let mut callee_saved = in_use
+            .values()
+            .filter(|r| is_callee_saved(r))
+            .copied()
+            .collect::<Vec<X86Reg>>();
+        callee_saved.sort();
+        callee_saved.dedup();
+        let state = Self {
+            in_use,
+            live_across,
+            callee_saved,
+            slots,
+            spilled,
+            frame_size: frame_size.next_multiple_of(16) as i32,
+            abi,
+        };
+        Ok((state, current.body))
+    }
+
+    pub fn get_reg(&mut self, reg: &Reg) -> X86Reg {
+        self.in_use
+            .get(reg)
+            .copied()
+            .unwrap_or_else(|| panic!("{reg:?} was never given a register"))
+    }
+
+    pub fn get_ret_reg(&mut self) -> X86Reg {
+        X86RegRet::RAX.into()
+    }
+
+    /// The registers to keep safe on the stack around the call defining
+    /// `ret`.
+    pub fn live_across(&self, ret: &Reg) -> &[X86Reg] {
+        self.live_across.get(ret).map_or(&[], Vec::as_slice)
+    }
+
+    /// Where the arguments of the call defining `ret` go.
+    pub fn call_abi(&self, ret: &Reg, args: usize) -> CallAbi {
+        self.abi.call(ret, args)
+    }
+
+    /// The eightbytes the function returns in rax and rdx, if it returns
+    /// them rather than an integer.
+    pub fn ret_words(&self) -> Option<u32> {
+        self.abi.params.ret_words
+    }
+
+    /// The offset from rbp of the slot `reg` holds the address of.
+    pub fn slot(&self, reg: &Reg) -> i32 {
+        self.slots[reg]
+    }
+
+    /// Like `slot`, for a `reg` that may not be defined by an `Alloca`.
+    pub fn slot_of(&self, reg: &Reg) -> Option<i32> {
+        self.slots.get(reg).copied()
+    }
+
+    /// The offset from rbp of the slot `reg` is kept in, for a value that
+    /// did not get a register.
+    pub fn spilled(&self, reg: &Reg) -> Option<i32> {
+        self.spilled.get(reg).copied()
+    }
+
+    /// Bytes the prologue reserves for slots.
+    pub fn frame_size(&self) -> i32 {
+        self.frame_size
+    }
+
+    /// Callee saved registers the function writes to, pushed by the prologue.
+    pub fn callee_saved(&self) -> &[X86Reg] {
+        &self.callee_saved
+    }
+}
+
+/// Gives every register a color that none of its neighbours has, or the
+/// first register left without one.
+fn color(interference: &Interference, params: &[Reg]) -> Result<HashMap<Reg, usize>, Reg> {
+    let mut colors: HashMap<Reg, usize> = HashMap::new();
+    // Parameters arrive in the calling convention registers.
+    for (i, reg) in params.iter().enumerate() {
+        colors.insert(*reg, i);
+    }
+    let mut regs = interference.edges.keys().copied().collect::<Vec<Reg>>();
+    regs.sort_by_key(|Reg(r)| *r);
+    for reg in regs {
+        if colors.contains_key(&reg) {
+            continue;
+        }
+        let taken = interference.edges[&reg]
+            .iter()
+            .filter_map(|r| colors.get(r))
+            .copied()
+            .collect::<HashSet<usize>>();
+        // Registers joined by a copy try to share so the move goes away.
+        let hint = interference
+            .copies
+            .get(&reg)
+            .into_iter()
+            .flatten()
+            .filter_map(|r| colors.get(r))
+            .find(|c| !taken.contains(c))
+            .copied();
+        let color = hint
+            .or_else(|| (0..REG_COUNT).find(|c| !taken.contains(c)))
+            .ok_or(reg)?;
+        colors.insert(reg, color);
     }
+    Ok(colors)
+}
+
+fn load(des: Reg, slot: Reg) -> ir::Instruction {
+    ir::Instruction::Load(ir::Load {
+        des,
+        addr: slot,
+        offset: 0,
+        size: 8,
+    })
+}
 
-    pub fn reset(&mut self) {
-        *self = Self::default()
+fn store(slot: Reg, src: Reg) -> ir::Instruction {
+    ir::Instruction::Store(ir::Store {
+        addr: slot,
+        offset: 0,
+        src,
+        size: 8,
+    })
+}
+
+/// Keeps `reg` in `slot` instead of a register, every read of it loads it
+/// into a new register first and every write stores it right after.
+fn spill(
+    body: Vec<ir::Instruction>,
+    reg: Reg,
+    slot: Reg,
+    fresh: &mut impl FnMut() -> Reg,
+    abi: &mut Abi,
+) -> Vec<ir::Instruction> {
+    let mut result = Vec::with_capacity(body.len());
+    for mut inst in body {
+        match &inst {
+            ir::Instruction::Copy(ir::Copy { to, from }) if *to == reg && *from == reg => {}
+            ir::Instruction::Copy(ir::Copy { to, from }) if *to == reg => {
+                result.push(store(slot, *from))
+            }
+            ir::Instruction::Copy(ir::Copy { to, from }) if *from == reg => {
+                result.push(load(*to, slot))
+            }
+            _ => {
+                // Arguments past the registers are pushed straight from the
+                // slot by the call.
+                let uses = match &mut inst {
+                    ir::Instruction::Call(ir::Call { args, ret, .. }) => {
+                        let call = abi.call(ret, args.len());
+                        let in_regs = call.in_regs + call.ret_words.is_some() as usize;
+                        args.iter_mut().take(in_regs).collect()
+                    }
+                    inst => inst.uses_mut(),
+                };
+                let mut uses = uses.into_iter().filter(|r| **r == reg).peekable();
+                if uses.peek().is_some() {
+                    let temp = fresh();
+                    result.push(load(temp, slot));
+                    for r in uses {
+                        *r = temp;
+                    }
+                }
+                let def = inst.def_mut().filter(|des| **des == reg).map(|des| {
+                    *des = fresh();
+                    *des
+                });
+                // A call is known by the register it defines.
+                if let (Some(temp), ir::Instruction::Call(_)) = (def, &inst) {
+                    if let Some(call) = abi.calls.remove(&reg) {
+                        abi.calls.insert(temp, call);
+                    }
+                }
+                result.push(inst);
+                result.extend(def.map(|temp| store(slot, temp)));
+            }
+        }
     }
+    result
+}
+
+#[derive(Debug, Default)]
+struct Interference {
+    edges: HashMap<Reg, HashSet<Reg>>,
+    copies: HashMap<Reg, Vec<Reg>>,
+    live_across: HashMap<Reg, HashSet<Reg>>,
+}
 
-    pub fn release_reg(&mut self, reg: &Reg) {
-        self.in_use.get(reg).and_then(|r| {
-            match r {
-                X86Reg::RegRet(reg) => self.ret[(*reg) as usize] = false,
-                X86Reg::RegParam(reg) => self.args[(*reg) as usize] = false,
-                X86Reg::Reg64(..) => unreachable!(),
-                X86Reg::Reg32(..) => unreachable!(),
-                X86Reg::Reg16(..) => unreachable!(),
-                X86Reg::RegHigh8(..) => unreachable!(),
-                X86Reg::RegLow8(..) => unreachable!(),
+impl Interference {
+    /// Leaves out the registers in `skip`, which are kept in slots.
+    fn new(func: &ir::DefFunc, skip: &HashSet<Reg>) -> Self {
+        let mut graph = Self::default();
+        let cfg = Cfg::new(func);
+        let liveness = Liveness::new(&cfg);
+        let kept = |reg: &Reg| !skip.contains(reg);
+        let params = func
+            .params
+            .iter()
+            .map(|(r, _)| *r)
+            .filter(kept)
+            .collect::<Vec<Reg>>();
+        for reg in params.iter() {
+            graph.add_node(*reg);
+            let live = liveness.live_in[cfg.entry().0].iter().filter(|r| kept(r));
+            for other in params.iter().chain(live) {
+                graph.add_edge(*reg, *other);
             }
-            None::<X86Reg>
-        });
+        }
+        for id in cfg.ids() {
+            let mut live = liveness.live_out[id.0]
+                .iter()
+                .copied()
+                .filter(kept)
+                .collect::<HashSet<Reg>>();
+            for inst in cfg.block(id).body.iter().rev() {
+                let uses = inst.uses().into_iter().filter(kept).collect::<Vec<Reg>>();
+                for reg in uses.iter() {
+                    graph.add_node(*reg);
+                }
+                if let Some(des) = inst.def() {
+                    graph.add_node(des);
+                    if let ir::Instruction::Call(..) = inst {
+                        let across = live.iter().filter(|r| **r != des).copied().collect();
+                        graph.live_across.insert(des, across);
+                    }
+                    // The source of a copy holds the same value as the
+                    // destination so they may share a register.
+                    let source = match inst {
+                        ir::Instruction::Copy(ir::Copy { to, from }) => {
+                            graph.copies.entry(*to).or_default().push(*from);
+                            graph.copies.entry(*from).or_default().push(*to);
+                            Some(*from)
+                        }
+                        _ => None,
+                    };
+                    // Two operand instructions move the first operand into the
+                    // destination before reading the others, so only that
+                    // operand may share a register with it.
+                    let tied = match inst {
+                        ir::Instruction::Add(ir::Add { lhs, rhs, .. })
+                        | ir::Instruction::Sub(ir::Sub { lhs, rhs, .. })
+                        | ir::Instruction::Mul(ir::Mul { lhs, rhs, .. })
+                        | ir::Instruction::Div(ir::Div { lhs, rhs, .. })
+                        | ir::Instruction::Grt(ir::Grt { lhs, rhs, .. })
+                            if lhs != rhs =>
+                        {
+                            Some(*lhs)
+                        }
+                        _ => source,
+                    };
+                    for other in live.iter().filter(|r| Some(**r) != source) {
+                        graph.add_edge(des, *other);
+                    }
+                    for other in uses.iter().filter(|r| Some(**r) != tied) {
+                        graph.add_edge(des, *other);
+                    }
+                    live.remove(&des);
+                }
+                live.extend(uses);
+            }
+        }
+        graph
     }
 
-    pub fn get_reg(&mut self, reg: &Reg) -> X86Reg {
-        self.in_use.get(reg).copied().unwrap_or_else(|| {
-            let xreg = self.get_param_reg(reg);
-            self.push_reg(reg, xreg);
-            xreg
-        })
-    }
-
-    pub fn get_param_reg(&mut self, reg: &Reg) -> X86Reg {
-        let xreg = self
-            .args
-            .iter_mut()
-            .enumerate()
-            .find(|(_, r)| !**r)
-            .map(|(i, r)| {
-                *r = true;
-                X86RegParam::from(i).into()
-            })
+    fn add_node(&mut self, reg: Reg) {
+        self.edges.entry(reg).or_default();
+    }
+
+    fn add_edge(&mut self, a: Reg, b: Reg) {
+        if a == b {
+            return;
+        }
+        self.edges.entry(a).or_default().insert(b);
+        self.edges.entry(b).or_default().insert(a);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::lex;
+    use crate::parse::parse;
+    use crate::x86_64_linux as x86;
+    use crate::{ir, vm};
+    use pretty_assertions::assert_eq;
+
+    /// Runs `src` natively and in the vm, giving back both exit codes.
+    fn run(name: &str, src: &str) -> (Option<i32>, Option<i32>) {
+        let code = lex(src)
+            .and_then(parse)
+            .and_then(ir::code_gen)
+            .and_then(ir::ssa::construct)
+            .and_then(ir::ssa::destruct)
+            .unwrap();
+        let program = vm::lower(code.clone()).unwrap();
+        let expected = vm::run(&program, &[]).ok().map(|code| code as i32 & 0xff);
+        let elf = x86::compile_ir_code(code)
+            .map(x86::with_runtime)
+            .and_then(x86::with_start_func)
+            .and_then(x86::assemble)
+            .and_then(x86::elf::write_executable)
             .unwrap();
-        self.push_reg(reg, xreg);
-        xreg
+        let path = std::env::temp_dir().join(format!("{name}_{}", std::process::id()));
+        std::fs::write(&path, elf).unwrap();
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let status = std::process::Command::new(&path).status();
+        std::fs::remove_file(&path).unwrap();
+        (status.unwrap().code(), expected)
     }
 
-    pub fn get_ret_reg(&mut self) -> X86Reg {
-        X86RegRet::RAX.into()
-        // self.ret
-        //     .iter_mut()
-        //     .enumerate()
-        //     .find(|(_, r)| !**r)
-        //     .and_then(|(i, r)| {
-        //         *r = true;
-        //         Some(X86RegRet::from(i).into())
-        //     })
-        //     .unwrap_or(X86RegRet::RAX.into())
+    #[test]
+    fn more_locals_than_registers() {
+        let names = ('a'..='t').collect::<Vec<char>>();
+        let lets = names
+            .iter()
+            .zip(1..)
+            .map(|(name, i)| format!("let {name} = {i};"))
+            .collect::<String>();
+        let sum = names.iter().map(char::to_string).collect::<Vec<String>>();
+        let src = format!("fn main() {{ {lets} return {}; }}", sum.join(" + "));
+        assert_eq!(run("spill_locals", &src), (Some(210), Some(210)));
+    }
+
+    #[test]
+    fn values_live_across_a_call() {
+        let src = "fn id(x: u64) -> u64 { return x; }
+            fn main() {
+                let a = 1; let b = 2; let c = 3; let d = 4; let e = 5; let f = 6;
+                let g = 7; let h = 8; let i = 9; let j = 10; let k = 11; let l = 12;
+                let n = 0;
+                let s = 0;
+                while 10 > n {
+                    s = s + id(a) + b + c + d + e + f + g + h + i + j + k + l;
+                    a = a + 1;
+                    n = n + 1;
+                };
+                return s + id(100) - 800;
+            }";
+        assert_eq!(run("spill_call", src), (Some(125), Some(125)));
+    }
+
+    #[test]
+    fn arguments_past_the_registers() {
+        let src = "fn f(a: u64, b: u64, c: u64, d: u64, e: u64, g: u64, h: u64) -> u64 {
+                return a + b + c + d + e + g + h;
+            }
+            fn k(a: u64, b: u64, c: u64, d: u64, e: u64, g: u64, h: u64, i: u64) -> u64 {
+                if h > 0 { return k(b, c, d, e, g, a, h - 1, i + a); };
+                return i * 100 + a - h;
+            }
+            fn main() {
+                let a = 1; let b = 2; let c = 3; let d = 4; let e = 5; let g = 6;
+                let h = 7; let i = 8; let j = 9; let l = 10; let m = 11; let n = 12;
+                let x = f(a, b, c, d, e, g, h) + f(i, j, l, m, n, a, b) + k(a, b, c, d, e, g, 3, 0);
+                return x + a + b + c + d + e + g + h + i + j + l + m + n - 600;
+            }";
+        assert_eq!(run("stack_args", src), (Some(163), Some(163)));
+    }
+
+    #[test]
+    fn structs_passed_by_value() {
+        let src = "struct S { a: u64, b: u32 }
+            struct B { a: u64, b: u64, c: u64 }
+            struct T { x: u8 }
+            fn small(s: S) -> u64 { return s.a * 10 + s.b; }
+            fn big(b: B) -> u64 { return b.a + b.b * 10 + b.c * 100; }
+            fn make(a: u64) -> S { return S { a: a, b: 7 }; }
+            fn make_big(a: u64) -> B { return B { a: a, b: 2, c: 3 }; }
+            fn tiny(t: T) -> T { return T { x: t.x + 1 }; }
+            fn mixed(a: u64, b: u64, c: u64, d: u64, e: u64, s: S, f: u64) -> u64 {
+                return a + b + c + d + e + s.a + s.b + f;
+            }
+            fn main() {
+                let s = make(4);
+                let b = make_big(1);
+                let t = tiny(T { x: 5 });
+                return small(s) + big(b) + t.x + mixed(1, 1, 1, 1, 1, s, 1) - 300;
+            }";
+        assert_eq!(run("struct_args", src), (Some(91), Some(91)));
     }
 }
@@ -0,0 +1,440 @@
+//! Builtin functions, written in assembly and added to programs that call
+//! them without defining a function of the same name, and the `_start` glue
+//! calling `main`.
+//!
+//! They only use registers the callee may clobber in the System V abi, so
+//! they can be called from C as well.
+use std::collections::HashSet;
+
+use super::{Instruction, X86Reg, X86Reg64, X86RegLow8};
+use crate::ir::INDEX_OUT_OF_BOUNDS;
+
+/// Every builtin with the builtins it calls itself.
+pub const FUNCTIONS: [(&str, &[&str]); 9] = [
+    ("print", &[]),
+    ("println", &["print"]),
+    ("print_int", &[]),
+    ("arg_count", &[]),
+    ("arg", &[STRLEN]),
+    ("env", &[STRLEN]),
+    (INDEX_OUT_OF_BOUNDS, &[EPRINT_INT]),
+    (STRLEN, &[]),
+    (EPRINT_INT, &[]),
+];
+
+/// Counts the bytes of a nul terminated string, only called by other
+/// builtins.
+const STRLEN: &str = "__strlen";
+/// `print_int` writing to stderr, only called by other builtins.
+const EPRINT_INT: &str = "__eprint_int";
+
+/// Globals `_start` fills in from the initial stack. An object linked by `cc`
+/// has `main` called by libc instead, so `ir::code_gen_exported` rejects the
+/// builtins reading them.
+const ARGC: &str = "argc";
+const ARGV: &str = "argv";
+const ENVP: &str = "envp";
+
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+const WRITE: u64 = 1;
+const EXIT: u64 = 60;
+
+/// Calls `main` with `argc` and `argv` from the initial stack and exits with
+/// what it returned.
+pub fn with_start_func(mut code: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    use X86Reg64::*;
+    if !code.contains(&Instruction::DefLabel("main".into())) {
+        return Err(vec!["program has no 'main' function".into()]);
+    }
+    code.extend([
+        Instruction::DefLabel(super::elf::ENTRY.into()),
+        Instruction::Load(reg(RDI), reg(RSP), 0),
+        Instruction::MoveImm(reg(RCX), 8),
+        Instruction::MoveReg(reg(RSI), reg(RSP)),
+        Instruction::Add(reg(RSI), reg(RCX)),
+        // envp follows the null ending argv.
+        Instruction::MoveReg(reg(RDX), reg(RDI)),
+        Instruction::Mul(reg(RDX), reg(RCX)),
+        Instruction::Add(reg(RDX), reg(RSI)),
+        Instruction::Add(reg(RDX), reg(RCX)),
+        Instruction::LoadGlobal(reg(RAX), ARGC.into()),
+        Instruction::Store(reg(RAX), 0, reg(RDI)),
+        Instruction::LoadGlobal(reg(RAX), ARGV.into()),
+        Instruction::Store(reg(RAX), 0, reg(RSI)),
+        Instruction::LoadGlobal(reg(RAX), ENVP.into()),
+        Instruction::Store(reg(RAX), 0, reg(RDX)),
+        // The call pushes the return address, leaving `main` with the
+        // alignment every other function gets.
+        Instruction::MoveImm(reg(RAX), -16i64 as u64),
+        Instruction::And(reg(RSP), reg(RAX)),
+        Instruction::Call("main".into()),
+        Instruction::MoveReg(reg(RDI), reg(RAX)),
+        Instruction::MoveImm(reg(RAX), EXIT),
+        Instruction::Syscall,
+    ]);
+    Ok(code)
+}
+
+pub fn with_runtime(mut code: Vec<Instruction>) -> Vec<Instruction> {
+    let defined = code
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::DefLabel(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect::<HashSet<&str>>();
+    let mut needed = code
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::Call(name) | Instruction::TailJump(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .filter(|name| !defined.contains(name))
+        .collect::<HashSet<&str>>();
+    for (name, calls) in FUNCTIONS {
+        if needed.contains(name) {
+            needed.extend(calls.iter().filter(|name| !defined.contains(*name)));
+        }
+    }
+    let runtime = FUNCTIONS
+        .iter()
+        .filter(|(name, _)| needed.contains(name))
+        .flat_map(|(name, _)| function(name))
+        .collect::<Vec<Instruction>>();
+    code.extend(runtime);
+    code
+}
+
+fn reg(reg: X86Reg64) -> X86Reg {
+    reg.into()
+}
+
+fn function(name: &str) -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![Instruction::DefLabel(name.into()), Instruction::ProLog];
+    code.extend(match name {
+        "print" => print(),
+        "println" => {
+            let mut code = vec![Instruction::Call("print".into())];
+            code.extend(write_literal(STDOUT, "\n"));
+            code.push(Instruction::MoveImm(reg(RAX), 0));
+            code
+        }
+        "print_int" => print_int(STDOUT),
+        EPRINT_INT => print_int(STDERR),
+        "arg_count" => vec![
+            Instruction::LoadGlobal(reg(RAX), ARGC.into()),
+            Instruction::Load(reg(RAX), reg(RAX), 0),
+        ],
+        "arg" => arg(),
+        "env" => env(),
+        INDEX_OUT_OF_BOUNDS => index_out_of_bounds(),
+        STRLEN => strlen(),
+        _ => unreachable!("'{name}' is not a builtin"),
+    });
+    code.push(Instruction::Epilog);
+    code
+}
+
+/// Loads the byte at `[address]` into `des`, clobbering rax.
+fn load_byte(des: X86Reg64, address: X86Reg64) -> [Instruction; 2] {
+    [
+        Instruction::Load(X86RegLow8::AL.into(), reg(address), 0),
+        Instruction::MoveZx(reg(des)),
+    ]
+}
+
+/// `print(s: str)`, rdi holds the address of the bytes and their number.
+fn print() -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![
+        Instruction::Load(reg(RSI), reg(RDI), 0),
+        Instruction::Load(reg(RDX), reg(RDI), 8),
+    ];
+    code.extend(write(STDOUT));
+    code.push(Instruction::MoveImm(reg(RAX), 0));
+    code
+}
+
+/// Writes `value` to `fd`.
+fn write_literal(fd: u64, value: &str) -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![
+        Instruction::LoadStr(reg(RSI), value.into()),
+        Instruction::MoveImm(reg(RDX), value.len() as u64),
+    ];
+    code.extend(write(fd));
+    code
+}
+
+/// Writes the rdx bytes at rsi to `fd`.
+fn write(fd: u64) -> [Instruction; 3] {
+    use X86Reg64::*;
+    [
+        Instruction::MoveImm(reg(RDI), fd),
+        Instruction::MoveImm(reg(RAX), WRITE),
+        Instruction::Syscall,
+    ]
+}
+
+/// `__index_out_of_bounds(index: i64, len: u64)`, reports both on stderr and
+/// exits with 1 like the interpreter and the vm do on an error.
+fn index_out_of_bounds() -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![Instruction::Push(reg(RDI)), Instruction::Push(reg(RSI))];
+    code.extend(write_literal(STDERR, "index "));
+    code.extend([
+        Instruction::Load(reg(RDI), reg(RSP), 8),
+        Instruction::Call(EPRINT_INT.into()),
+    ]);
+    code.extend(write_literal(STDERR, " is out of bounds for length "));
+    code.extend([
+        Instruction::Load(reg(RDI), reg(RSP), 0),
+        Instruction::Call(EPRINT_INT.into()),
+    ]);
+    code.extend(write_literal(STDERR, "\n"));
+    code.extend([
+        Instruction::MoveImm(reg(RDI), 1),
+        Instruction::MoveImm(reg(RAX), EXIT),
+        Instruction::Syscall,
+    ]);
+    code
+}
+
+/// `print_int(n: i64)` writing to `fd`, the digits are written backwards from `rbp` into a
+/// buffer on the stack. `idiv` leaves a negative remainder for a negative
+/// `n`, so every remainder is multiplied by the sign of `n`.
+fn print_int(fd: u64) -> Vec<Instruction> {
+    use X86Reg64::*;
+    let digit = X86RegLow8::DL.into();
+    vec![
+        // 20 digits and a sign.
+        Instruction::MoveImm(reg(RAX), 32),
+        Instruction::Sub(reg(RSP), reg(RAX)),
+        Instruction::MoveReg(reg(RSI), reg(RBP)),
+        // r8 = n < 0, r9 = 1 - 2 * r8
+        Instruction::MoveImm(reg(R8), 0),
+        Instruction::Cmp(reg(R8), reg(RDI)),
+        Instruction::SetG,
+        Instruction::MoveZx(reg(R8)),
+        Instruction::MoveImm(reg(R9), 1),
+        Instruction::Sub(reg(R9), reg(R8)),
+        Instruction::Sub(reg(R9), reg(R8)),
+        Instruction::MoveImm(reg(R11), 10),
+        Instruction::MoveImm(reg(RCX), 1),
+        Instruction::MoveReg(reg(RAX), reg(RDI)),
+        Instruction::DefLabel(".digit".into()),
+        Instruction::Cqo,
+        Instruction::Div(reg(R11)),
+        Instruction::Mul(reg(RDX), reg(R9)),
+        Instruction::MoveImm(reg(R10), b'0' as u64),
+        Instruction::Add(reg(RDX), reg(R10)),
+        Instruction::Sub(reg(RSI), reg(RCX)),
+        Instruction::Store(reg(RSI), 0, digit),
+        Instruction::Test(reg(RAX), reg(RAX)),
+        Instruction::JumpZero(".sign".into()),
+        Instruction::Jump(".digit".into()),
+        Instruction::DefLabel(".sign".into()),
+        Instruction::Test(reg(R8), reg(R8)),
+        Instruction::JumpZero(".write".into()),
+        Instruction::MoveImm(reg(RDX), b'-' as u64),
+        Instruction::Sub(reg(RSI), reg(RCX)),
+        Instruction::Store(reg(RSI), 0, digit),
+        Instruction::DefLabel(".write".into()),
+        Instruction::MoveReg(reg(RDX), reg(RBP)),
+        Instruction::Sub(reg(RDX), reg(RSI)),
+        Instruction::MoveImm(reg(RDI), fd),
+        Instruction::MoveImm(reg(RAX), WRITE),
+        Instruction::Syscall,
+        Instruction::MoveImm(reg(RAX), 0),
+    ]
+}
+
+/// `arg(i: u64) -> str` returned through rdi, an empty `str` when there is
+/// no argument `i`. The `str` points into argv.
+fn arg() -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![
+        Instruction::MoveReg(reg(R8), reg(RDI)),
+        // There are only jumps for signed comparisons, so check i > -1 too.
+        Instruction::MoveImm(reg(RCX), -1i64 as u64),
+        Instruction::Cmp(reg(RSI), reg(RCX)),
+        Instruction::JumpLessEq(".empty".into()),
+        Instruction::LoadGlobal(reg(RAX), ARGC.into()),
+        Instruction::Load(reg(RAX), reg(RAX), 0),
+        Instruction::Cmp(reg(RAX), reg(RSI)),
+        Instruction::JumpLessEq(".empty".into()),
+        Instruction::LoadGlobal(reg(RAX), ARGV.into()),
+        Instruction::Load(reg(RAX), reg(RAX), 0),
+        Instruction::MoveImm(reg(RCX), 8),
+        Instruction::Mul(reg(RSI), reg(RCX)),
+        Instruction::Add(reg(RSI), reg(RAX)),
+        Instruction::Load(reg(RSI), reg(RSI), 0),
+        Instruction::Call(STRLEN.into()),
+        Instruction::Jump(".done".into()),
+        Instruction::DefLabel(".empty".into()),
+        Instruction::LoadStr(reg(RSI), "".into()),
+        Instruction::MoveImm(reg(RDX), 0),
+        Instruction::DefLabel(".done".into()),
+    ];
+    code.extend(store_str(R8));
+    code
+}
+
+/// `env(name: str) -> str` returned through rdi, the value of the first
+/// `name=value` in envp or an empty `str`. The `str` points into envp.
+fn env() -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![
+        Instruction::Push(reg(RDI)),
+        Instruction::Load(reg(RDI), reg(RSI), 0),
+        Instruction::Load(reg(R9), reg(RSI), 8),
+        Instruction::LoadGlobal(reg(RAX), ENVP.into()),
+        Instruction::Load(reg(R8), reg(RAX), 0),
+        Instruction::MoveImm(reg(RCX), 1),
+        Instruction::MoveImm(reg(R11), 8),
+        Instruction::Test(reg(R8), reg(R8)),
+        Instruction::JumpZero(".empty".into()),
+        // r8 points at the entry in rsi, r10 indexes both it and the name.
+        Instruction::DefLabel(".entry".into()),
+        Instruction::Load(reg(RSI), reg(R8), 0),
+        Instruction::Test(reg(RSI), reg(RSI)),
+        Instruction::JumpZero(".empty".into()),
+        Instruction::MoveImm(reg(R10), 0),
+        Instruction::DefLabel(".compare".into()),
+        Instruction::Cmp(reg(R9), reg(R10)),
+        Instruction::JumpLessEq(".equals".into()),
+        Instruction::MoveReg(reg(RDX), reg(RSI)),
+        Instruction::Add(reg(RDX), reg(R10)),
+    ];
+    code.extend(load_byte(RDX, RDX));
+    code.extend([
+        Instruction::MoveReg(reg(RAX), reg(RDI)),
+        Instruction::Add(reg(RAX), reg(R10)),
+    ]);
+    code.extend(load_byte(RAX, RAX));
+    code.extend([
+        Instruction::Sub(reg(RAX), reg(RDX)),
+        Instruction::Test(reg(RAX), reg(RAX)),
+        Instruction::JumpZero(".same".into()),
+        Instruction::Jump(".next".into()),
+        Instruction::DefLabel(".same".into()),
+        Instruction::Add(reg(R10), reg(RCX)),
+        Instruction::Jump(".compare".into()),
+        Instruction::DefLabel(".equals".into()),
+        Instruction::MoveReg(reg(RDX), reg(RSI)),
+        Instruction::Add(reg(RDX), reg(R10)),
+    ]);
+    code.extend(load_byte(RAX, RDX));
+    code.extend([
+        Instruction::MoveImm(reg(R10), b'=' as u64),
+        Instruction::Sub(reg(RAX), reg(R10)),
+        Instruction::Test(reg(RAX), reg(RAX)),
+        Instruction::JumpZero(".found".into()),
+        Instruction::DefLabel(".next".into()),
+        Instruction::Add(reg(R8), reg(R11)),
+        Instruction::Jump(".entry".into()),
+        Instruction::DefLabel(".found".into()),
+        Instruction::MoveReg(reg(RSI), reg(RDX)),
+        Instruction::Add(reg(RSI), reg(RCX)),
+        Instruction::Call(STRLEN.into()),
+        Instruction::Jump(".done".into()),
+        Instruction::DefLabel(".empty".into()),
+        Instruction::LoadStr(reg(RSI), "".into()),
+        Instruction::MoveImm(reg(RDX), 0),
+        Instruction::DefLabel(".done".into()),
+        Instruction::Pop(reg(R8)),
+    ]);
+    code.extend(store_str(R8));
+    code
+}
+
+/// Stores the `str` of rsi and rdx at `to` and returns its address.
+fn store_str(to: X86Reg64) -> [Instruction; 3] {
+    use X86Reg64::*;
+    [
+        Instruction::Store(reg(to), 0, reg(RSI)),
+        Instruction::Store(reg(to), 8, reg(RDX)),
+        Instruction::MoveReg(reg(RAX), reg(to)),
+    ]
+}
+
+/// Counts the bytes of the nul terminated string at rsi into rdx, only
+/// clobbering rax and rcx.
+fn strlen() -> Vec<Instruction> {
+    use X86Reg64::*;
+    let mut code = vec![
+        Instruction::MoveImm(reg(RDX), 0),
+        Instruction::MoveImm(reg(RCX), 1),
+        Instruction::DefLabel(".count".into()),
+        Instruction::MoveReg(reg(RAX), reg(RSI)),
+        Instruction::Add(reg(RAX), reg(RDX)),
+    ];
+    code.extend(load_byte(RAX, RAX));
+    code.extend([
+        Instruction::Test(reg(RAX), reg(RAX)),
+        Instruction::JumpZero(".done".into()),
+        Instruction::Add(reg(RDX), reg(RCX)),
+        Instruction::Jump(".count".into()),
+        Instruction::DefLabel(".done".into()),
+    ]);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn labels(code: &[Instruction]) -> Vec<&str> {
+        code.iter()
+            .filter_map(|i| match i {
+                Instruction::DefLabel(name) if !name.starts_with('.') => Some(name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_missing_functions_are_added() {
+        let code = with_runtime(vec![
+            Instruction::DefLabel("main".into()),
+            Instruction::Call("println".into()),
+            Instruction::TailJump("print_int".into()),
+            Instruction::DefLabel("print_int".into()),
+        ]);
+        assert_eq!(labels(&code), ["main", "print_int", "print", "println"]);
+        let code = with_runtime(vec![Instruction::DefLabel("main".into())]);
+        assert_eq!(labels(&code), ["main"]);
+    }
+
+    #[test]
+    fn start_calls_main() {
+        let code = with_start_func(vec![Instruction::DefLabel("main".into())]).unwrap();
+        assert!(code.contains(&Instruction::Call("main".into())));
+        assert_eq!(
+            labels(&with_runtime(code)),
+            ["main", crate::x86_64_linux::elf::ENTRY]
+        );
+        assert_eq!(
+            with_start_func(vec![]),
+            Err(vec!["program has no 'main' function".to_string()])
+        );
+    }
+
+    #[test]
+    fn builtins_pull_in_what_they_call() {
+        let code = with_runtime(vec![
+            Instruction::DefLabel("main".into()),
+            Instruction::Call("arg".into()),
+            Instruction::Call("env".into()),
+        ]);
+        assert_eq!(labels(&code), ["main", "arg", "env", STRLEN]);
+        let code = with_runtime(vec![
+            Instruction::DefLabel("main".into()),
+            Instruction::Call(INDEX_OUT_OF_BOUNDS.into()),
+        ]);
+        assert_eq!(labels(&code), ["main", INDEX_OUT_OF_BOUNDS, EPRINT_INT]);
+    }
+}
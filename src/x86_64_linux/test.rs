@@ -23,3 +23,4 @@ macro_rules! snapshot {
 
 snapshot!(binary, "testdata/snapshots/binary.a");
 snapshot!(ifelse, "testdata/snapshots/ifelse.a");
+snapshot!(var, "testdata/snapshots/var.a");
@@ -50,6 +50,18 @@ impl fmt::Display for X86Reg {
 }
 
 impl X86Reg {
+    pub fn as_64_bit(&self) -> X86Reg64 {
+        match self {
+            Self::RegRet(reg) => reg.as_64_bit(),
+            Self::RegParam(reg) => reg.as_64_bit(),
+            Self::Reg64(reg) => *reg,
+            Self::Reg32(reg) => reg.as_64_bit(),
+            Self::Reg16(reg) => reg.as_64_bit(),
+            Self::RegHigh8(reg) => reg.as_64_bit(),
+            Self::RegLow8(reg) => reg.as_64_bit(),
+        }
+    }
+
     pub fn as_32_bit(&self) -> X86Reg32 {
         match self {
             Self::RegRet(reg) => reg.as_64_bit().as_32_bit(),
@@ -195,6 +207,9 @@ impl From<usize> for X86RegParam {
 }
 
 impl X86RegParam {
+    /// Arguments past this many are passed on the stack.
+    pub const COUNT: usize = 6;
+
     pub fn as_64_bit(&self) -> X86Reg64 {
         match self {
             Self::RDI => X86Reg64::RDI,